@@ -10,7 +10,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 KvStore::open(temp_dir.path()).unwrap()
             },
-            |mut store| {
+            |store| {
                 for i in 1..(1 << 8) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -24,7 +24,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 SledKvsEngine::open(temp_dir.path()).unwrap()
             },
-            |mut store| {
+            |store| {
                 for i in 1..(1 << 8) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -0,0 +1,124 @@
+use crate::auth::Credentials;
+use crate::common::{read_message, AdminCommand, MessageBuf, Request, Response, ResponseBody};
+use crate::{Error, Result, ServerInfo};
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// AsyncKvsClient
+/// Connect to remote server and send commands to server without blocking a thread
+pub struct AsyncKvsClient {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    buf: MessageBuf,
+}
+
+impl AsyncKvsClient {
+    /// Connect to a kvs server
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            buf: MessageBuf::default(),
+        })
+    }
+
+    /// Authenticate the connection, required first when the server has an ACL.
+    pub async fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        match self.call(Request::Auth { credentials }).await? {
+            ResponseBody::Ok(_) => Ok(()),
+            ResponseBody::Err(e) => Err(Error::AuthError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Get value of key from remote server
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(Request::Get { key }).await? {
+            ResponseBody::Ok(val) => Ok(val),
+            ResponseBody::Err(e) => Err(Error::ClientGetError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Set key-value to remote server
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(Request::Set { key, value }).await? {
+            ResponseBody::Ok(_) => Ok(()),
+            ResponseBody::Err(e) => Err(Error::ClientSetError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Remove key-value to remote server
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(Request::Remove { key }).await? {
+            ResponseBody::Ok(_) => Ok(()),
+            ResponseBody::Err(e) => Err(Error::ClientRemoveError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Get the values of several keys at once, in the order of `keys`
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.call(Request::GetMany { keys }).await? {
+            ResponseBody::Values(values) => Ok(values),
+            ResponseBody::Err(e) => Err(Error::ClientGetError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// List the keys starting with `prefix`, sorted
+    pub async fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        match self.call(Request::Scan { prefix }).await? {
+            ResponseBody::Keys(keys) => Ok(keys),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Check that the server answers
+    pub async fn ping(&mut self) -> Result<()> {
+        match self.call(Request::Ping).await? {
+            ResponseBody::Ok(_) => Ok(()),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Ask the server about itself
+    pub async fn info(&mut self) -> Result<ServerInfo> {
+        match self.call(Request::Info).await? {
+            ResponseBody::Info(info) => Ok(*info),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Run a maintenance command on the server, returns its message if any
+    pub async fn admin(&mut self, cmd: AdminCommand) -> Result<Option<String>> {
+        match self.call(Request::Admin(cmd)).await? {
+            ResponseBody::Ok(msg) => Ok(msg),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> Result<ResponseBody> {
+        self.writer.write_all(&serde_json::to_vec(&req)?).await?;
+        let rsp: Response = read_message(&mut self.reader, &mut self.buf)
+            .await?
+            .ok_or_else(|| Error::IoError(io::ErrorKind::UnexpectedEof.into()))?;
+        match rsp.body {
+            ResponseBody::Busy => Err(Error::ServerBusy),
+            ResponseBody::NotLeader { leader } => Err(Error::NotLeader(leader)),
+            ResponseBody::RateLimited { retry_after_ms } => {
+                Err(Error::RateLimited(Duration::from_millis(retry_after_ms)))
+            }
+            body => Ok(body),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::auth::Acl;
+use crate::common::{read_message, MessageBuf, Request, Response, ResponseBody};
+use crate::context::{Context, Session};
+use crate::metrics::Metrics;
+use crate::net::Waker;
+use crate::ratelimit::{Quota, RateLimits};
+use crate::server::{respond, ConnectionLimits, Reload};
+use crate::shutdown::ShutdownHandle;
+use crate::trace::{self, RequestLog, Timings};
+use crate::{Error, KvsEngine, Result};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, debug_span, error, info, warn, Instrument};
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// AsyncKvsServer serves the native protocol on a tokio runtime.
+///
+/// Connections are tasks rather than threads, so idle clients cost no
+/// thread, while engine calls run on the blocking pool of the runtime.
+/// The queue time of slow requests is their wait for the blocking pool,
+/// their read time is not measured.
+pub struct AsyncKvsServer<T: KvsEngine> {
+    context: Context<T>,
+    shutdown_timeout: Duration,
+}
+
+impl<T: KvsEngine> AsyncKvsServer<T> {
+    /// New AsyncKvsServer with engine
+    pub fn new(engine: T) -> Self {
+        AsyncKvsServer {
+            context: Context::new(engine),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Require clients to authenticate, and check their requests against `acl`.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.context.set_acl(Some(acl));
+    }
+
+    /// Handle to stop the server once it runs
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.context.shutdown_handle().clone()
+    }
+
+    /// How long connections get to finish their requests on shutdown, 10 seconds by default.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Limit the number of connections and how long they may block.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.context.set_limits(limits);
+    }
+
+    /// Set whether values are logged and which requests count as slow.
+    pub fn set_request_log(&mut self, request_log: RequestLog) {
+        self.context.set_request_log(request_log);
+    }
+
+    /// Limit the rate of the requests of each client, clients going faster
+    /// are told to retry later.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.context.set_rate_limits(limits);
+    }
+
+    /// Refuse writes that would take the keys under a prefix over its quota.
+    ///
+    /// Reads the data already stored under the prefixes.
+    pub fn set_quotas(&mut self, quotas: Vec<Quota>) -> Result<()> {
+        self.context.set_quotas(quotas)
+    }
+
+    /// Serve admin requests without an ACL, off by default.
+    pub fn set_open_admin(&mut self, open: bool) {
+        self.context.set_open_admin(open);
+    }
+
+    /// Create the checkpoints asked for by `AdminCommand::Checkpoint` under `dir`.
+    pub fn set_checkpoint_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.context.set_checkpoint_dir(dir.into());
+    }
+
+    /// Carry out `AdminCommand::ReloadConfig` with `reload`, which reads the new settings.
+    pub fn on_reload(&mut self, reload: impl Fn() -> Result<Reload> + Send + Sync + 'static) {
+        self.context.set_reload(Arc::new(reload));
+    }
+
+    /// Report metrics to `metrics` rather than to a registry of the server's own.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.context.set_metrics(metrics);
+    }
+
+    /// Metrics of the server and its engine
+    pub fn metrics(&self) -> Metrics {
+        self.context.metrics().clone()
+    }
+
+    /// Run to listen the addr and process commands from client
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_listener(TcpListener::bind(addr).await?).await
+    }
+
+    /// Run to accept connections on `listener` until shut down
+    pub async fn run_listener(self, listener: TcpListener) -> Result<()> {
+        let shutdown = self.shutdown_handle();
+        shutdown.register(Waker::tcp(listener.local_addr()?));
+        let context = self.context.clone();
+        let expiry = tokio::task::spawn_blocking(move || context.start_expiry())
+            .await
+            .map_err(|e| Error::StringError(e.to_string()))??;
+        let (stopping_tx, stopping_rx) = watch::channel(false);
+
+        let mut connections = JoinSet::new();
+        let res = loop {
+            let accepted = listener.accept().await;
+            if shutdown.is_shutdown() {
+                break Ok(());
+            }
+            // Reap finished connections so the set does not grow forever.
+            while connections.try_join_next().is_some() {}
+            match accepted {
+                Ok((mut stream, peer)) => {
+                    let context = self.context.clone();
+                    let connection = match context.untracked_connection() {
+                        Some(connection) => connection,
+                        None => {
+                            info!("connection rejected, too many connections");
+                            let rsp = Response {
+                                body: ResponseBody::Busy,
+                            };
+                            connections.spawn(async move {
+                                let _ = stream.write_all(&serde_json::to_vec(&rsp).unwrap()).await;
+                            });
+                            continue;
+                        }
+                    };
+                    let stopping = stopping_rx.clone();
+                    let span = debug_span!("conn", id = connection.id());
+                    connections.spawn(
+                        async move {
+                            let _connection = connection;
+                            let peer = peer.ip().to_string();
+                            match handle(context.clone(), stream, peer, stopping).await {
+                                Err(Error::IoError(e)) if e.kind() == io::ErrorKind::TimedOut => {
+                                    info!("connection timed out");
+                                    context.record_timeout();
+                                }
+                                Err(e) => error!("handle err {:?}", e),
+                                Ok(()) => {}
+                            }
+                        }
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    error!("connection err {}", e);
+                    shutdown.shutdown();
+                    break Err(Error::Unknown);
+                }
+            }
+        };
+
+        info!("shutting down, waiting for connections");
+        let _ = stopping_tx.send(true);
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "connections still open after {:?}, closing them",
+                self.shutdown_timeout
+            );
+            connections.shutdown().await;
+        }
+        let context = self.context.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = expiry.join();
+            context.flush()
+        })
+        .await
+        .map_err(|e| Error::StringError(e.to_string()))??;
+        info!("engine flushed");
+        res
+    }
+}
+
+async fn handle<T: KvsEngine>(
+    context: Context<T>,
+    stream: TcpStream,
+    peer: String,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = MessageBuf::default();
+    let mut session = Session::new(peer);
+    let limits = context.limits();
+
+    loop {
+        // The idle timeout applies until a request starts arriving.
+        let read_timeout = if buf.is_blank() {
+            limits.idle_timeout
+        } else {
+            limits.read_timeout
+        };
+        // Only idle connections are closed on shutdown,
+        // a request already received is still answered.
+        let req = tokio::select! {
+            biased;
+            req = with_timeout(read_timeout, read_message::<_, Request>(&mut reader, &mut buf)) => req?,
+            _ = stopping.wait_for(|&stopping| stopping) => return Ok(()),
+        };
+        let req = match req {
+            Some(req) => req,
+            None => return Ok(()),
+        };
+
+        let desc = trace::describe_request(&req, context.request_log().redact_values);
+        let span = debug_span!("request", req = %desc);
+        debug!(parent: &span, "req {}", desc);
+
+        let blocking_context = context.clone();
+        let queued = Instant::now();
+        let (rsp, close, returned, mut timings) = tokio::task::spawn_blocking(move || {
+            let _span = span.enter();
+            let queue = queued.elapsed();
+            let started = Instant::now();
+            let (rsp, close) = respond(&blocking_context, &mut session, req);
+            let timings = Timings {
+                queue,
+                engine: started.elapsed(),
+                ..Timings::default()
+            };
+            (rsp, close, session, timings)
+        })
+        .await
+        .map_err(|e| Error::StringError(e.to_string()))?;
+        session = returned;
+
+        let written = Instant::now();
+        let rsp = serde_json::to_vec(&rsp)?;
+        with_timeout(limits.write_timeout, async {
+            writer.write_all(&rsp).await?;
+            Ok(())
+        })
+        .await?;
+        timings.write = written.elapsed();
+        context.log_slow_request(&desc, timings);
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+async fn with_timeout<F, R>(timeout: Option<Duration>, future: F) -> Result<R>
+where
+    F: Future<Output = Result<R>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::IoError(io::ErrorKind::TimedOut.into()))?,
+        None => future.await,
+    }
+}
@@ -0,0 +1,191 @@
+//! Authentication and per-key access control
+//!
+//! The ACL file lists principals and the operations they may run on key prefixes:
+//!
+//! ```text
+//! # principals: `user <name> <password>` or `token <name> <token>`
+//! user  alice   s3cret
+//! token ci-bot  9f8e7d6c
+//!
+//! # grants: `allow <name> <permission>[,<permission>] <prefix>`, `*` is every key
+//! allow alice   read,write  app/
+//! allow ci-bot  read        *
+//! ```
+//!
+//! Permissions are `read`, `write` and `admin`, `admin` implies the other two.
+
+use crate::err::Error;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Credentials sent by a client as the first message of a connection
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Credentials {
+    /// Token of a `token` principal
+    Token(String),
+    /// Name and password of a `user` principal
+    Password {
+        /// user name
+        user: String,
+        /// password
+        password: String,
+    },
+}
+
+// Secrets stay out of the request logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?}, .. }}", user),
+        }
+    }
+}
+
+/// Operation class checked against the ACL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read values and list keys
+    Read,
+    /// Set and remove keys
+    Write,
+    /// Administrative commands, implies read and write
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "admin" => Ok(Permission::Admin),
+            _ => Err(Error::StringError(format!("unknown permission {}", s))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Grant {
+    principal: String,
+    permissions: Vec<Permission>,
+    prefix: String,
+}
+
+/// Acl maps principals to the operations allowed on key prefixes
+#[derive(Debug, Default)]
+pub struct Acl {
+    passwords: HashMap<String, String>,
+    // token -> principal name
+    tokens: HashMap<String, String>,
+    grants: Vec<Grant>,
+}
+
+impl Acl {
+    /// Load an ACL file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        text.parse().map_err(|e| match e {
+            Error::StringError(msg) => Error::StringError(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// Name of the principal the credentials belong to
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<String> {
+        match credentials {
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .find(|(known, _)| constant_time_eq(known, token))
+                .map(|(_, name)| name.clone()),
+            Credentials::Password { user, password } => match self.passwords.get(user) {
+                Some(known) if constant_time_eq(known, password) => Some(user.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether the principal may perform `permission` on `key`
+    pub fn allows(&self, principal: &str, permission: Permission, key: &str) -> bool {
+        self.grants.iter().any(|grant| {
+            grant.principal == principal
+                && key.starts_with(&grant.prefix)
+                && grant
+                    .permissions
+                    .iter()
+                    .any(|&p| p == permission || p == Permission::Admin)
+        })
+    }
+
+    fn is_principal(&self, name: &str) -> bool {
+        self.passwords.contains_key(name) || self.tokens.values().any(|n| n == name)
+    }
+}
+
+impl FromStr for Acl {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut acl = Acl::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let err = |msg: &str| Error::StringError(format!("line {}: {}", i + 1, msg));
+            match fields.as_slice() {
+                [] => {}
+                ["user", name, password] => {
+                    if acl.is_principal(name) {
+                        return Err(err("duplicate principal"));
+                    }
+                    acl.passwords.insert(name.to_string(), password.to_string());
+                }
+                ["token", name, token] => {
+                    if acl.is_principal(name) || acl.tokens.contains_key(*token) {
+                        return Err(err("duplicate principal or token"));
+                    }
+                    acl.tokens.insert(token.to_string(), name.to_string());
+                }
+                ["allow", name, permissions, prefix] => {
+                    let permissions = permissions
+                        .split(',')
+                        .map(Permission::from_str)
+                        .collect::<Result<Vec<_>>>()
+                        .map_err(|_| err("unknown permission"))?;
+                    let prefix = if *prefix == "*" { "" } else { prefix };
+                    acl.grants.push(Grant {
+                        principal: name.to_string(),
+                        permissions,
+                        prefix: prefix.to_owned(),
+                    });
+                }
+                _ => return Err(err("expected `user`, `token` or `allow` statement")),
+            }
+        }
+        for grant in &acl.grants {
+            if !acl.is_principal(&grant.principal) {
+                return Err(Error::StringError(format!(
+                    "unknown principal {} in grant",
+                    grant.principal
+                )));
+            }
+        }
+        Ok(acl)
+    }
+}
+
+// Compare secrets without leaking the position of the first difference.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -1,8 +1,21 @@
-use clap::{arg, Arg, Command};
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
 use err::Result;
-use kvs::{err, KvsClient};
-use std::env;
+use kvs::auth::Credentials;
+use kvs::{err, tls, AdminCommand, KvsClient, ServerInfo};
+#[cfg(feature = "shell")]
+use rustyline::{error::ReadlineError, DefaultEditor};
+#[cfg(not(feature = "shell"))]
+use std::io::Write;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::Path;
 use std::process::exit;
+use std::time::Instant;
+#[cfg(feature = "shell")]
+use std::{env, path::PathBuf};
+
+// History of the shell, in the home directory unless `--history` is given
+#[cfg(feature = "shell")]
+const HISTORY_FILE: &str = ".kvs_client_history";
 
 fn main() -> Result<()> {
     env_logger::init();
@@ -10,28 +23,16 @@ fn main() -> Result<()> {
     let matches = cli().get_matches();
 
     match matches.subcommand() {
-        Some(("set", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").expect("addr");
-            let mut client = KvsClient::new(addr)?;
-            let key = sub_matches.get_one::<String>("KEY").expect("require");
-            let val = sub_matches.get_one::<String>("VALUE").expect("require");
-            client.set(key.to_owned(), val.to_owned())?;
-        }
-        Some(("get", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").expect("addr");
-            let mut client = KvsClient::new(addr)?;
-            let key = sub_matches.get_one::<String>("KEY").expect("require");
-            let rsp = client.get(key.to_owned())?;
-            match rsp {
-                Some(val) => println!("{}", val),
-                None => println!("Key not found"),
-            }
+        Some(("shell", sub_matches)) => shell(sub_matches)?,
+        Some(("admin", sub_matches)) => {
+            // the connection arguments come after the admin command
+            let (_, cmd_matches) = sub_matches.subcommand().expect("require");
+            let mut client = connect(cmd_matches)?;
+            execute(&mut client, "admin", sub_matches)?;
         }
-        Some(("rm", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").expect("addr");
-            let mut client = KvsClient::new(addr)?;
-            let key = sub_matches.get_one::<String>("KEY").expect("require");
-            client.remove(key.to_owned())?;
+        Some((name @ ("set" | "get" | "rm" | "scan" | "ping" | "info"), sub_matches)) => {
+            let mut client = connect(sub_matches)?;
+            execute(&mut client, name, sub_matches)?;
         }
         _ => {
             eprintln!("unimplemented");
@@ -41,6 +42,428 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+// Run the command `name` of the command line or of the shell.
+fn execute(client: &mut KvsClient, name: &str, matches: &ArgMatches) -> Result<()> {
+    let arg = |name: &str| {
+        matches
+            .get_one::<String>(name)
+            .cloned()
+            .ok_or_else(|| err::Error::StringError(format!("missing {}", name)))
+    };
+    match name {
+        "set" => client.set(arg("KEY")?, arg("VALUE")?)?,
+        "get" => match client.get(arg("KEY")?)? {
+            Some(val) => println!("{}", val),
+            None => println!("Key not found"),
+        },
+        "rm" => client.remove(arg("KEY")?)?,
+        "scan" => {
+            let prefix = matches.get_one::<String>("PREFIX").cloned();
+            for key in client.scan(prefix.unwrap_or_default())? {
+                println!("{}", key);
+            }
+        }
+        "ping" => {
+            client.ping()?;
+            println!("pong");
+        }
+        "info" => print_info(&client.info()?),
+        "admin" => {
+            let (name, cmd_matches) = matches.subcommand().expect("require");
+            let arg = |name| {
+                cmd_matches
+                    .get_one::<String>(name)
+                    .expect("require")
+                    .to_owned()
+            };
+            let cmd = match name {
+                "compact" => AdminCommand::Compact,
+                "flush" => AdminCommand::Flush,
+                "checkpoint" => AdminCommand::Checkpoint { path: arg("PATH") },
+                "reload" => AdminCommand::ReloadConfig,
+                "log-level" => AdminCommand::SetLogLevel {
+                    level: arg("LEVEL"),
+                },
+                _ => unreachable!(),
+            };
+            if let Some(msg) = client.admin(cmd)? {
+                println!("{}", msg);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// Run the commands typed in, or read from stdin when it is not a
+// terminal, over one connection. Failed commands are reported and the
+// next ones still run, the exit code is 1 if any failed.
+fn shell(matches: &ArgMatches) -> Result<()> {
+    let mut client = connect(matches)?;
+    let interactive = io::stdin().is_terminal();
+    let mut timing = interactive || matches.get_flag("timing");
+    let mut failed = false;
+    let mut run = |line: &str| -> Control {
+        let words = match split(line) {
+            Ok(words) if words.is_empty() => return Control::Continue,
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                failed = true;
+                return Control::Continue;
+            }
+        };
+        match words[0].as_str() {
+            "quit" | "exit" => return Control::Quit,
+            "timing" => {
+                match words.get(1).map(String::as_str) {
+                    Some("on") => timing = true,
+                    Some("off") => timing = false,
+                    _ => println!("timing is {}", if timing { "on" } else { "off" }),
+                }
+                return Control::Continue;
+            }
+            _ => {}
+        }
+        let matches = match shell_cli().try_get_matches_from(&words) {
+            Ok(matches) => matches,
+            Err(e) => {
+                // help is asked for rather than an error
+                if e.use_stderr() {
+                    eprint!("{}", e.render());
+                    failed = true;
+                } else {
+                    print!("{}", e.render());
+                }
+                return Control::Continue;
+            }
+        };
+        let (name, sub_matches) = matches.subcommand().expect("require");
+        let started = Instant::now();
+        if let Err(e) = execute(&mut client, name, sub_matches) {
+            eprintln!("error: {}", e);
+            failed = true;
+        }
+        if timing {
+            eprintln!("({:.3} ms)", started.elapsed().as_secs_f64() * 1000.0);
+        }
+        Control::Continue
+    };
+
+    if interactive {
+        edit(matches, &mut run)?;
+    } else {
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            // comments of scripts
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            if let Control::Quit = run(&line) {
+                break;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+    Ok(())
+}
+
+enum Control {
+    Continue,
+    Quit,
+}
+
+// Read the lines typed in with line editing and history.
+#[cfg(feature = "shell")]
+fn edit(matches: &ArgMatches, run: &mut impl FnMut(&str) -> Control) -> Result<()> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = matches
+        .get_one::<String>("history")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE)));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    let prompt = prompt(matches);
+    loop {
+        match editor.readline(&prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                if let Control::Quit = run(&line) {
+                    break;
+                }
+            }
+            // Ctrl-C drops the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("history not saved: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Read the lines typed in, built without the `shell` feature.
+#[cfg(not(feature = "shell"))]
+fn edit(matches: &ArgMatches, run: &mut impl FnMut(&str) -> Control) -> Result<()> {
+    let prompt = prompt(matches);
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        match lines.next() {
+            Some(line) => {
+                if let Control::Quit = run(&line?) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+fn prompt(matches: &ArgMatches) -> String {
+    match matches.get_one::<String>("unix") {
+        Some(path) => format!("kvs {}> ", path),
+        None => format!("kvs {}> ", matches.get_one::<String>("addr").expect("addr")),
+    }
+}
+
+#[cfg(feature = "shell")]
+fn readline_error(e: ReadlineError) -> err::Error {
+    err::Error::StringError(e.to_string())
+}
+
+// Words of a shell line, which double or single quotes keep together
+// and a backslash escapes a character in.
+fn split(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_owned()),
+            },
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_owned());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn print_info(info: &ServerInfo) {
+    println!("engine:       {}", info.engine);
+    println!("version:      {}", info.version);
+    println!("uptime:       {}", duration(info.uptime_secs));
+    println!("keys:         {}", info.keys);
+    println!("disk size:    {}", bytes(info.disk_bytes));
+    println!(
+        "garbage:      {} ({:.1}%)",
+        bytes(info.garbage_bytes),
+        info.garbage_ratio * 100.0
+    );
+    println!(
+        "connections:  {} active, {} total, {} rejected, {} timed out",
+        info.connections_active,
+        info.connections_total,
+        info.connections_rejected,
+        info.connections_timed_out
+    );
+    println!(
+        "requests:     {} ({} errors)",
+        info.requests_total, info.errors_total
+    );
+    let threads = info
+        .threads
+        .map_or_else(|| "-".to_owned(), |threads| threads.to_string());
+    println!("threads:      {}, {} queued", threads, info.queued_jobs);
+    let replication = &info.replication;
+    match &replication.leader {
+        Some(leader) => println!(
+            "replication:  following {} ({}), {} writes behind",
+            leader,
+            if replication.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            replication.lag_entries
+        ),
+        None => println!(
+            "replication:  log at {}, {} followers",
+            replication.log_seq, replication.followers
+        ),
+    }
+    if let Some(cluster) = &info.cluster {
+        let leader = cluster
+            .leader
+            .map_or_else(|| "unknown".to_owned(), |leader| leader.to_string());
+        println!(
+            "cluster:      node {} {:?} in term {}, leader {}, {} of {} entries committed",
+            cluster.id,
+            cluster.role,
+            cluster.term,
+            leader,
+            cluster.commit_index,
+            cluster.last_index
+        );
+    }
+}
+
+// e.g. `1d 2h 3m 4s`, leading zero units left out
+fn duration(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+    ];
+    let mut out: String = units
+        .iter()
+        .skip_while(|(n, _)| *n == 0)
+        .map(|(n, unit)| format!("{}{} ", n, unit))
+        .collect();
+    out.push_str(&format!("{}s", secs % 60));
+    out
+}
+
+fn bytes(n: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// Clients over TCP connect again and retry after a failure, which keeps a
+// shell usable across a restart of the server.
+fn connect(matches: &ArgMatches) -> Result<KvsClient> {
+    let credentials = match (
+        matches.get_one::<String>("token"),
+        matches.get_one::<String>("user"),
+        matches.get_one::<String>("password"),
+    ) {
+        (Some(token), _, _) => Some(Credentials::Token(token.to_owned())),
+        (None, Some(user), Some(password)) => Some(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        }),
+        _ => None,
+    };
+    #[cfg(unix)]
+    if let Some(path) = matches.get_one::<String>("unix") {
+        let mut client = KvsClient::connect_unix(path)?;
+        if let Some(credentials) = credentials {
+            client.auth(credentials)?;
+        }
+        return Ok(client);
+    }
+    let addr = matches.get_one::<String>("addr").expect("addr");
+    let mut builder = KvsClient::builder(addr.as_str());
+    if let Some(ca) = matches.get_one::<String>("tls-ca") {
+        let identity = match (
+            matches.get_one::<String>("tls-cert"),
+            matches.get_one::<String>("tls-key"),
+        ) {
+            (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+            _ => None,
+        };
+        let config = tls::client_config(ca, identity)?;
+        let server_name = match matches.get_one::<String>("tls-server-name") {
+            Some(name) => name.as_str(),
+            None => host(addr),
+        };
+        builder = builder.tls(server_name, config);
+    }
+    if let Some(credentials) = credentials {
+        builder = builder.credentials(credentials);
+    }
+    builder.build()
+}
+
+// Host part of `host:port`, without the brackets of an IPv6 address
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn connection_args() -> Vec<Arg> {
+    vec![
+        Arg::new("addr")
+            .short('a')
+            .long("addr")
+            .value_name("ADDR")
+            .default_value("127.0.0.1:4000")
+            .help("IP address"),
+        Arg::new("unix")
+            .long("unix")
+            .value_name("PATH")
+            .help("Unix domain socket path, used instead of the IP address"),
+        Arg::new("tls-ca")
+            .long("tls-ca")
+            .value_name("FILE")
+            .help("Connect with TLS, trusting the CA certificates in this PEM file"),
+        Arg::new("tls-cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .requires_all(["tls-ca", "tls-key"])
+            .help("Client certificate chain (PEM) for servers requiring client authentication"),
+        Arg::new("tls-key")
+            .long("tls-key")
+            .value_name("FILE")
+            .requires_all(["tls-ca", "tls-cert"])
+            .help("Private key (PEM) of the client certificate"),
+        Arg::new("tls-server-name")
+            .long("tls-server-name")
+            .value_name("NAME")
+            .requires("tls-ca")
+            .help("Name to verify the server certificate against, defaults to the host of ADDR"),
+        Arg::new("token")
+            .long("token")
+            .value_name("TOKEN")
+            .conflicts_with("user")
+            .help("Authenticate with an access token"),
+        Arg::new("user")
+            .long("user")
+            .value_name("NAME")
+            .requires("password")
+            .help("Authenticate as this user"),
+        Arg::new("password")
+            .long("password")
+            .value_name("PASSWORD")
+            .requires("user")
+            .help("Password of the user"),
+    ]
+}
+
 fn cli() -> Command {
     Command::new("kvs-client")
         .about("A key-value store client")
@@ -49,46 +472,97 @@ fn cli() -> Command {
         .subcommand_required(true)
         .arg_required_else_help(true)
         .allow_external_subcommands(true)
+        .subcommands(commands(connection_args))
         .subcommand(
-            Command::new("set")
-                .about("set key and value to store")
-                .args([arg!([KEY] "key"), arg!([VALUE] "value")])
-                .arg_required_else_help(true)
+            Command::new("shell")
+                .about("run commands typed in or read from stdin over one connection")
                 .arg(
-                    Arg::new("addr")
-                        .short('a')
-                        .long("addr")
-                        .value_name("ADDR")
-                        .default_value("127.0.0.1:4000")
-                        .help("IP address"),
-                ),
-        )
-        .subcommand(
-            Command::new("get")
-                .about("get value from store")
-                .arg(arg!([KEY] "key"))
-                .arg_required_else_help(true)
+                    Arg::new("history")
+                        .long("history")
+                        .value_name("FILE")
+                        .help("History file, ~/.kvs_client_history by default"),
+                )
                 .arg(
-                    Arg::new("addr")
-                        .short('a')
-                        .long("addr")
-                        .value_name("ADDR")
-                        .default_value("127.0.0.1:4000")
-                        .help("IP address"),
-                ),
+                    Arg::new("timing")
+                        .long("timing")
+                        .action(ArgAction::SetTrue)
+                        .help("Print how long each command took, always done in a terminal"),
+                )
+                .args(connection_args()),
         )
+}
+
+// Commands of the shell, connected already.
+fn shell_cli() -> Command {
+    Command::new("kvs")
+        .no_binary_name(true)
+        .subcommand_required(true)
+        .disable_version_flag(true)
+        .subcommands(commands(Vec::new))
         .subcommand(
-            Command::new("rm")
-                .about("remove a pair of key-value")
-                .arg(arg!([KEY] "key"))
-                .arg_required_else_help(true)
-                .arg(
-                    Arg::new("addr")
-                        .short('a')
-                        .long("addr")
-                        .value_name("ADDR")
-                        .default_value("127.0.0.1:4000")
-                        .help("IP address"),
-                ),
+            Command::new("timing")
+                .about("show, or turn on or off with `on` or `off`, the timing of commands"),
         )
+        .subcommand(Command::new("quit").alias("exit").about("leave the shell"))
+}
+
+// Commands with the arguments of their connection.
+fn commands(connection_args: fn() -> Vec<Arg>) -> Vec<Command> {
+    vec![
+        Command::new("set")
+            .about("set key and value to store")
+            .args([arg!([KEY] "key"), arg!([VALUE] "value")])
+            .arg_required_else_help(true)
+            .args(connection_args()),
+        Command::new("get")
+            .about("get value from store")
+            .arg(arg!([KEY] "key"))
+            .arg_required_else_help(true)
+            .args(connection_args()),
+        Command::new("rm")
+            .about("remove a pair of key-value")
+            .arg(arg!([KEY] "key"))
+            .arg_required_else_help(true)
+            .args(connection_args()),
+        Command::new("scan")
+            .about("list the keys starting with a prefix")
+            .arg(arg!([PREFIX] "prefix, every key if left out"))
+            .args(connection_args()),
+        Command::new("ping")
+            .about("check that the server answers")
+            .args(connection_args()),
+        Command::new("info")
+            .about("show the engine, data size and connections of the server")
+            .args(connection_args()),
+        Command::new("admin")
+            .about("administer the server, requires the admin permission")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("compact")
+                    .about("compact the engine's log now")
+                    .args(connection_args()),
+            )
+            .subcommand(
+                Command::new("flush")
+                    .about("sync the engine's writes to disk")
+                    .args(connection_args()),
+            )
+            .subcommand(
+                Command::new("checkpoint")
+                    .about("copy the data to a new directory on the server")
+                    .arg(arg!(<PATH> "directory to create under the checkpoint directory of the server"))
+                    .args(connection_args()),
+            )
+            .subcommand(
+                Command::new("reload")
+                    .about("read the server's config file again")
+                    .args(connection_args()),
+            )
+            .subcommand(
+                Command::new("log-level")
+                    .about("set the maximum level of the server's log")
+                    .arg(arg!(<LEVEL> "off, error, warn, info, debug or trace"))
+                    .args(connection_args()),
+            ),
+    ]
 }
@@ -0,0 +1,154 @@
+use clap::builder::RangedU64ValueParser;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use env_logger::Env;
+use kvs::auth::Credentials;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{proxy, ConnectionLimits, HashRing, KvsProxy, ShutdownHandle};
+use log::{error, info};
+use std::process::exit;
+use std::time::Duration;
+
+fn main() {
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    let matches = cli().get_matches();
+
+    if let Some(("reshard", sub_matches)) = matches.subcommand() {
+        let from = ring(sub_matches, "from");
+        let to = ring(sub_matches, "to");
+        let credentials = sub_matches
+            .get_one::<String>("token")
+            .map(|token| Credentials::Token(token.to_owned()));
+        match proxy::reshard(&from, &to, credentials) {
+            Ok(moved) => println!("{} keys moved", moved),
+            Err(e) => {
+                error!("reshard err {}", e);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let addr = matches.get_one::<String>("addr").expect("default");
+    let ring = ring(&matches, "backend");
+    info!("kvs-proxy - {}", env!("CARGO_PKG_VERSION"));
+    info!("ADDR {}", addr);
+    info!("BACKENDS {}", ring.nodes().join(", "));
+    let threads = matches
+        .get_one::<usize>("threads")
+        .copied()
+        .unwrap_or_else(num_cpus::get);
+    info!("THREADS {}", threads);
+    let thread_pool = SharedQueueThreadPool::new(threads).expect("init pool");
+    let mut proxy = KvsProxy::new(ring, thread_pool);
+    let secs = |name| {
+        matches
+            .get_one::<u64>(name)
+            .map(|secs| Duration::from_secs(*secs))
+    };
+    proxy.set_limits(ConnectionLimits {
+        max_connections: matches.get_one::<usize>("max-connections").copied(),
+        idle_timeout: secs("idle-timeout"),
+        read_timeout: secs("read-timeout"),
+        write_timeout: secs("write-timeout"),
+    });
+    handle_signals(proxy.shutdown_handle());
+    if let Err(e) = proxy.run(addr) {
+        error!("proxy err {}", e);
+        exit(1);
+    }
+}
+
+fn ring(matches: &ArgMatches, name: &str) -> HashRing {
+    let nodes = matches.get_many::<String>(name).expect("require");
+    HashRing::new(nodes).unwrap_or_else(|e| {
+        error!("{} err {}", name, e);
+        exit(1);
+    })
+}
+
+fn handle_signals(shutdown: ShutdownHandle) {
+    ctrlc::set_handler(move || {
+        if shutdown.is_shutdown() {
+            exit(1);
+        }
+        info!("signal received, shutting down");
+        shutdown.shutdown();
+    })
+    .expect("set signal handler");
+}
+
+fn backends(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .value_name("ADDR")
+        .action(ArgAction::Append)
+        .value_delimiter(',')
+        .required(true)
+        .help(help)
+}
+
+fn cli() -> Command {
+    Command::new("kvs-proxy")
+        .about("A proxy sharding keys over key-value store servers")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(env!("CARGO_PKG_VERSION"))
+        .arg_required_else_help(true)
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .arg(
+            Arg::new("addr")
+                .short('a')
+                .long("addr")
+                .value_name("ADDR")
+                .default_value("127.0.0.1:4000")
+                .help("IP address"),
+        )
+        .arg(backends(
+            "backend",
+            "Address of a backend server, repeated or comma separated",
+        ))
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Number of threads serving the clients [default: the number of CPUs]"),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Refuse connections beyond this number with a busy reply"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections idle between requests for this long"),
+        )
+        .arg(
+            Arg::new("read-timeout")
+                .long("read-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections that stall for this long in the middle of a request"),
+        )
+        .arg(
+            Arg::new("write-timeout")
+                .long("write-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections whose responses cannot be written for this long"),
+        )
+        .subcommand(
+            Command::new("reshard")
+                .about("move the keys of the backends to their backends on a new ring")
+                .arg(backends("from", "Backends the keys are on"))
+                .arg(backends("to", "Backends the proxy will use"))
+                .arg(Arg::new("token").long("token").value_name("TOKEN").help(
+                    "Authenticate with an access token, allowed to read and write every key",
+                )),
+        )
+}
@@ -1,51 +1,419 @@
-use clap::{Arg, Command};
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, KvsServer, SledKvsEngine};
-use log::{error, info};
+use clap::builder::RangedU64ValueParser;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use env_logger::Env;
+use kvs::auth::Acl;
+use kvs::config::{ServerConfig, ThreadPoolKind, TlsConfig};
+use kvs::net::Listener;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+#[cfg(feature = "async")]
+use kvs::AsyncKvsServer;
+use kvs::{
+    tls, Cluster, KvStore, KvStoreOptions, KvsEngine, KvsServer, Protocol, Reload, ShutdownHandle,
+    SledKvsEngine,
+};
+use log::{error, info, LevelFilter};
 use std::env::current_dir;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
 use std::{env, fs};
 
 fn main() {
-    env_logger::init();
-
     let matches = cli().get_matches();
-    let addr = matches.get_one::<String>("addr").unwrap();
-    let engine_name = matches.get_one::<String>("engine").unwrap();
-    info!("kvs - {}", env!("CARGO_PKG_VERSION"));
-    info!("ADDR {}", addr);
-    info!("ENGINE-NAME {}", engine_name);
-
-    let engine_file = current_dir().expect("cur die").join("engine");
-    if !engine_file.exists() {
-        fs::write(current_dir().unwrap().join("engine"), engine_name).expect("write engine err");
-    }
-    if fs::read_to_string(engine_file).expect("read engines file") != *engine_name {
-        error!("unmatched engines");
+    let config = config(&matches).unwrap_or_else(|e| {
+        eprintln!("config err {}", e);
         exit(1);
+    });
+    if matches.get_flag("print-config") {
+        print!("{}", config.to_toml());
+        return;
     }
 
-    let thread_pool = SharedQueueThreadPool::new(num_cpus::get()).expect("init pool");
-    match engine_name.as_str() {
-        "sled" => {
-            let mut server = KvsServer::new(
-                SledKvsEngine::open(current_dir().unwrap()).unwrap(),
-                thread_pool,
-            );
-            server.run(addr).unwrap();
+    init_logger(&config.log.level);
+    info!("kvs - {}", env!("CARGO_PKG_VERSION"));
+    info!("ADDR {}", config.listen.addr);
+    let data_dir = match &config.data_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir).expect("create data dir");
+            dir.clone()
         }
+        None => current_dir().expect("cur dir"),
+    };
+    info!("DATA-DIR {}", data_dir.display());
+    let engine = engine(&data_dir, config.engine.as_deref());
+    info!("ENGINE-NAME {}", engine);
+    let cluster = config
+        .cluster
+        .as_ref()
+        .map(|cluster| cluster.cluster(&data_dir));
+
+    let sync_writes = config.storage.sync_writes;
+    match engine.as_str() {
+        "sled" => run(
+            SledKvsEngine::open_with(data_dir, sync_writes.unwrap_or(true)).unwrap(),
+            &config,
+            cluster,
+            &matches,
+        ),
         _ => {
-            let mut server = KvsServer::new(
-                KvStore::open(env::current_dir().unwrap()).unwrap(),
-                thread_pool,
+            let options = KvStoreOptions {
+                sync_writes: sync_writes.unwrap_or(false),
+                compaction_threshold: config.storage.compaction_threshold,
+            };
+            run(
+                KvStore::open_with(data_dir, options).unwrap(),
+                &config,
+                cluster,
+                &matches,
+            )
+        }
+    }
+}
+
+// The engine the data directory was created with, recorded in its `engine` marker file.
+// A new directory gets the requested engine, `kvs` if none.
+fn engine(data_dir: &Path, requested: Option<&str>) -> String {
+    let engine_file = data_dir.join("engine");
+    let existing = engine_file
+        .exists()
+        .then(|| fs::read_to_string(&engine_file).expect("read engines file"));
+    match (requested, existing) {
+        (Some(requested), Some(existing)) if requested != existing => {
+            error!(
+                "unmatched engines, {} holds {} data",
+                data_dir.display(),
+                existing
             );
-            server.run(addr).unwrap();
+            exit(1);
+        }
+        (_, Some(existing)) => existing,
+        (requested, None) => {
+            let engine = requested.unwrap_or("kvs");
+            fs::write(&engine_file, engine).expect("write engine err");
+            engine.to_owned()
+        }
+    }
+}
+
+// The config file, if any, with the flags given on the command line applied over it.
+fn config(matches: &ArgMatches) -> kvs::Result<ServerConfig> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    let string = |name| matches.get_one::<String>(name).cloned();
+    let secs = |name| matches.get_one::<u64>(name).copied();
+
+    if let Some(engine) = string("engine") {
+        config.engine = Some(engine.to_lowercase());
+    }
+    if let Some(data_dir) = string("data-dir") {
+        config.data_dir = Some(data_dir.into());
+    }
+    if let Some(kind) = string("thread-pool") {
+        config.thread_pool.kind = kind.parse().unwrap();
+    }
+    if let Some(threads) = matches.get_one::<usize>("threads") {
+        config.thread_pool.size = *threads;
+    }
+    let listen = &mut config.listen;
+    if let Some(addr) = string("addr") {
+        listen.addr = addr;
+    }
+    listen.resp = string("resp-addr").or(listen.resp.take());
+    listen.http = string("http-addr").or(listen.http.take());
+    listen.memcache = string("memcache-addr").or(listen.memcache.take());
+    listen.metrics = string("metrics-addr").or(listen.metrics.take());
+    listen.unix = string("unix").map(PathBuf::from).or(listen.unix.take());
+    listen.unix_mode = string("unix-mode").or(listen.unix_mode.take());
+    if let (Some(cert), Some(key)) = (string("tls-cert"), string("tls-key")) {
+        config.tls = Some(TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: string("tls-client-ca").map(PathBuf::from),
+        });
+    }
+    if let Some(acl) = string("acl") {
+        config.auth.acl = Some(acl.into());
+    }
+    if matches.get_flag("open-admin") {
+        config.admin.open = true;
+    }
+    if let Some(dir) = string("checkpoint-dir") {
+        config.admin.checkpoint_dir = Some(dir.into());
+    }
+    let limits = &mut config.limits;
+    if let Some(max) = matches.get_one::<usize>("max-connections") {
+        limits.max_connections = Some(*max);
+    }
+    limits.idle_timeout = secs("idle-timeout").or(limits.idle_timeout);
+    limits.read_timeout = secs("read-timeout").or(limits.read_timeout);
+    limits.write_timeout = secs("write-timeout").or(limits.write_timeout);
+    if let Some(secs) = secs("shutdown-timeout") {
+        limits.shutdown_timeout = secs;
+    }
+    let rate_limit = &mut config.rate_limit;
+    let per_sec = |name| matches.get_one::<u64>(name).copied();
+    rate_limit.ops_per_sec = per_sec("ops-per-sec").or(rate_limit.ops_per_sec);
+    rate_limit.bytes_per_sec = per_sec("bytes-per-sec").or(rate_limit.bytes_per_sec);
+    let replication = &mut config.replication;
+    replication.leader = string("leader").or(replication.leader.take());
+    replication.token = string("leader-token").or(replication.token.take());
+    if let Some(capacity) = matches.get_one::<usize>("replication-log") {
+        replication.log_capacity = *capacity;
+    }
+    if let Some(id) = matches.get_one::<u64>("node-id") {
+        let cluster = config.cluster.as_mut().ok_or_else(|| {
+            kvs::Error::StringError("--node-id needs a [cluster] section".to_owned())
+        })?;
+        cluster.id = *id;
+        cluster.validate()?;
+    }
+    let log = &mut config.log;
+    if matches.get_flag("log-values") {
+        log.redact_values = false;
+    }
+    log.slow_threshold_ms = matches
+        .get_one::<u64>("slow-threshold")
+        .copied()
+        .or(log.slow_threshold_ms);
+    Ok(config)
+}
+
+fn init_logger(level: &str) {
+    match max_log_level(level) {
+        Some(max) => {
+            env_logger::Builder::new()
+                .filter_level(LevelFilter::Trace)
+                .init();
+            log::set_max_level(max);
+        }
+        None => env_logger::init_from_env(Env::default().default_filter_or(level)),
+    }
+}
+
+// A plain level is applied as the maximum level rather than as a filter of the logger,
+// so that it can be raised at run time. `RUST_LOG` and filters by module are fixed.
+fn max_log_level(level: &str) -> Option<LevelFilter> {
+    if env::var_os(env_logger::DEFAULT_FILTER_ENV).is_some() {
+        return None;
+    }
+    level.parse().ok()
+}
+
+// Reads the config file again for `kvs-client admin reload`.
+fn reloader(matches: &ArgMatches) -> impl Fn() -> kvs::Result<Reload> + Send + Sync + 'static {
+    let matches = matches.clone();
+    move || {
+        let config = config(&matches)?;
+        if let Some(max) = max_log_level(&config.log.level) {
+            log::set_max_level(max);
+        }
+        let acl = config.auth.acl.as_ref().map(Acl::load).transpose()?;
+        info!("config reloaded");
+        Ok(Reload {
+            acl,
+            limits: config.limits.connection_limits(),
+            request_log: config.log.request_log(),
+            rate_limits: config.rate_limit.rate_limits(),
+            quotas: config.quotas(),
+        })
+    }
+}
+
+fn run<E: KvsEngine>(
+    engine: E,
+    config: &ServerConfig,
+    cluster: Option<Cluster>,
+    matches: &ArgMatches,
+) {
+    #[cfg(feature = "async")]
+    if matches.get_flag("async") {
+        return run_async(engine, config, matches);
+    }
+    let size = config.thread_pool.size;
+    info!("THREAD-POOL {:?} {}", config.thread_pool.kind, size);
+    let pool = config.thread_pool.kind;
+    match pool {
+        ThreadPoolKind::Naive => {
+            serve(engine, NaiveThreadPool::new(size), config, cluster, matches)
+        }
+        ThreadPoolKind::SharedQueue => serve(
+            engine,
+            SharedQueueThreadPool::new(size),
+            config,
+            cluster,
+            matches,
+        ),
+        ThreadPoolKind::Rayon => {
+            serve(engine, RayonThreadPool::new(size), config, cluster, matches)
         }
     }
 }
 
+fn serve<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    thread_pool: kvs::Result<P>,
+    config: &ServerConfig,
+    cluster: Option<Cluster>,
+    matches: &ArgMatches,
+) {
+    let thread_pool = thread_pool.expect("init pool");
+    let mut server = KvsServer::new(engine, thread_pool);
+    if let Some(acl) = load_acl(config) {
+        server.set_acl(acl);
+    }
+    server.set_open_admin(config.admin.open);
+    if let Some(dir) = &config.admin.checkpoint_dir {
+        server.set_checkpoint_dir(dir);
+    }
+    server.on_reload(reloader(matches));
+    let listen = &config.listen;
+    if let Some(resp_addr) = &listen.resp {
+        info!("RESP-ADDR {}", resp_addr);
+        server.listen(Protocol::Resp, resp_addr).unwrap();
+    }
+    if let Some(http_addr) = &listen.http {
+        info!("HTTP-ADDR {}", http_addr);
+        server.listen(Protocol::Http, http_addr).unwrap();
+    }
+    if let Some(memcache_addr) = &listen.memcache {
+        info!("MEMCACHE-ADDR {}", memcache_addr);
+        server.listen(Protocol::Memcache, memcache_addr).unwrap();
+    }
+    if let Some(metrics_addr) = &listen.metrics {
+        info!("METRICS-ADDR {}", metrics_addr);
+        server.listen(Protocol::Metrics, metrics_addr).unwrap();
+    }
+    #[cfg(unix)]
+    if let Some(path) = &listen.unix {
+        info!("UNIX {}", path.display());
+        let mode = listen.unix_mode.as_ref().map(|mode| {
+            u32::from_str_radix(mode, 8).unwrap_or_else(|_| {
+                error!("invalid socket mode {}", mode);
+                exit(1);
+            })
+        });
+        let listener = Listener::bind_unix(path, mode).unwrap();
+        server.add_listener(Protocol::Kvs, listener);
+    }
+    server.set_shutdown_timeout(Duration::from_secs(config.limits.shutdown_timeout));
+    let limits = config.limits.connection_limits();
+    info!("LIMITS {:?}", limits);
+    server.set_limits(limits);
+    server.set_request_log(config.log.request_log());
+    server.set_rate_limits(config.rate_limit.rate_limits());
+    if let Err(e) = server.set_quotas(config.quotas()) {
+        error!("quota err {:?}", e);
+        exit(1);
+    }
+    let replication = &config.replication;
+    server.set_replication_log(replication.log_capacity);
+    if let Some(leader) = &replication.leader {
+        info!("LEADER {}", leader);
+        server.follow(leader, replication.credentials());
+    }
+    if let Some(cluster) = cluster {
+        if replication.leader.is_some() {
+            error!("a cluster node cannot follow a leader");
+            exit(1);
+        }
+        info!("CLUSTER node {} of {}", cluster.id, cluster.nodes.len());
+        if let Err(e) = server.set_cluster(cluster) {
+            error!("cluster err {:?}", e);
+            exit(1);
+        }
+    }
+    handle_signals(server.shutdown_handle());
+
+    let addr = &listen.addr;
+    let listener = match &config.tls {
+        Some(tls) => {
+            let client_ca = tls.client_ca.as_deref();
+            info!("TLS enabled, client auth {}", client_ca.is_some());
+            let tls_config =
+                tls::server_config(&tls.cert, &tls.key, client_ca).unwrap_or_else(|e| {
+                    error!("tls config err {:?}", e);
+                    exit(1);
+                });
+            Listener::bind_tls(addr, tls_config).unwrap()
+        }
+        None => Listener::bind(addr).unwrap(),
+    };
+    server.run_listener(listener).unwrap();
+    drop(server);
+    info!("stopped");
+}
+
+#[cfg(feature = "async")]
+fn run_async<E: KvsEngine>(engine: E, config: &ServerConfig, matches: &ArgMatches) {
+    info!("ASYNC");
+    let listen = &config.listen;
+    if listen.resp.is_some()
+        || listen.http.is_some()
+        || listen.memcache.is_some()
+        || listen.metrics.is_some()
+        || listen.unix.is_some()
+        || config.tls.is_some()
+    {
+        error!("async serves only the native protocol on a TCP address");
+        exit(1);
+    }
+    if config.replication.leader.is_some() || config.cluster.is_some() {
+        error!("async does not replicate");
+        exit(1);
+    }
+    let runtime = tokio::runtime::Runtime::new().expect("init runtime");
+    let mut server = AsyncKvsServer::new(engine);
+    if let Some(acl) = load_acl(config) {
+        server.set_acl(acl);
+    }
+    server.set_open_admin(config.admin.open);
+    if let Some(dir) = &config.admin.checkpoint_dir {
+        server.set_checkpoint_dir(dir);
+    }
+    server.on_reload(reloader(matches));
+    server.set_shutdown_timeout(Duration::from_secs(config.limits.shutdown_timeout));
+    let limits = config.limits.connection_limits();
+    info!("LIMITS {:?}", limits);
+    server.set_limits(limits);
+    server.set_request_log(config.log.request_log());
+    server.set_rate_limits(config.rate_limit.rate_limits());
+    if let Err(e) = server.set_quotas(config.quotas()) {
+        error!("quota err {:?}", e);
+        exit(1);
+    }
+    handle_signals(server.shutdown_handle());
+
+    runtime.block_on(server.run(&listen.addr)).unwrap();
+    info!("stopped");
+}
+
+fn load_acl(config: &ServerConfig) -> Option<Acl> {
+    let path = config.auth.acl.as_ref()?;
+    info!("ACL {}", path.display());
+    let acl = Acl::load(path).unwrap_or_else(|e| {
+        error!("acl err {}", e);
+        exit(1);
+    });
+    Some(acl)
+}
+
+// The first SIGINT/SIGTERM shuts the server down gracefully, a second one exits at once.
+fn handle_signals(shutdown: ShutdownHandle) {
+    ctrlc::set_handler(move || {
+        if shutdown.is_shutdown() {
+            error!("forced exit");
+            exit(1);
+        }
+        info!("signal received, shutting down");
+        shutdown.shutdown();
+    })
+    .expect("set signal handler");
+}
+
 fn cli() -> Command {
-    Command::new("kvs-server")
+    let cmd = Command::new("kvs-server")
         .about("A key-value store server")
         .version(env!("CARGO_PKG_VERSION"))
         .long_version(env!("CARGO_PKG_VERSION"))
@@ -55,17 +423,227 @@ fn cli() -> Command {
                 .short('a')
                 .long("addr")
                 .value_name("ADDR")
-                .default_value("127.0.0.1:4000")
                 .ignore_case(true)
-                .help("IP address"),
+                .help("IP address [default: 127.0.0.1:4000]"),
         )
         .arg(
             Arg::new("engine")
                 .short('e')
                 .long("engine")
                 .value_name("ENGINE-NAME")
-                .default_value("kvs")
-                .help("engine name")
+                .value_parser(["kvs", "sled"])
+                .help("engine name [default: the engine of the existing data, kvs if none]")
                 .ignore_case(true),
         )
+        .arg(
+            Arg::new("data-dir")
+                .short('d')
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory of the engine files [default: the working directory]"),
+        )
+        .arg(
+            Arg::new("thread-pool")
+                .long("thread-pool")
+                .value_name("KIND")
+                .value_parser(["naive", "shared-queue", "rayon"])
+                .help("Thread pool serving the connections [default: shared-queue]"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Number of threads of the pool [default: the number of CPUs]"),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("TOML configuration file, flags override its values"),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the configuration merged from the file and the flags, then exit"),
+        )
+        .arg(
+            Arg::new("resp-addr")
+                .long("resp-addr")
+                .value_name("ADDR")
+                .help("IP address to serve the Redis protocol (RESP2) on"),
+        )
+        .arg(
+            Arg::new("http-addr")
+                .long("http-addr")
+                .value_name("ADDR")
+                .help("IP address to serve the HTTP/JSON gateway on"),
+        )
+        .arg(
+            Arg::new("memcache-addr")
+                .long("memcache-addr")
+                .value_name("ADDR")
+                .help("IP address to serve the memcached ASCII protocol on"),
+        )
+        .arg(
+            Arg::new("metrics-addr")
+                .long("metrics-addr")
+                .value_name("ADDR")
+                .help("IP address to serve Prometheus metrics on, at /metrics"),
+        )
+        .arg(
+            Arg::new("unix")
+                .long("unix")
+                .value_name("PATH")
+                .help("Unix domain socket path to serve the native protocol on"),
+        )
+        .arg(
+            Arg::new("unix-mode")
+                .long("unix-mode")
+                .value_name("MODE")
+                .requires("unix")
+                .help("File permissions of the Unix domain socket, in octal (e.g. 660)"),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .requires("tls-key")
+                .help("Serve TLS on ADDR with this certificate chain (PEM)"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .value_name("FILE")
+                .requires("tls-cert")
+                .help("Private key (PEM) of the TLS certificate"),
+        )
+        .arg(
+            Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .value_name("FILE")
+                .requires("tls-cert")
+                .help("Require client certificates issued by the CAs in this PEM file"),
+        )
+        .arg(
+            Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seconds open connections get to finish on SIGINT/SIGTERM [default: 10]"),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Refuse connections beyond this number with a busy reply"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections idle between requests for this long"),
+        )
+        .arg(
+            Arg::new("read-timeout")
+                .long("read-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections that stall for this long in the middle of a request"),
+        )
+        .arg(
+            Arg::new("write-timeout")
+                .long("write-timeout")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Close connections whose responses cannot be written for this long"),
+        )
+        .arg(Arg::new("acl").long("acl").value_name("FILE").help(
+            "Require clients to authenticate, and check their requests against this ACL file",
+        ))
+        .arg(
+            Arg::new("open-admin")
+                .long("open-admin")
+                .action(ArgAction::SetTrue)
+                .help("Serve admin requests and followers without an ACL"),
+        )
+        .arg(
+            Arg::new("checkpoint-dir")
+                .long("checkpoint-dir")
+                .value_name("DIR")
+                .help("Directory to create the checkpoints of `kvs-client admin checkpoint` in"),
+        )
+        .arg(
+            Arg::new("ops-per-sec")
+                .long("ops-per-sec")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Tell clients sending more requests per second to retry later"),
+        )
+        .arg(
+            Arg::new("bytes-per-sec")
+                .long("bytes-per-sec")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help(
+                    "Tell clients sending more bytes of keys and values per second to retry later",
+                ),
+        )
+        .arg(Arg::new("leader").long("leader").value_name("ADDR").help(
+            "Follow the server at ADDR, applying its writes and refusing writes from clients",
+        ))
+        .arg(
+            Arg::new("leader-token")
+                .long("leader-token")
+                .value_name("TOKEN")
+                .help("Token to authenticate with on the leader, needs the admin permission"),
+        )
+        .arg(
+            Arg::new("replication-log")
+                .long("replication-log")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Latest writes kept for followers to catch up from [default: 10000]"),
+        )
+        .arg(
+            Arg::new("node-id")
+                .long("node-id")
+                .value_name("ID")
+                .value_parser(clap::value_parser!(u64))
+                .help("Identifier of the server among the nodes of the [cluster] section"),
+        )
+        .arg(
+            Arg::new("slow-threshold")
+                .long("slow-threshold")
+                .value_name("MS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Log requests taking longer than this many milliseconds, with their timings"),
+        )
+        .arg(
+            Arg::new("log-values")
+                .long("log-values")
+                .action(ArgAction::SetTrue)
+                .help("Show values in the request logs rather than their length"),
+        );
+    #[cfg(feature = "async")]
+    let cmd = cmd.arg(
+        Arg::new("async")
+            .long("async")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all([
+                "resp-addr",
+                "http-addr",
+                "memcache-addr",
+                "metrics-addr",
+                "unix",
+                "tls-cert",
+                "leader",
+            ])
+            .help("Serve the native protocol on a tokio runtime instead of the thread pool"),
+    );
+    cmd
 }
@@ -1,68 +1,625 @@
-use crate::common::{Request, Response, ResponseBody};
+use crate::auth::Credentials;
+use crate::common::{AdminCommand, Request, RequestId, Response, ResponseBody};
 use crate::err;
 use crate::err::Error;
+use crate::net::Stream;
+use crate::tls;
+use crate::tracking::{Lookup, NearCache, NearCacheStats};
+use crate::ServerInfo;
 use err::Result;
+use log::warn;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Redirects to the leader of a cluster followed by a request at most
+const MAX_REDIRECTS: usize = 3;
 
 /// KvsClient
 /// Connect to remote server and send commands to server
 pub struct KvsClient {
-    writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: BufWriter<Stream>,
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    // authenticated with, again after a redirect
+    credentials: Option<Credentials>,
+    // a request failed midway, the connection cannot be used any more
+    broken: bool,
+    // how to connect again, `None` for a connection given to `with_stream`
+    connector: Option<Connector>,
+    retry: Option<RetryPolicy>,
+    // `Some` when writes are sent with a request id
+    client_id: Option<String>,
+    seq: u64,
+    // state of the jitter of the backoff
+    rng: u64,
+    cache: Option<NearCache>,
+}
+
+/// When and how often `KvsClient` sends a request again after a failure
+///
+/// Reads are sent again after the connection failed, sets and removes only
+/// with request ids, which make the server answer a repeated request with
+/// its first response. Admin commands are never sent again. Waits asked by
+/// a rate limited server are capped at `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, 3 by default, 0 disables retries
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every next one, 100 milliseconds by default
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts, 5 seconds by default
+    pub max_backoff: Duration,
+    /// Wait a random time between half the backoff and all of it, true by default
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never send a request again
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Wait before the retry following `attempt`, counted from 0.
+    fn backoff(&self, attempt: u32, rng: &mut u64) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        let half = backoff / 2;
+        let spread = (backoff - half).as_micros() as u64 + 1;
+        half + Duration::from_micros(next_random(rng) % spread)
+    }
+}
+
+/// Settings of a `KvsClient` to make with `KvsClient::builder`
+pub struct KvsClientBuilder {
+    addr: String,
+    tls: Option<(String, Arc<ClientConfig>)>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    credentials: Option<Credentials>,
+    retry: RetryPolicy,
+    request_ids: bool,
+    // capacity of the near cache
+    cache_capacity: Option<usize>,
+    tracked_prefixes: Vec<String>,
+}
+
+impl KvsClientBuilder {
+    /// Give up connecting after `timeout`, waits for the system by default
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request whose response takes longer than `timeout`, none by default
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request that cannot be sent within `timeout`, none by default
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Connect over TLS, the server certificate has to be valid for `server_name`
+    pub fn tls(mut self, server_name: &str, config: Arc<ClientConfig>) -> Self {
+        self.tls = Some((server_name.to_owned(), config));
+        self
+    }
+
+    /// Authenticate every connection with `credentials`
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Retry failed requests and connections as `policy` says, `RetryPolicy::default()` unless set
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Send writes with request ids, so that sets and removes are retried too, true by default
+    pub fn request_ids(mut self, enabled: bool) -> Self {
+        self.request_ids = enabled;
+        self
+    }
+
+    /// Cache up to `capacity` values got, dropped when the server tells
+    /// that their keys were written, none by default
+    pub fn near_cache(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Only cache the keys starting with one of `prefixes`, every key unless set
+    pub fn cache_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        self.tracked_prefixes = prefixes;
+        self
+    }
+
+    /// Connect to the server, retrying as the retry policy says
+    pub fn build(self) -> Result<KvsClient> {
+        let tls = match self.tls {
+            Some((server_name, config)) => Some((tls::server_name(&server_name)?, config)),
+            None => None,
+        };
+        let connector = Connector {
+            addr: self.addr,
+            tls,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        };
+        let mut rng = seed();
+        let mut attempt = 0;
+        let stream = loop {
+            match connector.connect() {
+                Ok(stream) => break stream,
+                Err(e) if attempt < self.retry.max_retries && is_connection_error(&e) => {
+                    let backoff = self.retry.backoff(attempt, &mut rng);
+                    warn!(
+                        "connecting to {} failed: {}, retry in {:?}",
+                        connector.addr, e, backoff
+                    );
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let mut client = KvsClient::with_stream(stream)?;
+        client.connector = Some(connector);
+        client.retry = Some(self.retry);
+        client.rng = rng;
+        if self.request_ids {
+            client.client_id = Some(format!("{:016x}", next_random(&mut client.rng)));
+        }
+        if let Some(capacity) = self.cache_capacity {
+            let connector = client.connector.clone().unwrap();
+            let prefixes = self.tracked_prefixes;
+            let cache = NearCache::start(connector, self.credentials.clone(), capacity, prefixes)?;
+            client.cache = Some(cache);
+        }
+        if let Some(credentials) = self.credentials {
+            client.auth(credentials)?;
+        }
+        Ok(client)
+    }
+}
+
+// Address and settings of the connections of a client.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    addr: String,
+    tls: Option<(ServerName<'static>, Arc<ClientConfig>)>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl Connector {
+    pub(crate) fn connect(&self) -> Result<Stream> {
+        let stream = match (&self.tls, self.connect_timeout) {
+            (Some((server_name, config)), timeout) => Stream::connect_tls_timeout(
+                self.addr.as_str(),
+                server_name.clone(),
+                Arc::clone(config),
+                timeout,
+            )?,
+            (None, Some(timeout)) => Stream::connect_timeout(self.addr.as_str(), timeout)?,
+            (None, None) => Stream::connect(self.addr.as_str())?,
+        };
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(stream)
+    }
 }
 
 impl KvsClient {
     /// New a kvs client with socket addr
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
+        Self::with_stream(Stream::connect(addr)?)
+    }
+
+    /// Settings of a client to `addr` that connects again and retries failed requests
+    pub fn builder(addr: impl Into<String>) -> KvsClientBuilder {
+        KvsClientBuilder {
+            addr: addr.into(),
+            tls: None,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            credentials: None,
+            retry: RetryPolicy::default(),
+            request_ids: true,
+            cache_capacity: None,
+            tracked_prefixes: Vec::new(),
+        }
+    }
+
+    /// New a kvs client over TLS, the server certificate has to be valid for `server_name`
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let server_name = tls::server_name(server_name)?;
+        Self::with_stream(Stream::connect_tls(addr, server_name, config)?)
+    }
+
+    /// New a kvs client connected to a Unix domain socket
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_stream(Stream::connect_unix(path)?)
+    }
+
+    /// New a kvs client over an established connection
+    pub fn with_stream(stream: Stream) -> Result<Self> {
         let reader_stream = stream.try_clone()?;
 
         let writer = BufWriter::new(stream);
         let reader = BufReader::new(reader_stream);
         let reader = serde_json::Deserializer::from_reader(reader);
-        Ok(KvsClient { writer, reader })
+        Ok(KvsClient {
+            writer,
+            reader,
+            credentials: None,
+            broken: false,
+            connector: None,
+            retry: None,
+            client_id: None,
+            seq: 0,
+            rng: 0,
+            cache: None,
+        })
     }
 
-    /// Get value of key from remote server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let req = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
+    /// Authenticate the connection, required first when the server has an ACL.
+    ///
+    /// The server closes the connection when the credentials are rejected.
+    pub fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        let req = Request::Auth {
+            credentials: credentials.clone(),
+        };
+        match self.call(req)? {
+            ResponseBody::Ok(_) => {
+                self.credentials = Some(credentials);
+                Ok(())
+            }
+            ResponseBody::Err(e) => Err(Error::AuthError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
 
-        let rsp = Response::deserialize(&mut self.reader)?;
-        match rsp.body {
-            ResponseBody::Ok(val) => Ok(val),
+    /// Get value of key from remote server, or from the near cache if enabled
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let epoch = match self.cache.as_ref().map(|cache| cache.get(&key)) {
+            Some(Lookup::Hit(val)) => return Ok(val),
+            Some(Lookup::Miss(epoch)) => epoch,
+            None => None,
+        };
+        let cached_key = epoch.map(|_| key.clone());
+        match self.call(Request::Get { key })? {
+            ResponseBody::Ok(val) => {
+                if let (Some(cache), Some(key), Some(epoch)) = (&self.cache, cached_key, epoch) {
+                    cache.insert(key, val.clone(), epoch);
+                }
+                Ok(val)
+            }
             ResponseBody::Err(e) => Err(Error::ClientGetError(e)),
+            body => Err(body.unexpected()),
         }
     }
 
     /// Set key-value to remote server
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let req = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-
-        let rsp = Response::deserialize(&mut self.reader)?;
-        match rsp.body {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        match self.call(Request::Set { key, value })? {
             ResponseBody::Ok(_) => Ok(()),
             ResponseBody::Err(e) => Err(Error::ClientSetError(e)),
+            body => Err(body.unexpected()),
         }
     }
 
     /// Remove key-value to remote server
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let req = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-
-        let rsp = Response::deserialize(&mut self.reader)?;
-        match rsp.body {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        match self.call(Request::Remove { key })? {
             ResponseBody::Ok(_) => Ok(()),
             ResponseBody::Err(e) => Err(Error::ClientRemoveError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Get the values of several keys at once, in the order of `keys`
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.call(Request::GetMany { keys })? {
+            ResponseBody::Values(values) => Ok(values),
+            ResponseBody::Err(e) => Err(Error::ClientGetError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// List the keys starting with `prefix`, sorted
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        match self.call(Request::Scan { prefix })? {
+            ResponseBody::Keys(keys) => Ok(keys),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Check that the server answers
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(Request::Ping)? {
+            ResponseBody::Ok(_) => Ok(()),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Ask the server about itself
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        match self.call(Request::Info)? {
+            ResponseBody::Info(info) => Ok(*info),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    /// Counters of the near cache, `None` unless enabled
+    pub fn cache_stats(&self) -> Option<NearCacheStats> {
+        self.cache.as_ref().map(NearCache::stats)
+    }
+
+    /// Run a maintenance command on the server, returns its message if any
+    pub fn admin(&mut self, cmd: AdminCommand) -> Result<Option<String>> {
+        match self.call(Request::Admin(cmd))? {
+            ResponseBody::Ok(msg) => Ok(msg),
+            ResponseBody::Err(e) => Err(Error::ServerError(e)),
+            body => Err(body.unexpected()),
+        }
+    }
+
+    // Requests failing on the connection are sent again over a new one,
+    // if the retry policy allows it and the request is safe to repeat.
+    pub(crate) fn call(&mut self, req: Request) -> Result<ResponseBody> {
+        let req = self.tag(req);
+        let mut attempt = 0;
+        loop {
+            // whether the request may have reached the server
+            let (res, sent) = match self.reopen_if_broken() {
+                Ok(()) => (self.send(&req), true),
+                Err(e) => (Err(e), false),
+            };
+            match res {
+                Err(e) if self.may_retry(&req, &e, sent, attempt) => {
+                    let policy = self.retry.unwrap();
+                    let backoff = match e {
+                        Error::RateLimited(retry_after) => retry_after.min(policy.max_backoff),
+                        _ => policy.backoff(attempt, &mut self.rng),
+                    };
+                    warn!("request failed: {}, retry in {:?}", e, backoff);
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // Give the writes an id when enabled, the same for all their attempts.
+    fn tag(&mut self, req: Request) -> Request {
+        match (&self.client_id, &req) {
+            (Some(client), Request::Set { .. } | Request::Remove { .. }) => {
+                self.seq += 1;
+                Request::WithId {
+                    id: RequestId {
+                        client: client.clone(),
+                        seq: self.seq,
+                    },
+                    request: Box::new(req),
+                }
+            }
+            _ => req,
+        }
+    }
+
+    fn may_retry(&self, req: &Request, e: &Error, sent: bool, attempt: u32) -> bool {
+        let Some(policy) = self.retry else {
+            return false;
+        };
+        if attempt >= policy.max_retries {
+            return false;
+        }
+        match e {
+            // refused before being served
+            Error::RateLimited(_) | Error::ServerBusy => true,
+            e if is_connection_error(e) => !sent || is_idempotent(req),
+            _ => false,
+        }
+    }
+
+    // Writes sent to a node of a cluster other than the leader are
+    // redirected to the leader over the same transport, which the client
+    // stays connected to. Clients that cannot connect the same way fail
+    // with `Error::NotLeader` instead.
+    fn send(&mut self, req: &Request) -> Result<ResponseBody> {
+        let mut redirects = 0;
+        loop {
+            let rsp = match self.exchange(req) {
+                Ok(rsp) => rsp,
+                Err(e) => {
+                    self.broken = true;
+                    return Err(e);
+                }
+            };
+            match rsp.body {
+                ResponseBody::Busy => {
+                    // closed by the server after this
+                    self.broken = true;
+                    return Err(Error::ServerBusy);
+                }
+                ResponseBody::RateLimited { retry_after_ms } => {
+                    return Err(Error::RateLimited(Duration::from_millis(retry_after_ms)))
+                }
+                ResponseBody::NotLeader {
+                    leader: Some(leader),
+                } if redirects < MAX_REDIRECTS && self.can_redirect() => {
+                    redirects += 1;
+                    self.reconnect(&leader)?;
+                }
+                ResponseBody::NotLeader { leader } => return Err(Error::NotLeader(leader)),
+                body => return Ok(body),
+            }
+        }
+    }
+
+    fn exchange(&mut self, req: &Request) -> Result<Response> {
+        serde_json::to_writer(&mut self.writer, req)?;
+        self.writer.flush()?;
+        Ok(Response::deserialize(&mut self.reader)?)
+    }
+
+    /// Whether a request failed midway, leaving the connection unusable
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    // Connect again after a failure, and authenticate as before.
+    fn reopen_if_broken(&mut self) -> Result<()> {
+        if !self.broken {
+            return Ok(());
+        }
+        let Some(connector) = &self.connector else {
+            return Ok(());
+        };
+        let stream = connector.connect()?;
+        self.reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+        self.writer = BufWriter::new(stream);
+        self.broken = false;
+        if let Some(credentials) = self.credentials.clone() {
+            match self.send(&Request::Auth { credentials })? {
+                ResponseBody::Ok(_) => {}
+                ResponseBody::Err(e) => return Err(Error::AuthError(e)),
+                body => return Err(body.unexpected()),
+            }
         }
+        Ok(())
     }
+
+    // Plain TCP clients, and TLS clients that know their TLS settings.
+    // A Unix socket names no host, and credentials must not go out in clear.
+    fn can_redirect(&self) -> bool {
+        match self.writer.get_ref() {
+            Stream::Tcp(_) => true,
+            Stream::Tls(_) => self
+                .connector
+                .as_ref()
+                .is_some_and(|connector| connector.tls.is_some()),
+            #[cfg(unix)]
+            Stream::Unix(_) => false,
+        }
+    }
+
+    // TLS settings and timeouts carry over to the leader, whose certificate
+    // has to be valid for the same server name.
+    fn reconnect(&mut self, addr: &str) -> Result<()> {
+        let connector = match &self.connector {
+            Some(connector) => Connector {
+                addr: addr.to_owned(),
+                ..connector.clone()
+            },
+            None => Connector {
+                addr: addr.to_owned(),
+                tls: None,
+                connect_timeout: None,
+                read_timeout: None,
+                write_timeout: None,
+            },
+        };
+        self.connector = Some(connector);
+        self.broken = true;
+        self.reopen_if_broken()
+    }
+}
+
+// Whether the request may be applied twice without harm. A set repeated
+// after another client's write would overwrite it, unless it has an id.
+fn is_idempotent(req: &Request) -> bool {
+    match req {
+        Request::Get { .. }
+        | Request::GetMany { .. }
+        | Request::Scan { .. }
+        | Request::Info
+        | Request::Ping
+        | Request::Auth { .. }
+        | Request::WithId { .. } => true,
+        Request::Set { .. }
+        | Request::Remove { .. }
+        | Request::Admin(_)
+        | Request::Replicate { .. }
+        | Request::Track { .. } => false,
+    }
+}
+
+/// Whether `e` means the server could not be reached, rather than that it refused the request
+pub(crate) fn is_connection_error(e: &Error) -> bool {
+    match e {
+        Error::IoError(_) | Error::ServerBusy => true,
+        Error::JSONSerializeError(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
+
+fn seed() -> u64 {
+    // randomly keyed for every process
+    RandomState::new().hash_one(SystemTime::now()) | 1
+}
+
+// xorshift64
+fn next_random(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
 }
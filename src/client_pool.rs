@@ -0,0 +1,337 @@
+//! Pool of client connections shared by threads
+//!
+//! `KvsClientPool::get` checks a connection out, which goes back to the
+//! pool when the `PooledClient` is dropped, unless a request broke it.
+//! At most `max_connections` are open at once, threads wait up to the
+//! checkout timeout for one of them to come back.
+//!
+//! Connections left idle are pinged before use and closed after the idle
+//! timeout, down to `min_connections`. A thread of the pool does the same
+//! in the background, and opens connections up to `min_connections`.
+
+use crate::err::Error;
+use crate::{KvsClient, Result};
+use log::{debug, warn};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Settings of a `KvsClientPool`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Connections kept open even when idle, 0 by default
+    pub min_connections: usize,
+    /// Connections open at most, 10 by default
+    pub max_connections: usize,
+    /// How long `get` waits for a free connection, 5 seconds by default
+    pub checkout_timeout: Duration,
+    /// Idle connections above `min_connections` are closed after this long, 1 minute by default
+    pub idle_timeout: Option<Duration>,
+    /// Idle connections unchecked for this long are pinged before use, 10 seconds by default
+    pub health_check_interval: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            min_connections: 0,
+            max_connections: 10,
+            checkout_timeout: Duration::from_secs(5),
+            idle_timeout: Some(Duration::from_secs(60)),
+            health_check_interval: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+/// Connections of a pool, see `KvsClientPool::status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// Connections open, idle or checked out
+    pub open: usize,
+    /// Connections waiting in the pool
+    pub idle: usize,
+}
+
+type Connect = dyn Fn() -> Result<KvsClient> + Send + Sync;
+
+/// KvsClientPool hands out connections to a server from a shared pool
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connect: Box<Connect>,
+    options: PoolOptions,
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    // most recently used last
+    idle: Vec<Idle>,
+    open: usize,
+}
+
+struct Idle {
+    client: KvsClient,
+    // returned to the pool, for the idle timeout
+    since: Instant,
+    // returned or last pinged, for the health checks
+    checked: Instant,
+}
+
+impl KvsClientPool {
+    /// New a pool of plain TCP connections to `addr`
+    pub fn new(addr: impl Into<String>, options: PoolOptions) -> Result<Self> {
+        let addr = addr.into();
+        Self::with_connect(move || KvsClient::new(&addr), options)
+    }
+
+    /// New a pool opening its connections with `connect`, which may
+    /// authenticate them or connect over TLS.
+    ///
+    /// Fails if the first `min_connections` cannot be opened.
+    pub fn with_connect<F>(connect: F, options: PoolOptions) -> Result<Self>
+    where
+        F: Fn() -> Result<KvsClient> + Send + Sync + 'static,
+    {
+        if options.max_connections == 0 || options.min_connections > options.max_connections {
+            return Err(Error::StringError(
+                "max_connections must be at least 1 and min_connections".to_owned(),
+            ));
+        }
+        let inner = Arc::new(Inner {
+            connect: Box::new(connect),
+            options,
+            state: Mutex::new(State::default()),
+            returned: Condvar::new(),
+        });
+        inner.fill()?;
+        let period = [options.idle_timeout, options.health_check_interval]
+            .into_iter()
+            .flatten()
+            .min();
+        if let Some(period) = period {
+            let weak = Arc::downgrade(&inner);
+            let period = (period / 2).max(Duration::from_millis(10));
+            thread::spawn(move || maintain(weak, period));
+        }
+        Ok(KvsClientPool { inner })
+    }
+
+    /// Check a connection out, waiting up to the checkout timeout for one
+    pub fn get(&self) -> Result<PooledClient> {
+        let inner = &self.inner;
+        let deadline = Instant::now() + inner.options.checkout_timeout;
+        let mut state = inner.state();
+        loop {
+            inner.evict(&mut state);
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                match inner.check(idle) {
+                    Some(idle) => return Ok(self.pooled(idle.client)),
+                    None => {
+                        state = inner.state();
+                        continue;
+                    }
+                }
+            }
+            if state.open < inner.options.max_connections {
+                state.open += 1;
+                drop(state);
+                return match (inner.connect)() {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        inner.closed();
+                        Err(e)
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::PoolTimeout);
+            }
+            state = inner
+                .returned
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Connections open and idle now
+    pub fn status(&self) -> PoolStatus {
+        let state = self.inner.state();
+        PoolStatus {
+            open: state.open,
+            idle: state.idle.len(),
+        }
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // Close the connections idle for too long, keeping `min_connections` open.
+    fn evict(&self, state: &mut State) {
+        let Some(timeout) = self.options.idle_timeout else {
+            return;
+        };
+        let now = Instant::now();
+        // the least recently used ones first
+        while state.open > self.options.min_connections
+            && state
+                .idle
+                .first()
+                .is_some_and(|idle| now - idle.since >= timeout)
+        {
+            state.idle.remove(0);
+            state.open -= 1;
+            debug!("idle connection closed");
+        }
+    }
+
+    fn check_due(&self, idle: &Idle) -> bool {
+        self.options
+            .health_check_interval
+            .is_some_and(|interval| idle.checked.elapsed() >= interval)
+    }
+
+    // Ping a connection unchecked for long enough, returns it if alive.
+    fn check(&self, mut idle: Idle) -> Option<Idle> {
+        if !self.check_due(&idle) {
+            return Some(idle);
+        }
+        match idle.client.ping() {
+            Ok(()) => {
+                idle.checked = Instant::now();
+                Some(idle)
+            }
+            Err(e) => {
+                warn!("pooled connection dropped: {}", e);
+                self.closed();
+                None
+            }
+        }
+    }
+
+    fn put(&self, client: KvsClient) {
+        let now = Instant::now();
+        self.restore(Idle {
+            client,
+            since: now,
+            checked: now,
+        });
+    }
+
+    // Return an idle connection to its place among the others.
+    fn restore(&self, idle: Idle) {
+        let mut state = self.state();
+        let pos = state
+            .idle
+            .partition_point(|other| other.since <= idle.since);
+        state.idle.insert(pos, idle);
+        drop(state);
+        self.returned.notify_one();
+    }
+
+    fn closed(&self) {
+        self.state().open -= 1;
+        self.returned.notify_one();
+    }
+
+    // Open connections up to `min_connections`.
+    fn fill(&self) -> Result<()> {
+        loop {
+            let mut state = self.state();
+            if state.open >= self.options.min_connections {
+                return Ok(());
+            }
+            state.open += 1;
+            drop(state);
+            match (self.connect)() {
+                Ok(client) => self.put(client),
+                Err(e) => {
+                    self.closed();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// Evict and check the idle connections every `period`, until the pool is dropped.
+fn maintain(pool: Weak<Inner>, period: Duration) {
+    loop {
+        thread::sleep(period);
+        let Some(inner) = pool.upgrade() else {
+            return;
+        };
+        let due = {
+            let mut state = inner.state();
+            inner.evict(&mut state);
+            let idle = std::mem::take(&mut state.idle);
+            let (due, fresh) = idle.into_iter().partition(|idle| inner.check_due(idle));
+            state.idle = fresh;
+            due
+        };
+        for idle in due {
+            if let Some(idle) = inner.check(idle) {
+                inner.restore(idle);
+            }
+        }
+        if let Err(e) = inner.fill() {
+            warn!("pool refill err {}", e);
+        }
+    }
+}
+
+/// Connection checked out of a `KvsClientPool`, returned to it when dropped
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    pool: Arc<Inner>,
+}
+
+impl PooledClient {
+    /// Close the connection rather than return it to the pool
+    pub fn discard(mut self) {
+        self.client = None;
+        self.pool.closed();
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) if !client.is_broken() => self.pool.put(client),
+            Some(_) => self.pool.closed(),
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,561 @@
+//! Cluster mode: `kvs-server` nodes forming a Raft group
+//!
+//! Writes are proposed to the Raft log by the leader, and applied to the
+//! engine of every node once committed. Other nodes refuse writes and tell
+//! the client where the leader is. Reads are served by every node from its
+//! own engine, so they may lag behind the leader.
+//!
+//! Nodes send each other Raft messages on their Raft address, one JSON
+//! message per line, after a first line with the secret of the cluster.
+//! Connections without it are closed, the secret is sent in clear so the
+//! Raft addresses should only be reachable on a private network.
+//! The Raft state and log are kept in the state directory, the log is not
+//! compacted.
+//! Conditional writes and increments are not supported in cluster mode.
+
+use crate::common::write_line;
+use crate::context::Context;
+use crate::err::Error;
+use crate::net::{Listener, Stream};
+use crate::raft::{Entry, HardState, Message, NodeId, RaftConfig, RaftNode, Role};
+use crate::replication::WriteOp;
+use crate::{KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+const DEFAULT_TICK: Duration = Duration::from_millis(100);
+// A write not committed by then is reported as failed, it may still be later.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+// Messages to an unreachable node are dropped for this long, Raft sends them again.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+// How often the readers of Raft connections check for shutdown
+const POLL: Duration = Duration::from_secs(1);
+// Longest Raft message accepted
+const MAX_LINE_LEN: usize = 64 * 1024 * 1024;
+
+/// A node of a cluster
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterNode {
+    /// Identifier of the node, unique within the cluster
+    pub id: NodeId,
+    /// Address of the native protocol, where clients are redirected to
+    pub addr: String,
+    /// Address the nodes exchange Raft messages on
+    pub raft_addr: String,
+}
+
+/// Membership of a server in a cluster, see `KvsServer::set_cluster`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cluster {
+    /// Identifier of this server
+    pub id: NodeId,
+    /// Every node of the cluster, including this server
+    pub nodes: Vec<ClusterNode>,
+    /// Directory of the Raft state and log
+    pub state_dir: PathBuf,
+    /// Interval of the Raft clock, a node stands for election after
+    /// 10 to 20 ticks without a leader
+    pub tick: Duration,
+    /// Shared by the nodes, which only take Raft messages from connections presenting it
+    pub secret: String,
+}
+
+impl Cluster {
+    /// Membership of node `id`, ticking every 100 milliseconds
+    pub fn new(
+        id: NodeId,
+        nodes: Vec<ClusterNode>,
+        state_dir: impl Into<PathBuf>,
+        secret: impl Into<String>,
+    ) -> Self {
+        Cluster {
+            id,
+            nodes,
+            state_dir: state_dir.into(),
+            tick: DEFAULT_TICK,
+            secret: secret.into(),
+        }
+    }
+}
+
+/// Raft state of a node, see `ServerInfo::cluster`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterInfo {
+    /// Identifier of the node
+    pub id: NodeId,
+    /// Role of the node in the current term
+    pub role: Role,
+    /// Current term
+    pub term: u64,
+    /// Leader of the current term, if known
+    pub leader: Option<NodeId>,
+    /// Index of the last committed entry of the log
+    pub commit_index: u64,
+    /// Index of the last entry of the log
+    pub last_index: u64,
+}
+
+enum Event {
+    Message(NodeId, Message<WriteOp>),
+    Propose(WriteOp, Sender<Result<()>>),
+}
+
+// First line of a Raft connection
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    secret: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    from: NodeId,
+    message: Message<WriteOp>,
+}
+
+/// What the context needs of the Raft node
+pub(crate) struct ClusterHandle {
+    events: Mutex<Sender<Event>>,
+    info: Arc<Mutex<ClusterInfo>>,
+}
+
+impl ClusterHandle {
+    /// Commit a write through the log, returns once this node applied it.
+    pub fn propose(&self, op: WriteOp) -> Result<()> {
+        let (reply, replied) = mpsc::channel();
+        self.events
+            .lock()
+            .unwrap()
+            .send(Event::Propose(op, reply))
+            .map_err(|_| Error::StringError("cluster node stopped".to_owned()))?;
+        replied
+            .recv_timeout(PROPOSAL_TIMEOUT)
+            .unwrap_or_else(|_| Err(Error::StringError("write not committed in time".to_owned())))
+    }
+
+    pub fn info(&self) -> ClusterInfo {
+        self.info.lock().unwrap().clone()
+    }
+}
+
+/// The Raft node of a server, until the server starts it
+pub(crate) struct ClusterDriver {
+    cluster: Cluster,
+    node: RaftNode<WriteOp>,
+    storage: RaftStorage,
+    listener: Listener,
+    events: Receiver<Event>,
+    sender: Sender<Event>,
+    info: Arc<Mutex<ClusterInfo>>,
+}
+
+/// Load the Raft state of the node and bind its Raft address.
+pub(crate) fn open(cluster: Cluster) -> Result<(ClusterHandle, ClusterDriver)> {
+    let me = cluster
+        .nodes
+        .iter()
+        .find(|node| node.id == cluster.id)
+        .ok_or_else(|| Error::StringError(format!("node {} is not in the cluster", cluster.id)))?;
+    if cluster.secret.is_empty() {
+        return Err(Error::StringError("the cluster secret is empty".to_owned()));
+    }
+    let listener = Listener::bind(&me.raft_addr)?;
+    let (storage, state, log, applied) = RaftStorage::open(&cluster.state_dir)?;
+    let mut config = RaftConfig::new(cluster.id, cluster.nodes.iter().map(|n| n.id).collect());
+    // nodes started together should not time out together
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    config.seed = cluster.id ^ now.as_nanos() as u64;
+    let node = RaftNode::new(config, state, log, applied);
+    info!(
+        "raft node {} at term {}, {} entries, {} applied",
+        cluster.id,
+        node.term(),
+        node.last_index(),
+        applied
+    );
+
+    let info = Arc::new(Mutex::new(node_info(&node)));
+    let (sender, events) = mpsc::channel();
+    let handle = ClusterHandle {
+        events: Mutex::new(sender.clone()),
+        info: Arc::clone(&info),
+    };
+    let driver = ClusterDriver {
+        cluster,
+        node,
+        storage,
+        listener,
+        events,
+        sender,
+        info,
+    };
+    Ok((handle, driver))
+}
+
+fn node_info(node: &RaftNode<WriteOp>) -> ClusterInfo {
+    ClusterInfo {
+        id: node.id(),
+        role: node.role(),
+        term: node.term(),
+        leader: node.leader(),
+        commit_index: node.commit_index(),
+        last_index: node.last_index(),
+    }
+}
+
+impl ClusterDriver {
+    /// Run the node in threads of its own until the server shuts down,
+    /// applying the committed writes through `context`.
+    pub fn start<T: KvsEngine>(self, context: Context<T>) -> Result<Vec<JoinHandle<()>>> {
+        let ClusterDriver {
+            cluster,
+            node,
+            storage,
+            listener,
+            events,
+            sender,
+            info,
+        } = self;
+        let shutdown = context.shutdown_handle().clone();
+        shutdown.register(listener.waker()?);
+        let mut handles = Vec::new();
+
+        let listener_shutdown = shutdown.clone();
+        let secret = cluster.secret.clone();
+        handles.push(thread::spawn(move || loop {
+            let accepted = listener.accept();
+            if listener_shutdown.is_shutdown() {
+                info!("raft listener {} closed", listener);
+                return;
+            }
+            match accepted {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    let shutdown = listener_shutdown.clone();
+                    let secret = secret.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = receive(stream, &secret, sender, || shutdown.is_shutdown())
+                        {
+                            debug!("raft connection err {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("raft listener err {}", e);
+                    return;
+                }
+            }
+        }));
+
+        let peers = cluster
+            .nodes
+            .iter()
+            .filter(|node| node.id != cluster.id)
+            .map(|node| {
+                let (peer, messages) = mpsc::channel();
+                let (id, addr) = (cluster.id, node.raft_addr.clone());
+                let secret = cluster.secret.clone();
+                thread::spawn(move || send_to_peer(id, addr, secret, messages));
+                (node.id, peer)
+            })
+            .collect();
+        let mut driver = Driver {
+            context,
+            node,
+            storage,
+            peers,
+            addrs: cluster
+                .nodes
+                .iter()
+                .map(|node| (node.id, node.addr.clone()))
+                .collect(),
+            pending: HashMap::new(),
+            info,
+        };
+        handles.push(thread::spawn(move || {
+            if let Err(e) = driver.run(events, cluster.tick) {
+                error!("raft node stopped: {}", e);
+            }
+        }));
+        Ok(handles)
+    }
+}
+
+struct Driver<T: KvsEngine> {
+    context: Context<T>,
+    node: RaftNode<WriteOp>,
+    storage: RaftStorage,
+    peers: HashMap<NodeId, Sender<Message<WriteOp>>>,
+    // native protocol addresses, to redirect clients to
+    addrs: HashMap<NodeId, String>,
+    // proposals waiting for their entry to commit, by index, with the term they were made in
+    pending: HashMap<u64, (u64, Sender<Result<()>>)>,
+    info: Arc<Mutex<ClusterInfo>>,
+}
+
+impl<T: KvsEngine> Driver<T> {
+    fn run(&mut self, events: Receiver<Event>, tick: Duration) -> Result<()> {
+        let shutdown = self.context.shutdown_handle().clone();
+        let mut next_tick = Instant::now() + tick;
+        while !shutdown.is_shutdown() {
+            match events.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(Event::Message(from, msg)) => self.node.step(from, msg),
+                Ok(Event::Propose(op, reply)) => match self.node.propose(op) {
+                    Ok(index) => {
+                        self.pending.insert(index, (self.node.term(), reply));
+                    }
+                    Err(leader) => {
+                        let _ = reply.send(Err(self.not_leader(leader)));
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            let now = Instant::now();
+            if now >= next_tick {
+                self.node.tick();
+                next_tick = (next_tick + tick).max(now);
+            }
+            self.handle_ready()?;
+        }
+        Ok(())
+    }
+
+    fn handle_ready(&mut self) -> Result<()> {
+        let ready = self.node.ready();
+        if let Some(state) = ready.hard_state {
+            self.storage.save_state(&state)?;
+        }
+        if !ready.entries.is_empty() {
+            self.storage.append(&ready.entries)?;
+        }
+        for (to, msg) in ready.messages {
+            if let Some(peer) = self.peers.get(&to) {
+                let _ = peer.send(msg);
+            }
+        }
+        if let Some(last) = ready.committed.last() {
+            let applied = last.index;
+            for entry in ready.committed {
+                if let Some(op) = entry.command {
+                    if let Err(e) = self.context.apply(op) {
+                        error!("apply of entry {} err {:?}", entry.index, e);
+                    }
+                }
+                if let Some((term, reply)) = self.pending.remove(&entry.index) {
+                    // another leader's entry took the place of the proposal
+                    let res = match term == entry.term {
+                        true => Ok(()),
+                        false => Err(self.not_leader(self.node.leader())),
+                    };
+                    let _ = reply.send(res);
+                }
+            }
+            self.storage.save_applied(applied)?;
+        }
+        *self.info.lock().unwrap() = node_info(&self.node);
+        Ok(())
+    }
+
+    fn not_leader(&self, leader: Option<NodeId>) -> Error {
+        Error::NotLeader(leader.and_then(|id| self.addrs.get(&id).cloned()))
+    }
+}
+
+// Passes the messages of a connection to the driver, once it presented the secret.
+fn receive(
+    stream: Stream,
+    secret: &str,
+    sender: Sender<Event>,
+    stopped: impl Fn() -> bool,
+) -> Result<()> {
+    stream.set_read_timeout(Some(POLL))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = Vec::new();
+    let mut hello = true;
+    while !stopped() {
+        let limit = (MAX_LINE_LEN + 1).saturating_sub(line.len()) as u64;
+        match (&mut reader).take(limit).read_until(b'\n', &mut line) {
+            Ok(0) if line.len() > MAX_LINE_LEN => {
+                return Err(Error::StringError("raft message too long".to_owned()))
+            }
+            Ok(0) => return Ok(()),
+            Ok(_) if line.ends_with(b"\n") => {}
+            Ok(_) => continue,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if hello {
+            let Hello { secret: presented } = serde_json::from_slice(&line)?;
+            if !constant_time_eq(presented.as_bytes(), secret.as_bytes()) {
+                warn!("raft connection from {} with a wrong secret", stream.peer());
+                return Ok(());
+            }
+            hello = false;
+            line.clear();
+            continue;
+        }
+        let envelope: Envelope = serde_json::from_slice(&line)?;
+        line.clear();
+        if sender
+            .send(Event::Message(envelope.from, envelope.message))
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Whether `a` and `b` are equal, in a time that does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Sends the messages for a node over a connection of its own,
+// until the driver stops.
+fn send_to_peer(from: NodeId, addr: String, secret: String, messages: Receiver<Message<WriteOp>>) {
+    let mut writer = None;
+    let mut retry_at = Instant::now();
+    while let Ok(message) = messages.recv() {
+        if writer.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match Stream::connect(&addr).and_then(|stream| {
+                stream.set_write_timeout(Some(RECONNECT_DELAY))?;
+                let mut writer = BufWriter::new(stream);
+                let secret = secret.clone();
+                write_line(&mut writer, &Hello { secret })?;
+                Ok(writer)
+            }) {
+                Ok(stream) => writer = Some(stream),
+                Err(e) => {
+                    debug!("raft node {} unreachable: {}", addr, e);
+                    retry_at = Instant::now() + RECONNECT_DELAY;
+                    continue;
+                }
+            }
+        }
+        let envelope = Envelope { from, message };
+        if let Err(e) = write_line(writer.as_mut().unwrap(), &envelope) {
+            warn!("raft connection to {} lost: {}", addr, e);
+            writer = None;
+            retry_at = Instant::now() + RECONNECT_DELAY;
+        }
+    }
+}
+
+// Raft state and log of a node:
+// `state` holds the term and vote, `log` an entry per line and
+// `applied` the index of the last entry applied to the engine.
+struct RaftStorage {
+    dir: PathBuf,
+    log: File,
+    // offset of the entry of index `i` in the log file at `i - 1`
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl RaftStorage {
+    fn open(dir: &Path) -> Result<(Self, HardState, Vec<Entry<WriteOp>>, u64)> {
+        fs::create_dir_all(dir)?;
+        let state = match fs::read_to_string(dir.join("state")) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let applied = match fs::read_to_string(dir.join("applied")) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| Error::StringError("invalid raft applied index".to_owned()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join("log"))?;
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut len = 0;
+        let mut reader = BufReader::new(&mut log);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            // a line cut short by a crash is dropped
+            match serde_json::from_slice::<Entry<WriteOp>>(&line) {
+                Ok(entry) if line.ends_with(b"\n") => entries.push(entry),
+                _ => break,
+            }
+            offsets.push(len);
+            len += line.len() as u64;
+            line.clear();
+        }
+        log.set_len(len)?;
+        let storage = RaftStorage {
+            dir: dir.to_path_buf(),
+            log,
+            offsets,
+            len,
+        };
+        Ok((storage, state, entries, applied))
+    }
+
+    fn save_state(&mut self, state: &HardState) -> Result<()> {
+        let tmp = self.dir.join("state.tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, state)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join("state"))?;
+        Ok(())
+    }
+
+    // Replaces the entries from the index of the first one.
+    fn append(&mut self, entries: &[Entry<WriteOp>]) -> Result<()> {
+        let first = entries[0].index as usize;
+        if first <= self.offsets.len() {
+            self.len = self.offsets[first - 1];
+            self.offsets.truncate(first - 1);
+            self.log.set_len(self.len)?;
+        }
+        self.log.seek(SeekFrom::Start(self.len))?;
+        let mut writer = BufWriter::new(&mut self.log);
+        for entry in entries {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+            self.offsets.push(self.len);
+            self.len += line.len() as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.log.sync_data()?;
+        Ok(())
+    }
+
+    fn save_applied(&mut self, applied: u64) -> Result<()> {
+        fs::write(self.dir.join("applied"), applied.to_string())?;
+        Ok(())
+    }
+}
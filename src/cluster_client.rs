@@ -0,0 +1,369 @@
+//! Client spreading keys over several servers
+//!
+//! `KvsClusterClient` maps every key onto a server with a `HashRing` and
+//! keeps a connection to each server it used. A key belongs to as many
+//! servers as there are replicas, the first ones on the ring for the key.
+//! A server that cannot be reached is marked down until it is tried again
+//! after the retry interval.
+//!
+//! Writes go to every replica up, so that a value outlives the failure of
+//! all but one of them. The keys written while a replica was down are
+//! remembered, up to `MAX_PENDING_REPAIRS` for each server, and copied to it
+//! from another replica once it is back. Until then reads are served by the
+//! first replica up that is known to hold the latest write, and fail when
+//! none does. Only the writes of this client are tracked: other clients may
+//! still read a stale value from a replica that missed a write. Requests on
+//! a key fail while all its replicas are down, other servers never hold it.
+
+use crate::auth::Credentials;
+use crate::client::is_connection_error;
+use crate::err::Error;
+use crate::ring::HashRing;
+use crate::{KvsClient, Result};
+use log::warn;
+use std::collections::BTreeSet;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Time a server is left out for after failing, 5 seconds unless set
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keys remembered for a server that missed their writes, later ones are
+/// not repaired
+pub const MAX_PENDING_REPAIRS: usize = 100_000;
+
+/// KvsClusterClient sends each key to its servers among several
+pub struct KvsClusterClient {
+    ring: HashRing,
+    nodes: Vec<Node>,
+    replicas: usize,
+    retry_interval: Duration,
+    credentials: Option<Credentials>,
+}
+
+#[derive(Default)]
+struct Node {
+    client: Option<KvsClient>,
+    down_until: Option<Instant>,
+    consecutive_failures: u64,
+    requests: u64,
+    failures: u64,
+    last_error: Option<String>,
+    // keys written while the server was down
+    missed: BTreeSet<String>,
+}
+
+/// Health of a server of a `KvsClusterClient`, see `KvsClusterClient::health`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeHealth {
+    /// Address of the server
+    pub addr: String,
+    /// Weight of the server on the ring
+    pub weight: u32,
+    /// Whether keys go to the server, false from a connection failure until it is retried
+    pub up: bool,
+    /// Whether the client holds a connection to the server
+    pub connected: bool,
+    /// Connection failures since the last request the server answered
+    pub consecutive_failures: u64,
+    /// Requests sent to the server
+    pub requests: u64,
+    /// Requests that failed to reach the server
+    pub failures: u64,
+    /// Error of the last failure
+    pub last_error: Option<String>,
+    /// Keys whose writes the server missed, copied to it once it is back
+    pub pending_repairs: usize,
+}
+
+impl KvsClusterClient {
+    /// New a client over the servers at `addrs`, all of the same weight
+    pub fn new<I, S>(addrs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Ok(Self::with_ring(HashRing::new(addrs)?))
+    }
+
+    /// New a client over servers with their weights, a server of weight 2
+    /// gets about twice as many keys as one of weight 1
+    pub fn weighted<I, S>(nodes: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, u32)>,
+        S: Into<String>,
+    {
+        Ok(Self::with_ring(HashRing::weighted(nodes)?))
+    }
+
+    /// New a client over the servers of `ring`
+    pub fn with_ring(ring: HashRing) -> Self {
+        KvsClusterClient {
+            nodes: ring.nodes().iter().map(|_| Node::default()).collect(),
+            ring,
+            replicas: 1,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            credentials: None,
+        }
+    }
+
+    /// Keep every key on `replicas` servers, 1 by default and at most all of them
+    pub fn set_replicas(&mut self, replicas: usize) {
+        self.replicas = replicas.clamp(1, self.nodes.len());
+    }
+
+    /// How long a server is left out for after failing
+    pub fn set_retry_interval(&mut self, interval: Duration) {
+        self.retry_interval = interval;
+    }
+
+    /// Authenticate to every server up, and to the others once they are tried again
+    pub fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        self.credentials = Some(credentials);
+        for i in 0..self.nodes.len() {
+            self.nodes[i].client = None;
+            if self.is_up(i) {
+                match self.call(i, |_| Ok(())) {
+                    Err(e) if !is_connection_error(&e) => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the value of `key` from the first of its replicas up that did
+    /// not miss a write of it
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let mut failure = None;
+        for i in self.candidates(&key) {
+            self.repair(i);
+            if self.nodes[i].missed.contains(&key) {
+                continue;
+            }
+            match self.call(i, |client| client.get(key.clone())) {
+                Err(e) if is_connection_error(&e) => failure = Some(e),
+                res => return res,
+            }
+        }
+        Err(unavailable(failure))
+    }
+
+    /// Set `key` on its replicas up, the others get it once they are back
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut written = Vec::new();
+        let mut failure = None;
+        let mut refused = None;
+        for i in self.candidates(&key) {
+            match self.call(i, |client| client.set(key.clone(), value.clone())) {
+                Ok(()) => written.push(i),
+                Err(e) if is_connection_error(&e) => failure = Some(e),
+                Err(e) => {
+                    refused = Some(e);
+                    break;
+                }
+            }
+        }
+        if !written.is_empty() {
+            self.record_missed(&key, &written);
+        }
+        match (refused, written.len()) {
+            (Some(e), _) => Err(e),
+            (None, 0) => Err(unavailable(failure)),
+            (None, _) => Ok(()),
+        }
+    }
+
+    /// Remove `key` from its replicas up, succeeds if any of them had it,
+    /// the others lose it once they are back
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let mut reached = Vec::new();
+        let mut removed = false;
+        let mut failure = None;
+        for i in self.candidates(&key) {
+            match self.call(i, |client| client.remove(key.clone())) {
+                Err(e) if is_connection_error(&e) => failure = Some(e),
+                res => {
+                    reached.push(i);
+                    match res {
+                        Ok(()) => removed = true,
+                        Err(e) => failure = Some(e),
+                    }
+                }
+            }
+        }
+        if !reached.is_empty() {
+            self.record_missed(&key, &reached);
+        }
+        match (removed, reached.len()) {
+            (true, _) => Ok(()),
+            (false, 0) => Err(unavailable(failure)),
+            (false, _) => Err(failure.unwrap_or(Error::RecordNotFound)),
+        }
+    }
+
+    /// List the keys starting with `prefix` on every server up, sorted
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut reached = false;
+        let mut failure = None;
+        for i in 0..self.nodes.len() {
+            if !self.is_up(i) {
+                continue;
+            }
+            match self.call(i, |client| client.scan(prefix.clone())) {
+                Ok(found) => {
+                    reached = true;
+                    keys.extend(found);
+                }
+                Err(e) if is_connection_error(&e) => failure = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        if !reached {
+            return Err(unavailable(failure));
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Health of every server, in the order given
+    pub fn health(&self) -> Vec<NodeHealth> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| NodeHealth {
+                addr: self.ring.nodes()[i].clone(),
+                weight: self.ring.weight(i),
+                up: self.is_up(i),
+                connected: node.client.is_some(),
+                consecutive_failures: node.consecutive_failures,
+                requests: node.requests,
+                failures: node.failures,
+                last_error: node.last_error.clone(),
+                pending_repairs: node.missed.len(),
+            })
+            .collect()
+    }
+
+    // Replicas of `key`, in the order of the ring.
+    fn replicas(&self, key: &str) -> Vec<usize> {
+        let mut indexes = self.ring.indexes(key);
+        indexes.truncate(self.replicas);
+        indexes
+    }
+
+    // Replicas of `key` up, in the order of the ring.
+    fn candidates(&self, key: &str) -> Vec<usize> {
+        let mut indexes = self.replicas(key);
+        indexes.retain(|&i| self.is_up(i));
+        indexes
+    }
+
+    // The replicas of `key` other than `written` missed its last write,
+    // and the written ones are up to date.
+    fn record_missed(&mut self, key: &str, written: &[usize]) {
+        for i in self.replicas(key) {
+            let node = &mut self.nodes[i];
+            if written.contains(&i) {
+                node.missed.remove(key);
+            } else if node.missed.len() < MAX_PENDING_REPAIRS {
+                node.missed.insert(key.to_owned());
+            } else if !node.missed.contains(key) {
+                warn!(
+                    "server {} missed too many writes, {} is not repaired",
+                    self.ring.nodes()[i],
+                    key
+                );
+            }
+        }
+    }
+
+    // Copy the keys server `i` missed the writes of from their other
+    // replicas. Keys none of them can be read from now are left for later.
+    fn repair(&mut self, i: usize) {
+        if self.nodes[i].missed.is_empty() || !self.is_up(i) {
+            return;
+        }
+        let missed = mem::take(&mut self.nodes[i].missed);
+        let mut left = BTreeSet::new();
+        for key in missed {
+            if !self.is_up(i) || !self.repair_key(i, &key) {
+                left.insert(key);
+            }
+        }
+        self.nodes[i].missed.append(&mut left);
+    }
+
+    fn repair_key(&mut self, i: usize, key: &str) -> bool {
+        let sources: Vec<usize> = self
+            .candidates(key)
+            .into_iter()
+            .filter(|&j| j != i && !self.nodes[j].missed.contains(key))
+            .collect();
+        for j in sources {
+            let value = match self.call(j, |client| client.get(key.to_owned())) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let res = self.call(i, |client| match value {
+                Some(value) => client.set(key.to_owned(), value),
+                None => match client.remove(key.to_owned()) {
+                    Err(Error::RecordNotFound) => Ok(()),
+                    res => res,
+                },
+            });
+            return res.is_ok();
+        }
+        false
+    }
+
+    fn is_up(&self, i: usize) -> bool {
+        self.nodes[i]
+            .down_until
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    // Run `f` on the connection to server `i`, made first if needed.
+    // A server that cannot be reached is marked down.
+    fn call<R>(&mut self, i: usize, f: impl FnOnce(&mut KvsClient) -> Result<R>) -> Result<R> {
+        let res = self.connect(i).and_then(f);
+        let node = &mut self.nodes[i];
+        node.requests += 1;
+        match &res {
+            Err(e) if is_connection_error(e) => {
+                warn!("server {} down: {}", self.ring.nodes()[i], e);
+                node.client = None;
+                node.down_until = Some(Instant::now() + self.retry_interval);
+                node.consecutive_failures += 1;
+                node.failures += 1;
+                node.last_error = Some(e.to_string());
+            }
+            _ => {
+                node.down_until = None;
+                node.consecutive_failures = 0;
+            }
+        }
+        res
+    }
+
+    fn connect(&mut self, i: usize) -> Result<&mut KvsClient> {
+        let node = &mut self.nodes[i];
+        if node.client.is_none() {
+            let mut client = KvsClient::new(&self.ring.nodes()[i])?;
+            if let Some(credentials) = &self.credentials {
+                client.auth(credentials.clone())?;
+            }
+            node.client = Some(client);
+        }
+        Ok(node.client.as_mut().unwrap())
+    }
+}
+
+fn unavailable(failure: Option<Error>) -> Error {
+    match failure {
+        Some(e) => Error::ServerError(format!("no server available, last error: {}", e)),
+        None => Error::ServerError("no server available".to_owned()),
+    }
+}
@@ -1,10 +1,91 @@
+use crate::auth::Credentials;
+use crate::err::Error;
+use crate::server::ServerInfo;
+use crate::Result;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+#[cfg(feature = "async")]
+use {
+    serde::de::DeserializeOwned,
+    std::io,
+    tokio::io::{AsyncRead, AsyncReadExt},
+};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    // Keys starting with `prefix`, sorted.
+    Scan {
+        prefix: String,
+    },
+    Auth {
+        credentials: Credentials,
+    },
+    Info,
+    // Answered with `Ok(None)`, to check that the connection is alive.
+    Ping,
+    Admin(AdminCommand),
+    // Turns the connection into a stream of the writes following `after`
+    // in the log `log_id`, see `replication`.
+    Replicate {
+        log_id: Option<String>,
+        after: u64,
+    },
+    // A request that may be sent again after a lost response, the server
+    // answers a repeated id with the response it sent first.
+    WithId {
+        id: RequestId,
+        request: Box<Request>,
+    },
+    // Turns the connection into a stream of the keys written from now on
+    // with one of `prefixes`, or any key, see `tracking`.
+    Track {
+        prefixes: Vec<String>,
+    },
+}
+
+/// Id of a request, unique to the client sending it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    /// Random id of the client
+    pub client: String,
+    /// Sequence of the request on the client
+    pub seq: u64,
+}
+
+/// Maintenance command for a running server, requires the `admin` permission
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    /// Reclaim the space of stale entries now
+    Compact,
+    /// Flush buffered writes and sync them to disk
+    Flush,
+    /// Copy a snapshot of the data to a new directory on the server,
+    /// which `kvs-server --data-dir` can open
+    Checkpoint {
+        /// Directory relative to the checkpoint directory of the server,
+        /// must not exist or be empty
+        path: String,
+    },
+    /// Reload the ACL, connection limits and log level from the configuration
+    ReloadConfig,
+    /// Set the maximum level of the server log: `off`, `error`, `warn`, `info`, `debug` or `trace`
+    SetLogLevel {
+        /// Name of the level
+        level: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -12,8 +93,164 @@ pub struct Response {
     pub body: ResponseBody,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ResponseBody {
     Ok(Option<String>),
     Err(String),
+    // The server has too many connections, sent before closing.
+    Busy,
+    // The client exceeded its rate limits, and may retry after this time.
+    RateLimited { retry_after_ms: u64 },
+    Info(Box<ServerInfo>),
+    // Values of `GetMany`, in the order of its keys.
+    Values(Vec<Option<String>>),
+    Keys(Vec<String>),
+    // The server is not the leader of its cluster, writes go to `leader`.
+    NotLeader { leader: Option<String> },
+}
+
+impl ResponseBody {
+    /// Error for a response that does not fit the request.
+    pub fn unexpected(self) -> Error {
+        Error::StringError(format!("unexpected response {:?}", self))
+    }
+}
+
+/// Write a JSON message followed by a newline, and flush it.
+pub(crate) fn write_line<W: Write, M: Serialize>(writer: &mut W, message: &M) -> Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Longest message `read_message` accepts
+#[cfg(feature = "async")]
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Bytes read by `read_message` and not consumed yet
+///
+/// Remembers how far the current message has been scanned, so that the
+/// bytes of a message arriving in pieces are only looked at once.
+#[cfg(feature = "async")]
+#[derive(Default)]
+pub(crate) struct MessageBuf {
+    buf: Vec<u8>,
+    // scanned bytes of `buf`, and the state of the scan there
+    pos: usize,
+    started: bool,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+#[cfg(feature = "async")]
+impl MessageBuf {
+    /// Whether nothing but whitespace is buffered
+    pub(crate) fn is_blank(&self) -> bool {
+        self.buf.iter().all(u8::is_ascii_whitespace)
+    }
+
+    // Length of the first complete value in `buf`, scanning from `pos`.
+    // A bare number or literal is only complete once followed by a delimiter.
+    fn scan(&mut self) -> Option<usize> {
+        while self.pos < self.buf.len() {
+            let b = self.buf[self.pos];
+            self.pos += 1;
+            if !self.started {
+                match b {
+                    b if b.is_ascii_whitespace() => {}
+                    b'{' | b'[' => {
+                        self.started = true;
+                        self.depth = 1;
+                    }
+                    b'"' => {
+                        self.started = true;
+                        self.in_string = true;
+                    }
+                    _ => self.started = true,
+                }
+                continue;
+            }
+            if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return Some(self.pos);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if self.depth == 0 {
+                // a bare number or literal
+                if b.is_ascii_whitespace() || b"{}[],:\"".contains(&b) {
+                    self.pos -= 1;
+                    return Some(self.pos);
+                }
+                continue;
+            }
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(self.pos);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Remove the first `len` bytes, and start scanning the next message.
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        self.pos = 0;
+        self.started = false;
+        self.depth = 0;
+        self.in_string = false;
+        self.escaped = false;
+    }
+}
+
+/// Read the next JSON message of the stream, `None` once it is closed.
+///
+/// `buf` keeps the bytes read past the message for the next call.
+/// Messages longer than `MAX_MESSAGE_LEN` are refused.
+#[cfg(feature = "async")]
+pub(crate) async fn read_message<R, M>(reader: &mut R, buf: &mut MessageBuf) -> Result<Option<M>>
+where
+    R: AsyncRead + Unpin,
+    M: DeserializeOwned,
+{
+    loop {
+        if let Some(len) = buf.scan() {
+            let message = serde_json::from_slice(&buf.buf[..len]);
+            buf.consume(len);
+            return Ok(Some(message?));
+        }
+        if buf.buf.len() > MAX_MESSAGE_LEN {
+            return Err(Error::StringError("message too long".to_owned()));
+        }
+        if reader.read_buf(&mut buf.buf).await? == 0 {
+            if buf.is_blank() {
+                return Ok(None);
+            }
+            // a bare number or literal ends with the stream
+            if buf.started && buf.depth == 0 && !buf.in_string {
+                let len = buf.buf.len();
+                let message = serde_json::from_slice(&buf.buf[..len]);
+                buf.consume(len);
+                return Ok(Some(message?));
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
 }
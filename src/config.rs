@@ -0,0 +1,530 @@
+//! Server configuration file
+//!
+//! `kvs-server --config FILE` reads a TOML file, every section and field is optional:
+//!
+//! ```toml
+//! data_dir = "/var/lib/kvs"
+//! engine = "kvs"
+//!
+//! [thread_pool]
+//! kind = "shared-queue"   # naive, shared-queue or rayon
+//! size = 8
+//!
+//! [storage]
+//! sync_writes = true
+//! compaction_threshold = 1048576
+//!
+//! [listen]
+//! addr = "127.0.0.1:4000"
+//! resp = "127.0.0.1:6379"
+//!
+//! [tls]
+//! cert = "server.pem"
+//! key = "server.key"
+//!
+//! [auth]
+//! acl = "kvs.acl"
+//!
+//! [admin]
+//! open = false                    # serve admin requests without an ACL
+//! checkpoint_dir = "/var/backups/kvs"
+//!
+//! [limits]
+//! max_connections = 1024
+//! idle_timeout = 300
+//!
+//! [rate_limit]
+//! ops_per_sec = 1000
+//! bytes_per_sec = 1048576
+//!
+//! [replication]
+//! leader = "10.0.0.1:4000"   # follow this server, read-only
+//! token = "s3cret"           # credentials of the follower on the leader
+//! log_capacity = 10000
+//!
+//! [cluster]
+//! id = 1
+//! secret = "change me"
+//! nodes = [
+//!     { id = 1, addr = "10.0.0.1:4000", raft_addr = "10.0.0.1:4100" },
+//!     { id = 2, addr = "10.0.0.2:4000", raft_addr = "10.0.0.2:4100" },
+//!     { id = 3, addr = "10.0.0.3:4000", raft_addr = "10.0.0.3:4100" },
+//! ]
+//!
+//! [log]
+//! level = "info"
+//! redact_values = true
+//! slow_threshold_ms = 100
+//!
+//! [[quota]]
+//! prefix = "batch/"
+//! max_bytes = 1073741824
+//! ```
+//!
+//! Flags given on the command line override the values of the file.
+
+use crate::auth::Credentials;
+use crate::err::Error;
+use crate::raft::NodeId;
+use crate::{
+    Cluster, ClusterNode, ConnectionLimits, KvStoreOptions, Quota, RateLimits, RequestLog, Result,
+    DEFAULT_LOG_CAPACITY,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Printed in place of the secrets
+const REDACTED: &str = "***";
+
+/// Configuration of `kvs-server`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Directory of the engine files, the working directory if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    /// `kvs` or `sled`, the engine of the existing data if unset, `kvs` for a new data directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub engine: Option<String>,
+    /// Thread pool serving the connections
+    pub thread_pool: ThreadPoolConfig,
+    /// Durability and compaction
+    pub storage: StorageConfig,
+    /// Addresses to serve on
+    pub listen: ListenConfig,
+    /// TLS on the native protocol address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Authentication
+    pub auth: AuthConfig,
+    /// Admin requests
+    pub admin: AdminConfig,
+    /// Connection limits and timeouts
+    pub limits: LimitsConfig,
+    /// Rate limits of every client
+    pub rate_limit: RateLimitConfig,
+    /// Replication from a leader, and to followers
+    pub replication: ReplicationConfig,
+    /// Raft cluster the server is a node of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<ClusterConfig>,
+    /// Logging
+    pub log: LogConfig,
+    /// Storage quotas
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quota: Vec<QuotaConfig>,
+}
+
+impl ServerConfig {
+    /// Read the configuration from a TOML file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        text.parse().map_err(|e| match e {
+            Error::StringError(msg) => Error::StringError(format!("{}: {}", path.display(), msg)),
+            e => e,
+        })
+    }
+
+    /// The configuration as a TOML document, with the secrets redacted
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        if let Some(token) = &mut config.replication.token {
+            *token = REDACTED.to_owned();
+        }
+        if let Some(cluster) = &mut config.cluster {
+            cluster.secret = REDACTED.to_owned();
+        }
+        toml::to_string(&config).expect("config serializes to toml")
+    }
+
+    /// The storage quotas
+    pub fn quotas(&self) -> Vec<Quota> {
+        self.quota
+            .iter()
+            .map(|quota| Quota {
+                prefix: quota.prefix.clone(),
+                max_bytes: quota.max_bytes,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for ServerConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let config: ServerConfig =
+            toml::from_str(s).map_err(|e| Error::StringError(e.message().to_owned()))?;
+        if let Some(engine) = config
+            .engine
+            .as_deref()
+            .filter(|e| *e != "kvs" && *e != "sled")
+        {
+            return Err(Error::StringError(format!("unknown engine `{}`", engine)));
+        }
+        if config.thread_pool.size == 0 {
+            return Err(Error::StringError(
+                "thread pool size must not be 0".to_owned(),
+            ));
+        }
+        let limits = &config.limits;
+        if [
+            limits.idle_timeout,
+            limits.read_timeout,
+            limits.write_timeout,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(Error::StringError("timeouts must not be 0".to_owned()));
+        }
+        let rate_limit = &config.rate_limit;
+        if [rate_limit.ops_per_sec, rate_limit.bytes_per_sec].contains(&Some(0)) {
+            return Err(Error::StringError("rate limits must not be 0".to_owned()));
+        }
+        if let Some(cluster) = &config.cluster {
+            cluster.validate()?;
+        }
+        Ok(config)
+    }
+}
+
+/// Kind of thread pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThreadPoolKind {
+    /// A new thread per job
+    Naive,
+    /// Fixed threads taking jobs from a shared queue
+    SharedQueue,
+    /// rayon's thread pool
+    Rayon,
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared-queue" => Ok(ThreadPoolKind::SharedQueue),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(Error::StringError(format!("unknown thread pool `{}`", s))),
+        }
+    }
+}
+
+/// `[thread_pool]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadPoolConfig {
+    /// Kind of the pool, `shared-queue` by default
+    pub kind: ThreadPoolKind,
+    /// Number of threads, the number of CPUs by default
+    pub size: usize,
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        ThreadPoolConfig {
+            kind: ThreadPoolKind::SharedQueue,
+            size: num_cpus::get(),
+        }
+    }
+}
+
+/// `[storage]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Sync every write to disk, the engine's default if unset
+    /// (off for `kvs`, on for `sled`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_writes: Option<bool>,
+    /// Bytes of stale log entries that trigger a compaction of the `kvs` engine, 0 never compacts
+    pub compaction_threshold: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            sync_writes: None,
+            compaction_threshold: KvStoreOptions::default().compaction_threshold,
+        }
+    }
+}
+
+/// `[listen]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Address of the native protocol
+    pub addr: String,
+    /// Address of the Redis protocol
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resp: Option<String>,
+    /// Address of the HTTP/JSON gateway
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    /// Address of the memcached protocol
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memcache: Option<String>,
+    /// Address of the Prometheus metrics endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<String>,
+    /// Unix domain socket of the native protocol
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix: Option<PathBuf>,
+    /// File permissions of the Unix domain socket, in octal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unix_mode: Option<String>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            addr: "127.0.0.1:4000".to_owned(),
+            resp: None,
+            http: None,
+            memcache: None,
+            metrics: None,
+            unix: None,
+            unix_mode: None,
+        }
+    }
+}
+
+/// `[tls]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain (PEM)
+    pub cert: PathBuf,
+    /// Private key (PEM)
+    pub key: PathBuf,
+    /// Require client certificates issued by these CAs (PEM)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// `[auth]` section
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// ACL file, clients need not authenticate if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acl: Option<PathBuf>,
+}
+
+/// `[admin]` section
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Serve admin requests and followers without an ACL, which would let any client send them
+    pub open: bool,
+    /// Directory checkpoints are created in, checkpoints are refused if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkpoint_dir: Option<PathBuf>,
+}
+
+/// `[limits]` section, timeouts are in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum number of open connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Close connections idle between requests for this long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    /// Close connections that stall for this long in the middle of a request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+    /// Close connections whose responses cannot be written for this long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_timeout: Option<u64>,
+    /// Time open connections get to finish on shutdown
+    pub shutdown_timeout: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            shutdown_timeout: 10,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// The limits of the connections
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            read_timeout: self.read_timeout.map(Duration::from_secs),
+            write_timeout: self.write_timeout.map(Duration::from_secs),
+        }
+    }
+}
+
+/// `[rate_limit]` section, each client gets these rates
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Requests per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ops_per_sec: Option<u64>,
+    /// Bytes of keys and values sent per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl RateLimitConfig {
+    /// The rate limits of the clients
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            ops_per_sec: self.ops_per_sec,
+            bytes_per_sec: self.bytes_per_sec,
+        }
+    }
+}
+
+/// `[[quota]]` section, one per prefix
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Prefix of the keys
+    pub prefix: String,
+    /// Maximum total size of the keys and their values
+    pub max_bytes: u64,
+}
+
+/// `[replication]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Address of the leader to follow, the server refuses writes from clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+    /// Token the follower authenticates with, when the leader has an ACL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Latest writes kept for followers to catch up from
+    pub log_capacity: usize,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            leader: None,
+            token: None,
+            log_capacity: DEFAULT_LOG_CAPACITY,
+        }
+    }
+}
+
+impl ReplicationConfig {
+    /// Credentials of the follower on its leader
+    pub fn credentials(&self) -> Option<Credentials> {
+        self.token.clone().map(Credentials::Token)
+    }
+}
+
+/// `[cluster]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Identifier of this server, one of the nodes
+    pub id: NodeId,
+    /// Every node of the cluster, including this server
+    pub nodes: Vec<ClusterNode>,
+    /// Secret shared by the nodes, required on their Raft connections
+    pub secret: String,
+    /// Directory of the Raft state and log, `raft` in the data directory if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+    /// Interval of the Raft clock in milliseconds, 100 if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tick_ms: Option<u64>,
+}
+
+impl ClusterConfig {
+    /// Check that the server is one of the nodes, which are told apart by their identifiers
+    pub fn validate(&self) -> Result<()> {
+        let mut ids = HashSet::new();
+        if !self.nodes.iter().all(|node| ids.insert(node.id)) {
+            return Err(Error::StringError(
+                "cluster node ids must be unique".to_owned(),
+            ));
+        }
+        if !ids.contains(&self.id) {
+            return Err(Error::StringError(format!(
+                "node {} is not in the cluster",
+                self.id
+            )));
+        }
+        if self.tick_ms == Some(0) {
+            return Err(Error::StringError("tick_ms must not be 0".to_owned()));
+        }
+        if self.secret.is_empty() {
+            return Err(Error::StringError(
+                "the cluster secret must not be empty".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Membership of the server, keeping the Raft state under `data_dir` unless set
+    pub fn cluster(&self, data_dir: &Path) -> Cluster {
+        let state_dir = self
+            .state_dir
+            .clone()
+            .unwrap_or_else(|| data_dir.join("raft"));
+        let mut cluster = Cluster::new(self.id, self.nodes.clone(), state_dir, &*self.secret);
+        if let Some(tick_ms) = self.tick_ms {
+            cluster.tick = Duration::from_millis(tick_ms);
+        }
+        cluster
+    }
+}
+
+/// `[log]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in `RUST_LOG` syntax, `RUST_LOG` itself takes precedence
+    pub level: String,
+    /// Log values as their length only
+    pub redact_values: bool,
+    /// Log requests taking longer than this many milliseconds at `warn`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_threshold_ms: Option<u64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            redact_values: true,
+            slow_threshold_ms: None,
+        }
+    }
+}
+
+impl LogConfig {
+    /// How requests are logged
+    pub fn request_log(&self) -> RequestLog {
+        RequestLog {
+            redact_values: self.redact_values,
+            slow_threshold: self.slow_threshold_ms.map(Duration::from_millis),
+        }
+    }
+}
@@ -0,0 +1,1343 @@
+//! State shared by all connections of a server
+
+use crate::auth::{Acl, Credentials, Permission};
+use crate::cluster::ClusterHandle;
+use crate::common::{AdminCommand, RequestId, ResponseBody};
+use crate::err::Error;
+use crate::metrics::Metrics;
+use crate::net::{Socket, Stream};
+use crate::ratelimit::{Quota, RateLimiter, RateLimits};
+use crate::replication::{FollowerState, ReplLog, ReplicationInfo, WriteOp};
+use crate::server::{ConnectionLimits, Reload, ServerInfo};
+use crate::shutdown::ShutdownHandle;
+use crate::trace::{RequestLog, Timings};
+use crate::{KvsEngine, Result};
+use log::{info, warn, LevelFilter};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::Shutdown;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Context wraps the engine with the server-wide state.
+///
+/// Every front-end goes through the context instead of the engine,
+/// so that key metadata such as expirations stays consistent
+/// whichever protocol a client speaks.
+#[derive(Clone)]
+pub(crate) struct Context<T: KvsEngine> {
+    engine: T,
+    shared: Arc<Shared>,
+    metrics: Metrics,
+    // of the thread pool, `None` without one or without a fixed number
+    threads: Option<usize>,
+    reload: Option<Arc<ReloadFn>>,
+    // writes go through the Raft log of the cluster
+    cluster: Option<Arc<ClusterHandle>>,
+    // admin requests are served without an ACL
+    open_admin: bool,
+    // checkpoints are created under it, refused if unset
+    checkpoint_dir: Option<Arc<Path>>,
+}
+
+pub(crate) type ReloadFn = dyn Fn() -> Result<Reload> + Send + Sync;
+
+struct Shared {
+    started: Instant,
+    stats: Stats,
+    // Metadata of the keys, striped by key. Writes hold the stripe of their
+    // key, which makes conditional sets atomic, reads only look it up.
+    meta: Vec<Mutex<Meta>>,
+    // Taken after a stripe, never before.
+    quotas: Mutex<Vec<QuotaUsage>>,
+    // Last version given to a write. It starts from the time the server
+    // started, above the versions handed out before a restart.
+    versions: AtomicU64,
+    // of the keys not written since the server started
+    first_version: u64,
+    // Engine keys under `META_PREFIX`, left out of the key count
+    meta_keys: AtomicU64,
+    // `None` lets every client do anything.
+    acl: RwLock<Option<Arc<Acl>>>,
+    shutdown: ShutdownHandle,
+    // Sockets of the open connections, shut down to stop the server.
+    connections: Mutex<Connections>,
+    limits: RwLock<ConnectionLimits>,
+    request_log: RwLock<RequestLog>,
+    rate_limiter: Mutex<RateLimiter>,
+    repl_log: ReplLog,
+    // `Some` on a follower, which refuses writes from clients
+    follower: Mutex<Option<FollowerState>>,
+    replies: Mutex<ReplyCache>,
+}
+
+#[derive(Default)]
+struct Connections {
+    sockets: HashMap<u64, Socket>,
+    last_id: u64,
+}
+
+// Responses to the last requests sent with an id, for the clients retrying them.
+#[derive(Default)]
+struct ReplyCache {
+    responses: HashMap<RequestId, ResponseBody>,
+    // oldest first
+    order: VecDeque<RequestId>,
+}
+
+// Requests whose responses are kept, enough for the retries of many clients
+const REPLY_CACHE_CAPACITY: usize = 10_000;
+
+// Stripes of the key metadata, writes of keys in different ones run concurrently
+const META_STRIPES: usize = 64;
+
+// Engine keys holding the expiration and flags of the key after the prefix,
+// hidden from clients
+const META_PREFIX: &str = "\0meta\0";
+
+// How often expired keys nobody reads are removed, and how many of a stripe
+// at most each time, so that writes do not wait long for the stripe.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+const SWEEP_BATCH: usize = 100;
+
+#[derive(Default)]
+struct Stats {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    // refused because of the connection limit
+    connections_rejected: AtomicU64,
+    // closed by an idle, read or write timeout
+    connections_timed_out: AtomicU64,
+    requests_total: AtomicU64,
+    errors_total: AtomicU64,
+}
+
+/// Point-in-time copy of the server counters
+#[derive(Debug, Serialize)]
+pub(crate) struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub keys: usize,
+    pub connections_total: u64,
+    pub connections_active: u64,
+    pub connections_rejected: u64,
+    pub connections_timed_out: u64,
+    pub requests_total: u64,
+    pub errors_total: u64,
+    pub replication: ReplicationInfo,
+}
+
+/// Tracks a connection as active until dropped
+pub(crate) struct ConnectionGuard {
+    shared: Arc<Shared>,
+    metrics: Metrics,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let stats = &self.shared.stats;
+        stats.connections_active.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.add_connections(-1);
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .sockets
+            .remove(&self.id);
+    }
+}
+
+/// Principal a connection authenticated as
+#[derive(Debug)]
+pub(crate) struct Session {
+    principal: Option<String>,
+    // address of the client, see `Stream::peer`
+    peer: String,
+}
+
+impl Session {
+    pub fn new(peer: String) -> Self {
+        Session {
+            principal: None,
+            peer,
+        }
+    }
+
+    // Whom the rate limits apply to.
+    fn client(&self) -> String {
+        match &self.principal {
+            Some(principal) => format!("principal {}", principal),
+            None => format!("address {}", self.peer),
+        }
+    }
+}
+
+// Keys of a stripe written since the server started, or with an expiration
+#[derive(Default)]
+struct Meta {
+    keys: HashMap<String, KeyMeta>,
+    // expiring keys, soonest first
+    deadlines: BTreeSet<(Instant, String)>,
+}
+
+struct QuotaUsage {
+    quota: Quota,
+    used: u64,
+}
+
+// Metadata of a key. The expiration and flags are stored in the engine
+// too, under `META_PREFIX`, the version only in memory.
+struct KeyMeta {
+    expires: Option<Instant>,
+    flags: u32,
+    // CAS token, a new one for every write
+    version: u64,
+}
+
+impl Meta {
+    fn expires(&self, key: &str) -> Option<Instant> {
+        self.keys.get(key).and_then(|meta| meta.expires)
+    }
+
+    fn flags(&self, key: &str) -> u32 {
+        self.keys.get(key).map_or(0, |meta| meta.flags)
+    }
+
+    fn version(&self, key: &str, first_version: u64) -> u64 {
+        self.keys
+            .get(key)
+            .map_or(first_version, |meta| meta.version)
+    }
+
+    // Whether the engine has the expiration and flags of the key.
+    fn persisted(&self, key: &str) -> bool {
+        self.keys
+            .get(key)
+            .is_some_and(|meta| meta.expires.is_some() || meta.flags != 0)
+    }
+
+    // Record the metadata of a key just written.
+    fn insert(&mut self, key: String, expires: Option<Instant>, flags: u32, version: u64) {
+        self.remove(&key);
+        if let Some(deadline) = expires {
+            self.deadlines.insert((deadline, key.clone()));
+        }
+        let meta = KeyMeta {
+            expires,
+            flags,
+            version,
+        };
+        self.keys.insert(key, meta);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(KeyMeta {
+            expires: Some(deadline),
+            ..
+        }) = self.keys.remove(key)
+        {
+            self.deadlines.remove(&(deadline, key.to_owned()));
+        }
+    }
+
+    // Keys expired by `now`, `max` at most.
+    fn expired(&self, now: Instant, max: usize) -> Vec<String> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .take(max)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    // Give a new version to a key whose value alone changed.
+    fn touch(&mut self, key: &str, version: u64) {
+        match self.keys.get_mut(key) {
+            Some(meta) => meta.version = version,
+            None => self.insert(key.to_owned(), None, 0, version),
+        }
+    }
+}
+
+/// Options of a conditional set
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SetOptions {
+    pub expire: Option<Duration>,
+    pub flags: u32,
+    pub only_if_absent: bool,
+    pub only_if_present: bool,
+    /// Only set if the key still has this version
+    pub cas: Option<u64>,
+}
+
+/// Outcome of a conditional set
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SetResult {
+    Stored,
+    /// `only_if_absent` or `only_if_present` did not hold
+    NotStored,
+    /// The key was modified since the `cas` version
+    Exists,
+    /// `cas` was given but the key does not exist
+    NotFound,
+}
+
+/// Value read together with its metadata
+#[derive(Debug)]
+pub(crate) struct Item {
+    pub value: String,
+    pub version: u64,
+    pub flags: u32,
+}
+
+/// Outcome of an increment
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum IncrResult {
+    Value(u64),
+    NotFound,
+    NotNumeric,
+}
+
+impl<T: KvsEngine> Context<T> {
+    pub fn new(engine: T) -> Self {
+        let metrics = Metrics::default();
+        let first_version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |since| since.as_micros() as u64);
+        engine.set_metrics(metrics.clone());
+        Context {
+            engine,
+            metrics,
+            threads: None,
+            reload: None,
+            cluster: None,
+            open_admin: false,
+            checkpoint_dir: None,
+            shared: Arc::new(Shared {
+                started: Instant::now(),
+                stats: Stats::default(),
+                meta: (0..META_STRIPES).map(|_| Mutex::default()).collect(),
+                quotas: Mutex::new(Vec::new()),
+                versions: AtomicU64::new(first_version),
+                first_version,
+                meta_keys: AtomicU64::new(0),
+                acl: RwLock::new(None),
+                shutdown: ShutdownHandle::default(),
+                connections: Mutex::new(Connections::default()),
+                limits: RwLock::new(ConnectionLimits::default()),
+                request_log: RwLock::new(RequestLog::default()),
+                rate_limiter: Mutex::new(RateLimiter::default()),
+                repl_log: ReplLog::default(),
+                follower: Mutex::new(None),
+                replies: Mutex::new(ReplyCache::default()),
+            }),
+        }
+    }
+
+    /// Report to `metrics` instead, along with the engine.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.engine.set_metrics(metrics.clone());
+        self.metrics = metrics;
+    }
+
+    pub fn set_threads(&mut self, threads: Option<usize>) {
+        self.threads = threads;
+    }
+
+    pub fn set_reload(&mut self, reload: Arc<ReloadFn>) {
+        self.reload = Some(reload);
+    }
+
+    pub fn set_cluster(&mut self, cluster: Arc<ClusterHandle>) {
+        self.cluster = Some(cluster);
+    }
+
+    pub fn set_open_admin(&mut self, open: bool) {
+        self.open_admin = open;
+    }
+
+    pub fn set_checkpoint_dir(&mut self, dir: PathBuf) {
+        self.checkpoint_dir = Some(dir.into());
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.shared.started.elapsed()
+    }
+
+    pub fn shutdown_handle(&self) -> &ShutdownHandle {
+        &self.shared.shutdown
+    }
+
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        *self.shared.limits.write().unwrap() = limits;
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        *self.shared.limits.read().unwrap()
+    }
+
+    pub fn set_request_log(&self, request_log: RequestLog) {
+        *self.shared.request_log.write().unwrap() = request_log;
+    }
+
+    pub fn request_log(&self) -> RequestLog {
+        *self.shared.request_log.read().unwrap()
+    }
+
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.shared.rate_limiter.lock().unwrap().set_limits(limits);
+    }
+
+    /// Count a request sending `bytes` of keys and values against the rate
+    /// limits of the session's client.
+    pub fn throttle(&self, session: &Session, bytes: usize) -> Result<()> {
+        self.shared
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .take(&session.client(), bytes as u64)
+            .map_err(Error::RateLimited)
+    }
+
+    /// Replace the storage quotas, which are checked against the data
+    /// already stored under their prefixes.
+    pub fn set_quotas(&self, quotas: Vec<Quota>) -> Result<()> {
+        // no write runs meanwhile
+        let _meta = self.lock_all();
+        let mut usages = Vec::with_capacity(quotas.len());
+        for quota in quotas {
+            let mut used = 0;
+            for key in self.engine.scan(quota.prefix.clone())? {
+                if reserved(&key) {
+                    continue;
+                }
+                if let Some(value) = self.engine.get(key.clone())? {
+                    used += (key.len() + value.len()) as u64;
+                }
+            }
+            usages.push(QuotaUsage { quota, used });
+        }
+        *self.shared.quotas.lock().unwrap() = usages;
+        Ok(())
+    }
+
+    pub fn repl_log(&self) -> &ReplLog {
+        &self.shared.repl_log
+    }
+
+    /// Refuse writes from clients from now on, they come from `leader` instead.
+    pub fn set_leader(&self, leader: String) {
+        *self.shared.follower.lock().unwrap() = Some(FollowerState::new(leader));
+    }
+
+    /// Update the progress of the follower, `None` if the server is not one.
+    pub fn update_follower<R>(&self, f: impl FnOnce(&mut FollowerState) -> R) -> Option<R> {
+        self.shared.follower.lock().unwrap().as_mut().map(f)
+    }
+
+    pub fn replication_info(&self) -> ReplicationInfo {
+        let log = &self.shared.repl_log;
+        match &*self.shared.follower.lock().unwrap() {
+            Some(state) => state.info(log),
+            None => ReplicationInfo {
+                log_seq: log.last_seq(),
+                followers: log.followers(),
+                ..ReplicationInfo::default()
+            },
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.shared.follower.lock().unwrap().is_some() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Every live key as a write, and the sequence number of the last write
+    /// of the log they include.
+    ///
+    /// Writes go on while the keys are read. They are logged after being
+    /// applied, so the writes after the sequence number taken first include
+    /// every one the snapshot may have missed, and replaying those it did
+    /// not miss writes the same values again.
+    pub fn snapshot(&self) -> Result<(u64, Vec<WriteOp>)> {
+        let seq = self.shared.repl_log.last_seq();
+        let mut ops = Vec::new();
+        for key in self.engine.scan(String::new())? {
+            if reserved(&key) || self.expired(&key) {
+                continue;
+            }
+            if let Some(value) = self.engine.get(key.clone())? {
+                let op = set_op(&self.meta(&key), key, value);
+                ops.push(op);
+            }
+        }
+        Ok((seq, ops))
+    }
+
+    /// Load the expirations and flags stored with the keys, then remove
+    /// the keys as they expire, until the server shuts down.
+    ///
+    /// Expired keys are removed when read too, the thread removes those
+    /// nobody reads, which would stay on disk otherwise.
+    pub fn start_expiry(&self) -> Result<JoinHandle<()>> {
+        self.load_meta()?;
+        let context = self.clone();
+        Ok(thread::spawn(move || {
+            while !context.shutdown_handle().is_shutdown() {
+                context.sweep();
+                thread::sleep(SWEEP_INTERVAL);
+            }
+        }))
+    }
+
+    fn load_meta(&self) -> Result<()> {
+        for stored in self.engine.scan(META_PREFIX.to_owned())? {
+            let key = &stored[META_PREFIX.len()..];
+            let mut meta = self.meta(key);
+            let value = match self.engine.get(stored.clone())? {
+                Some(value) => value,
+                None => continue,
+            };
+            let parsed = value
+                .split_once(' ')
+                .and_then(|(expires, flags)| Some((expires.parse().ok()?, flags.parse().ok()?)));
+            let (expires_ms, flags): (u64, u32) = match parsed {
+                Some(parsed) if self.engine.get(key.to_owned())?.is_some() => parsed,
+                // left by a crash between the writes of a key and its metadata
+                _ => {
+                    self.engine.remove(stored)?;
+                    continue;
+                }
+            };
+            self.shared.meta_keys.fetch_add(1, Ordering::SeqCst);
+            let expires = (expires_ms != 0).then(|| {
+                let left = expires_ms.saturating_sub(unix_ms(SystemTime::now()));
+                Instant::now() + Duration::from_millis(left)
+            });
+            meta.insert(key.to_owned(), expires, flags, self.shared.first_version);
+        }
+        Ok(())
+    }
+
+    // Removes the keys expired by now.
+    fn sweep(&self) {
+        let now = Instant::now();
+        for stripe in &self.shared.meta {
+            let mut meta = stripe.lock().unwrap();
+            for key in meta.expired(now, SWEEP_BATCH) {
+                if let Err(e) = self.purge_expired(&mut meta, &key) {
+                    warn!("failed to remove expired key {:?}: {:?}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Apply a write of the leader, regardless of quotas.
+    pub fn apply(&self, op: WriteOp) -> Result<()> {
+        let mut meta = self.meta(op.key());
+        match op {
+            WriteOp::Set {
+                key,
+                value,
+                ttl_ms,
+                flags,
+            } => {
+                let delta = self.size_delta(&key, Some(&value))?;
+                let expires = ttl_ms.map(|ttl| Instant::now() + Duration::from_millis(ttl));
+                self.persist_meta(&meta, &key, expires, flags)?;
+                self.engine.set(key.clone(), value.clone())?;
+                self.charge(&key, delta);
+                meta.insert(key.clone(), expires, flags, self.next_version());
+                self.shared.repl_log.append(set_op(&meta, key, value));
+            }
+            WriteOp::Remove { key } => match self.remove_key(&mut meta, key) {
+                Ok(()) | Err(Error::RecordNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+        Ok(())
+    }
+
+    /// Remove the keys missing from `keys`, once a snapshot has been applied.
+    pub fn retain_keys(&self, keys: &HashSet<String>) -> Result<()> {
+        for key in self.engine.scan(String::new())? {
+            if !reserved(&key) && !keys.contains(&key) {
+                self.apply(WriteOp::Remove { key })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the request described by `req` if it was slower than the threshold.
+    pub fn log_slow_request(&self, req: &str, timings: Timings) {
+        match self.request_log().slow_threshold {
+            Some(threshold) if timings.total() > threshold => {
+                warn!(target: "kvs::slow", "slow request {} took {}", req, timings)
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a new connection, which stays active while the guard lives,
+    /// or `None` if the connection limit has been reached.
+    ///
+    /// The connection is tracked so that shutdown can close it, and is
+    /// closed for reading right away if shutdown has already begun.
+    pub fn connection(&self, stream: &Stream) -> Option<ConnectionGuard> {
+        let guard = self.untracked_connection()?;
+        let mut connections = self.shared.connections.lock().unwrap();
+        let id = guard.id;
+        match stream.socket() {
+            Ok(socket) => {
+                if self.shared.shutdown.is_shutdown() {
+                    let _ = socket.shutdown(Shutdown::Read);
+                }
+                connections.sockets.insert(id, socket);
+            }
+            Err(e) => warn!("connection {} is not tracked: {}", id, e),
+        }
+        Some(guard)
+    }
+
+    /// Count a new connection that shutdown cannot close,
+    /// its owner has to watch the `ShutdownHandle` instead.
+    pub fn untracked_connection(&self) -> Option<ConnectionGuard> {
+        let stats = &self.shared.stats;
+        let max = self
+            .limits()
+            .max_connections
+            .map_or(u64::MAX, |max| max as u64);
+        let admitted =
+            stats
+                .connections_active
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                    (active < max).then_some(active + 1)
+                });
+        if admitted.is_err() {
+            stats.connections_rejected.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        stats.connections_total.fetch_add(1, Ordering::SeqCst);
+        self.metrics.add_connections(1);
+        let mut connections = self.shared.connections.lock().unwrap();
+        connections.last_id += 1;
+        Some(ConnectionGuard {
+            shared: Arc::clone(&self.shared),
+            metrics: self.metrics.clone(),
+            id: connections.last_id,
+        })
+    }
+
+    /// Count a connection closed by a timeout.
+    pub fn record_timeout(&self) {
+        let stats = &self.shared.stats;
+        stats.connections_timed_out.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Wait for the open connections to finish within `timeout`.
+    ///
+    /// Connections are closed for reading, so the requests being served
+    /// can still reply while idle connections see the end of the stream.
+    /// Those still open after the timeout are closed entirely.
+    pub fn drain_connections(&self, timeout: Duration) {
+        let shutdown_all = |how| {
+            for socket in self.shared.connections.lock().unwrap().sockets.values() {
+                let _ = socket.shutdown(how);
+            }
+        };
+        shutdown_all(Shutdown::Read);
+        if !self.wait_connections(timeout) {
+            warn!("connections still open after {:?}, closing them", timeout);
+            shutdown_all(Shutdown::Both);
+            self.wait_connections(timeout);
+        }
+    }
+
+    // Returns whether all connections have finished.
+    fn wait_connections(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let active = || self.shared.stats.connections_active.load(Ordering::SeqCst);
+        while active() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Flush buffered writes of the engine to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    /// Response sent to the request `id` already, if still kept.
+    pub fn replayed(&self, id: &RequestId) -> Option<ResponseBody> {
+        self.shared
+            .replies
+            .lock()
+            .unwrap()
+            .responses
+            .get(id)
+            .cloned()
+    }
+
+    /// Keep the response to the request `id`, forgetting the oldest ones past the capacity.
+    pub fn remember(&self, id: RequestId, body: ResponseBody) {
+        let mut replies = self.shared.replies.lock().unwrap();
+        if replies.responses.insert(id.clone(), body).is_none() {
+            replies.order.push_back(id);
+        }
+        while replies.order.len() > REPLY_CACHE_CAPACITY {
+            if let Some(oldest) = replies.order.pop_front() {
+                replies.responses.remove(&oldest);
+            }
+        }
+    }
+
+    /// Count a served request and whether it failed.
+    pub fn record_request(&self, failed: bool) {
+        let stats = &self.shared.stats;
+        stats.requests_total.fetch_add(1, Ordering::SeqCst);
+        if failed {
+            stats.errors_total.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn stats(&self) -> Result<StatsSnapshot> {
+        let stats = &self.shared.stats;
+        Ok(StatsSnapshot {
+            uptime_secs: self.uptime().as_secs(),
+            keys: self.key_count()?,
+            connections_total: stats.connections_total.load(Ordering::SeqCst),
+            connections_active: stats.connections_active.load(Ordering::SeqCst),
+            connections_rejected: stats.connections_rejected.load(Ordering::SeqCst),
+            connections_timed_out: stats.connections_timed_out.load(Ordering::SeqCst),
+            requests_total: stats.requests_total.load(Ordering::SeqCst),
+            errors_total: stats.errors_total.load(Ordering::SeqCst),
+            replication: self.replication_info(),
+        })
+    }
+
+    /// Number of keys, without scanning them. Keys expired moments ago may
+    /// still be counted until removed.
+    pub fn key_count(&self) -> Result<usize> {
+        let keys = self.engine.stats()?.keys;
+        let meta_keys = self.shared.meta_keys.load(Ordering::SeqCst);
+        Ok(keys.saturating_sub(meta_keys) as usize)
+    }
+
+    /// Describe the server, its engine and its connections.
+    pub fn info(&self) -> Result<ServerInfo> {
+        let stats = self.stats()?;
+        let engine = self.engine.stats()?;
+        let garbage_ratio = match engine.disk_bytes {
+            0 => 0.0,
+            disk_bytes => engine.garbage_bytes as f64 / disk_bytes as f64,
+        };
+        Ok(ServerInfo {
+            engine: self.engine.name().to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime_secs: stats.uptime_secs,
+            keys: stats.keys,
+            disk_bytes: engine.disk_bytes,
+            garbage_bytes: engine.garbage_bytes,
+            garbage_ratio,
+            connections_active: stats.connections_active,
+            connections_total: stats.connections_total,
+            connections_rejected: stats.connections_rejected,
+            connections_timed_out: stats.connections_timed_out,
+            requests_total: stats.requests_total,
+            errors_total: stats.errors_total,
+            threads: self.threads,
+            queued_jobs: self.metrics.queued_jobs(),
+            replication: stats.replication,
+            cluster: self.cluster.as_ref().map(|cluster| cluster.info()),
+        })
+    }
+
+    /// Carry out a maintenance command, returns a message for the operator if any.
+    pub fn admin(&self, cmd: AdminCommand) -> Result<Option<String>> {
+        info!("admin {:?}", cmd);
+        match cmd {
+            AdminCommand::Compact => {
+                let before = self.engine.stats()?;
+                self.engine.compact()?;
+                let after = self.engine.stats()?;
+                Ok(Some(format!(
+                    "compacted {} bytes to {}",
+                    before.disk_bytes, after.disk_bytes
+                )))
+            }
+            AdminCommand::Flush => {
+                self.engine.flush()?;
+                Ok(None)
+            }
+            AdminCommand::Checkpoint { path } => {
+                let path = self.checkpoint_path(&path)?;
+                let path = path.as_path();
+                self.engine.checkpoint(path)?;
+                // the marker `kvs-server` checks the engine against
+                fs::write(path.join("engine"), self.engine.name())?;
+                Ok(Some(format!("checkpoint created at {}", path.display())))
+            }
+            AdminCommand::ReloadConfig => {
+                let reload = self
+                    .reload
+                    .as_ref()
+                    .ok_or_else(|| Error::StringError("no configuration to reload".to_owned()))?;
+                let Reload {
+                    acl,
+                    limits,
+                    request_log,
+                    rate_limits,
+                    quotas,
+                } = reload()?;
+                self.set_quotas(quotas)?;
+                self.set_acl(acl);
+                self.set_limits(limits);
+                self.set_request_log(request_log);
+                self.set_rate_limits(rate_limits);
+                Ok(None)
+            }
+            AdminCommand::SetLogLevel { level } => {
+                let level = level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| Error::StringError(format!("unknown log level `{}`", level)))?;
+                log::set_max_level(level);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Replace the ACL checked by every connection, `None` disables access control.
+    pub fn set_acl(&self, acl: Option<Acl>) {
+        *self.shared.acl.write().unwrap() = acl.map(Arc::new);
+    }
+
+    fn acl(&self) -> Option<Arc<Acl>> {
+        self.shared.acl.read().unwrap().clone()
+    }
+
+    /// Whether clients have to authenticate before sending commands.
+    pub fn requires_auth(&self) -> bool {
+        self.acl().is_some()
+    }
+
+    /// Authenticate the session, any credentials are accepted without an ACL.
+    pub fn authenticate(&self, session: &mut Session, credentials: &Credentials) -> Result<()> {
+        let acl = match self.acl() {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        match acl.authenticate(credentials) {
+            Some(principal) => {
+                session.principal = Some(principal);
+                Ok(())
+            }
+            None => {
+                session.principal = None;
+                Err(Error::AuthError("invalid credentials".to_owned()))
+            }
+        }
+    }
+
+    /// Check that the session has been authenticated, if required.
+    pub fn check_authenticated(&self, session: &Session) -> Result<()> {
+        if self.requires_auth() && session.principal.is_none() {
+            return Err(Error::AuthError("authentication required".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Check that the session may send admin requests or replicate: it needs
+    /// the `admin` permission, or the server has no ACL and admin is open.
+    pub fn authorize_admin(&self, session: &Session) -> Result<()> {
+        if self.acl().is_none() && !self.open_admin {
+            return Err(Error::PermissionDenied);
+        }
+        self.authorize(session, Permission::Admin, "")
+    }
+
+    // Where to create the checkpoint `path`, relative to the checkpoint directory.
+    fn checkpoint_path(&self, path: &str) -> Result<PathBuf> {
+        let dir = self
+            .checkpoint_dir
+            .as_ref()
+            .ok_or_else(|| Error::StringError("no checkpoint directory configured".to_owned()))?;
+        let path = Path::new(path);
+        let relative = path.components().count() > 0
+            && path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !relative {
+            return Err(Error::StringError(format!(
+                "checkpoint path {} must be relative to the checkpoint directory",
+                path.display()
+            )));
+        }
+        Ok(dir.join(path))
+    }
+
+    /// Check that the session may perform `permission` on `key`.
+    pub fn authorize(&self, session: &Session, permission: Permission, key: &str) -> Result<()> {
+        self.check_authenticated(session)?;
+        if self.allows(session, permission, key) {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied)
+        }
+    }
+
+    /// Whether the session may perform `permission` on `key`, used to filter key listings.
+    pub fn allows(&self, session: &Session, permission: Permission, key: &str) -> bool {
+        match (self.acl(), &session.principal) {
+            (None, _) => true,
+            (Some(acl), Some(principal)) => acl.allows(principal, permission, key),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.observe("get", || {
+            if reserved(&key) || self.purge_if_expired(&key)? {
+                return Ok(None);
+            }
+            self.engine.get(key)
+        })
+    }
+
+    /// Get the value with its version and flags.
+    pub fn get_item(&self, key: String) -> Result<Option<Item>> {
+        self.observe("get", || self.get_item_inner(key))
+    }
+
+    fn get_item_inner(&self, key: String) -> Result<Option<Item>> {
+        if reserved(&key) || self.purge_if_expired(&key)? {
+            return Ok(None);
+        }
+        // Read before the value, a write in between gives an older version
+        // than the value has, which fails a `cas` rather than passing it.
+        let (flags, version) = {
+            let meta = self.meta(&key);
+            (
+                meta.flags(&key),
+                meta.version(&key, self.shared.first_version),
+            )
+        };
+        let value = match self.engine.get(key.clone())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        Ok(Some(Item {
+            value,
+            version,
+            flags,
+        }))
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with(key, value, SetOptions::default())?;
+        Ok(())
+    }
+
+    /// Set the value if the conditions in `opts` hold.
+    pub fn set_with(&self, key: String, value: String, opts: SetOptions) -> Result<SetResult> {
+        self.observe("set", || self.set_inner(key, value, opts))
+    }
+
+    fn set_inner(&self, key: String, value: String, opts: SetOptions) -> Result<SetResult> {
+        self.check_writable()?;
+        check_key(&key)?;
+        if let Some(cluster) = &self.cluster {
+            return self.cluster_set(cluster, key, value, opts);
+        }
+        let mut meta = self.meta(&key);
+        if opts.only_if_absent || opts.only_if_present || opts.cas.is_some() {
+            let current = match self.purge_expired(&mut meta, &key)? {
+                true => None,
+                false => self.engine.get(key.clone())?,
+            };
+            let exists = current.is_some();
+            if (opts.only_if_absent && exists) || (opts.only_if_present && !exists) {
+                return Ok(SetResult::NotStored);
+            }
+            if let Some(cas) = opts.cas {
+                match current {
+                    None => return Ok(SetResult::NotFound),
+                    Some(_) if meta.version(&key, self.shared.first_version) != cas => {
+                        return Ok(SetResult::Exists)
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        // Expired already, as with a negative memcache exptime.
+        if opts.expire == Some(Duration::ZERO) {
+            return match self.remove_key(&mut meta, key) {
+                Ok(()) | Err(Error::RecordNotFound) => Ok(SetResult::Stored),
+                Err(e) => Err(e),
+            };
+        }
+        let delta = self.reserve(&key, Some(&value))?;
+        let expires = opts.expire.map(|ttl| Instant::now() + ttl);
+        let written = self
+            .persist_meta(&meta, &key, expires, opts.flags)
+            .and_then(|()| self.engine.set(key.clone(), value.clone()));
+        if let Err(e) = written {
+            self.charge(&key, -delta);
+            return Err(e);
+        }
+        meta.insert(key.clone(), expires, opts.flags, self.next_version());
+        self.shared.repl_log.append(set_op(&meta, key, value));
+        Ok(SetResult::Stored)
+    }
+
+    /// Add `delta` to a decimal value, or subtract it when `decrement` is set.
+    ///
+    /// Increments wrap around at 64 bits, decrements stop at 0.
+    /// Expiration and flags of the key are kept.
+    pub fn incr(&self, key: String, delta: u64, decrement: bool) -> Result<IncrResult> {
+        self.observe("incr", || self.incr_inner(key, delta, decrement))
+    }
+
+    fn incr_inner(&self, key: String, delta: u64, decrement: bool) -> Result<IncrResult> {
+        self.check_writable()?;
+        check_key(&key)?;
+        if self.cluster.is_some() {
+            return Err(unsupported_in_cluster("increments"));
+        }
+        let mut meta = self.meta(&key);
+        if self.purge_expired(&mut meta, &key)? {
+            return Ok(IncrResult::NotFound);
+        }
+        let current = match self.engine.get(key.clone())? {
+            Some(value) => match value.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => return Ok(IncrResult::NotNumeric),
+            },
+            None => return Ok(IncrResult::NotFound),
+        };
+        let value = if decrement {
+            current.saturating_sub(delta)
+        } else {
+            current.wrapping_add(delta)
+        };
+        let value_str = value.to_string();
+        let delta = self.reserve(&key, Some(&value_str))?;
+        if let Err(e) = self.engine.set(key.clone(), value_str.clone()) {
+            self.charge(&key, -delta);
+            return Err(e);
+        }
+        meta.touch(&key, self.next_version());
+        self.shared.repl_log.append(set_op(&meta, key, value_str));
+        Ok(IncrResult::Value(value))
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.observe("remove", || {
+            self.check_writable()?;
+            check_key(&key)?;
+            if let Some(cluster) = &self.cluster {
+                return self.cluster_remove(cluster, key);
+            }
+            let mut meta = self.meta(&key);
+            if self.purge_expired(&mut meta, &key)? {
+                return Err(Error::RecordNotFound);
+            }
+            self.remove_key(&mut meta, key)
+        })
+    }
+
+    // Writes of a cluster node are applied once committed, by the Raft node.
+    fn cluster_set(
+        &self,
+        cluster: &ClusterHandle,
+        key: String,
+        value: String,
+        opts: SetOptions,
+    ) -> Result<SetResult> {
+        if opts.only_if_absent || opts.only_if_present || opts.cas.is_some() {
+            return Err(unsupported_in_cluster("conditional writes"));
+        }
+        if opts.expire == Some(Duration::ZERO) {
+            cluster.propose(WriteOp::Remove { key })?;
+            return Ok(SetResult::Stored);
+        }
+        self.quota_delta(&key, Some(&value))?;
+        cluster.propose(WriteOp::Set {
+            key,
+            value,
+            ttl_ms: opts.expire.map(|ttl| ttl.as_millis() as u64),
+            flags: opts.flags,
+        })?;
+        Ok(SetResult::Stored)
+    }
+
+    fn cluster_remove(&self, cluster: &ClusterHandle, key: String) -> Result<()> {
+        let exists = !self.purge_if_expired(&key)? && self.engine.get(key.clone())?.is_some();
+        if !exists {
+            return Err(Error::RecordNotFound);
+        }
+        cluster.propose(WriteOp::Remove { key })
+    }
+
+    pub fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.observe("scan", || self.scan_keys(prefix))
+    }
+
+    fn scan_keys(&self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = self.engine.scan(prefix)?;
+        keys.retain(|key| !reserved(key) && !self.expired(key));
+        Ok(keys)
+    }
+
+    // Records the latency of an operation and whether it failed.
+    fn observe<R>(&self, op: &'static str, f: impl FnOnce() -> Result<R>) -> Result<R> {
+        let started = Instant::now();
+        let res = f();
+        self.metrics.observe_op(op, started.elapsed(), res.is_err());
+        res
+    }
+
+    // Versions only go up, a key written back to an earlier value gets a new one.
+    fn next_version(&self) -> u64 {
+        self.shared.versions.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Stripe of the metadata of `key`.
+    fn meta(&self, key: &str) -> MutexGuard<'_, Meta> {
+        self.shared.meta[stripe(key)].lock().unwrap()
+    }
+
+    // Every stripe, which holds off all writes.
+    fn lock_all(&self) -> Vec<MutexGuard<'_, Meta>> {
+        self.shared
+            .meta
+            .iter()
+            .map(|meta| meta.lock().unwrap())
+            .collect()
+    }
+
+    fn expired(&self, key: &str) -> bool {
+        matches!(self.meta(key).expires(key), Some(deadline) if deadline <= Instant::now())
+    }
+
+    // Same as `purge_expired`, locking the stripe only for an expired key.
+    fn purge_if_expired(&self, key: &str) -> Result<bool> {
+        if !self.expired(key) {
+            return Ok(false);
+        }
+        let mut meta = self.meta(key);
+        self.purge_expired(&mut meta, key)
+    }
+
+    // Removes the key if it has expired, returns whether it had.
+    fn purge_expired(&self, meta: &mut Meta, key: &str) -> Result<bool> {
+        match meta.expires(key) {
+            Some(deadline) if deadline <= Instant::now() => {
+                match self.remove_key(meta, key.to_owned()) {
+                    Ok(()) | Err(Error::RecordNotFound) => Ok(true),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Removes the key with its metadata and logs it.
+    fn remove_key(&self, meta: &mut Meta, key: String) -> Result<()> {
+        let delta = self.size_delta(&key, None)?;
+        match self.engine.remove(key.clone()) {
+            Ok(()) => {}
+            Err(Error::RecordNotFound) => {
+                self.forget_meta(meta, &key)?;
+                return Err(Error::RecordNotFound);
+            }
+            Err(e) => return Err(e),
+        }
+        self.charge(&key, delta);
+        self.forget_meta(meta, &key)?;
+        self.shared.repl_log.append(WriteOp::Remove { key });
+        Ok(())
+    }
+
+    // Stores the expiration and flags of a key about to be written, before
+    // its value so that a crash in between cannot leave it without them.
+    fn persist_meta(
+        &self,
+        meta: &Meta,
+        key: &str,
+        expires: Option<Instant>,
+        flags: u32,
+    ) -> Result<()> {
+        if expires.is_none() && flags == 0 {
+            if meta.persisted(key) {
+                self.remove_meta(key)?;
+            }
+            return Ok(());
+        }
+        let expires_ms = expires.map_or(0, |deadline| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            unix_ms(SystemTime::now() + left).max(1)
+        });
+        let value = format!("{} {}", expires_ms, flags);
+        self.engine.set(meta_key(key), value)?;
+        if !meta.persisted(key) {
+            self.shared.meta_keys.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // Drops the metadata of a removed key.
+    fn forget_meta(&self, meta: &mut Meta, key: &str) -> Result<()> {
+        if meta.persisted(key) {
+            self.remove_meta(key)?;
+        }
+        meta.remove(key);
+        Ok(())
+    }
+
+    fn remove_meta(&self, key: &str) -> Result<()> {
+        match self.engine.remove(meta_key(key)) {
+            Ok(()) => {
+                self.shared.meta_keys.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(Error::RecordNotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Change in the bytes stored under the quotas of `key` if its value
+    // becomes `value`, or it is removed. Fails if it would exceed a quota.
+    fn quota_delta(&self, key: &str, value: Option<&str>) -> Result<i64> {
+        let delta = self.size_delta(key, value)?;
+        check_quotas(&self.shared.quotas.lock().unwrap(), key, delta)?;
+        Ok(delta)
+    }
+
+    // Same as `quota_delta`, charging the change right away so that
+    // concurrent writes of other keys see it.
+    fn reserve(&self, key: &str, value: Option<&str>) -> Result<i64> {
+        let delta = self.size_delta(key, value)?;
+        let mut quotas = self.shared.quotas.lock().unwrap();
+        check_quotas(&quotas, key, delta)?;
+        charge(&mut quotas, key, delta);
+        Ok(delta)
+    }
+
+    // Account for `delta` bytes more or less under the prefixes of `key`.
+    fn charge(&self, key: &str, delta: i64) {
+        if delta != 0 {
+            charge(&mut self.shared.quotas.lock().unwrap(), key, delta);
+        }
+    }
+
+    // Same as `quota_delta` without the check, 0 for a key under no quota.
+    fn size_delta(&self, key: &str, value: Option<&str>) -> Result<i64> {
+        let quoted = self
+            .shared
+            .quotas
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|usage| key.starts_with(&usage.quota.prefix));
+        if !quoted {
+            return Ok(0);
+        }
+        let size = |value: Option<&str>| value.map_or(0, |value| (key.len() + value.len()) as i64);
+        Ok(size(value) - size(self.engine.get(key.to_owned())?.as_deref()))
+    }
+}
+
+// Keys of the engine clients do not see.
+fn reserved(key: &str) -> bool {
+    key.starts_with(META_PREFIX)
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if reserved(key) {
+        return Err(Error::StringError(format!("key {:?} is reserved", key)));
+    }
+    Ok(())
+}
+
+fn meta_key(key: &str) -> String {
+    format!("{}{}", META_PREFIX, key)
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn stripe(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % META_STRIPES
+}
+
+fn check_quotas(quotas: &[QuotaUsage], key: &str, delta: i64) -> Result<()> {
+    if delta > 0 {
+        for usage in quotas {
+            if key.starts_with(&usage.quota.prefix)
+                && usage.used + delta as u64 > usage.quota.max_bytes
+            {
+                return Err(Error::QuotaExceeded(usage.quota.prefix.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn charge(quotas: &mut [QuotaUsage], key: &str, delta: i64) {
+    for usage in quotas {
+        if key.starts_with(&usage.quota.prefix) {
+            usage.used = usage.used.saturating_add_signed(delta);
+        }
+    }
+}
+
+fn unsupported_in_cluster(what: &str) -> Error {
+    Error::StringError(format!("{} are not supported in cluster mode", what))
+}
+
+// The write of `key` for the followers, with the expiration and flags it has now.
+fn set_op(meta: &Meta, key: String, value: String) -> WriteOp {
+    let (ttl_ms, flags) = match meta.keys.get(&key) {
+        Some(key_meta) => (
+            key_meta.expires.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64
+            }),
+            key_meta.flags,
+        ),
+        None => (None, 0),
+    };
+    WriteOp::Set {
+        key,
+        value,
+        ttl_ms,
+        flags,
+    }
+}
@@ -1,12 +1,27 @@
 //! KvsEngine
 
-pub use self::kvs::KvStore;
+pub use self::kvs::{KvStore, KvStoreOptions};
 pub use self::sled::SledKvsEngine;
 
 mod kvs;
 mod sled;
 
-use crate::err::Result;
+use crate::err::{Error, Result};
+use crate::metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Storage figures of an engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Size of the data on disk
+    pub disk_bytes: u64,
+    /// Part of it taken by stale entries, reclaimed by compaction
+    pub garbage_bytes: u64,
+    /// Number of keys, kept up to date rather than counted
+    pub keys: u64,
+}
 
 /// KvsEngine
 pub trait KvsEngine: Clone + Send + 'static {
@@ -18,4 +33,37 @@ pub trait KvsEngine: Clone + Send + 'static {
 
     /// remove
     fn remove(&self, key: String) -> Result<()>;
+
+    /// scan keys starting with prefix, in ascending order
+    fn scan(&self, prefix: String) -> Result<Vec<String>>;
+
+    /// flush buffered writes and sync them to disk
+    fn flush(&self) -> Result<()>;
+
+    /// name of the engine, as given to `kvs-server --engine`
+    fn name(&self) -> &'static str;
+
+    /// size of the data on disk and number of keys
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// reclaim the space of stale entries now
+    fn compact(&self) -> Result<()>;
+
+    /// copy a consistent snapshot of the data to `path`, which must not exist or be empty
+    fn checkpoint(&self, path: &Path) -> Result<()>;
+
+    /// report storage metrics, such as log size and compactions, to `metrics`
+    fn set_metrics(&self, _metrics: Metrics) {}
+}
+
+// Creates the directory at `path`, or checks that it is empty.
+fn create_empty_dir(path: &Path) -> Result<()> {
+    if path.exists() && fs::read_dir(path)?.next().is_some() {
+        return Err(Error::StringError(format!(
+            "{} is not empty",
+            path.display()
+        )));
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
 }
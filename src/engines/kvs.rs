@@ -1,6 +1,8 @@
+use super::create_empty_dir;
 use crate::err::Error;
 use crate::err::Result;
-use crate::KvsEngine;
+use crate::metrics::Metrics;
+use crate::{EngineStats, KvsEngine};
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
@@ -9,12 +11,31 @@ use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use std::{fs, io};
-use std::sync::atomic::{AtomicU64, Ordering};
 
 const MAX_COMPACT_SIZE: u64 = 1024;
 
+/// Options of a `KvStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvStoreOptions {
+    /// Sync the log file to disk after every write, rather than leaving it to the OS
+    pub sync_writes: bool,
+    /// Compact the log once this many bytes are stale, never if 0
+    pub compaction_threshold: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_writes: false,
+            compaction_threshold: MAX_COMPACT_SIZE,
+        }
+    }
+}
+
 /// `KvStore` stores key-value pairs in memory.
 ///
 /// The pairs are stored in an internal HashMap.
@@ -96,11 +117,71 @@ impl KvsEngine for KvStore {
         self.writer.lock().unwrap().remove(key)?;
         Ok(())
     }
+
+    /// Scans keys which start with the given prefix.
+    ///
+    /// Keys are returned in ascending order.
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        let keys = self
+            .index
+            .range(prefix.clone()..)
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(&prefix))
+            .collect();
+        Ok(keys)
+    }
+
+    /// Flushes the active log file and syncs it to disk.
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    /// Size of the log files, and of their stale entries.
+    fn stats(&self) -> Result<EngineStats> {
+        let writer = self.writer.lock().unwrap();
+        Ok(EngineStats {
+            disk_bytes: writer.log_bytes,
+            garbage_bytes: writer.uncompacted,
+            keys: self.index.len() as u64,
+        })
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    /// Copies the log files, writes are held until they are copied.
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.sync()?;
+        create_empty_dir(path)?;
+        for id in gen_log_file_id(&writer.path)? {
+            let name = format!("{}.log", id);
+            fs::copy(writer.path.join(&name), path.join(&name))?;
+        }
+        Ok(())
+    }
+
+    /// Reports the size of the log, its stale part and the compactions.
+    fn set_metrics(&self, metrics: Metrics) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.metrics = metrics;
+        writer.report_size();
+    }
 }
 
 impl KvStore {
     /// Open file to store log
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open file to store log, with options
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
 
         fs::create_dir_all(&path)?;
@@ -110,9 +191,11 @@ impl KvStore {
         let mut readers = HashMap::new();
         let mut index = SkipMap::new();
         let mut uncompacted = 0;
+        let mut log_bytes = 0;
         for id in ids {
             let file = path.join(format!("{}.log", id));
             let f = fs::File::open(file)?;
+            log_bytes += f.metadata()?.len();
             let reader = BufReader::new(f);
             let mut reader = BufReaderWithPos::new(reader)?;
 
@@ -138,6 +221,9 @@ impl KvStore {
             path: Arc::clone(&path),
             file_id: cur_file_id,
             uncompacted,
+            log_bytes,
+            options,
+            metrics: Metrics::default(),
         }));
 
         Ok(KvStore {
@@ -152,7 +238,6 @@ fn new_log_file(file_id: u64, path: &Path) -> Result<BufWriterWithPos> {
     let log_file = path.join(format!("{}.log", file_id));
     let f = match fs::OpenOptions::new()
         .read(true)
-        .write(true)
         .create(true)
         .append(true)
         .open(log_file.as_path())
@@ -294,6 +379,10 @@ struct KvStoreWriter {
     path: Arc<PathBuf>,
     file_id: u64,
     uncompacted: u64,
+    // size of all log files
+    log_bytes: u64,
+    options: KvStoreOptions,
+    metrics: Metrics,
 }
 
 impl KvStoreWriter {
@@ -307,6 +396,7 @@ impl KvStoreWriter {
 
         let start = self.writer.pos;
         let len = self.writer.write(&command_serialize)?;
+        self.log_bytes += len as u64;
         let command_pos = CommandPos {
             file_id: self.file_id,
             pos: start,
@@ -320,10 +410,15 @@ impl KvStoreWriter {
 
         // insert or overwrite
         self.index.insert(key, command_pos);
+        if self.options.sync_writes {
+            self.sync()?;
+        }
 
-        if self.uncompacted > MAX_COMPACT_SIZE {
+        let threshold = self.options.compaction_threshold;
+        if threshold > 0 && self.uncompacted > threshold {
             self.compact()?;
         }
+        self.report_size();
         Ok(())
     }
 
@@ -339,13 +434,29 @@ impl KvStoreWriter {
                 };
                 let command_serialize = serde_json::to_vec(&command)?;
                 self.writer.write_all(&command_serialize)?;
+                self.log_bytes += command_serialize.len() as u64;
             }
             None => return Err(Error::RecordNotFound),
         }
+        if self.options.sync_writes {
+            self.sync()?;
+        }
+        self.report_size();
+        Ok(())
+    }
+
+    fn report_size(&self) {
+        self.metrics.set_log_bytes(self.log_bytes, self.uncompacted);
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_all()?;
         Ok(())
     }
 
     fn compact(&mut self) -> Result<()> {
+        let started = Instant::now();
         let compact_file_id = self.file_id + 1;
         self.file_id = compact_file_id + 1;
         self.writer = new_log_file(self.file_id, &self.path)?;
@@ -374,6 +485,8 @@ impl KvStoreWriter {
         self.readers.close_files();
 
         self.uncompacted = 0;
+        self.log_bytes = new_pos;
+        self.metrics.observe_compaction(started.elapsed());
         Ok(())
     }
 }
@@ -1,32 +1,53 @@
+use super::create_empty_dir;
 use crate::err::Error;
-use crate::KvsEngine;
 use crate::Result;
+use crate::{EngineStats, KvsEngine};
 use sled::Tree;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 /// SledKvsEngine contains sled db
 #[derive(Clone)]
 pub struct SledKvsEngine {
     sled: sled::Db,
+    sync_writes: bool,
+    // counted once when opened, sled only counts by iterating
+    keys: Arc<AtomicU64>,
 }
 
 impl SledKvsEngine {
     /// New SledKvsEngine
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, true)
+    }
+
+    /// New SledKvsEngine, flushing every write to disk if `sync_writes` is set
+    /// rather than leaving it to sled's periodic flush
+    pub fn open_with(path: impl Into<PathBuf>, sync_writes: bool) -> Result<Self> {
         let path = path.into();
         let sled = match sled::open(path) {
             Ok(db) => db,
             Err(e) => return Err(Error::ServerError(e.to_string())),
         };
-        Ok(SledKvsEngine { sled })
+        let keys = Arc::new(AtomicU64::new(sled.len() as u64));
+        Ok(SledKvsEngine {
+            sled,
+            sync_writes,
+            keys,
+        })
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
         let tree: &Tree = &self.sled;
-        tree.insert(key, value.as_bytes())?;
-        tree.flush()?;
+        if tree.insert(key, value.as_bytes())?.is_none() {
+            self.keys.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.sync_writes {
+            tree.flush()?;
+        }
         Ok(())
     }
 
@@ -48,7 +69,53 @@ impl KvsEngine for SledKvsEngine {
 
     fn remove(&self, key: String) -> Result<()> {
         self.sled.remove(key)?.ok_or(Error::RecordNotFound)?;
+        self.keys.fetch_sub(1, Ordering::SeqCst);
+        if self.sync_writes {
+            self.sled.flush()?;
+        }
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self.sled.scan_prefix(prefix.as_bytes()) {
+            let (key, _) = item?;
+            match String::from_utf8(key.to_vec()) {
+                Ok(key) => keys.push(key),
+                Err(e) => return Err(Error::ServerError(e.to_string())),
+            }
+        }
+        Ok(keys)
+    }
+
+    fn flush(&self) -> Result<()> {
         self.sled.flush()?;
         Ok(())
     }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    /// sled compacts its files by itself, there is nothing to force.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        create_empty_dir(path)?;
+        let checkpoint = sled::open(path)?;
+        checkpoint.import(self.sled.export());
+        checkpoint.flush()?;
+        Ok(())
+    }
+
+    /// sled does not tell how much of its files is garbage.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            disk_bytes: self.sled.size_on_disk()?,
+            garbage_bytes: 0,
+            keys: self.keys.load(Ordering::SeqCst),
+        })
+    }
 }
@@ -1,6 +1,7 @@
 //! err
 
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Error
@@ -46,6 +47,47 @@ pub enum Error {
     #[error("sled error {0:?}")]
     SledError(#[from] sled::Error),
 
+    /// TLS error
+    #[error("tls error: {0}")]
+    TlsError(#[from] rustls::Error),
+
+    /// Authentication required or failed
+    #[error("authentication error: {0}")]
+    AuthError(String),
+
+    /// The principal may not perform the operation
+    #[error("permission denied")]
+    PermissionDenied,
+
+    /// The server refused the connection, it has too many already
+    #[error("server busy")]
+    ServerBusy,
+
+    /// The client sends too fast, and may retry after the given time
+    #[error("rate limited, retry in {0:?}")]
+    RateLimited(Duration),
+
+    /// The write would take the keys of a prefix over their storage quota
+    #[error("storage quota of prefix `{0}` exceeded")]
+    QuotaExceeded(String),
+
+    /// The server is a follower, writes go to its leader
+    #[error("read-only replica")]
+    ReadOnly,
+
+    /// The server is a node of a cluster but not its leader, which is at
+    /// the given address if known
+    #[error("not the leader{}", .0.as_ref().map(|addr| format!(", the leader is at {}", addr)).unwrap_or_default())]
+    NotLeader(Option<String>),
+
+    /// No connection of a pool was free in time
+    #[error("timed out waiting for a pooled connection")]
+    PoolTimeout,
+
+    /// A value could not be encoded or decoded by the codec of a typed client or engine
+    #[error("codec error: {0}")]
+    CodecError(String),
+
     /// Normal error
     #[error("{0:?}")]
     StringError(String),
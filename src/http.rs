@@ -0,0 +1,481 @@
+//! HTTP/JSON gateway
+//!
+//! A small HTTP/1.1 front-end for `curl` and web services:
+//!
+//! - `GET /keys/{key}`, `PUT /keys/{key}` (the body is the value), `DELETE /keys/{key}`
+//! - `GET /keys?prefix={prefix}` lists keys
+//! - `GET /health` and `GET /stats`
+//!
+//! When the server has an ACL, requests other than `/health` carry
+//! `Authorization: Bearer <token>` or `Authorization: Basic <user:password>`.
+//!
+//! The metrics listener speaks the same HTTP, but only serves `GET /metrics`
+//! in the Prometheus text format, and `GET /health`.
+
+use crate::auth::{Credentials, Permission};
+use crate::context::{Context, Session};
+use crate::err::Error;
+use crate::net::Stream;
+use crate::server::next_request;
+use crate::trace::RequestTimer;
+use crate::{KvsEngine, Result};
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+use tracing::{debug, debug_span, error};
+
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    keep_alive: bool,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    body: Body,
+    // sent as `Retry-After` with 429
+    retry_after: Option<Duration>,
+}
+
+enum Body {
+    Json(serde_json::Value),
+    // Prometheus text format
+    Metrics(String),
+}
+
+impl HttpResponse {
+    fn new(status: u16, body: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            body: Body::Json(body),
+            retry_after: None,
+        }
+    }
+
+    fn error(status: u16, msg: impl Into<String>) -> Self {
+        HttpResponse::new(status, json!({ "error": msg.into() }))
+    }
+
+    // Responses for the errors that are the client's doing.
+    fn from_client_error(e: Error) -> Result<Self> {
+        match e {
+            Error::AuthError(msg) => Ok(HttpResponse::error(401, msg)),
+            Error::PermissionDenied => Ok(HttpResponse::error(403, "permission denied")),
+            Error::RateLimited(retry_after) => Ok(HttpResponse {
+                retry_after: Some(retry_after),
+                ..HttpResponse::error(429, "rate limited")
+            }),
+            e @ Error::QuotaExceeded(_) => Ok(HttpResponse::error(507, e.to_string())),
+            e @ Error::ReadOnly => Ok(HttpResponse::error(403, e.to_string())),
+            e @ Error::NotLeader(_) => Ok(HttpResponse::error(421, e.to_string())),
+            e => Err(e),
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> Result<()> {
+        let (body, content_type) = match &self.body {
+            _ if self.status == 204 => (Vec::new(), ""),
+            Body::Json(body) => (serde_json::to_vec(body)?, "application/json"),
+            Body::Metrics(body) => (body.clone().into_bytes(), "text/plain; version=0.0.4"),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if !body.is_empty() {
+            write!(writer, "Content-Type: {}\r\n", content_type)?;
+        }
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        if self.status == 401 {
+            write!(writer, "WWW-Authenticate: Bearer, Basic realm=\"kvs\"\r\n")?;
+        }
+        if let Some(retry_after) = self.retry_after {
+            // whole seconds, rounded up
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            write!(writer, "Retry-After: {}\r\n", secs)?;
+        }
+        if !keep_alive {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) fn handle<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    timer: RequestTimer,
+) -> Result<()> {
+    serve(context, stream, timer, route)
+}
+
+/// Serve the metrics of the server.
+pub(crate) fn handle_metrics<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    timer: RequestTimer,
+) -> Result<()> {
+    serve(context, stream, timer, route_metrics)
+}
+
+fn serve<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    mut timer: RequestTimer,
+    route: fn(&Context<T>, &str, &HttpRequest) -> HttpResponse,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let peer = stream.peer();
+
+    while next_request(&context, &stream, &mut reader)? {
+        timer.start();
+        let req = match read_request(&mut reader, &mut writer) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let msg = match &e {
+                    Error::StringError(msg) => msg.clone(),
+                    e => e.to_string(),
+                };
+                HttpResponse::error(400, msg).write_to(&mut writer, false)?;
+                return Err(e);
+            }
+        };
+        timer.read();
+        // values only travel in bodies, which are left out
+        let desc = format!("{} {}", req.method, req.path);
+        let span = debug_span!("request", req = %desc);
+        let _span = span.enter();
+        debug!("http req {}", desc);
+        let rsp = route(&context, &peer, &req);
+        timer.engine();
+        context.record_request(rsp.status >= 500);
+        rsp.write_to(&mut writer, req.keep_alive)?;
+        context.log_slow_request(&desc, timer.write());
+        if !req.keep_alive {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn route_metrics<T: KvsEngine>(
+    context: &Context<T>,
+    _peer: &str,
+    req: &HttpRequest,
+) -> HttpResponse {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/metrics") => HttpResponse {
+            status: 200,
+            body: Body::Metrics(context.metrics().render()),
+            retry_after: None,
+        },
+        ("GET", "/health") => HttpResponse::new(200, json!({ "status": "ok" })),
+        (_, "/metrics") | (_, "/health") => HttpResponse::error(405, "method not allowed"),
+        _ => HttpResponse::error(404, "not found"),
+    }
+}
+
+fn route<T: KvsEngine>(context: &Context<T>, peer: &str, req: &HttpRequest) -> HttpResponse {
+    if req.path == "/health" && req.method == "GET" {
+        return HttpResponse::new(200, json!({ "status": "ok" }));
+    }
+    let res = authenticate(context, peer, req)
+        .and_then(|session| {
+            context.throttle(&session, req.path.len() + req.body.len())?;
+            route_session(context, &session, req)
+        })
+        .or_else(HttpResponse::from_client_error);
+    res.unwrap_or_else(|e| {
+        error!("http error {:?}", e);
+        HttpResponse::error(500, e.to_string())
+    })
+}
+
+// Every request is authenticated on its own, as HTTP clients may
+// spread them over several connections.
+fn authenticate<T: KvsEngine>(
+    context: &Context<T>,
+    peer: &str,
+    req: &HttpRequest,
+) -> Result<Session> {
+    let mut session = Session::new(peer.to_owned());
+    if let Some(value) = &req.authorization {
+        let credentials = parse_authorization(value)
+            .ok_or_else(|| Error::AuthError("malformed authorization header".to_owned()))?;
+        context.authenticate(&mut session, &credentials)?;
+    }
+    context.check_authenticated(&session)?;
+    Ok(session)
+}
+
+fn parse_authorization(value: &str) -> Option<Credentials> {
+    let (scheme, param) = value.split_once(' ')?;
+    let param = param.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Token(param.to_owned()))
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(base64_decode(param)?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some(Credentials::Password {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    } else {
+        None
+    }
+}
+
+fn route_session<T: KvsEngine>(
+    context: &Context<T>,
+    session: &Session,
+    req: &HttpRequest,
+) -> Result<HttpResponse> {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/stats") => context
+            .stats()
+            .map(|stats| HttpResponse::new(200, json!(stats))),
+        (_, "/health") | (_, "/stats") | (_, "/keys") if req.method != "GET" => {
+            Ok(HttpResponse::error(405, "method not allowed"))
+        }
+        ("GET", "/keys") => list_keys(context, session, req.query.as_deref()),
+        (method, path) => match path.strip_prefix("/keys/") {
+            Some(key) if !key.is_empty() => match percent_decode(key) {
+                Some(key) => key_route(context, session, method, key, &req.body),
+                None => Ok(HttpResponse::error(400, "invalid key encoding")),
+            },
+            _ => Ok(HttpResponse::error(404, "not found")),
+        },
+    }
+}
+
+fn key_route<T: KvsEngine>(
+    context: &Context<T>,
+    session: &Session,
+    method: &str,
+    key: String,
+    body: &[u8],
+) -> Result<HttpResponse> {
+    match method {
+        "GET" | "PUT" | "DELETE" => {
+            let permission = if method == "GET" {
+                Permission::Read
+            } else {
+                Permission::Write
+            };
+            context.authorize(session, permission, &key)?;
+        }
+        _ => return Ok(HttpResponse::error(405, "method not allowed")),
+    }
+    match method {
+        "GET" => match context.get(key.clone())? {
+            Some(value) => Ok(HttpResponse::new(
+                200,
+                json!({ "key": key, "value": value }),
+            )),
+            None => Ok(HttpResponse::error(404, "Key not found")),
+        },
+        "PUT" => {
+            let value = match String::from_utf8(body.to_vec()) {
+                Ok(value) => value,
+                Err(_) => return Ok(HttpResponse::error(400, "value is not valid UTF-8")),
+            };
+            context.set(key, value)?;
+            Ok(HttpResponse::new(204, json!(null)))
+        }
+        "DELETE" => match context.remove(key) {
+            Ok(()) => Ok(HttpResponse::new(204, json!(null))),
+            Err(Error::RecordNotFound) => Ok(HttpResponse::error(404, "Key not found")),
+            Err(e) => Err(e),
+        },
+        _ => Ok(HttpResponse::error(405, "method not allowed")),
+    }
+}
+
+fn list_keys<T: KvsEngine>(
+    context: &Context<T>,
+    session: &Session,
+    query: Option<&str>,
+) -> Result<HttpResponse> {
+    let mut prefix = String::new();
+    for pair in query.unwrap_or_default().split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if name == "prefix" {
+            match percent_decode(&value.replace('+', " ")) {
+                Some(value) => prefix = value,
+                None => return Ok(HttpResponse::error(400, "invalid prefix encoding")),
+            }
+        }
+    }
+    let keys: Vec<String> = context
+        .scan(prefix)?
+        .into_iter()
+        .filter(|key| context.allows(session, Permission::Read, key))
+        .collect();
+    Ok(HttpResponse::new(200, json!({ "keys": keys })))
+}
+
+// Returns `None` when the connection is closed before a new request. A
+// client expecting `100 Continue` is told to send the body.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<HttpRequest>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(bad_request("malformed request line")),
+    };
+
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    let mut authorization = None;
+    for _ in 0..=MAX_HEADERS {
+        let header = read_line(reader)?.ok_or_else(|| bad_request("unexpected eof"))?;
+        if header.is_empty() {
+            if expect_continue && content_length > 0 {
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                writer.flush()?;
+            }
+            // only what was sent is allocated, not what was announced
+            let mut body = Vec::new();
+            reader
+                .by_ref()
+                .take(content_length as u64)
+                .read_to_end(&mut body)?;
+            if body.len() < content_length {
+                return Err(bad_request("unexpected eof"));
+            }
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_owned())),
+                None => (target, None),
+            };
+            return Ok(Some(HttpRequest {
+                method: method.to_ascii_uppercase(),
+                path: path.to_owned(),
+                query,
+                keep_alive,
+                authorization,
+                body,
+            }));
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid content-length"))?;
+                if content_length > MAX_BODY_LEN {
+                    return Err(bad_request("body too large"));
+                }
+            }
+            "transfer-encoding" => return Err(bad_request("transfer-encoding not supported")),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "authorization" => authorization = Some(value.to_owned()),
+            "connection" => keep_alive = value.eq_ignore_ascii_case("keep-alive"),
+            _ => {}
+        }
+    }
+    Err(bad_request("too many headers"))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    let len = reader
+        .take(MAX_LINE_LEN as u64)
+        .read_line(&mut line)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => bad_request("request is not valid UTF-8"),
+            _ => Error::IoError(e),
+        })?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(bad_request("line too long"));
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn bad_request(msg: &str) -> Error {
+    Error::StringError(format!("bad request: {}", msg))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Standard alphabet, padding optional.
+pub(crate) fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buf = (buf << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buf >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error",
+    }
+}
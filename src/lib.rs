@@ -1,13 +1,55 @@
 #![deny(missing_docs)]
 //! A simple key-value store
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
+pub use client::{KvsClient, KvsClientBuilder, RetryPolicy};
+pub use client_pool::{KvsClientPool, PoolOptions, PoolStatus, PooledClient};
+pub use cluster::{Cluster, ClusterInfo, ClusterNode};
+pub use cluster_client::{KvsClusterClient, NodeHealth};
+pub use common::AdminCommand;
+pub use engines::{EngineStats, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
 pub use err::{Error, Result};
-pub use server::KvsServer;
+pub use metrics::Metrics;
+pub use proxy::KvsProxy;
+pub use ratelimit::{Quota, RateLimits};
+pub use replication::{ReplicationInfo, DEFAULT_LOG_CAPACITY};
+pub use ring::HashRing;
+pub use server::{ConnectionLimits, KvsServer, Protocol, Reload, ServerInfo};
+pub use shutdown::ShutdownHandle;
+pub use trace::RequestLog;
+pub use tracking::NearCacheStats;
+pub use typed::{Codec, TypedKvsClient, TypedKvsEngine};
 
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_server;
+pub mod auth;
 mod client;
+mod client_pool;
+mod cluster;
+mod cluster_client;
 mod common;
+pub mod config;
+mod context;
 mod engines;
 pub mod err;
+mod http;
+mod memcache;
+pub mod metrics;
+pub mod net;
+pub mod proxy;
+pub mod raft;
+mod ratelimit;
+mod replication;
+mod resp;
+pub mod ring;
 mod server;
+mod shutdown;
 pub mod thread_pool;
+pub mod tls;
+mod trace;
+mod tracking;
+mod typed;
@@ -0,0 +1,292 @@
+//! Memcached ASCII protocol front-end
+//!
+//! Supports `get`, `gets`, `set`, `add`, `replace`, `cas`, `delete`,
+//! `incr`, `decr`, `stats`, `version` and `quit`.
+//! CAS tokens change on every write of a key, flags and expiration
+//! times are stored with it.
+//! Values must be valid UTF-8, as the engine stores strings.
+//! The ASCII protocol has no authentication, so connections are
+//! refused while the server has an ACL.
+
+use crate::context::{Context, IncrResult, Session, SetOptions, SetResult};
+use crate::err::Error;
+use crate::net::Stream;
+use crate::server::next_request;
+use crate::trace::RequestTimer;
+use crate::{KvsEngine, Result};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, debug_span, error};
+
+const MAX_KEY_LEN: usize = 250;
+const MAX_LINE_LEN: u64 = 4096;
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+// Expiration times above 30 days are absolute unix timestamps.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+pub(crate) fn handle<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    mut timer: RequestTimer,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let session = Session::new(stream.peer());
+
+    while next_request(&context, &stream, &mut reader)? {
+        timer.start();
+        let mut line = String::new();
+        let len = (&mut reader).take(MAX_LINE_LEN).read_line(&mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') {
+            write!(writer, "CLIENT_ERROR line too long\r\n")?;
+            writer.flush()?;
+            return Ok(());
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            write!(writer, "ERROR\r\n")?;
+            writer.flush()?;
+            continue;
+        }
+        // The data block of storage commands is read along with the request,
+        // so it counts as engine time. Values are never in the command line.
+        timer.read();
+        let desc = args.join(" ");
+        let span = debug_span!("request", req = %desc);
+        let _span = span.enter();
+        debug!("memcache req {}", desc);
+        if args[0] == "quit" {
+            return Ok(());
+        }
+        if context.requires_auth() {
+            write!(writer, "SERVER_ERROR authentication required\r\n")?;
+            writer.flush()?;
+            return Ok(());
+        }
+
+        let data_len = data_len(&args);
+        let rsp = match context.throttle(&session, line.len() + data_len) {
+            Err(e) => {
+                if data_len > 0 {
+                    io::copy(
+                        &mut (&mut reader).take(data_len as u64 + 2),
+                        &mut io::sink(),
+                    )?;
+                }
+                Some(format!("SERVER_ERROR {}\r\n", e))
+            }
+            Ok(()) => match execute(&context, &args, &mut reader) {
+                Ok(rsp) => rsp,
+                Err(e) => {
+                    error!("memcache error {:?}", e);
+                    Some(format!("SERVER_ERROR {}\r\n", e))
+                }
+            },
+        };
+        timer.engine();
+        context.record_request(matches!(&rsp, Some(rsp) if rsp.starts_with("SERVER_ERROR")));
+        if let Some(rsp) = rsp {
+            writer.write_all(rsp.as_bytes())?;
+            writer.flush()?;
+        }
+        context.log_slow_request(&desc, timer.write());
+    }
+    Ok(())
+}
+
+// Returns the reply, or `None` when the client asked for `noreply`.
+fn execute<T: KvsEngine, R: BufRead>(
+    context: &Context<T>,
+    args: &[&str],
+    reader: &mut R,
+) -> Result<Option<String>> {
+    let rsp = match args[0] {
+        "get" | "gets" if args.len() > 1 => {
+            let mut rsp = String::new();
+            for &key in &args[1..] {
+                if let Some(item) = context.get_item(key.to_owned())? {
+                    rsp.push_str(&format!(
+                        "VALUE {} {} {}",
+                        key,
+                        item.flags,
+                        item.value.len()
+                    ));
+                    if args[0] == "gets" {
+                        rsp.push_str(&format!(" {}", item.version));
+                    }
+                    rsp.push_str(&format!("\r\n{}\r\n", item.value));
+                }
+            }
+            rsp.push_str("END\r\n");
+            rsp
+        }
+        "set" | "add" | "replace" | "cas" => return store(context, args, reader),
+        "delete" if args.len() == 2 || args.len() == 3 => {
+            let rsp = match context.remove(args[1].to_owned()) {
+                Ok(()) => "DELETED\r\n",
+                Err(Error::RecordNotFound) => "NOT_FOUND\r\n",
+                Err(e) => return Err(e),
+            };
+            return Ok(reply(rsp.to_owned(), noreply(args, 2)));
+        }
+        "incr" | "decr" if args.len() == 3 || args.len() == 4 => {
+            let delta = match args[2].parse::<u64>() {
+                Ok(delta) => delta,
+                Err(_) => {
+                    return Ok(Some(
+                        "CLIENT_ERROR invalid numeric delta argument\r\n".to_owned(),
+                    ))
+                }
+            };
+            let rsp = match context.incr(args[1].to_owned(), delta, args[0] == "decr")? {
+                IncrResult::Value(value) => format!("{}\r\n", value),
+                IncrResult::NotFound => "NOT_FOUND\r\n".to_owned(),
+                IncrResult::NotNumeric => {
+                    "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_owned()
+                }
+            };
+            return Ok(reply(rsp, noreply(args, 3)));
+        }
+        "stats" if args.len() == 1 => {
+            let stats = context.stats()?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let mut rsp = String::new();
+            for (name, value) in [
+                ("pid", std::process::id().to_string()),
+                ("uptime", stats.uptime_secs.to_string()),
+                ("time", now.to_string()),
+                ("version", env!("CARGO_PKG_VERSION").to_owned()),
+                ("curr_items", stats.keys.to_string()),
+                ("curr_connections", stats.connections_active.to_string()),
+                ("total_connections", stats.connections_total.to_string()),
+                (
+                    "rejected_connections",
+                    stats.connections_rejected.to_string(),
+                ),
+                ("idle_kicks", stats.connections_timed_out.to_string()),
+                ("cmd_total", stats.requests_total.to_string()),
+            ] {
+                rsp.push_str(&format!("STAT {} {}\r\n", name, value));
+            }
+            rsp.push_str("END\r\n");
+            rsp
+        }
+        "version" => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+        "get" | "gets" | "delete" | "incr" | "decr" | "stats" => {
+            "CLIENT_ERROR bad command line format\r\n".to_owned()
+        }
+        _ => "ERROR\r\n".to_owned(),
+    };
+    Ok(Some(rsp))
+}
+
+// <command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]\r\n<data>\r\n
+// Length of the data block following a storage command.
+fn data_len(args: &[&str]) -> usize {
+    match args[0] {
+        "set" | "add" | "replace" | "cas" => args.get(4).and_then(|len| len.parse().ok()),
+        _ => None,
+    }
+    .unwrap_or(0)
+}
+
+fn store<T: KvsEngine, R: BufRead>(
+    context: &Context<T>,
+    args: &[&str],
+    reader: &mut R,
+) -> Result<Option<String>> {
+    let is_cas = args[0] == "cas";
+    let required = if is_cas { 6 } else { 5 };
+    if args.len() != required && args.len() != required + 1 {
+        return Ok(Some("CLIENT_ERROR bad command line format\r\n".to_owned()));
+    }
+    let (flags, exptime, bytes) = match (
+        args[2].parse::<u32>(),
+        args[3].parse::<i64>(),
+        args[4].parse::<usize>(),
+    ) {
+        (Ok(flags), Ok(exptime), Ok(bytes)) => (flags, exptime, bytes),
+        _ => return Ok(Some("CLIENT_ERROR bad command line format\r\n".to_owned())),
+    };
+    let cas = if is_cas {
+        match args[5].parse::<u64>() {
+            Ok(cas) => Some(cas),
+            Err(_) => return Ok(Some("CLIENT_ERROR bad command line format\r\n".to_owned())),
+        }
+    } else {
+        None
+    };
+
+    // The data block has to be consumed even if the command is rejected.
+    if bytes > MAX_VALUE_LEN {
+        io::copy(&mut reader.take(bytes as u64 + 2), &mut io::sink())?;
+        return Ok(Some(
+            "SERVER_ERROR object too large for cache\r\n".to_owned(),
+        ));
+    }
+    let mut data = vec![0; bytes + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(Some("CLIENT_ERROR bad data chunk\r\n".to_owned()));
+    }
+    data.truncate(bytes);
+
+    let key = args[1];
+    if key.len() > MAX_KEY_LEN {
+        return Ok(Some("CLIENT_ERROR key too long\r\n".to_owned()));
+    }
+    let value = match String::from_utf8(data) {
+        Ok(value) => value,
+        Err(_) => return Ok(Some("CLIENT_ERROR value is not valid UTF-8\r\n".to_owned())),
+    };
+
+    let opts = SetOptions {
+        expire: expire_after(exptime),
+        flags,
+        only_if_absent: args[0] == "add",
+        only_if_present: args[0] == "replace",
+        cas,
+    };
+    let rsp = match context.set_with(key.to_owned(), value, opts)? {
+        SetResult::Stored => "STORED\r\n",
+        SetResult::NotStored => "NOT_STORED\r\n",
+        SetResult::Exists => "EXISTS\r\n",
+        SetResult::NotFound => "NOT_FOUND\r\n",
+    };
+    Ok(reply(rsp.to_owned(), noreply(args, required)))
+}
+
+fn noreply(args: &[&str], pos: usize) -> bool {
+    args.get(pos) == Some(&"noreply")
+}
+
+fn reply(rsp: String, noreply: bool) -> Option<String> {
+    if noreply {
+        None
+    } else {
+        Some(rsp)
+    }
+}
+
+fn expire_after(exptime: i64) -> Option<Duration> {
+    if exptime == 0 {
+        return None;
+    }
+    if exptime < 0 {
+        return Some(Duration::ZERO);
+    }
+    if exptime <= MAX_RELATIVE_EXPTIME {
+        return Some(Duration::from_secs(exptime as u64));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    Some(Duration::from_secs((exptime - now).max(0) as u64))
+}
@@ -0,0 +1,234 @@
+//! Metrics in the Prometheus text format
+//!
+//! A `Metrics` registry is shared by the server, its engine and its thread pool,
+//! and rendered by the metrics listener of `kvs-server` at `GET /metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Upper bounds of the duration histograms, in seconds.
+const DURATION_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// Registry of the server metrics, cheap to clone
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ops: Mutex<BTreeMap<&'static str, Arc<OpMetrics>>>,
+    connections_active: AtomicI64,
+    queue_depth: AtomicI64,
+    log_bytes: AtomicU64,
+    garbage_bytes: AtomicU64,
+    compactions: Histogram,
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    errors: AtomicU64,
+    duration: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // not cumulative, the last one counts what is above every bound
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    // Writes the `_bucket`, `_sum` and `_count` series, `labels` is either empty or ends with a comma.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = match DURATION_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+    }
+}
+
+impl Metrics {
+    /// New empty registry
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Record an operation of type `op` that took `duration`
+    pub fn observe_op(&self, op: &'static str, duration: Duration, failed: bool) {
+        let metrics = Arc::clone(self.inner.ops.lock().unwrap().entry(op).or_default());
+        metrics.duration.observe(duration);
+        if failed {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count connections opened, or closed with a negative `delta`
+    pub fn add_connections(&self, delta: i64) {
+        self.inner
+            .connections_active
+            .fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Count jobs waiting for a thread, or taken by one with a negative `delta`
+    pub fn add_queued_jobs(&self, delta: i64) {
+        self.inner.queue_depth.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Number of jobs waiting for a thread
+    pub fn queued_jobs(&self) -> u64 {
+        self.inner.queue_depth.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    /// Set the size of the engine's log and how much of it is stale
+    pub fn set_log_bytes(&self, total: u64, garbage: u64) {
+        self.inner.log_bytes.store(total, Ordering::Relaxed);
+        self.inner.garbage_bytes.store(garbage, Ordering::Relaxed);
+    }
+
+    /// Record a compaction of the log that took `duration`
+    pub fn observe_compaction(&self, duration: Duration) {
+        self.inner.compactions.observe(duration);
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let ops: Vec<_> = self
+            .inner
+            .ops
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, metrics)| (*op, Arc::clone(metrics)))
+            .collect();
+
+        header(
+            &mut out,
+            "kvs_ops_total",
+            "counter",
+            "Operations served, by type",
+        );
+        for (op, metrics) in &ops {
+            let _ = writeln!(
+                out,
+                "kvs_ops_total{{op=\"{}\"}} {}",
+                op,
+                metrics.duration.count()
+            );
+        }
+        header(
+            &mut out,
+            "kvs_op_errors_total",
+            "counter",
+            "Operations that failed, by type",
+        );
+        for (op, metrics) in &ops {
+            let _ = writeln!(
+                out,
+                "kvs_op_errors_total{{op=\"{}\"}} {}",
+                op,
+                metrics.errors.load(Ordering::Relaxed)
+            );
+        }
+        header(
+            &mut out,
+            "kvs_op_duration_seconds",
+            "histogram",
+            "Latency of the operations, by type",
+        );
+        for (op, metrics) in &ops {
+            let labels = format!("op=\"{}\",", op);
+            metrics
+                .duration
+                .render(&mut out, "kvs_op_duration_seconds", &labels);
+        }
+
+        let inner = &self.inner;
+        let gauges = [
+            (
+                "kvs_connections_active",
+                "Open client connections",
+                inner.connections_active.load(Ordering::Relaxed),
+            ),
+            (
+                "kvs_thread_pool_queue_depth",
+                "Jobs waiting for a thread of the pool",
+                inner.queue_depth.load(Ordering::Relaxed),
+            ),
+            (
+                "kvs_log_bytes",
+                "Size of the engine's log files",
+                inner.log_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "kvs_garbage_bytes",
+                "Bytes of stale entries in the engine's log",
+                inner.garbage_bytes.load(Ordering::Relaxed) as i64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        header(
+            &mut out,
+            "kvs_compactions_total",
+            "counter",
+            "Compactions of the engine's log",
+        );
+        let _ = writeln!(out, "kvs_compactions_total {}", inner.compactions.count());
+        header(
+            &mut out,
+            "kvs_compaction_duration_seconds",
+            "histogram",
+            "Duration of the compactions",
+        );
+        inner
+            .compactions
+            .render(&mut out, "kvs_compaction_duration_seconds", "");
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
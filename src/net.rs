@@ -0,0 +1,498 @@
+//! Listener and stream abstractions over TCP, TLS and Unix domain sockets
+
+#[cfg(unix)]
+use crate::err::Error;
+use crate::Result;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Listener accepts connections on a TCP or Unix domain socket
+pub enum Listener {
+    /// TCP socket
+    Tcp(TcpListener),
+    /// TCP socket, connections are encrypted with TLS
+    Tls(TcpListener, Arc<ServerConfig>),
+    /// Unix domain socket, the socket file is removed on drop
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind a TCP socket
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    /// Bind a TCP socket serving TLS connections
+    pub fn bind_tls<A: ToSocketAddrs>(addr: A, config: Arc<ServerConfig>) -> Result<Self> {
+        Ok(Listener::Tls(TcpListener::bind(addr)?, config))
+    }
+
+    /// Bind a Unix domain socket at `path`, with the file permission `mode` if given.
+    ///
+    /// A stale socket file left behind by a dead server is replaced,
+    /// while binding fails if a server is still listening on it.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, mode: Option<u32>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Ok(meta) = path.symlink_metadata() {
+            if !meta.file_type().is_socket() {
+                return Err(Error::StringError(format!(
+                    "{} exists and is not a socket",
+                    path.display()
+                )));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::IoError(io::ErrorKind::AddrInUse.into()));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = match mode {
+            Some(mode) => bind_private(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    // Connecting to the listener unblocks a thread waiting in `accept`.
+    pub(crate) fn waker(&self) -> io::Result<Waker> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => {
+                Ok(Waker::tcp(listener.local_addr()?))
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Waker::Unix(path.clone())),
+        }
+    }
+
+    /// Wait for a new connection.
+    ///
+    /// The TLS handshake happens on the first read or write of the stream,
+    /// so that it does not hold up accepting other connections.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Tls(listener, config) => {
+                let (stream, _) = listener.accept()?;
+                let conn = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+                Ok(Stream::tls(TlsStream::Server(StreamOwned::new(
+                    conn, stream,
+                ))))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+}
+
+// Binds the socket in a directory only the owner can enter, and moves it
+// to `path` once it has `mode`, so it is never reachable with the
+// permissions of the umask.
+#[cfg(unix)]
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::StringError(format!("{} is not a file path", path.display())))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("sock");
+    let res = UnixListener::bind(&tmp).and_then(|listener| {
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    Ok(res?)
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Tls(listener, _) => match listener.local_addr() {
+                Ok(addr) => write!(f, "tls:{}", addr),
+                Err(_) => write!(f, "tls"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub(crate) enum Waker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Waker {
+    // Wildcard addresses are reached through the loopback interface.
+    pub(crate) fn tcp(mut addr: SocketAddr) -> Self {
+        if addr.ip().is_unspecified() {
+            let loopback = match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            addr.set_ip(loopback);
+        }
+        Waker::Tcp(addr)
+    }
+
+    pub(crate) fn wake(&self) {
+        let _ = match self {
+            Waker::Tcp(addr) => TcpStream::connect(addr).map(drop),
+            #[cfg(unix)]
+            Waker::Unix(path) => UnixStream::connect(path).map(drop),
+        };
+    }
+}
+
+/// Stream is a connection over TCP, TLS or a Unix domain socket
+pub enum Stream {
+    /// TCP connection
+    Tcp(TcpStream),
+    /// TLS connection over TCP, shared by the clones of the stream
+    Tls(Arc<Mutex<TlsStream>>),
+    /// Unix domain socket connection
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connect to a TCP address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// Connect to a TCP address, giving up on each of its IPs after `timeout`
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self> {
+        Ok(Stream::Tcp(tcp_connect(addr, Some(timeout))?))
+    }
+
+    /// Connect to a TCP address and secure the connection with TLS.
+    ///
+    /// The server certificate is checked against `server_name`.
+    pub fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        Self::connect_tls_timeout(addr, server_name, config, None)
+    }
+
+    /// Connect to a TCP address and secure the connection with TLS, the
+    /// connection and the handshake giving up after `timeout` if given.
+    pub fn connect_tls_timeout<A: ToSocketAddrs>(
+        addr: A,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let stream = tcp_connect(addr, timeout)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let conn = ClientConnection::new(config, server_name)?;
+        let mut stream = TlsStream::Client(StreamOwned::new(conn, stream));
+        stream.handshake()?;
+        if let TlsStream::Client(s) = &stream {
+            s.sock.set_read_timeout(None)?;
+            s.sock.set_write_timeout(None)?;
+        }
+        Ok(Stream::tls(stream))
+    }
+
+    fn tls(stream: TlsStream) -> Self {
+        Stream::Tls(Arc::new(Mutex::new(stream)))
+    }
+
+    /// Connect to a Unix domain socket
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    /// Set the timeout of reads, `None` blocks indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => match &*lock(stream) {
+                TlsStream::Server(s) => s.sock.set_read_timeout(timeout),
+                TlsStream::Client(s) => s.sock.set_read_timeout(timeout),
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Set the timeout of writes, `None` blocks indefinitely
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => match &*lock(stream) {
+                TlsStream::Server(s) => s.sock.set_write_timeout(timeout),
+                TlsStream::Client(s) => s.sock.set_write_timeout(timeout),
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// IP address of the other end, the user and process for a Unix domain socket
+    pub(crate) fn peer(&self) -> String {
+        let addr = match self {
+            Stream::Tcp(stream) => stream.peer_addr(),
+            Stream::Tls(stream) => match &*lock(stream) {
+                TlsStream::Server(s) => s.sock.peer_addr(),
+                TlsStream::Client(s) => s.sock.peer_addr(),
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => return unix_peer(stream),
+        };
+        addr.map_or_else(|_| "unknown".to_owned(), |addr| addr.ip().to_string())
+    }
+
+    pub(crate) fn socket(&self) -> io::Result<Socket> {
+        match self {
+            Stream::Tcp(stream) => Ok(Socket::Tcp(stream.try_clone()?)),
+            Stream::Tls(stream) => {
+                let socket = match &*lock(stream) {
+                    TlsStream::Server(s) => s.sock.try_clone()?,
+                    TlsStream::Client(s) => s.sock.try_clone()?,
+                };
+                Ok(Socket::Tcp(socket))
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Socket::Unix(stream.try_clone()?)),
+        }
+    }
+
+    /// Another handle to the same connection
+    pub fn try_clone(&self) -> Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            Stream::Tls(stream) => Ok(Stream::Tls(Arc::clone(stream))),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+}
+
+// Credentials of the process that connected, so that local clients are
+// told apart like remote ones are by their addresses.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unix_peer(stream: &UnixStream) -> String {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the kernel writes at most `len` bytes to `cred`, both live
+    // through the call
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == 0 {
+        format!("unix uid {} pid {}", cred.uid, cred.pid)
+    } else {
+        format!("unix fd {}", stream.as_raw_fd())
+    }
+}
+
+// Without `SO_PEERCRED` the connection stands for the client.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn unix_peer(stream: &UnixStream) -> String {
+    format!("unix fd {}", stream.as_raw_fd())
+}
+
+/// The socket under a stream, to shut it down from another thread
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(socket) => socket.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.shutdown(how),
+        }
+    }
+}
+
+/// Either side of a TLS connection
+pub enum TlsStream {
+    /// Accepted by a server
+    Server(StreamOwned<ServerConnection, TcpStream>),
+    /// Opened by a client
+    Client(StreamOwned<ClientConnection, TcpStream>),
+}
+
+impl TlsStream {
+    fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Server(s) => {
+                while s.conn.is_handshaking() {
+                    s.conn.complete_io(&mut s.sock)?;
+                }
+            }
+            TlsStream::Client(s) => {
+                while s.conn.is_handshaking() {
+                    s.conn.complete_io(&mut s.sock)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.read(buf),
+            TlsStream::Client(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.write(buf),
+            TlsStream::Client(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Server(s) => s.flush(),
+            TlsStream::Client(s) => s.flush(),
+        }
+    }
+}
+
+// Tell the peer the connection is closed on purpose, rather than truncated.
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        let _ = match self {
+            TlsStream::Server(s) => {
+                s.conn.send_close_notify();
+                s.conn.complete_io(&mut s.sock)
+            }
+            TlsStream::Client(s) => {
+                s.conn.send_close_notify();
+                s.conn.complete_io(&mut s.sock)
+            }
+        };
+    }
+}
+
+// Try the addresses `addr` resolves to in turn, returns the error of the last one.
+fn tcp_connect<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addr);
+    };
+    let mut last = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")))
+}
+
+fn lock(stream: &Mutex<TlsStream>) -> MutexGuard<'_, TlsStream> {
+    stream.lock().unwrap()
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => lock(stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => lock(stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => lock(stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
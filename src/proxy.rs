@@ -0,0 +1,299 @@
+//! Routing proxy over hash-sharded servers
+//!
+//! `KvsProxy` speaks the native protocol and sends every key to the backend
+//! `kvs-server` owning it on a `HashRing`. Requests on several keys are split
+//! by backend, scans and admin commands go to every backend.
+//!
+//! Client connections are served by a thread pool, within the limits set
+//! like a server's. Each gets connections to the backends of its own, made
+//! when first needed and authenticated as the client did.
+//! `reshard` moves keys to their backends on a new ring when backends are
+//! added or removed.
+
+use crate::auth::Credentials;
+use crate::common::{write_line, Request, Response, ResponseBody};
+use crate::err::Error;
+use crate::net::{Listener, Stream};
+use crate::ring::HashRing;
+use crate::server::{self, ConnectionLimits, Protocol};
+use crate::shutdown::ShutdownHandle;
+use crate::thread_pool::ThreadPool;
+use crate::{trace, KvsClient, Result};
+use log::{debug, error, info};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, BufWriter};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// KvsProxy routes the requests of its clients to the backends of a ring
+pub struct KvsProxy<P: ThreadPool> {
+    ring: Arc<HashRing>,
+    thread_pool: P,
+    limits: ConnectionLimits,
+    // open client connections
+    active: Arc<AtomicUsize>,
+    shutdown: ShutdownHandle,
+}
+
+impl<P: ThreadPool> KvsProxy<P> {
+    /// New a proxy over the backends of `ring`, serving its clients on `thread_pool`
+    pub fn new(ring: HashRing, thread_pool: P) -> Self {
+        KvsProxy {
+            ring: Arc::new(ring),
+            thread_pool,
+            limits: ConnectionLimits::default(),
+            active: Arc::new(AtomicUsize::new(0)),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Limit the number of client connections and how long they may block.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    /// Handle to stop the proxy from accepting connections
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Run the proxy listening on `addr`
+    pub fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        self.run_listener(Listener::bind(addr)?)
+    }
+
+    /// Run the proxy accepting connections on `listener` until it is shut down
+    pub fn run_listener(&self, listener: Listener) -> Result<()> {
+        self.shutdown.register(listener.waker()?);
+        loop {
+            let accepted = listener.accept();
+            if self.shutdown.is_shutdown() {
+                info!("proxy listener {} closed", listener);
+                return Ok(());
+            }
+            match accepted {
+                Ok(stream) => {
+                    // counted before queueing, like the connections of a server
+                    let active = self.active.fetch_add(1, Ordering::SeqCst);
+                    let guard = ActiveGuard(Arc::clone(&self.active));
+                    if self.limits.max_connections.is_some_and(|max| active >= max) {
+                        drop(guard);
+                        info!("proxy connection rejected, too many connections");
+                        if let Err(e) = server::reject(Protocol::Kvs, &stream) {
+                            error!("reject err {:?}", e);
+                        }
+                        continue;
+                    }
+                    let (ring, limits) = (Arc::clone(&self.ring), self.limits);
+                    self.thread_pool.spawn(move || {
+                        if let Err(e) = handle(&ring, &limits, stream) {
+                            error!("proxy connection err {:?}", e);
+                        }
+                        drop(guard);
+                    });
+                }
+                Err(e) => error!("accept err {}", e),
+            }
+        }
+    }
+}
+
+// Counts a client connection as open until dropped.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle(ring: &HashRing, limits: &ConnectionLimits, stream: Stream) -> Result<()> {
+    stream.set_read_timeout(limits.idle_timeout)?;
+    stream.set_write_timeout(limits.write_timeout)?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut session = Session::new(ring);
+    while server::wait_for_request(limits, &stream, &mut reader)? {
+        let req =
+            match Request::deserialize(&mut serde_json::Deserializer::from_reader(&mut reader)) {
+                Ok(req) => req,
+                Err(e) if e.is_eof() => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+        debug!("proxy req {}", trace::describe_request(&req, true));
+        let (body, close) = session.respond(req);
+        write_line(&mut writer, &Response { body })?;
+        if close {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Connections of a client to the backends.
+struct Session<'a> {
+    ring: &'a HashRing,
+    backends: Vec<Option<KvsClient>>,
+    credentials: Option<Credentials>,
+}
+
+impl<'a> Session<'a> {
+    fn new(ring: &'a HashRing) -> Self {
+        Session {
+            ring,
+            backends: ring.nodes().iter().map(|_| None).collect(),
+            credentials: None,
+        }
+    }
+
+    // Returns the response and whether to close the connection after it.
+    fn respond(&mut self, req: Request) -> (ResponseBody, bool) {
+        let body = match req {
+            Request::Auth { credentials } => {
+                // checked by every backend now rather than on first use
+                self.credentials = Some(credentials);
+                self.backends.iter_mut().for_each(|backend| *backend = None);
+                for i in 0..self.backends.len() {
+                    if let Err(e) = self.backend(i) {
+                        return (ResponseBody::Err(e.to_string()), true);
+                    }
+                }
+                ResponseBody::Ok(None)
+            }
+            Request::Get { ref key }
+            | Request::Set { ref key, .. }
+            | Request::Remove { ref key } => {
+                let i = self.ring.index(key);
+                self.call(i, req)
+            }
+            Request::GetMany { keys } => self.get_many(keys),
+            // the backend of the key answers retries of the request
+            Request::WithId { id, request } => match &*request {
+                Request::Get { key } | Request::Set { key, .. } | Request::Remove { key } => {
+                    let i = self.ring.index(key);
+                    self.call(i, Request::WithId { id, request })
+                }
+                _ => return self.respond(*request),
+            },
+            Request::Scan { prefix } => {
+                let mut keys = Vec::new();
+                for i in 0..self.backends.len() {
+                    let prefix = prefix.clone();
+                    match self.call(i, Request::Scan { prefix }) {
+                        ResponseBody::Keys(found) => keys.extend(found),
+                        body => return (body, false),
+                    }
+                }
+                keys.sort_unstable();
+                ResponseBody::Keys(keys)
+            }
+            Request::Admin(cmd) => {
+                for i in 0..self.backends.len() {
+                    match self.call(i, Request::Admin(cmd.clone())) {
+                        ResponseBody::Ok(_) => {}
+                        body => return (body, false),
+                    }
+                }
+                ResponseBody::Ok(None)
+            }
+            Request::Ping => ResponseBody::Ok(None),
+            Request::Info => ResponseBody::Err("info is served by the backends".to_owned()),
+            Request::Replicate { .. } => {
+                ResponseBody::Err("replication is not served here".to_owned())
+            }
+            Request::Track { .. } => ResponseBody::Err("tracking is not served here".to_owned()),
+        };
+        (body, false)
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> ResponseBody {
+        let mut values = vec![None; keys.len()];
+        // positions of the keys of each backend
+        let mut shards: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (pos, key) in keys.iter().enumerate() {
+            shards.entry(self.ring.index(key)).or_default().push(pos);
+        }
+        for (i, positions) in shards {
+            let keys = positions.iter().map(|&pos| keys[pos].clone()).collect();
+            match self.call(i, Request::GetMany { keys }) {
+                ResponseBody::Values(found) if found.len() == positions.len() => {
+                    for (pos, value) in positions.into_iter().zip(found) {
+                        values[pos] = value;
+                    }
+                }
+                body => return body,
+            }
+        }
+        ResponseBody::Values(values)
+    }
+
+    fn backend(&mut self, i: usize) -> Result<&mut KvsClient> {
+        if self.backends[i].is_none() {
+            let mut client = KvsClient::new(&self.ring.nodes()[i])?;
+            if let Some(credentials) = &self.credentials {
+                client.auth(credentials.clone())?;
+            }
+            self.backends[i] = Some(client);
+        }
+        Ok(self.backends[i].as_mut().unwrap())
+    }
+
+    // Send `req` to backend `i`, errors of the backend are passed on to the client.
+    fn call(&mut self, i: usize, req: Request) -> ResponseBody {
+        match self.backend(i).and_then(|backend| backend.call(req)) {
+            Ok(body) => body,
+            Err(Error::RateLimited(retry_after)) => ResponseBody::RateLimited {
+                retry_after_ms: retry_after.as_millis() as u64,
+            },
+            Err(e) => {
+                // made again by the next request
+                self.backends[i] = None;
+                error!("backend {} err {:?}", self.ring.nodes()[i], e);
+                ResponseBody::Err(format!("backend {}: {}", self.ring.nodes()[i], e))
+            }
+        }
+    }
+}
+
+/// Move the keys of the backends of `from` that belong to another backend
+/// on `to`, returns how many were moved.
+///
+/// Keys are copied to their new backend, then removed from the old one.
+/// Meant to run before the proxies switch to `to`, with writes stopped,
+/// as writes to a key being moved may be lost. Expiration times are not
+/// carried over.
+pub fn reshard(from: &HashRing, to: &HashRing, credentials: Option<Credentials>) -> Result<usize> {
+    let connect = |addr: &str| -> Result<KvsClient> {
+        let mut client = KvsClient::new(addr)?;
+        if let Some(credentials) = &credentials {
+            client.auth(credentials.clone())?;
+        }
+        Ok(client)
+    };
+    let mut targets: HashMap<&str, KvsClient> = HashMap::new();
+    let mut moved = 0;
+    for source_addr in from.nodes() {
+        let mut source = connect(source_addr)?;
+        let mut count = 0;
+        for key in source.scan(String::new())? {
+            let target_addr = to.node(&key);
+            if target_addr == source_addr {
+                continue;
+            }
+            if let Some(value) = source.get(key.clone())? {
+                let target = match targets.get_mut(target_addr) {
+                    Some(target) => target,
+                    None => targets.entry(target_addr).or_insert(connect(target_addr)?),
+                };
+                target.set(key.clone(), value)?;
+                source.remove(key)?;
+                count += 1;
+            }
+        }
+        info!("moved {} keys off {}", count, source_addr);
+        moved += count;
+    }
+    Ok(moved)
+}
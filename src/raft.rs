@@ -0,0 +1,537 @@
+//! Raft consensus, without the I/O
+//!
+//! A `RaftNode` is driven by its owner: `tick` at a regular interval,
+//! `step` with the messages of the other nodes, and `propose` commands on
+//! the leader. What the node needs done is collected with `ready`: state
+//! and entries to persist, messages to send and entries committed, to be
+//! handled in that order.
+//!
+//! The cluster mode of `kvs-server` drives nodes over TCP, tests can drive
+//! them over a simulated network.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Identifier of a node, unique within its group
+pub type NodeId = u64;
+
+// Entries sent in one append message at most
+const MAX_APPEND: usize = 256;
+
+/// Settings of a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// Identifier of this node
+    pub id: NodeId,
+    /// Every node of the group, including this one
+    pub nodes: Vec<NodeId>,
+    /// Ticks without hearing from a leader before standing for election,
+    /// randomized up to twice as many
+    pub election_ticks: u64,
+    /// Ticks between the heartbeats of a leader, fewer than `election_ticks`
+    pub heartbeat_ticks: u64,
+    /// Seed of the randomized election timeouts
+    pub seed: u64,
+}
+
+impl RaftConfig {
+    /// Settings of node `id` with 10 election ticks and 2 heartbeat ticks
+    pub fn new(id: NodeId, nodes: Vec<NodeId>) -> Self {
+        RaftConfig {
+            id,
+            nodes,
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            seed: id,
+        }
+    }
+}
+
+/// State a node persists before sending any message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HardState {
+    /// Latest term seen
+    pub term: u64,
+    /// Candidate voted for in `term`
+    pub voted_for: Option<NodeId>,
+}
+
+/// Entry of the replicated log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry<C> {
+    /// Position in the log, from 1
+    pub index: u64,
+    /// Term of the leader that appended it
+    pub term: u64,
+    /// `None` for the entry a new leader appends to commit the previous terms
+    pub command: Option<C>,
+}
+
+/// Message between the nodes of a group
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message<C> {
+    /// A candidate asks for a vote
+    RequestVote {
+        /// Term of the candidate
+        term: u64,
+        /// Index of the last entry of the candidate
+        last_index: u64,
+        /// Term of the last entry of the candidate
+        last_term: u64,
+    },
+    /// Answer to `RequestVote`
+    Vote {
+        /// Term of the voter
+        term: u64,
+        /// Whether the vote was granted
+        granted: bool,
+    },
+    /// The leader appends entries, or only tells it is alive when there are none
+    Append {
+        /// Term of the leader
+        term: u64,
+        /// Index of the entry preceding `entries`
+        prev_index: u64,
+        /// Term of the entry preceding `entries`
+        prev_term: u64,
+        /// Entries to append
+        entries: Vec<Entry<C>>,
+        /// Commit index of the leader
+        commit: u64,
+    },
+    /// Answer to `Append`
+    AppendReply {
+        /// Term of the follower
+        term: u64,
+        /// Whether the entries were appended
+        success: bool,
+        /// Last index matching the leader on success, where to retry from on failure
+        last_index: u64,
+    },
+}
+
+impl<C> Message<C> {
+    /// Term of the sender
+    pub fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendReply { term, .. } => *term,
+        }
+    }
+}
+
+/// Role of a node in its term
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// Follows the leader
+    Follower,
+    /// Stands for election
+    Candidate,
+    /// Appends entries and replicates them
+    Leader,
+}
+
+/// What a node needs done, see `RaftNode::ready`
+#[derive(Debug)]
+pub struct Ready<C> {
+    /// Term and vote to persist, if they changed
+    pub hard_state: Option<HardState>,
+    /// Entries to persist, replacing the persisted ones from the index of the first
+    pub entries: Vec<Entry<C>>,
+    /// Messages to send, by recipient
+    pub messages: Vec<(NodeId, Message<C>)>,
+    /// Entries committed since the last call, to apply in order
+    pub committed: Vec<Entry<C>>,
+}
+
+/// A node of a Raft group
+pub struct RaftNode<C> {
+    config: RaftConfig,
+    state: HardState,
+    role: Role,
+    leader: Option<NodeId>,
+    // entry of index `i` at `i - 1`
+    log: Vec<Entry<C>>,
+    commit: u64,
+    // committed entries handed out by `ready`
+    applied: u64,
+    elapsed: u64,
+    timeout: u64,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    rng: u64,
+    state_changed: bool,
+    // first entry not handed out to persist yet
+    unstable_from: Option<u64>,
+    messages: Vec<(NodeId, Message<C>)>,
+}
+
+impl<C: Clone> RaftNode<C> {
+    /// A new node, or a restarted one from its persisted state and log,
+    /// with the entries up to `applied` already applied.
+    pub fn new(config: RaftConfig, state: HardState, log: Vec<Entry<C>>, applied: u64) -> Self {
+        let applied = applied.min(log.len() as u64);
+        let mut node = RaftNode {
+            rng: config.seed.max(1),
+            config,
+            state,
+            role: Role::Follower,
+            leader: None,
+            log,
+            commit: applied,
+            applied,
+            elapsed: 0,
+            timeout: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            state_changed: false,
+            unstable_from: None,
+            messages: Vec::new(),
+        };
+        node.reset_timeout();
+        node
+    }
+
+    /// Identifier of the node
+    pub fn id(&self) -> NodeId {
+        self.config.id
+    }
+
+    /// Current term
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    /// Role in the current term
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Leader of the current term, if known
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// Index of the last committed entry
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    /// Index of the last entry of the log
+    pub fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    /// Advance the clock of elections and heartbeats by one tick.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.role == Role::Leader {
+            if self.elapsed >= self.config.heartbeat_ticks {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+        } else if self.elapsed >= self.timeout {
+            self.campaign();
+        }
+    }
+
+    /// Append a command to the log of the leader, returns its index,
+    /// or the known leader if this node is not it.
+    pub fn propose(&mut self, command: C) -> Result<u64, Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        let index = self.append_local(Some(command));
+        self.maybe_commit();
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// Handle a message of node `from`.
+    pub fn step(&mut self, from: NodeId, msg: Message<C>) {
+        if msg.term() > self.state.term {
+            let leader = matches!(msg, Message::Append { .. }).then_some(from);
+            self.become_follower(msg.term(), leader);
+        }
+        match msg {
+            Message::RequestVote {
+                term,
+                last_index,
+                last_term,
+            } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.state.term
+                    && self.state.voted_for.is_none_or(|id| id == from)
+                    && up_to_date;
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.state_changed = true;
+                    self.elapsed = 0;
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.state.term,
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.state.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() > self.config.nodes.len() / 2 {
+                        self.become_leader();
+                    }
+                }
+            }
+            Message::Append {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => self.handle_append(from, term, prev_index, prev_term, entries, commit),
+            Message::AppendReply {
+                term,
+                success,
+                last_index,
+            } => {
+                if self.role != Role::Leader || term != self.state.term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_default();
+                    *matched = (*matched).max(last_index);
+                    self.next_index.insert(from, *matched + 1);
+                    self.maybe_commit();
+                    if last_index < self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    let next = next.saturating_sub(1).min(last_index + 1).max(1);
+                    self.next_index.insert(from, next);
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+
+    /// Take what the node needs done since the last call.
+    pub fn ready(&mut self) -> Ready<C> {
+        let hard_state = std::mem::take(&mut self.state_changed).then_some(self.state);
+        let entries = match self.unstable_from.take() {
+            Some(from) => self.log[from as usize - 1..].to_vec(),
+            None => Vec::new(),
+        };
+        let committed = self.log[self.applied as usize..self.commit as usize].to_vec();
+        self.applied = self.commit;
+        Ready {
+            hard_state,
+            entries,
+            messages: std::mem::take(&mut self.messages),
+            committed,
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry<C>>,
+        commit: u64,
+    ) {
+        if term < self.state.term {
+            let last_index = self.last_index();
+            self.send(
+                from,
+                Message::AppendReply {
+                    term: self.state.term,
+                    success: false,
+                    last_index,
+                },
+            );
+            return;
+        }
+        // a candidate of the same term lost the election
+        self.role = Role::Follower;
+        self.leader = Some(from);
+        self.elapsed = 0;
+
+        if prev_index > self.last_index() || self.term_at(prev_index) != prev_term {
+            let last_index = self.last_index().min(prev_index.saturating_sub(1));
+            self.send(
+                from,
+                Message::AppendReply {
+                    term: self.state.term,
+                    success: false,
+                    last_index,
+                },
+            );
+            return;
+        }
+        let last_new = prev_index + entries.len() as u64;
+        for entry in entries {
+            if entry.index <= self.last_index() {
+                if self.term_at(entry.index) == entry.term {
+                    continue;
+                }
+                // committed entries never conflict
+                self.log.truncate(entry.index as usize - 1);
+            }
+            self.mark_unstable(entry.index);
+            self.log.push(entry);
+        }
+        self.commit = self.commit.max(commit.min(last_new));
+        self.send(
+            from,
+            Message::AppendReply {
+                term: self.state.term,
+                success: true,
+                last_index: last_new,
+            },
+        );
+    }
+
+    fn campaign(&mut self) {
+        self.state.term += 1;
+        self.state.voted_for = Some(self.config.id);
+        self.state_changed = true;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.config.id]);
+        self.reset_timeout();
+        if self.votes.len() > self.config.nodes.len() / 2 {
+            self.become_leader();
+            return;
+        }
+        let msg = Message::RequestVote {
+            term: self.state.term,
+            last_index: self.last_index(),
+            last_term: self.last_term(),
+        };
+        for id in self.peers() {
+            self.send(id, msg.clone());
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        self.state.term = term;
+        self.state.voted_for = None;
+        self.state_changed = true;
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.config.id);
+        self.elapsed = 0;
+        let next = self.last_index() + 1;
+        self.next_index = self.peers().into_iter().map(|id| (id, next)).collect();
+        self.match_index = self.peers().into_iter().map(|id| (id, 0)).collect();
+        // entries of previous terms commit along with one of this term
+        self.append_local(None);
+        self.maybe_commit();
+        self.broadcast_append();
+    }
+
+    fn append_local(&mut self, command: Option<C>) -> u64 {
+        let index = self.last_index() + 1;
+        self.log.push(Entry {
+            index,
+            term: self.state.term,
+            command,
+        });
+        self.mark_unstable(index);
+        index
+    }
+
+    // Commit the last entry of this term stored on a majority.
+    fn maybe_commit(&mut self) {
+        let majority = self.config.nodes.len() / 2 + 1;
+        for index in (self.commit + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.state.term {
+                break;
+            }
+            let stored = 1 + self.match_index.values().filter(|&&m| m >= index).count();
+            if stored >= majority {
+                self.commit = index;
+                break;
+            }
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        for id in self.peers() {
+            self.send_append(id);
+        }
+    }
+
+    fn send_append(&mut self, to: NodeId) {
+        let next = self.next_index.get(&to).copied().unwrap_or(1).max(1);
+        let prev_index = next - 1;
+        let entries = self
+            .log
+            .iter()
+            .skip(prev_index as usize)
+            .take(MAX_APPEND)
+            .cloned()
+            .collect();
+        let msg = Message::Append {
+            term: self.state.term,
+            prev_index,
+            prev_term: self.term_at(prev_index),
+            entries,
+            commit: self.commit,
+        };
+        self.send(to, msg);
+    }
+
+    fn send(&mut self, to: NodeId, msg: Message<C>) {
+        self.messages.push((to, msg));
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        let id = self.config.id;
+        self.config
+            .nodes
+            .iter()
+            .copied()
+            .filter(|&n| n != id)
+            .collect()
+    }
+
+    fn mark_unstable(&mut self, index: u64) {
+        let from = self.unstable_from.map_or(index, |from| from.min(index));
+        self.unstable_from = Some(from);
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log.get(index as usize - 1).map_or(0, |e| e.term),
+        }
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    fn reset_timeout(&mut self) {
+        // xorshift, good enough to spread the elections
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let ticks = self.config.election_ticks.max(1);
+        self.timeout = ticks + self.rng % ticks;
+        self.elapsed = 0;
+    }
+}
@@ -0,0 +1,104 @@
+//! Per-client rate limits and storage quotas
+//!
+//! Every client gets token buckets that refill at the configured rates
+//! and hold up to one second of them. Clients are told apart by the
+//! principal they authenticated as, or by their address.
+//! Quotas are kept by the server context, which sees every write.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Clients idle for this long are forgotten, their buckets have refilled by then.
+const IDLE_CLIENT: Duration = Duration::from_secs(60);
+
+/// Rate limits applied to each client, unlimited by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Requests per second, not zero
+    pub ops_per_sec: Option<u64>,
+    /// Bytes of keys and values sent per second, not zero
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// Storage quota on the keys starting with a prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    /// Prefix of the keys, the empty prefix covers every key
+    pub prefix: String,
+    /// Maximum total size of the keys and their values
+    pub max_bytes: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    clients: HashMap<String, Buckets>,
+    last_pruned: Option<Instant>,
+}
+
+struct Buckets {
+    ops: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+        self.clients.clear();
+    }
+
+    /// Take a request of `bytes` from the buckets of `client`, or tell how
+    /// long to wait before it would be let through.
+    ///
+    /// A request larger than one second of bytes is let through once the
+    /// bucket is full, and leaves it in debt.
+    pub(crate) fn take(&mut self, client: &str, bytes: u64) -> Result<(), Duration> {
+        // a rate of zero would never let anything through
+        let ops_rate = self.limits.ops_per_sec.filter(|&rate| rate > 0);
+        let bytes_rate = self.limits.bytes_per_sec.filter(|&rate| rate > 0);
+        if ops_rate.is_none() && bytes_rate.is_none() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.prune(now);
+        let buckets = self
+            .clients
+            .entry(client.to_owned())
+            .or_insert_with(|| Buckets {
+                ops: ops_rate.unwrap_or(0) as f64,
+                bytes: bytes_rate.unwrap_or(0) as f64,
+                updated: now,
+            });
+        let elapsed = (now - buckets.updated).as_secs_f64();
+        buckets.updated = now;
+
+        let bytes = bytes as f64;
+        let mut wait: f64 = 0.0;
+        if let Some(rate) = ops_rate.map(|rate| rate as f64) {
+            buckets.ops = (buckets.ops + elapsed * rate).min(rate);
+            wait = wait.max((1.0 - buckets.ops) / rate);
+        }
+        if let Some(rate) = bytes_rate.map(|rate| rate as f64) {
+            buckets.bytes = (buckets.bytes + elapsed * rate).min(rate);
+            wait = wait.max((bytes.min(rate) - buckets.bytes) / rate);
+        }
+        if wait > 0.0 {
+            return Err(Duration::from_secs_f64(wait));
+        }
+        buckets.ops -= 1.0;
+        buckets.bytes -= bytes;
+        Ok(())
+    }
+
+    fn prune(&mut self, now: Instant) {
+        match self.last_pruned {
+            Some(last) if now - last < IDLE_CLIENT => {}
+            _ => {
+                self.last_pruned = Some(now);
+                self.clients
+                    .retain(|_, buckets| now - buckets.updated < IDLE_CLIENT);
+            }
+        }
+    }
+}
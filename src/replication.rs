@@ -0,0 +1,401 @@
+//! Leader–follower replication
+//!
+//! Every server keeps its latest writes in a bounded in-memory log, numbered
+//! by sequence. A follower connects to its leader with `Request::Replicate`,
+//! giving the last sequence number it applied, and the leader streams the
+//! writes that follow as JSON messages, one per line.
+//! A follower too far behind for the log, or following a leader that
+//! restarted since, is first sent a snapshot of the data.
+//!
+//! Followers refuse writes from clients, and can be followed in turn.
+//! The log only lives in memory, so a restarted follower starts from a snapshot.
+
+use crate::auth::Credentials;
+use crate::common::{write_line, Request, Response, ResponseBody};
+use crate::context::Context;
+use crate::err::Error;
+use crate::net::Stream;
+use crate::{KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Writes kept for followers to catch up from, by default
+pub const DEFAULT_LOG_CAPACITY: usize = 10_000;
+// A leader with nothing to send tells its log position this often.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+// A follower hearing nothing from its leader for this long reconnects.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// How often a waiting follower checks for shutdown.
+const POLL: Duration = Duration::from_millis(200);
+// Writes or snapshot keys per message
+const BATCH: usize = 1000;
+
+/// A write as replicated to the followers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum WriteOp {
+    Set {
+        key: String,
+        value: String,
+        // time left before the key expires
+        ttl_ms: Option<u64>,
+        flags: u32,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+impl WriteOp {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            WriteOp::Set { key, .. } | WriteOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Message of the replication stream, after the response to `Request::Replicate`
+#[derive(Serialize, Deserialize, Debug)]
+enum ReplMessage {
+    /// Keys of a snapshot, the follower drops the keys missing from it
+    Snapshot(Vec<WriteOp>),
+    /// The snapshot is complete, and includes the writes up to `seq`
+    SnapshotEnd { seq: u64 },
+    /// Writes numbered from `first_seq`
+    Writes { first_seq: u64, ops: Vec<WriteOp> },
+    /// Nothing new, the log ends at `seq`
+    Heartbeat { seq: u64 },
+}
+
+/// Replication state of a server, see `ServerInfo::replication`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplicationInfo {
+    /// Sequence number of the last write in the server's log
+    pub log_seq: u64,
+    /// Followers streaming the log of this server
+    pub followers: u64,
+    /// Address of the leader, `None` unless the server is a follower
+    pub leader: Option<String>,
+    /// Whether the follower is streaming from its leader
+    pub connected: bool,
+    /// Sequence number of the last write of the leader applied
+    pub applied_seq: u64,
+    /// Writes of the leader not applied yet, as of its last message
+    pub lag_entries: u64,
+    /// Milliseconds since the last message of the leader, `None` before the first one
+    pub last_contact_ms: Option<u64>,
+}
+
+/// The latest writes of a server
+pub(crate) struct ReplLog {
+    // tells a restarted server apart, whose sequence numbers start over
+    id: String,
+    inner: Mutex<LogInner>,
+    appended: Condvar,
+    followers: AtomicU64,
+}
+
+struct LogInner {
+    // the writes numbered up to `last_seq`
+    ops: VecDeque<WriteOp>,
+    last_seq: u64,
+    capacity: usize,
+}
+
+impl Default for ReplLog {
+    fn default() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        ReplLog {
+            id: format!("{}-{}", std::process::id(), started.as_nanos()),
+            inner: Mutex::new(LogInner {
+                ops: VecDeque::new(),
+                last_seq: 0,
+                capacity: DEFAULT_LOG_CAPACITY,
+            }),
+            appended: Condvar::new(),
+            followers: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ReplLog {
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        let excess = inner.ops.len().saturating_sub(capacity);
+        inner.ops.drain(..excess);
+    }
+
+    /// Number the next write, the oldest one is dropped once the log is full.
+    pub(crate) fn append(&self, op: WriteOp) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_seq += 1;
+        if inner.capacity > 0 {
+            if inner.ops.len() == inner.capacity {
+                inner.ops.pop_front();
+            }
+            inner.ops.push_back(op);
+        }
+        self.appended.notify_all();
+    }
+
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.inner.lock().unwrap().last_seq
+    }
+
+    pub(crate) fn followers(&self) -> u64 {
+        self.followers.load(Ordering::SeqCst)
+    }
+
+    /// Writes following `after`, waiting up to `timeout` for one,
+    /// or `None` if some of them are no longer kept.
+    pub(crate) fn read(&self, after: u64, timeout: Duration) -> Option<Vec<WriteOp>> {
+        let inner = self.inner.lock().unwrap();
+        let (inner, _) = self
+            .appended
+            .wait_timeout_while(inner, timeout, |inner| inner.last_seq == after)
+            .unwrap();
+        let first_kept = inner.last_seq - inner.ops.len() as u64 + 1;
+        if after + 1 < first_kept || after > inner.last_seq {
+            return None;
+        }
+        let skip = (after + 1 - first_kept) as usize;
+        Some(inner.ops.iter().skip(skip).take(BATCH).cloned().collect())
+    }
+}
+
+/// Where a follower streams from
+#[derive(Debug, Clone)]
+pub(crate) struct Leader {
+    pub addr: String,
+    // needed when the leader has an ACL
+    pub credentials: Option<Credentials>,
+}
+
+/// How far a follower got
+#[derive(Debug)]
+pub(crate) struct FollowerState {
+    leader: String,
+    connected: bool,
+    // log of the leader the applied writes come from
+    log_id: Option<String>,
+    applied_seq: u64,
+    leader_seq: u64,
+    last_contact: Option<Instant>,
+}
+
+impl FollowerState {
+    pub(crate) fn new(leader: String) -> Self {
+        FollowerState {
+            leader,
+            connected: false,
+            log_id: None,
+            applied_seq: 0,
+            leader_seq: 0,
+            last_contact: None,
+        }
+    }
+
+    pub(crate) fn info(&self, log: &ReplLog) -> ReplicationInfo {
+        ReplicationInfo {
+            log_seq: log.last_seq(),
+            followers: log.followers(),
+            leader: Some(self.leader.clone()),
+            connected: self.connected,
+            applied_seq: self.applied_seq,
+            lag_entries: self.leader_seq.saturating_sub(self.applied_seq),
+            last_contact_ms: self
+                .last_contact
+                .map(|contact| contact.elapsed().as_millis() as u64),
+        }
+    }
+}
+
+// Counts a follower while it streams.
+struct FollowerGuard<'a>(&'a ReplLog);
+
+impl Drop for FollowerGuard<'_> {
+    fn drop(&mut self) {
+        self.0.followers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stream the write log to a follower that applied it up to `after`,
+/// until it disconnects or the server shuts down.
+pub(crate) fn serve_follower<T: KvsEngine, W: Write>(
+    context: &Context<T>,
+    writer: &mut W,
+    log_id: Option<String>,
+    after: u64,
+) -> Result<()> {
+    let log = context.repl_log();
+    log.followers.fetch_add(1, Ordering::SeqCst);
+    let _follower = FollowerGuard(log);
+    write_line(
+        writer,
+        &Response {
+            body: ResponseBody::Ok(Some(log.id.clone())),
+        },
+    )?;
+
+    let shutdown = context.shutdown_handle();
+    let mut seq = after;
+    let mut needs_snapshot = log_id.as_deref() != Some(log.id.as_str());
+    while !shutdown.is_shutdown() {
+        if needs_snapshot {
+            let (snapshot_seq, ops) = context.snapshot()?;
+            info!(
+                "sending a snapshot of {} keys at {}",
+                ops.len(),
+                snapshot_seq
+            );
+            for batch in ops.chunks(BATCH) {
+                write_line(writer, &ReplMessage::Snapshot(batch.to_vec()))?;
+            }
+            write_line(writer, &ReplMessage::SnapshotEnd { seq: snapshot_seq })?;
+            seq = snapshot_seq;
+            needs_snapshot = false;
+        }
+        match log.read(seq, HEARTBEAT) {
+            None => needs_snapshot = true,
+            Some(ops) if ops.is_empty() => write_line(writer, &ReplMessage::Heartbeat { seq })?,
+            Some(ops) => {
+                let first_seq = seq + 1;
+                seq += ops.len() as u64;
+                write_line(writer, &ReplMessage::Writes { first_seq, ops })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Follow the leader until the server shuts down, reconnecting when
+/// the stream breaks.
+pub(crate) fn follow<T: KvsEngine>(context: Context<T>, leader: Leader) {
+    let shutdown = context.shutdown_handle().clone();
+    while !shutdown.is_shutdown() {
+        if let Err(e) = follow_once(&context, &leader) {
+            warn!("replication from {} failed: {}", leader.addr, e);
+        }
+        context.update_follower(|state| state.connected = false);
+        let retry = Instant::now() + RETRY_DELAY;
+        while !shutdown.is_shutdown() && Instant::now() < retry {
+            thread::sleep(POLL);
+        }
+    }
+}
+
+fn follow_once<T: KvsEngine>(context: &Context<T>, leader: &Leader) -> Result<()> {
+    let stream = Stream::connect(&leader.addr)?;
+    stream.set_read_timeout(Some(LEADER_TIMEOUT))?;
+    stream.set_write_timeout(Some(LEADER_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    if let Some(credentials) = &leader.credentials {
+        write_line(
+            &mut writer,
+            &Request::Auth {
+                credentials: credentials.clone(),
+            },
+        )?;
+        let rsp = Response::deserialize(&mut serde_json::Deserializer::from_reader(&mut reader))?;
+        match rsp.body {
+            ResponseBody::Ok(_) => {}
+            ResponseBody::Err(e) => return Err(Error::AuthError(e)),
+            body => return Err(body.unexpected()),
+        }
+    }
+    let (log_id, after) = context
+        .update_follower(|state| (state.log_id.clone(), state.applied_seq))
+        .unwrap_or_default();
+    write_line(&mut writer, &Request::Replicate { log_id, after })?;
+
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    let rsp: Response = serde_json::from_slice(&line)?;
+    let leader_log_id = match rsp.body {
+        ResponseBody::Ok(Some(id)) => id,
+        ResponseBody::Err(e) => return Err(Error::ServerError(e)),
+        body => return Err(body.unexpected()),
+    };
+    info!("replicating from {}", leader.addr);
+    context.update_follower(|state| {
+        state.connected = true;
+        state.last_contact = Some(Instant::now());
+    });
+
+    // Reads time out often enough to notice shutdown, a message cut by
+    // a timeout stays in `line` and is completed by the next read.
+    stream.set_read_timeout(Some(POLL))?;
+    let shutdown = context.shutdown_handle();
+    let mut last_message = Instant::now();
+    let mut snapshot_keys: Option<HashSet<String>> = None;
+    line.clear();
+    while !shutdown.is_shutdown() {
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) if line.ends_with(b"\n") => {}
+            Ok(_) => continue,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if last_message.elapsed() > LEADER_TIMEOUT {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let message: ReplMessage = serde_json::from_slice(&line)?;
+        line.clear();
+        last_message = Instant::now();
+
+        match message {
+            ReplMessage::Snapshot(ops) => {
+                let keys = snapshot_keys.get_or_insert_with(HashSet::new);
+                for op in ops {
+                    keys.insert(op.key().to_owned());
+                    context.apply(op)?;
+                }
+            }
+            ReplMessage::SnapshotEnd { seq } => {
+                let keys = snapshot_keys.take().unwrap_or_default();
+                context.retain_keys(&keys)?;
+                info!("snapshot of {} keys applied at {}", keys.len(), seq);
+                context.update_follower(|state| {
+                    state.log_id = Some(leader_log_id.clone());
+                    state.applied_seq = seq;
+                    state.leader_seq = seq;
+                });
+            }
+            ReplMessage::Writes { first_seq, ops } => {
+                let last_seq = first_seq + ops.len() as u64 - 1;
+                for op in ops {
+                    context.apply(op)?;
+                }
+                context.update_follower(|state| {
+                    state.log_id = Some(leader_log_id.clone());
+                    state.applied_seq = last_seq;
+                    state.leader_seq = state.leader_seq.max(last_seq);
+                });
+            }
+            ReplMessage::Heartbeat { seq } => {
+                context.update_follower(|state| state.leader_seq = seq);
+            }
+        }
+        context.update_follower(|state| state.last_contact = Some(last_message));
+    }
+    Ok(())
+}
@@ -0,0 +1,510 @@
+//! Redis serialization protocol (RESP2) front-end
+//!
+//! Translates a subset of Redis commands onto the engine, so that
+//! `redis-cli` and Redis client libraries can talk to kvs.
+//! Key expirations set with `EX`/`PX` are stored with the keys, which
+//! the server removes once expired.
+//! When the server has an ACL, clients authenticate with `AUTH <token>`
+//! or `AUTH <user> <password>`.
+
+use crate::auth::{Credentials, Permission};
+use crate::context::{Context, Session, SetOptions, SetResult};
+use crate::err::Error;
+use crate::net::Stream;
+use crate::server::next_request;
+use crate::trace::{self, RequestTimer};
+use crate::{KvsEngine, Result};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+use tracing::{debug, debug_span, error};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+const MAX_LINE_LEN: u64 = 64 * 1024;
+const MAX_ARGS: usize = 1024;
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_PATTERN_LEN: usize = 1024;
+
+/// Reply sent back to a RESP client
+#[derive(Debug)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK".to_owned())
+    }
+
+    fn err(msg: impl Into<String>) -> Reply {
+        Reply::Error(format!("ERR {}", msg.into()))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(e) => write!(writer, "-{}\r\n", e),
+            Reply::Integer(i) => write!(writer, ":{}\r\n", i),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items {
+                    item.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn handle<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    mut timer: RequestTimer,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut session = Session::new(stream.peer());
+
+    while next_request(&context, &stream, &mut reader)? {
+        timer.start();
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                Reply::err(format!("Protocol error: {}", e)).write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        timer.read();
+        let desc = trace::describe_command(&args, context.request_log().redact_values);
+        let span = debug_span!("request", req = %desc);
+        let _span = span.enter();
+        debug!("resp req {}", desc);
+        let quit = args[0].eq_ignore_ascii_case("quit");
+        let rsp = if quit {
+            Reply::ok()
+        } else {
+            match execute(&context, &mut session, args) {
+                Ok(rsp) => rsp,
+                Err(Error::AuthError(_)) => Reply::Error("NOAUTH Authentication required.".to_owned()),
+                Err(Error::RateLimited(retry_after)) => Reply::Error(format!(
+                    "RATELIMITED retry in {}ms",
+                    retry_after.as_micros().div_ceil(1000)
+                )),
+                Err(Error::ReadOnly) => {
+                    Reply::Error("READONLY You can't write against a read only replica.".to_owned())
+                }
+                Err(Error::PermissionDenied) => Reply::Error(
+                    "NOPERM this user has no permissions to access one of the keys used as arguments"
+                        .to_owned(),
+                ),
+                Err(e) => {
+                    error!("resp error {:?}", e);
+                    Reply::err(e.to_string())
+                }
+            }
+        };
+        timer.engine();
+        context.record_request(matches!(rsp, Reply::Error(_)));
+        rsp.write_to(&mut writer)?;
+        writer.flush()?;
+        context.log_slow_request(&desc, timer.write());
+        if quit {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Reads either a multi-bulk request or an inline command.
+// Returns `None` once the client has closed the connection.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = match line.strip_prefix('*') {
+        Some(count) => parse_len(count)?,
+        None => return Ok(Some(line.split_whitespace().map(String::from).collect())),
+    };
+    if count > MAX_ARGS {
+        return Err(Error::StringError("too many arguments".to_owned()));
+    }
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        let len = match line.strip_prefix('$') {
+            Some(len) => parse_len(len)?,
+            None => return Err(Error::StringError(format!("expected '$', got '{}'", line))),
+        };
+        if len > MAX_BULK_LEN {
+            return Err(Error::StringError("invalid bulk length".to_owned()));
+        }
+        // grows with the bytes actually sent rather than the declared length
+        let mut buf = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut buf)?;
+        if buf.len() < len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !buf.ends_with(b"\r\n") {
+            return Err(Error::StringError("bulk string not terminated".to_owned()));
+        }
+        buf.truncate(len);
+        let arg = String::from_utf8(buf)
+            .map_err(|_| Error::StringError("argument is not valid UTF-8".to_owned()))?;
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.by_ref().take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        if line.len() as u64 == MAX_LINE_LEN {
+            return Err(Error::StringError("line too long".to_owned()));
+        }
+        return Err(unexpected_eof());
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_len(s: &str) -> Result<usize> {
+    s.parse()
+        .map_err(|_| Error::StringError(format!("invalid length '{}'", s)))
+}
+
+fn unexpected_eof() -> Error {
+    Error::IoError(io::ErrorKind::UnexpectedEof.into())
+}
+
+fn execute<T: KvsEngine>(
+    context: &Context<T>,
+    session: &mut Session,
+    args: Vec<String>,
+) -> Result<Reply> {
+    let mut args = args.into_iter();
+    let name = args.next().unwrap_or_default().to_ascii_lowercase();
+    let args: Vec<String> = args.collect();
+
+    let arity_ok = match name.as_str() {
+        "auth" => args.len() == 1 || args.len() == 2,
+        "ping" => args.len() <= 1,
+        "echo" | "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" | "scan" => !args.is_empty(),
+        "info" => args.len() <= 1,
+        "command" => true,
+        _ => return Ok(Reply::err(format!("unknown command '{}'", name))),
+    };
+    if !arity_ok {
+        return Ok(Reply::err(format!(
+            "wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+
+    // AUTH password | AUTH username password
+    if name == "auth" {
+        let mut args = args.into_iter();
+        let first = args.next().unwrap();
+        let credentials = match args.next() {
+            Some(password) => Credentials::Password {
+                user: first,
+                password,
+            },
+            None => Credentials::Token(first),
+        };
+        return match context.authenticate(session, &credentials) {
+            Ok(()) => Ok(Reply::ok()),
+            Err(Error::AuthError(_)) => Ok(Reply::Error(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_owned(),
+            )),
+            Err(e) => Err(e),
+        };
+    }
+    context.check_authenticated(session)?;
+    context.throttle(session, args.iter().map(String::len).sum())?;
+    let permission = match name.as_str() {
+        "set" | "del" => Some(Permission::Write),
+        "get" | "exists" | "mget" => Some(Permission::Read),
+        _ => None,
+    };
+    if let Some(permission) = permission {
+        let keys = if name == "set" { &args[..1] } else { &args[..] };
+        for key in keys {
+            context.authorize(session, permission, key)?;
+        }
+    }
+
+    let rsp = match name.as_str() {
+        "ping" => match args.into_iter().next() {
+            Some(msg) => Reply::Bulk(Some(msg)),
+            None => Reply::Simple("PONG".to_owned()),
+        },
+        "echo" => Reply::Bulk(args.into_iter().next()),
+        "get" => Reply::Bulk(context.get(args[0].clone())?),
+        "set" => set(context, args)?,
+        "del" => {
+            let mut removed = 0;
+            for key in args {
+                match context.remove(key) {
+                    Ok(()) => removed += 1,
+                    Err(Error::RecordNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        "exists" => {
+            let mut found = 0;
+            for key in args {
+                if context.get(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        "mget" => {
+            let mut values = Vec::with_capacity(args.len());
+            for key in args {
+                values.push(Reply::Bulk(context.get(key)?));
+            }
+            Reply::Array(values)
+        }
+        "scan" => scan(context, session, args)?,
+        "info" => info(context, args.first().map(String::as_str))?,
+        // Client libraries and redis-cli probe for command docs on connect
+        "command" => Reply::Array(Vec::new()),
+        _ => unreachable!(),
+    };
+    Ok(rsp)
+}
+
+// SET key value [EX seconds | PX milliseconds] [NX | XX]
+fn set<T: KvsEngine>(context: &Context<T>, args: Vec<String>) -> Result<Reply> {
+    let mut args = args.into_iter();
+    let key = args.next().unwrap();
+    let value = args.next().unwrap();
+
+    let mut opts = SetOptions::default();
+    while let Some(opt) = args.next() {
+        match opt.to_ascii_lowercase().as_str() {
+            "nx" => opts.only_if_absent = true,
+            "xx" => opts.only_if_present = true,
+            unit @ ("ex" | "px") if opts.expire.is_none() => {
+                let amount = match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) if n > 0 => n,
+                    _ => return Ok(Reply::err("invalid expire time in 'set' command")),
+                };
+                opts.expire = Some(match unit {
+                    "ex" => Duration::from_secs(amount),
+                    _ => Duration::from_millis(amount),
+                });
+            }
+            _ => return Ok(Reply::err("syntax error")),
+        }
+    }
+    if opts.only_if_absent && opts.only_if_present {
+        return Ok(Reply::err("syntax error"));
+    }
+
+    if context.set_with(key, value, opts)? == SetResult::Stored {
+        Ok(Reply::ok())
+    } else {
+        Ok(Reply::Bulk(None))
+    }
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count]
+//
+// The cursor is the position in the sorted list of matching keys.
+fn scan<T: KvsEngine>(context: &Context<T>, session: &Session, args: Vec<String>) -> Result<Reply> {
+    let mut args = args.into_iter();
+    let cursor = match args.next().and_then(|c| c.parse::<usize>().ok()) {
+        Some(cursor) => cursor,
+        None => return Ok(Reply::err("invalid cursor")),
+    };
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let Some(opt) = args.next() {
+        match (opt.to_ascii_lowercase().as_str(), args.next()) {
+            ("match", Some(p)) if p.len() > MAX_PATTERN_LEN => {
+                return Ok(Reply::err("pattern too long"))
+            }
+            ("match", Some(p)) => pattern = Some(p),
+            ("count", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::err("value is not an integer or out of range")),
+            },
+            _ => return Ok(Reply::err("syntax error")),
+        }
+    }
+
+    let prefix = pattern.as_deref().map(literal_prefix).unwrap_or_default();
+    let keys: Vec<String> = context
+        .scan(prefix)?
+        .into_iter()
+        .filter(|key| match &pattern {
+            Some(p) => glob_match(p.as_bytes(), key.as_bytes()),
+            None => true,
+        })
+        .filter(|key| context.allows(session, Permission::Read, key))
+        .collect();
+
+    let page: Vec<Reply> = keys
+        .iter()
+        .skip(cursor)
+        .take(count)
+        .map(|key| Reply::Bulk(Some(key.clone())))
+        .collect();
+    let next = if cursor + count >= keys.len() {
+        0
+    } else {
+        cursor + count
+    };
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string())),
+        Reply::Array(page),
+    ]))
+}
+
+fn info<T: KvsEngine>(context: &Context<T>, section: Option<&str>) -> Result<Reply> {
+    let section = section.unwrap_or("default").to_ascii_lowercase();
+    let all = matches!(section.as_str(), "default" | "all" | "everything");
+
+    let mut text = String::new();
+    if all || section == "server" {
+        text.push_str("# Server\r\n");
+        text.push_str(&format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+        text.push_str(&format!(
+            "uptime_in_seconds:{}\r\n",
+            context.uptime().as_secs()
+        ));
+        text.push_str("\r\n");
+    }
+    if all || section == "stats" {
+        let stats = context.stats()?;
+        text.push_str("# Stats\r\n");
+        for (name, value) in [
+            ("connected_clients", stats.connections_active),
+            ("total_connections_received", stats.connections_total),
+            ("rejected_connections", stats.connections_rejected),
+            ("timed_out_connections", stats.connections_timed_out),
+            ("total_commands_processed", stats.requests_total),
+        ] {
+            text.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        text.push_str("\r\n");
+    }
+    if all || section == "replication" {
+        let replication = context.replication_info();
+        text.push_str("# Replication\r\n");
+        match &replication.leader {
+            Some(leader) => {
+                let link = if replication.connected { "up" } else { "down" };
+                text.push_str("role:slave\r\n");
+                text.push_str(&format!("master_host:{}\r\n", leader));
+                text.push_str(&format!("master_link_status:{}\r\n", link));
+                text.push_str(&format!(
+                    "slave_repl_offset:{}\r\n",
+                    replication.applied_seq
+                ));
+                text.push_str(&format!(
+                    "slave_lag_entries:{}\r\n",
+                    replication.lag_entries
+                ));
+            }
+            None => text.push_str("role:master\r\n"),
+        }
+        text.push_str(&format!("connected_slaves:{}\r\n", replication.followers));
+        text.push_str(&format!("master_repl_offset:{}\r\n", replication.log_seq));
+        text.push_str("\r\n");
+    }
+    if all || section == "keyspace" {
+        text.push_str("# Keyspace\r\n");
+        text.push_str(&format!("keys:{}\r\n", context.key_count()?));
+        text.push_str("\r\n");
+    }
+    Ok(Reply::Bulk(Some(text)))
+}
+
+// Longest prefix of a glob pattern without special characters
+fn literal_prefix(pattern: &str) -> String {
+    pattern
+        .chars()
+        .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+        .collect()
+}
+
+// Glob-style matching as done by Redis, supporting `*`, `?`, `[...]` and `\` escapes.
+// Only the position after the last `*` is backtracked to, which a later `*`
+// can always stand in for, so matching takes at most the product of the lengths.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // pattern after the last `*` and the text it is tried against from
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // the `*` takes one more character
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Length of the element starting `pattern`, when it matches the character `c`
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    let (len, matched) = match pattern.split_first()? {
+        (b'?', _) => (1, true),
+        (b'[', rest) => match rest.iter().position(|&c| c == b']') {
+            Some(end) => {
+                let (negate, class) = match &rest[..end] {
+                    [b'^', class @ ..] => (true, class),
+                    class => (false, class),
+                };
+                let mut found = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == b'-' {
+                        found |= class[i] <= c && c <= class[i + 2];
+                        i += 3;
+                    } else {
+                        found |= class[i] == c;
+                        i += 1;
+                    }
+                }
+                (end + 2, found != negate)
+            }
+            None => (1, c == b'['),
+        },
+        (b'\\', [escaped, ..]) => (2, *escaped == c),
+        (&p, _) => (1, p == c),
+    };
+    matched.then_some(len)
+}
@@ -0,0 +1,146 @@
+//! Consistent hashing of keys onto nodes
+//!
+//! Every node is placed at many points of a ring of 64-bit hashes, its
+//! virtual nodes, and a key belongs to the node of the first point at or
+//! after the hash of the key. Adding or removing a node only moves the
+//! keys of the ranges next to its points. A node of weight 2 gets twice as
+//! many points, and so about twice as many keys.
+//!
+//! The hash is FNV-1a with a final mix, stable across builds and
+//! platforms, so that every proxy and client maps keys alike.
+
+use crate::err::Error;
+use crate::Result;
+
+/// Virtual nodes of each node on the ring
+pub const DEFAULT_VNODES: usize = 160;
+
+/// Ring of nodes, identified by their address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRing {
+    nodes: Vec<String>,
+    weights: Vec<u32>,
+    // points of the ring sorted by hash, with the index of their node
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// Ring of `nodes` with `DEFAULT_VNODES` virtual nodes each
+    pub fn new<I, S>(nodes: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::with_vnodes(nodes, DEFAULT_VNODES)
+    }
+
+    /// Ring of `nodes` with `vnodes` virtual nodes each
+    pub fn with_vnodes<I, S>(nodes: I, vnodes: usize) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::weighted_with_vnodes(nodes.into_iter().map(|node| (node, 1)), vnodes)
+    }
+
+    /// Ring of `nodes` with their weights, `DEFAULT_VNODES` virtual nodes
+    /// per unit of weight
+    pub fn weighted<I, S>(nodes: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, u32)>,
+        S: Into<String>,
+    {
+        Self::weighted_with_vnodes(nodes, DEFAULT_VNODES)
+    }
+
+    /// Ring of `nodes` with their weights, `vnodes` virtual nodes per unit of weight
+    pub fn weighted_with_vnodes<I, S>(nodes: I, vnodes: usize) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, u32)>,
+        S: Into<String>,
+    {
+        let (nodes, weights): (Vec<String>, Vec<u32>) = nodes
+            .into_iter()
+            .map(|(node, weight)| (node.into(), weight))
+            .unzip();
+        if nodes.is_empty() || vnodes == 0 {
+            return Err(Error::StringError("a ring needs nodes".to_owned()));
+        }
+        if (1..nodes.len()).any(|i| nodes[..i].contains(&nodes[i])) {
+            return Err(Error::StringError("nodes of a ring must differ".to_owned()));
+        }
+        if weights.contains(&0) {
+            return Err(Error::StringError("weights must not be 0".to_owned()));
+        }
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .zip(&weights)
+            .enumerate()
+            .flat_map(|(i, (node, &weight))| {
+                (0..vnodes * weight as usize).map(move |v| (hash(&format!("{}#{}", node, v)), i))
+            })
+            .collect();
+        points.sort_unstable();
+        Ok(HashRing {
+            nodes,
+            weights,
+            points,
+        })
+    }
+
+    /// Nodes of the ring, in the order given
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// Weight of the node at `index` in `nodes`
+    pub fn weight(&self, index: usize) -> u32 {
+        self.weights[index]
+    }
+
+    /// Index in `nodes` of the node `key` belongs to
+    pub fn index(&self, key: &str) -> usize {
+        self.points[self.position(key)].1
+    }
+
+    /// Indexes in `nodes` of every node, in the order they take `key`
+    /// over when the ones before fail, its own node first
+    pub fn indexes(&self, key: &str) -> Vec<usize> {
+        let start = self.position(key);
+        let mut indexes = Vec::with_capacity(self.nodes.len());
+        for offset in 0..self.points.len() {
+            let index = self.points[(start + offset) % self.points.len()].1;
+            if !indexes.contains(&index) {
+                indexes.push(index);
+                if indexes.len() == self.nodes.len() {
+                    break;
+                }
+            }
+        }
+        indexes
+    }
+
+    /// Node `key` belongs to
+    pub fn node(&self, key: &str) -> &str {
+        &self.nodes[self.index(key)]
+    }
+
+    // Position in `points` of the first point at or after the hash of `key`.
+    fn position(&self, key: &str) -> usize {
+        let hash = hash(key);
+        self.points.partition_point(|&(point, _)| point < hash) % self.points.len()
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // FNV alone spreads keys differing in their last bytes poorly
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash
+}
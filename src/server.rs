@@ -1,97 +1,747 @@
-use crate::common::{Request, Response, ResponseBody};
+use crate::auth::{Acl, Credentials, Permission};
+use crate::cluster::{self, Cluster, ClusterDriver, ClusterInfo};
+use crate::common::{write_line, Request, Response, ResponseBody};
+use crate::context::{ConnectionGuard, Context, Session};
 use crate::err::Error;
+use crate::metrics::Metrics;
+use crate::net::{Listener, Stream};
+use crate::ratelimit::{Quota, RateLimits};
+use crate::replication::{self, Leader, ReplicationInfo};
+use crate::shutdown::ShutdownHandle;
 use crate::thread_pool::ThreadPool;
-use crate::{err, KvsEngine};
+use crate::trace::{self, RequestLog, RequestTimer};
+use crate::tracking;
+use crate::{err, http, memcache, resp, KvsEngine};
 use err::Result;
-use log::{error, info};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span, error, info};
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+// Bounds the time the accepting thread spends telling a client it is busy.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Protocol spoken on a listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Native protocol used by `KvsClient`
+    Kvs,
+    /// Redis serialization protocol (RESP2)
+    Resp,
+    /// HTTP/JSON gateway
+    Http,
+    /// Memcached ASCII protocol
+    Memcache,
+    /// Prometheus metrics over HTTP, at `GET /metrics`
+    Metrics,
+}
+
+/// Limits on the connections of a server, all unlimited by default.
+///
+/// Timeouts must not be zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Connections beyond this number are refused with a busy reply
+    pub max_connections: Option<usize>,
+    /// How long a connection may wait between requests before it is closed
+    pub idle_timeout: Option<Duration>,
+    /// How long a read may block once a request has started arriving
+    pub read_timeout: Option<Duration>,
+    /// How long a write of a response may block
+    pub write_timeout: Option<Duration>,
+}
+
+/// What a server tells about itself, see `KvsClient::info`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Name of the engine
+    pub engine: String,
+    /// Version of the server
+    pub version: String,
+    /// Seconds since the server started
+    pub uptime_secs: u64,
+    /// Number of keys
+    pub keys: usize,
+    /// Size of the data on disk
+    pub disk_bytes: u64,
+    /// Part of the data on disk taken by stale entries
+    pub garbage_bytes: u64,
+    /// `garbage_bytes` over `disk_bytes`
+    pub garbage_ratio: f64,
+    /// Open connections
+    pub connections_active: u64,
+    /// Connections accepted since the start
+    pub connections_total: u64,
+    /// Connections refused because of the connection limit
+    pub connections_rejected: u64,
+    /// Connections closed by a timeout
+    pub connections_timed_out: u64,
+    /// Requests served
+    pub requests_total: u64,
+    /// Requests that failed
+    pub errors_total: u64,
+    /// Threads serving the connections, `None` if there is no fixed number
+    pub threads: Option<usize>,
+    /// Connections waiting for a thread
+    pub queued_jobs: u64,
+    /// Replication log, and progress of a follower
+    pub replication: ReplicationInfo,
+    /// Raft state of a cluster node, `None` outside cluster mode
+    pub cluster: Option<ClusterInfo>,
+}
+
+/// Settings replaced by `AdminCommand::ReloadConfig`
+#[derive(Debug, Default)]
+pub struct Reload {
+    /// ACL to check requests against, `None` lets every client do anything
+    pub acl: Option<Acl>,
+    /// Limits of the connections accepted from now on
+    pub limits: ConnectionLimits,
+    /// How requests are logged
+    pub request_log: RequestLog,
+    /// Rate limits of every client
+    pub rate_limits: RateLimits,
+    /// Storage quotas
+    pub quotas: Vec<Quota>,
+}
 
 /// KvsServer contains engine
 pub struct KvsServer<T: KvsEngine, P: ThreadPool> {
-    engine: T,
-    thread_pool: P,
+    context: Context<T>,
+    thread_pool: Arc<P>,
+    listeners: Vec<(Protocol, Listener)>,
+    shutdown_timeout: Duration,
+    leader: Option<Leader>,
+    cluster: Option<ClusterDriver>,
 }
 
-impl<T: KvsEngine, P: ThreadPool> KvsServer<T, P> {
+impl<T: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<T, P> {
     /// New KvsServer with engine
     pub fn new(engine: T, thread_pool: P) -> Self {
+        let mut context = Context::new(engine);
+        context.set_threads(thread_pool.threads());
         KvsServer {
-            engine,
-            thread_pool,
+            context,
+            thread_pool: Arc::new(thread_pool),
+            listeners: Vec::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            leader: None,
+            cluster: None,
         }
     }
 
+    /// Handle to stop the server once it runs, from another thread
+    /// or a signal handler.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.context.shutdown_handle().clone()
+    }
+
+    /// How long connections get to finish their requests on shutdown, 10 seconds by default.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Limit the number of connections and how long they may block.
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.context.set_limits(limits);
+    }
+
+    /// Set whether values are logged and which requests count as slow.
+    pub fn set_request_log(&mut self, request_log: RequestLog) {
+        self.context.set_request_log(request_log);
+    }
+
+    /// Limit the rate of the requests of each client, clients going faster
+    /// are told to retry later.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.context.set_rate_limits(limits);
+    }
+
+    /// Refuse writes that would take the keys under a prefix over its quota.
+    ///
+    /// Reads the data already stored under the prefixes.
+    pub fn set_quotas(&mut self, quotas: Vec<Quota>) -> Result<()> {
+        self.context.set_quotas(quotas)
+    }
+
+    /// Keep the last `capacity` writes in memory for followers to catch up from,
+    /// `DEFAULT_LOG_CAPACITY` by default. Followers further behind start over
+    /// from a snapshot.
+    pub fn set_replication_log(&mut self, capacity: usize) {
+        self.context.repl_log().set_capacity(capacity);
+    }
+
+    /// Follow the leader at `addr`: once running, apply the writes it streams
+    /// and refuse writes from clients.
+    ///
+    /// `credentials` are needed when the leader has an ACL, and must grant
+    /// the `admin` permission.
+    pub fn follow(&mut self, addr: impl Into<String>, credentials: Option<Credentials>) {
+        let addr = addr.into();
+        self.context.set_leader(addr.clone());
+        self.leader = Some(Leader { addr, credentials });
+    }
+
+    /// Join a Raft cluster: writes are committed through the Raft log, and
+    /// only the leader accepts them, see the `cluster` module.
+    ///
+    /// Loads the Raft state and binds the Raft address of this node,
+    /// which starts once the server runs.
+    pub fn set_cluster(&mut self, cluster: Cluster) -> Result<()> {
+        let (handle, driver) = cluster::open(cluster)?;
+        self.context.set_cluster(Arc::new(handle));
+        self.cluster = Some(driver);
+        Ok(())
+    }
+
+    /// Require clients to authenticate, and check their requests against `acl`.
+    ///
+    /// Memcached clients cannot authenticate, so they are refused while an ACL is set.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.context.set_acl(Some(acl));
+    }
+
+    /// Serve admin requests and followers without an ACL, off by default.
+    ///
+    /// With an ACL they need the `admin` permission whatever this is set to.
+    pub fn set_open_admin(&mut self, open: bool) {
+        self.context.set_open_admin(open);
+    }
+
+    /// Create the checkpoints asked for by `AdminCommand::Checkpoint` under `dir`,
+    /// their paths must be relative to it. Without it checkpoints are refused.
+    pub fn set_checkpoint_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.context.set_checkpoint_dir(dir.into());
+    }
+
+    /// Carry out `AdminCommand::ReloadConfig` with `reload`, which reads the new settings.
+    ///
+    /// Without it the server has no configuration to reload.
+    pub fn on_reload(&mut self, reload: impl Fn() -> Result<Reload> + Send + Sync + 'static) {
+        self.context.set_reload(Arc::new(reload));
+    }
+
+    /// Report metrics to `metrics` rather than to a registry of the server's own,
+    /// e.g. to share one between servers.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.context.set_metrics(metrics);
+    }
+
+    /// Metrics of the server, its engine and its thread pool
+    pub fn metrics(&self) -> Metrics {
+        self.context.metrics().clone()
+    }
+
+    /// Bind an extra listener speaking `protocol`.
+    ///
+    /// Connections are accepted once `run` is called, and are served
+    /// by the same engine and thread pool as the main listener.
+    pub fn listen<A: ToSocketAddrs>(&mut self, protocol: Protocol, addr: A) -> Result<()> {
+        self.add_listener(protocol, Listener::bind(addr)?);
+        Ok(())
+    }
+
+    /// Add an extra bound listener speaking `protocol`, such as a Unix domain socket.
+    pub fn add_listener(&mut self, protocol: Protocol, listener: Listener) {
+        self.listeners.push((protocol, listener));
+    }
+
     /// Run to listen the addr and process commands from client
     pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let engine = self.engine.clone();
-                    self.thread_pool.spawn(move || {
-                        if let Err(e) = handle(engine, stream) {
-                            error!("handle err {:?}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("connection err {}", e);
-                    return Err(Error::Unknown);
+        self.run_listener(Listener::bind(addr)?)
+    }
+
+    /// Run to accept connections of the native protocol on `listener`,
+    /// as well as on the extra listeners.
+    ///
+    /// Returns once the server has been shut down through a `ShutdownHandle`,
+    /// the thread pool is joined when the server is dropped.
+    pub fn run_listener(&mut self, listener: Listener) -> Result<()> {
+        let shutdown = self.shutdown_handle();
+        let mut listeners = vec![(Protocol::Kvs, listener)];
+        listeners.append(&mut self.listeners);
+        for (_, listener) in &listeners {
+            shutdown.register(listener.waker()?);
+        }
+
+        let mut listeners = listeners.into_iter();
+        let (_, main_listener) = listeners.next().unwrap();
+        let mut handles = vec![self.context.start_expiry()?];
+        for (protocol, listener) in listeners {
+            let context = self.context.clone();
+            let thread_pool = Arc::clone(&self.thread_pool);
+            handles.push(thread::spawn(move || {
+                if let Err(e) = serve(listener, protocol, context, thread_pool) {
+                    error!("{:?} listener err {:?}", protocol, e);
                 }
+            }));
+        }
+        if let Some(leader) = self.leader.clone() {
+            let context = self.context.clone();
+            handles.push(thread::spawn(move || replication::follow(context, leader)));
+        }
+        if let Some(driver) = self.cluster.take() {
+            handles.extend(driver.start(self.context.clone())?);
+        }
+        let res = serve(
+            main_listener,
+            Protocol::Kvs,
+            self.context.clone(),
+            Arc::clone(&self.thread_pool),
+        );
+        // Stop the other listeners and the follower too if the main listener failed.
+        shutdown.shutdown();
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        info!("shutting down, waiting for connections");
+        self.context.drain_connections(self.shutdown_timeout);
+        self.context.flush()?;
+        info!("engine flushed");
+        res
+    }
+}
+
+fn serve<T: KvsEngine, P: ThreadPool>(
+    listener: Listener,
+    protocol: Protocol,
+    context: Context<T>,
+    thread_pool: Arc<P>,
+) -> Result<()> {
+    let shutdown = context.shutdown_handle().clone();
+    loop {
+        let accepted = listener.accept();
+        if shutdown.is_shutdown() {
+            info!("{:?} listener {} closed", protocol, listener);
+            return Ok(());
+        }
+        match accepted {
+            Ok(stream) => {
+                // Counted here rather than in the pool, so that queued
+                // connections count against the limit too.
+                let connection = match context.connection(&stream) {
+                    Some(connection) => connection,
+                    None => {
+                        info!("{:?} connection rejected, too many connections", protocol);
+                        if let Err(e) = reject(protocol, &stream) {
+                            error!("reject err {:?}", e);
+                        }
+                        continue;
+                    }
+                };
+                let context = context.clone();
+                let metrics = context.metrics().clone();
+                metrics.add_queued_jobs(1);
+                let queued = Instant::now();
+                thread_pool.spawn(move || {
+                    metrics.add_queued_jobs(-1);
+                    let timer = RequestTimer::new(queued.elapsed());
+                    let span = debug_span!("conn", id = connection.id(), ?protocol);
+                    let _span = span.enter();
+                    if let Err(e) = set_timeouts(&context, &stream) {
+                        return log_result(&context, protocol, Err(e));
+                    }
+                    // the native protocol may hand the connection over to a stream
+                    let res = match protocol {
+                        Protocol::Kvs => handle(context.clone(), stream, timer, connection),
+                        Protocol::Resp => resp::handle(context.clone(), stream, timer),
+                        Protocol::Http => http::handle(context.clone(), stream, timer),
+                        Protocol::Memcache => memcache::handle(context.clone(), stream, timer),
+                        Protocol::Metrics => http::handle_metrics(context.clone(), stream, timer),
+                    };
+                    log_result(&context, protocol, res);
+                });
+            }
+            Err(e) => {
+                error!("connection err {}", e);
+                return Err(Error::Unknown);
             }
         }
-        Ok(())
     }
 }
 
-fn handle<T: KvsEngine>(engine: T, stream: TcpStream) -> Result<()> {
-    let reader = BufReader::new(&stream);
+fn log_result<T: KvsEngine>(context: &Context<T>, protocol: Protocol, res: Result<()>) {
+    match res {
+        Err(e) if is_timeout(&e) => {
+            info!("{:?} connection timed out", protocol);
+            context.record_timeout();
+        }
+        Err(e) => error!("handle err {:?}", e),
+        Ok(()) => {}
+    }
+}
+
+// Serves a connection turned into a stream with `f` on a thread of its own,
+// so that it does not hold a worker of the pool for as long as it is open.
+// It still counts against the connection limit until it closes.
+fn detach<T, F>(
+    context: &Context<T>,
+    stream: Stream,
+    connection: ConnectionGuard,
+    f: F,
+) -> Result<()>
+where
+    T: KvsEngine,
+    F: FnOnce(&mut BufWriter<&Stream>) -> Result<()> + Send + 'static,
+{
+    let context = context.clone();
+    thread::Builder::new()
+        .name(format!("kvs-stream-{}", connection.id()))
+        .spawn(move || {
+            let span = debug_span!("conn", id = connection.id(), protocol = "stream");
+            let _span = span.enter();
+            let mut writer = BufWriter::new(&stream);
+            let res = f(&mut writer);
+            log_result(&context, Protocol::Kvs, res);
+            drop(connection);
+        })?;
+    Ok(())
+}
+
+// Tells a client over the connection limit that the server is busy.
+pub(crate) fn reject(protocol: Protocol, stream: &Stream) -> Result<()> {
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    stream.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    let mut writer = BufWriter::new(stream);
+    match protocol {
+        Protocol::Kvs => serde_json::to_writer(
+            &mut writer,
+            &Response {
+                body: ResponseBody::Busy,
+            },
+        )?,
+        Protocol::Resp => write!(writer, "-ERR max number of clients reached\r\n")?,
+        Protocol::Http | Protocol::Metrics => {
+            let body = r#"{"error":"server busy"}"#;
+            write!(
+                writer,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        }
+        Protocol::Memcache => write!(writer, "SERVER_ERROR too many open connections\r\n")?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn set_timeouts<T: KvsEngine>(context: &Context<T>, stream: &Stream) -> Result<()> {
+    let limits = context.limits();
+    stream.set_read_timeout(limits.idle_timeout)?;
+    stream.set_write_timeout(limits.write_timeout)?;
+    Ok(())
+}
+
+/// Wait for the next request to start arriving, within the idle timeout,
+/// then switch to the read timeout for the rest of it.
+///
+/// Returns `false` once the client has closed the connection.
+pub(crate) fn next_request<T: KvsEngine, R: BufRead>(
+    context: &Context<T>,
+    stream: &Stream,
+    reader: &mut R,
+) -> Result<bool> {
+    wait_for_request(&context.limits(), stream, reader)
+}
+
+/// `next_request` with the limits of a server without a context
+pub(crate) fn wait_for_request<R: BufRead>(
+    limits: &ConnectionLimits,
+    stream: &Stream,
+    reader: &mut R,
+) -> Result<bool> {
+    if limits.idle_timeout == limits.read_timeout {
+        return Ok(!reader.fill_buf()?.is_empty());
+    }
+    stream.set_read_timeout(limits.idle_timeout)?;
+    let more = !reader.fill_buf()?.is_empty();
+    stream.set_read_timeout(limits.read_timeout)?;
+    Ok(more)
+}
+
+fn is_timeout(e: &Error) -> bool {
+    let kind = match e {
+        Error::IoError(e) => Some(e.kind()),
+        Error::JSONSerializeError(e) => e.io_error_kind(),
+        _ => None,
+    };
+    matches!(
+        kind,
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+    )
+}
+
+fn handle<T: KvsEngine>(
+    context: Context<T>,
+    stream: Stream,
+    mut timer: RequestTimer,
+    connection: ConnectionGuard,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
 
-    let req_iter = serde_json::Deserializer::from_reader(reader).into_iter::<Request>();
-    for req in req_iter {
-        let req = req?;
-        info!("rep {:?}", req);
-        let rsp = match req {
-            Request::Get { key } => match engine.get(key) {
-                Ok(val) => Response {
-                    body: ResponseBody::Ok(val),
-                },
+    let mut session = Session::new(stream.peer());
+    while next_request(&context, &stream, &mut reader)? {
+        timer.start();
+        let req =
+            match Request::deserialize(&mut serde_json::Deserializer::from_reader(&mut reader)) {
+                Ok(req) => req,
+                // trailing whitespace before the client closed the connection
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+        timer.read();
+        let desc = trace::describe_request(&req, context.request_log().redact_values);
+        let span = debug_span!("request", req = %desc);
+        let _span = span.enter();
+        debug!("req {}", desc);
+        if let Request::Replicate { log_id, after } = &req {
+            // the connection only streams the write log from now on
+            return match authorize(&context, &mut session, &req) {
+                Ok(()) => {
+                    info!("follower {} replicating after {}", stream.peer(), after);
+                    let (log_id, after) = (log_id.clone(), *after);
+                    drop((reader, writer));
+                    let follower = context.clone();
+                    detach(&context, stream, connection, move |writer| {
+                        replication::serve_follower(&follower, writer, log_id, after)
+                    })
+                }
                 Err(e) => {
-                    error!("get error {:?}", e);
-                    Response {
-                        body: ResponseBody::Err(format!("{:?}", e)),
-                    }
+                    let rsp = Response {
+                        body: ResponseBody::Err(e.to_string()),
+                    };
+                    write_line(&mut writer, &rsp)
+                }
+            };
+        }
+        if let Request::Track { prefixes } = &req {
+            // the connection only streams the keys written from now on
+            return match authorize(&context, &mut session, &req) {
+                Ok(()) => {
+                    debug!("client {} tracking {:?}", stream.peer(), prefixes);
+                    let prefixes = prefixes.clone();
+                    drop((reader, writer));
+                    let tracker = context.clone();
+                    detach(&context, stream, connection, move |writer| {
+                        tracking::serve_tracker(&tracker, &session, writer, &prefixes)
+                    })
+                }
+                Err(e) => {
+                    let rsp = Response {
+                        body: ResponseBody::Err(e.to_string()),
+                    };
+                    write_line(&mut writer, &rsp)
                 }
+            };
+        }
+        let (rsp, close) = respond(&context, &mut session, req);
+        timer.engine();
+        serde_json::to_writer(&mut writer, &rsp).unwrap();
+        writer.flush()?;
+        context.log_slow_request(&desc, timer.write());
+        if close {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve one request of the native protocol, returns the response and
+/// whether the connection has to be closed after sending it.
+pub(crate) fn respond<T: KvsEngine>(
+    context: &Context<T>,
+    session: &mut Session,
+    req: Request,
+) -> (Response, bool) {
+    if let Request::WithId { id, request } = req {
+        // a retry of a request served already is answered as it was then
+        if authorize(context, session, &request).is_ok() {
+            if let Some(body) = context.replayed(&id) {
+                debug!("replayed response to {}/{}", id.client, id.seq);
+                return (Response { body }, false);
+            }
+        }
+        let (rsp, close) = respond(context, session, *request);
+        // the request was not served and has to be retried anyway
+        let served = !matches!(
+            rsp.body,
+            ResponseBody::Busy | ResponseBody::RateLimited { .. } | ResponseBody::NotLeader { .. }
+        );
+        if served && !close {
+            context.remember(id, rsp.body.clone());
+        }
+        return (rsp, close);
+    }
+    let authorized = authorize(context, session, &req).and_then(|()| match &req {
+        Request::Auth { .. } | Request::Ping => Ok(()),
+        req => context.throttle(session, request_bytes(req)),
+    });
+    // A client failing to authenticate is disconnected.
+    let close = matches!(authorized, Err(Error::AuthError(_)));
+    let rsp = match authorized.map(|()| req) {
+        Err(Error::RateLimited(retry_after)) => Response {
+            body: ResponseBody::RateLimited {
+                // rounded up, so that retrying on time succeeds
+                retry_after_ms: retry_after.as_micros().div_ceil(1000) as u64,
             },
-            Request::Set { key, value } => match engine.set(key, value) {
-                Ok(()) => Response {
-                    body: ResponseBody::Ok(None),
+        },
+        Err(e) => {
+            error!("auth error {:?}", e);
+            Response {
+                body: ResponseBody::Err(e.to_string()),
+            }
+        }
+        Ok(Request::Auth { .. } | Request::Ping) => Response {
+            body: ResponseBody::Ok(None),
+        },
+        Ok(Request::Admin(cmd)) => match context.admin(cmd) {
+            Ok(msg) => Response {
+                body: ResponseBody::Ok(msg),
+            },
+            Err(e) => {
+                error!("admin error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(e.to_string()),
+                }
+            }
+        },
+        Ok(Request::Info) => match context.info() {
+            Ok(info) => Response {
+                body: ResponseBody::Info(Box::new(info)),
+            },
+            Err(e) => {
+                error!("info error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(e.to_string()),
+                }
+            }
+        },
+        Ok(Request::Get { key }) => match context.get(key) {
+            Ok(val) => Response {
+                body: ResponseBody::Ok(val),
+            },
+            Err(e) => {
+                error!("get error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(format!("{:?}", e)),
+                }
+            }
+        },
+        Ok(Request::GetMany { keys }) => {
+            match keys.into_iter().map(|key| context.get(key)).collect() {
+                Ok(values) => Response {
+                    body: ResponseBody::Values(values),
                 },
                 Err(e) => {
-                    error!("set error {:?}", e);
+                    error!("get error {:?}", e);
                     Response {
                         body: ResponseBody::Err(format!("{:?}", e)),
                     }
                 }
+            }
+        }
+        Ok(Request::Scan { prefix }) => match context.scan(prefix) {
+            Ok(mut keys) => {
+                keys.retain(|key| context.allows(session, Permission::Read, key));
+                keys.sort_unstable();
+                Response {
+                    body: ResponseBody::Keys(keys),
+                }
+            }
+            Err(e) => {
+                error!("scan error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(e.to_string()),
+                }
+            }
+        },
+        Ok(Request::Set { key, value }) => match context.set(key, value) {
+            Ok(()) => Response {
+                body: ResponseBody::Ok(None),
             },
-            Request::Remove { key } => match engine.remove(key) {
-                Ok(()) => Response {
-                    body: ResponseBody::Ok(None),
-                },
-                Err(e) => {
-                    error!("rm error {:?}", e);
-                    Response {
-                        body: ResponseBody::Err(e.to_string()),
-                    }
+            Err(Error::NotLeader(leader)) => Response {
+                body: ResponseBody::NotLeader { leader },
+            },
+            Err(e) => {
+                error!("set error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(format!("{:?}", e)),
                 }
+            }
+        },
+        // taken apart above
+        Ok(Request::WithId { .. }) => unreachable!(),
+        // served by `handle` on a connection of its own
+        Ok(Request::Replicate { .. }) => Response {
+            body: ResponseBody::Err("replication is not served here".to_owned()),
+        },
+        Ok(Request::Track { .. }) => Response {
+            body: ResponseBody::Err("tracking is not served here".to_owned()),
+        },
+        Ok(Request::Remove { key }) => match context.remove(key) {
+            Ok(()) => Response {
+                body: ResponseBody::Ok(None),
             },
-        };
-        info!("rsp {:?}", rsp);
-        serde_json::to_writer(&mut writer, &rsp).unwrap();
-        writer.flush()?;
+            Err(Error::NotLeader(leader)) => Response {
+                body: ResponseBody::NotLeader { leader },
+            },
+            Err(e) => {
+                error!("rm error {:?}", e);
+                Response {
+                    body: ResponseBody::Err(e.to_string()),
+                }
+            }
+        },
+    };
+    context.record_request(matches!(rsp.body, ResponseBody::Err(_)));
+    let redact = context.request_log().redact_values;
+    debug!("rsp {}", trace::describe_response(&rsp.body, redact));
+    (rsp, close)
+}
+
+// Bytes of keys and values in a request, for the rate limits.
+fn request_bytes(req: &Request) -> usize {
+    match req {
+        Request::Get { key } | Request::Remove { key } => key.len(),
+        Request::Set { key, value } => key.len() + value.len(),
+        Request::GetMany { keys } => keys.iter().map(String::len).sum(),
+        Request::Scan { prefix } => prefix.len(),
+        Request::WithId { request, .. } => request_bytes(request),
+        _ => 0,
     }
+}
 
-    Ok(())
+fn authorize<T: KvsEngine>(
+    context: &Context<T>,
+    session: &mut Session,
+    req: &Request,
+) -> Result<()> {
+    match req {
+        Request::Auth { credentials } => context.authenticate(session, credentials),
+        Request::Ping => Ok(()),
+        // keys the principal may not read are left out
+        Request::Info | Request::Scan { .. } | Request::Track { .. } => {
+            context.check_authenticated(session)
+        }
+        // admin on every key
+        Request::Admin(_) | Request::Replicate { .. } => context.authorize_admin(session),
+        Request::Get { key } => context.authorize(session, Permission::Read, key),
+        Request::GetMany { keys } => keys
+            .iter()
+            .try_for_each(|key| context.authorize(session, Permission::Read, key)),
+        Request::Set { key, .. } | Request::Remove { key } => {
+            context.authorize(session, Permission::Write, key)
+        }
+        Request::WithId { request, .. } => authorize(context, session, request),
+    }
 }
@@ -0,0 +1,45 @@
+//! Graceful shutdown of a running server
+
+use crate::net::Waker;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// ShutdownHandle stops the server it was taken from.
+///
+/// Listeners stop accepting connections, requests being served get
+/// until the shutdown timeout to finish, then the engine is flushed
+/// and `KvsServer::run` returns.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to shut down, returns without waiting for it.
+    pub fn shutdown(&self) {
+        if !self.inner.requested.swap(true, Ordering::SeqCst) {
+            for waker in self.inner.wakers.lock().unwrap().iter() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Whether shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register(&self, waker: Waker) {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        if self.is_shutdown() {
+            waker.wake();
+        }
+        wakers.push(waker);
+    }
+}
@@ -21,4 +21,9 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// number of threads, `None` if there is no fixed number
+    fn threads(&self) -> Option<usize> {
+        None
+    }
 }
@@ -26,4 +26,8 @@ impl ThreadPool for RayonThreadPool {
     {
         self.pool.spawn(job);
     }
+
+    fn threads(&self) -> Option<usize> {
+        Some(self.pool.current_num_threads())
+    }
 }
@@ -1,6 +1,6 @@
 use crate::err::Result;
 use crate::thread_pool::ThreadPool;
-use log::{error, info};
+use log::{debug, error};
 use std::panic::AssertUnwindSafe;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
@@ -40,11 +40,16 @@ impl ThreadPool for SharedQueueThreadPool {
         let job = Box::new(job);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
+
+    fn threads(&self) -> Option<usize> {
+        Some(self.workers.len())
+    }
 }
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
         drop(self.sender.take());
         for worker in &mut self.workers {
+            debug!("shutting down worker-{}", worker.id);
             if let Some(handle) = worker.thread.take() {
                 handle.join().unwrap();
             }
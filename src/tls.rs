@@ -0,0 +1,97 @@
+//! TLS configuration from PEM files
+//!
+//! Server and client configs are built with the `ring` crypto provider.
+//! A server given a client CA requires clients to present a certificate
+//! signed by it (mutual TLS).
+
+use crate::err::Error;
+use crate::Result;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::path::Path;
+use std::sync::Arc;
+
+pub use rustls::{ClientConfig as TlsClientConfig, ServerConfig as TlsServerConfig};
+
+/// Build a server config from a certificate chain and its private key.
+///
+/// When `client_ca` is given, clients must authenticate with a
+/// certificate issued by one of its CAs.
+pub fn server_config(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(ca) => {
+            let roots = Arc::new(load_roots(ca)?);
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                .build()
+                .map_err(|e| Error::StringError(format!("client verifier: {}", e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+    Ok(Arc::new(config))
+}
+
+/// Build a client config trusting the CAs in `ca`.
+///
+/// `identity` is the certificate chain and private key presented to
+/// servers requiring client authentication.
+pub fn client_config(
+    ca: impl AsRef<Path>,
+    identity: Option<(&Path, &Path)>,
+) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca.as_ref())?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Parse the name the server certificate is checked against,
+/// a DNS name or an IP address.
+pub fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned())
+        .map_err(|_| Error::StringError(format!("invalid server name {}", name)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(Error::StringError(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> Error {
+    Error::StringError(format!("read {}: {}", path.display(), e))
+}
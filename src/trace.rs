@@ -0,0 +1,192 @@
+//! Request tracing and the slow-request log
+//!
+//! Connections and requests run in `tracing` spans. Without a `tracing`
+//! subscriber, spans and events go to the `log` facade, so `kvs-server`
+//! prints them with its logger.
+//! Values are hidden from the logs unless `RequestLog::redact_values` is off.
+
+use crate::common::{Request, ResponseBody};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How requests are logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLog {
+    /// Log values as their length only, on by default
+    pub redact_values: bool,
+    /// Log requests taking longer than this at `warn`, with their timing breakdown
+    pub slow_threshold: Option<Duration>,
+}
+
+impl Default for RequestLog {
+    fn default() -> Self {
+        RequestLog {
+            redact_values: true,
+            slow_threshold: None,
+        }
+    }
+}
+
+/// A value in a log line
+pub(crate) struct Value<'a> {
+    value: &'a str,
+    redact: bool,
+}
+
+impl<'a> Value<'a> {
+    pub(crate) fn new(value: &'a str, redact: bool) -> Self {
+        Value { value, redact }
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.redact {
+            write!(f, "<{} bytes>", self.value.len())
+        } else {
+            write!(f, "{:?}", self.value)
+        }
+    }
+}
+
+/// A request of the native protocol for the logs
+pub(crate) fn describe_request(req: &Request, redact: bool) -> String {
+    match req {
+        Request::Get { key } => format!("get {:?}", key),
+        Request::Set { key, value } => format!("set {:?} {}", key, Value::new(value, redact)),
+        Request::Remove { key } => format!("rm {:?}", key),
+        Request::GetMany { keys } => format!("get {:?}", keys),
+        Request::Scan { prefix } => format!("scan {:?}", prefix),
+        Request::Auth { credentials } => format!("auth {:?}", credentials),
+        Request::Info => "info".to_owned(),
+        Request::Ping => "ping".to_owned(),
+        Request::Admin(cmd) => format!("admin {:?}", cmd),
+        Request::Replicate { after, .. } => format!("replicate after {}", after),
+        Request::Track { prefixes } => format!("track {:?}", prefixes),
+        Request::WithId { id, request } => format!(
+            "{} id {}/{}",
+            describe_request(request, redact),
+            id.client,
+            id.seq
+        ),
+    }
+}
+
+/// A response of the native protocol for the logs
+pub(crate) fn describe_response(body: &ResponseBody, redact: bool) -> String {
+    match body {
+        ResponseBody::Ok(Some(val)) => format!("ok {}", Value::new(val, redact)),
+        ResponseBody::Ok(None) => "ok".to_owned(),
+        ResponseBody::Err(e) => format!("err {}", e),
+        ResponseBody::Busy => "busy".to_owned(),
+        ResponseBody::RateLimited { retry_after_ms } => {
+            format!("rate limited for {}ms", retry_after_ms)
+        }
+        ResponseBody::Info(_) => "info".to_owned(),
+        ResponseBody::Values(values) => format!("{} values", values.len()),
+        ResponseBody::Keys(keys) => format!("{} keys", keys.len()),
+        ResponseBody::NotLeader { leader } => format!("not leader, leader {:?}", leader),
+    }
+}
+
+/// A command made of arguments for the logs, the first one being the name
+/// and the second one the key, the others are hidden as values.
+/// The arguments of `AUTH` are always hidden.
+pub(crate) fn describe_command<S: AsRef<str>>(args: &[S], redact: bool) -> String {
+    let mut line = String::new();
+    for (i, arg) in args.iter().enumerate() {
+        let arg = arg.as_ref();
+        if i > 0 {
+            line.push(' ');
+        }
+        let secret = i > 0 && args[0].as_ref().eq_ignore_ascii_case("auth");
+        if secret || (i > 1 && redact) {
+            line.push_str(&Value::new(arg, true).to_string());
+        } else {
+            line.push_str(arg);
+        }
+    }
+    line
+}
+
+/// Where the time of a request went
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Timings {
+    /// Waiting for a thread, only the first request of a connection waits
+    pub queue: Duration,
+    /// Reading the request once it started arriving
+    pub read: Duration,
+    /// Carrying it out
+    pub engine: Duration,
+    /// Writing the response
+    pub write: Duration,
+}
+
+impl Timings {
+    pub(crate) fn total(&self) -> Duration {
+        self.queue + self.read + self.engine + self.write
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (queue {:?}, read {:?}, engine {:?}, write {:?})",
+            self.total(),
+            self.queue,
+            self.read,
+            self.engine,
+            self.write
+        )
+    }
+}
+
+/// Times the phases of the requests of a connection, one after the other.
+pub(crate) struct RequestTimer {
+    timings: Timings,
+    phase: Instant,
+}
+
+impl RequestTimer {
+    /// Timer of a connection that waited `queue` for a thread
+    pub(crate) fn new(queue: Duration) -> Self {
+        RequestTimer {
+            timings: Timings {
+                queue,
+                ..Timings::default()
+            },
+            phase: Instant::now(),
+        }
+    }
+
+    /// A request starts arriving.
+    pub(crate) fn start(&mut self) {
+        self.phase = Instant::now();
+    }
+
+    /// The request has been read.
+    pub(crate) fn read(&mut self) {
+        self.timings.read = self.lap();
+    }
+
+    /// The request has been carried out.
+    pub(crate) fn engine(&mut self) {
+        self.timings.engine = self.lap();
+    }
+
+    /// The response has been written, returns the timings of the request.
+    pub(crate) fn write(&mut self) -> Timings {
+        self.timings.write = self.lap();
+        let timings = self.timings;
+        self.timings = Timings::default();
+        timings
+    }
+
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.phase;
+        self.phase = now;
+        elapsed
+    }
+}
@@ -0,0 +1,360 @@
+//! Client-side caching of values, invalidated by the server
+//!
+//! A client with a near cache keeps the values it got in a bounded LRU,
+//! and opens a second connection sending `Request::Track`. The server
+//! streams on it the keys written from then on, expired ones included,
+//! which the client drops from its cache, as Redis does in its
+//! broadcasting tracking mode.
+//!
+//! Values are only cached while the tracking connection is up. When it
+//! breaks the cache is emptied, as writes may have been missed, and the
+//! client connects it again in the background.
+
+use crate::auth::{Credentials, Permission};
+use crate::client::Connector;
+use crate::common::{write_line, Request, Response, ResponseBody};
+use crate::context::{Context, Session};
+use crate::err::Error;
+use crate::net::{Socket, Stream};
+use crate::{KvsEngine, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::de::IoRead;
+use serde_json::Deserializer;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Duration;
+
+// A server with no write to tell sends a heartbeat this often.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+// A client hearing nothing for this long reconnects.
+const TRACKING_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+type Reader = Deserializer<IoRead<BufReader<Stream>>>;
+
+/// Message of the tracking stream, after the response to `Request::Track`
+#[derive(Serialize, Deserialize, Debug)]
+enum TrackMessage {
+    /// Keys written since the last message
+    Keys(Vec<String>),
+    /// Writes were missed, every key may have changed
+    FlushAll,
+    /// Nothing was written
+    Heartbeat,
+}
+
+/// Stream the keys written with one of `prefixes` to a tracking client,
+/// until it disconnects or the server shuts down. Empty `prefixes` track
+/// every key, the keys the client may not read are left out.
+pub(crate) fn serve_tracker<T: KvsEngine, W: Write>(
+    context: &Context<T>,
+    session: &Session,
+    writer: &mut W,
+    prefixes: &[String],
+) -> Result<()> {
+    let log = context.repl_log();
+    let mut seq = log.last_seq();
+    write_line(
+        writer,
+        &Response {
+            body: ResponseBody::Ok(None),
+        },
+    )?;
+
+    let shutdown = context.shutdown_handle();
+    while !shutdown.is_shutdown() {
+        let message = match log.read(seq, HEARTBEAT) {
+            None => {
+                seq = log.last_seq();
+                TrackMessage::FlushAll
+            }
+            Some(ops) if ops.is_empty() => TrackMessage::Heartbeat,
+            Some(ops) => {
+                seq += ops.len() as u64;
+                let mut keys: Vec<String> = ops
+                    .iter()
+                    .map(|op| op.key())
+                    .filter(|key| {
+                        prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p.as_str()))
+                    })
+                    .filter(|key| context.allows(session, Permission::Read, key))
+                    .map(str::to_owned)
+                    .collect();
+                if keys.is_empty() {
+                    continue;
+                }
+                keys.sort_unstable();
+                keys.dedup();
+                TrackMessage::Keys(keys)
+            }
+        };
+        write_line(writer, &message)?;
+    }
+    Ok(())
+}
+
+/// Counters of a near cache, see `KvsClient::cache_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NearCacheStats {
+    /// Values in the cache
+    pub entries: usize,
+    /// Gets answered from the cache
+    pub hits: u64,
+    /// Gets sent to the server
+    pub misses: u64,
+    /// Keys dropped because the server wrote them, or every key when writes were missed
+    pub invalidations: u64,
+    /// Whether the tracking connection is up, values are cached only then
+    pub tracking: bool,
+}
+
+/// Outcome of looking a key up in a `NearCache`
+pub(crate) enum Lookup {
+    Hit(Option<String>),
+    // with the epoch to cache the value got from the server at, if it may be cached
+    Miss(Option<u64>),
+}
+
+/// Cache of the values got by a client
+pub(crate) struct NearCache {
+    state: Arc<Mutex<CacheState>>,
+    prefixes: Vec<String>,
+}
+
+struct CacheState {
+    // value, missing keys included, and when it was last used
+    entries: HashMap<String, (Option<String>, u64)>,
+    // keys by last use, for eviction
+    used: BTreeMap<u64, String>,
+    clock: u64,
+    capacity: usize,
+    // changes whenever keys are invalidated, so that a value got before
+    // then is not cached after
+    epoch: u64,
+    tracking: bool,
+    // of the tracking connection, shut down when the cache is dropped
+    socket: Option<Socket>,
+    hits: u64,
+    misses: u64,
+    invalidations: u64,
+}
+
+impl NearCache {
+    /// Track the keys with one of `prefixes`, or every key, on a
+    /// connection made with `connector`, and cache up to `capacity` values.
+    pub(crate) fn start(
+        connector: Connector,
+        credentials: Option<Credentials>,
+        capacity: usize,
+        prefixes: Vec<String>,
+    ) -> Result<Self> {
+        let state = Arc::new(Mutex::new(CacheState {
+            entries: HashMap::new(),
+            used: BTreeMap::new(),
+            clock: 0,
+            capacity,
+            epoch: 0,
+            tracking: false,
+            socket: None,
+            hits: 0,
+            misses: 0,
+            invalidations: 0,
+        }));
+        let tracker = Tracker {
+            connector,
+            credentials,
+            prefixes: prefixes.clone(),
+            state: Arc::downgrade(&state),
+        };
+        // the first connection fails the client, later ones are retried
+        let reader = tracker.connect()?;
+        thread::spawn(move || tracker.run(reader));
+        Ok(NearCache { state, prefixes })
+    }
+
+    /// Value of `key` if cached, or the epoch to pass to `insert` with the
+    /// value got from the server.
+    pub(crate) fn get(&self, key: &str) -> Lookup {
+        let mut state = self.lock();
+        let state = &mut *state;
+        state.clock += 1;
+        match state.entries.get_mut(key) {
+            Some((value, used)) => {
+                state.used.remove(used);
+                *used = state.clock;
+                state.used.insert(state.clock, key.to_owned());
+                state.hits += 1;
+                Lookup::Hit(value.clone())
+            }
+            None => {
+                state.misses += 1;
+                let cacheable = state.tracking && self.is_tracked(key);
+                Lookup::Miss(cacheable.then_some(state.epoch))
+            }
+        }
+    }
+
+    /// Cache the value of `key` got from the server, unless keys were
+    /// invalidated since `epoch`.
+    pub(crate) fn insert(&self, key: String, value: Option<String>, epoch: u64) {
+        let mut state = self.lock();
+        if !state.tracking || state.epoch != epoch || state.capacity == 0 {
+            return;
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some((_, used)) = state.entries.insert(key.clone(), (value, clock)) {
+            state.used.remove(&used);
+        }
+        state.used.insert(clock, key);
+        while state.entries.len() > state.capacity {
+            if let Some((_, oldest)) = state.used.pop_first() {
+                state.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drop `key`, written by this client.
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.lock().invalidate(key);
+    }
+
+    pub(crate) fn stats(&self) -> NearCacheStats {
+        let state = self.lock();
+        NearCacheStats {
+            entries: state.entries.len(),
+            hits: state.hits,
+            misses: state.misses,
+            invalidations: state.invalidations,
+            tracking: state.tracking,
+        }
+    }
+
+    fn is_tracked(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str()))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for NearCache {
+    fn drop(&mut self) {
+        if let Some(socket) = self.lock().socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl CacheState {
+    fn invalidate(&mut self, key: &str) {
+        self.epoch += 1;
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.used.remove(&used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.epoch += 1;
+        self.entries.clear();
+        self.used.clear();
+    }
+}
+
+// Background side of a near cache, reading the tracking connection.
+struct Tracker {
+    connector: Connector,
+    credentials: Option<Credentials>,
+    prefixes: Vec<String>,
+    state: Weak<Mutex<CacheState>>,
+}
+
+impl Tracker {
+    // Until the cache is dropped.
+    fn run(self, mut reader: Reader) {
+        loop {
+            if let Err(e) = self.track(&mut reader) {
+                warn!("tracking connection lost: {}", e);
+            }
+            match self.state.upgrade() {
+                Some(state) => {
+                    let mut state = state.lock().unwrap();
+                    state.tracking = false;
+                    state.socket = None;
+                    state.clear();
+                }
+                None => return,
+            }
+            reader = loop {
+                thread::sleep(RETRY_DELAY);
+                if self.state.strong_count() == 0 {
+                    return;
+                }
+                match self.connect() {
+                    Ok(reader) => break reader,
+                    Err(e) => warn!("tracking reconnect failed: {}", e),
+                }
+            };
+            info!("tracking connection restored");
+        }
+    }
+
+    // Open the tracking connection, the cache is used from then on.
+    fn connect(&self) -> Result<Reader> {
+        let stream = self.connector.connect()?;
+        stream.set_read_timeout(Some(TRACKING_TIMEOUT))?;
+        let mut reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+        let mut writer = BufWriter::new(&stream);
+        if let Some(credentials) = &self.credentials {
+            let credentials = credentials.clone();
+            write_line(&mut writer, &Request::Auth { credentials })?;
+            match Response::deserialize(&mut reader)?.body {
+                ResponseBody::Ok(_) => {}
+                ResponseBody::Err(e) => return Err(Error::AuthError(e)),
+                body => return Err(body.unexpected()),
+            }
+        }
+        let prefixes = self.prefixes.clone();
+        write_line(&mut writer, &Request::Track { prefixes })?;
+        match Response::deserialize(&mut reader)?.body {
+            ResponseBody::Ok(_) => {}
+            ResponseBody::Err(e) => return Err(Error::ServerError(e)),
+            body => return Err(body.unexpected()),
+        }
+        drop(writer);
+        if let Some(state) = self.state.upgrade() {
+            let mut state = state.lock().unwrap();
+            state.tracking = true;
+            state.socket = Some(stream.socket()?);
+        }
+        Ok(reader)
+    }
+
+    fn track(&self, reader: &mut Reader) -> Result<()> {
+        loop {
+            let message = TrackMessage::deserialize(&mut *reader)?;
+            let Some(state) = self.state.upgrade() else {
+                return Ok(());
+            };
+            let mut state = state.lock().unwrap();
+            match message {
+                TrackMessage::Keys(keys) => {
+                    for key in keys {
+                        state.invalidate(&key);
+                        state.invalidations += 1;
+                    }
+                }
+                TrackMessage::FlushAll => {
+                    state.clear();
+                    state.invalidations += 1;
+                }
+                TrackMessage::Heartbeat => {}
+            }
+        }
+    }
+}
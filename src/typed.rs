@@ -0,0 +1,193 @@
+//! Typed values over the string values of the store
+//!
+//! `TypedKvsClient` and `TypedKvsEngine` take and return values of any
+//! serde type, encoded to strings with a `Codec`. JSON is stored as is,
+//! the binary codecs as base64, so that every codec works with every
+//! server and engine.
+//!
+//! The binary codecs come with the `bincode` and `msgpack` features.
+
+use crate::{KvsClient, KvsEngine, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+#[cfg(any(feature = "bincode", feature = "msgpack"))]
+use {crate::err::Error, crate::http::base64_decode};
+
+/// Encoding of typed values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON text, readable by other clients
+    #[default]
+    Json,
+    /// bincode, in base64
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// MessagePack, in base64
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    /// Encode `value` to a value of the store
+    pub fn encode<V: Serialize + ?Sized>(&self, value: &V) -> Result<String> {
+        match self {
+            Codec::Json => Ok(serde_json::to_string(value)?),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serialize(value)
+                .map(|bytes| base64_encode(&bytes))
+                .map_err(|e| Error::CodecError(e.to_string())),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec(value)
+                .map(|bytes| base64_encode(&bytes))
+                .map_err(|e| Error::CodecError(e.to_string())),
+        }
+    }
+
+    /// Decode a value of the store encoded by `encode`
+    pub fn decode<V: DeserializeOwned>(&self, value: &str) -> Result<V> {
+        match self {
+            Codec::Json => Ok(serde_json::from_str(value)?),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::deserialize(&decode_base64(value)?)
+                .map_err(|e| Error::CodecError(e.to_string())),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_slice(&decode_base64(value)?)
+                .map_err(|e| Error::CodecError(e.to_string())),
+        }
+    }
+}
+
+/// TypedKvsClient sends values of type `V` to a server
+pub struct TypedKvsClient<V> {
+    client: KvsClient,
+    codec: Codec,
+    values: PhantomData<fn() -> V>,
+}
+
+impl<V: Serialize + DeserializeOwned> TypedKvsClient<V> {
+    /// New a typed client over `client`, encoding values with `codec`
+    pub fn new(client: KvsClient, codec: Codec) -> Self {
+        TypedKvsClient {
+            client,
+            codec,
+            values: PhantomData,
+        }
+    }
+
+    /// Get the value of `key`, an error if it does not decode to a `V`
+    pub fn get(&mut self, key: String) -> Result<Option<V>> {
+        match self.client.get(key)? {
+            Some(value) => Ok(Some(self.codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set `key` to `value`
+    pub fn set(&mut self, key: String, value: &V) -> Result<()> {
+        let value = self.codec.encode(value)?;
+        self.client.set(key, value)
+    }
+
+    /// Remove `key`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client.remove(key)
+    }
+
+    /// Get the values of several keys at once, in the order of `keys`
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<V>>> {
+        self.client
+            .get_many(keys)?
+            .into_iter()
+            .map(|value| value.map(|value| self.codec.decode(&value)).transpose())
+            .collect()
+    }
+
+    /// The untyped client, for the other requests
+    pub fn client_mut(&mut self) -> &mut KvsClient {
+        &mut self.client
+    }
+
+    /// Take the untyped client back
+    pub fn into_inner(self) -> KvsClient {
+        self.client
+    }
+}
+
+/// TypedKvsEngine stores values of type `V` in an engine
+pub struct TypedKvsEngine<E, V> {
+    engine: E,
+    codec: Codec,
+    values: PhantomData<fn() -> V>,
+}
+
+impl<E: KvsEngine, V: Serialize + DeserializeOwned> TypedKvsEngine<E, V> {
+    /// New a typed engine over `engine`, encoding values with `codec`
+    pub fn new(engine: E, codec: Codec) -> Self {
+        TypedKvsEngine {
+            engine,
+            codec,
+            values: PhantomData,
+        }
+    }
+
+    /// Get the value of `key`, an error if it does not decode to a `V`
+    pub fn get(&self, key: String) -> Result<Option<V>> {
+        match self.engine.get(key)? {
+            Some(value) => Ok(Some(self.codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set `key` to `value`
+    pub fn set(&self, key: String, value: &V) -> Result<()> {
+        self.engine.set(key, self.codec.encode(value)?)
+    }
+
+    /// Remove `key`
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    /// The untyped engine
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+}
+
+// Clones share the engine, whatever `V` is.
+impl<E: KvsEngine, V> Clone for TypedKvsEngine<E, V> {
+    fn clone(&self) -> Self {
+        TypedKvsEngine {
+            engine: self.engine.clone(),
+            codec: self.codec,
+            values: PhantomData,
+        }
+    }
+}
+
+#[cfg(any(feature = "bincode", feature = "msgpack"))]
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    base64_decode(value).ok_or_else(|| Error::CodecError("invalid base64".to_owned()))
+}
+
+// Standard alphabet, padded.
+#[cfg(any(feature = "bincode", feature = "msgpack"))]
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let buf = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |buf, (i, &b)| buf | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(buf >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
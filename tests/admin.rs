@@ -0,0 +1,247 @@
+use assert_cmd::prelude::*;
+use kvs::auth::Credentials;
+use kvs::{AdminCommand, ConnectionLimits, Error, KvStore, KvsClient, KvsEngine, Reload, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, Process};
+
+#[test]
+fn compact_and_flush() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4220", |server| server.set_open_admin(true));
+    let mut client = KvsClient::new("127.0.0.1:4220")?;
+    for i in 0..100 {
+        client.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let before = client.info()?;
+    assert!(before.garbage_bytes > 0);
+
+    let msg = client.admin(AdminCommand::Compact)?.unwrap();
+    assert!(msg.starts_with(&format!("compacted {} bytes to ", before.disk_bytes)));
+    let after = client.info()?;
+    assert!(after.disk_bytes < before.disk_bytes);
+    assert_eq!(after.garbage_bytes, 0);
+    assert_eq!(client.get("key".to_owned())?, Some("value99".to_owned()));
+
+    assert_eq!(client.admin(AdminCommand::Flush)?, None);
+    Ok(())
+}
+
+#[test]
+fn checkpoint() -> Result<()> {
+    let target = TempDir::new()?;
+    let checkpoint_dir = target.path().to_owned();
+    let acl = "token root r00t\nallow root admin *";
+    let _dir = start_server("127.0.0.1:4221", |server| {
+        server.set_acl(acl.parse().unwrap());
+        server.set_checkpoint_dir(checkpoint_dir);
+    });
+    let mut client = KvsClient::new("127.0.0.1:4221")?;
+    client.auth(Credentials::Token("r00t".to_owned()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // paths are relative to the checkpoint directory
+    for path in ["/tmp/checkpoint", "../checkpoint", "a/../../checkpoint", ""] {
+        let cmd = AdminCommand::Checkpoint {
+            path: path.to_owned(),
+        };
+        assert!(client.admin(cmd).is_err(), "{}", path);
+    }
+    let path = target.path().join("checkpoint");
+    let cmd = AdminCommand::Checkpoint {
+        path: "checkpoint".to_owned(),
+    };
+    assert!(client
+        .admin(cmd.clone())?
+        .unwrap()
+        .contains("checkpoint created"));
+    // later writes are not in the checkpoint
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    // an existing checkpoint is not overwritten
+    assert!(client.admin(cmd).is_err());
+
+    assert_eq!(fs::read_to_string(path.join("engine"))?, "kvs");
+    let store = KvStore::open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn set_log_level() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4222", |server| server.set_open_admin(true));
+    let mut client = KvsClient::new("127.0.0.1:4222")?;
+    assert_eq!(
+        client.admin(AdminCommand::SetLogLevel {
+            level: "debug".to_owned()
+        })?,
+        None
+    );
+    assert!(client
+        .admin(AdminCommand::SetLogLevel {
+            level: "loud".to_owned()
+        })
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn reload_config() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4223", |server| {
+        server.set_open_admin(true);
+        server.on_reload(|| {
+            Ok(Reload {
+                acl: Some("token root r00t\nallow root admin *".parse()?),
+                limits: ConnectionLimits {
+                    max_connections: Some(1),
+                    ..ConnectionLimits::default()
+                },
+                ..Reload::default()
+            })
+        })
+    });
+    let mut client = KvsClient::new("127.0.0.1:4223")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.admin(AdminCommand::ReloadConfig)?, None);
+
+    // the new ACL applies to the open connection
+    assert!(client.get("key1".to_owned()).is_err());
+
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::new("127.0.0.1:4223")?;
+    client.auth(Credentials::Token("r00t".to_owned()))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut rejected = KvsClient::new("127.0.0.1:4223")?;
+    assert!(matches!(
+        rejected.auth(Credentials::Token("r00t".to_owned())),
+        Err(Error::ServerBusy)
+    ));
+    Ok(())
+}
+
+#[test]
+fn reload_without_config() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4224", |server| server.set_open_admin(true));
+    let mut client = KvsClient::new("127.0.0.1:4224")?;
+    assert!(client.admin(AdminCommand::ReloadConfig).is_err());
+    Ok(())
+}
+
+// Without an ACL admin requests and followers are refused unless opted in,
+// and so are checkpoints without a directory for them.
+#[test]
+fn admin_needs_acl_or_opt_in() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4227", |_| {});
+    let mut client = KvsClient::new("127.0.0.1:4227")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(
+        matches!(client.admin(AdminCommand::Flush), Err(Error::ServerError(e)) if e == "permission denied")
+    );
+    let _follower_dir = start_server("127.0.0.1:4228", |server| {
+        server.follow("127.0.0.1:4227", None)
+    });
+    let info = KvsClient::new("127.0.0.1:4228")?.info()?.replication;
+    assert!(!info.connected);
+
+    let _dir = start_server("127.0.0.1:4229", |server| server.set_open_admin(true));
+    let mut client = KvsClient::new("127.0.0.1:4229")?;
+    assert_eq!(client.admin(AdminCommand::Flush)?, None);
+    let cmd = AdminCommand::Checkpoint {
+        path: "checkpoint".to_owned(),
+    };
+    assert!(client.admin(cmd).is_err());
+    Ok(())
+}
+
+#[test]
+fn admin_permission() -> Result<()> {
+    let acl = "token reader t0ken\ntoken root r00t\nallow reader read,write *\nallow root admin *";
+    let _dir = start_server("127.0.0.1:4225", |server| {
+        server.set_acl(acl.parse().unwrap())
+    });
+
+    let mut client = KvsClient::new("127.0.0.1:4225")?;
+    assert!(client.admin(AdminCommand::Flush).is_err());
+
+    let mut client = KvsClient::new("127.0.0.1:4225")?;
+    client.auth(Credentials::Token("t0ken".to_owned()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.admin(AdminCommand::Flush).is_err());
+    // info only needs to be authenticated
+    assert_eq!(client.info()?.keys, 1);
+
+    let mut client = KvsClient::new("127.0.0.1:4225")?;
+    client.auth(Credentials::Token("r00t".to_owned()))?;
+    assert_eq!(client.admin(AdminCommand::Flush)?, None);
+    Ok(())
+}
+
+#[test]
+fn cli_admin() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    let admin = format!(
+        "[admin]\nopen = true\ncheckpoint_dir = {:?}\n",
+        temp_dir.path().to_str().unwrap()
+    );
+    fs::write(
+        &config,
+        format!("[listen]\naddr = \"127.0.0.1:4226\"\n\n{}", admin),
+    )
+    .unwrap();
+    let _server = Process::spawn(&["--config", config.to_str().unwrap()], temp_dir.path());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4226"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value2"]).assert().success();
+    client(&["admin", "compact"])
+        .assert()
+        .success()
+        .stdout(contains("compacted"));
+    client(&["admin", "flush"]).assert().success();
+    client(&["admin", "checkpoint", "backup"])
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "checkpoint created at {}",
+            temp_dir.path().join("backup").display()
+        )));
+    assert!(temp_dir.path().join("backup").join("engine").exists());
+    client(&["admin", "log-level", "loud"]).assert().failure();
+    client(&["admin", "log-level", "debug"]).assert().success();
+
+    // the reloaded file requires authentication
+    let acl = temp_dir.path().join("kvs.acl");
+    fs::write(&acl, "token root r00t\nallow root admin *\n").unwrap();
+    fs::write(
+        &config,
+        format!(
+            "[listen]\naddr = \"127.0.0.1:4226\"\n\n[auth]\nacl = {:?}\n\n{}",
+            acl.to_str().unwrap(),
+            admin
+        ),
+    )
+    .unwrap();
+    client(&["admin", "reload"]).assert().success();
+    client(&["get", "key1"]).assert().failure();
+    client(&["get", "key1", "--token", "r00t"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "async")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsClient, AsyncKvsServer, KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = AsyncKvsServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let handle = tokio::spawn(async move { server.run("127.0.0.1:4170").await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // idle connections do not hold up the others
+    let mut idle = Vec::new();
+    for _ in 0..64 {
+        idle.push(AsyncKvsClient::connect("127.0.0.1:4170").await?);
+    }
+
+    let mut client = AsyncKvsClient::connect("127.0.0.1:4170").await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert!(client.remove("key1".to_owned()).await.is_err());
+    client.set("key2".to_owned(), "value2".to_owned()).await?;
+
+    // the blocking client speaks the same protocol
+    let value =
+        tokio::task::spawn_blocking(|| KvsClient::new("127.0.0.1:4170")?.get("key2".to_owned()))
+            .await
+            .unwrap()?;
+    assert_eq!(value, Some("value2".to_owned()));
+
+    shutdown.shutdown();
+    handle.await.unwrap()?;
+    assert!(AsyncKvsClient::connect("127.0.0.1:4170").await.is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_with_thread_pool_server() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut server = KvsServer::new(engine, pool);
+    thread::spawn(move || server.run("127.0.0.1:4171"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = AsyncKvsClient::connect("127.0.0.1:4171").await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_reads_messages_in_pieces() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let server = AsyncKvsServer::new(KvStore::open(temp_dir.path())?);
+    tokio::spawn(async move { server.run("127.0.0.1:4172").await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let stream = TcpStream::connect("127.0.0.1:4172").await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let req = br#"{"Set":{"key":"k}","value":"a{\"[b"}} "Ping""#;
+    for chunk in req.chunks(3) {
+        writer.write_all(chunk).await?;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let ok = br#"{"body":{"Ok":null}}"#;
+    for _ in 0..2 {
+        let mut rsp = [0; 20];
+        reader.read_exact(&mut rsp).await?;
+        assert_eq!(&rsp, ok);
+    }
+    let mut client = AsyncKvsClient::connect("127.0.0.1:4172").await?;
+    assert_eq!(
+        client.get("k}".to_owned()).await?,
+        Some("a{\"[b".to_owned())
+    );
+
+    // a message that never ends closes the connection
+    writer.write_all(br#"{"Set":{"key":"k","value":""#).await?;
+    let chunk = vec![b'a'; 1024 * 1024];
+    for _ in 0..80 {
+        if writer.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+    let mut rest = Vec::new();
+    assert!(reader.read_to_end(&mut rest).await.is_err() || rest.is_empty());
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use assert_cmd::prelude::*;
+use kvs::auth::{Acl, Credentials};
+use kvs::{Error, KvsClient, Protocol, Result};
+use predicates::str::contains;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+const ACL: &str = "
+# principals
+user  alice   s3cret
+token reader  t0ken
+
+# grants
+allow alice   read,write  app/
+allow reader  read        *
+";
+
+fn start_server(addr: &'static str, extra: &[(Protocol, &'static str)]) -> TempDir {
+    common::start_server(addr, |server| {
+        server.set_acl(ACL.parse().unwrap());
+        for &(protocol, extra_addr) in extra {
+            server.listen(protocol, extra_addr).unwrap();
+        }
+    })
+}
+
+fn alice() -> Credentials {
+    Credentials::Password {
+        user: "alice".to_owned(),
+        password: "s3cret".to_owned(),
+    }
+}
+
+#[test]
+fn acl_parse() {
+    let acl: Acl = ACL.parse().unwrap();
+    assert_eq!(acl.authenticate(&alice()), Some("alice".to_owned()));
+    assert_eq!(
+        acl.authenticate(&Credentials::Token("t0ken".to_owned())),
+        Some("reader".to_owned())
+    );
+    assert_eq!(
+        acl.authenticate(&Credentials::Token("s3cret".to_owned())),
+        None
+    );
+
+    assert!("allow bob read *".parse::<Acl>().is_err());
+    assert!("user bob pw\nallow bob delete *".parse::<Acl>().is_err());
+    assert!("user bob pw\ntoken bob t".parse::<Acl>().is_err());
+    assert!("grant bob read *".parse::<Acl>().is_err());
+}
+
+#[test]
+fn client_requires_auth() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4150", &[]);
+
+    let mut client = KvsClient::new("127.0.0.1:4150")?;
+    assert!(client.get("app/key".to_owned()).is_err());
+
+    let mut client = KvsClient::new("127.0.0.1:4150")?;
+    let wrong = Credentials::Password {
+        user: "alice".to_owned(),
+        password: "wrong".to_owned(),
+    };
+    assert!(matches!(client.auth(wrong), Err(Error::AuthError(_))));
+
+    let mut client = KvsClient::new("127.0.0.1:4150")?;
+    client.auth(alice())?;
+    client.set("app/key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("app/key".to_owned())?, Some("value".to_owned()));
+    assert!(client.set("other".to_owned(), "value".to_owned()).is_err());
+    assert!(client.get("other".to_owned()).is_err());
+
+    let mut client = KvsClient::new("127.0.0.1:4150")?;
+    client.auth(Credentials::Token("t0ken".to_owned()))?;
+    assert_eq!(client.get("app/key".to_owned())?, Some("value".to_owned()));
+    assert!(client.remove("app/key".to_owned()).is_err());
+    Ok(())
+}
+
+fn resp_call(reader: &mut BufReader<TcpStream>, args: &[&str]) -> String {
+    let mut buf = format!("*{}\r\n", args.len());
+    for arg in args {
+        buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    reader.get_mut().write_all(buf.as_bytes()).unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line.trim_end().to_owned()
+}
+
+#[test]
+fn resp_auth() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4151", &[(Protocol::Resp, "127.0.0.1:4152")]);
+    let mut conn = BufReader::new(TcpStream::connect("127.0.0.1:4152")?);
+
+    assert!(resp_call(&mut conn, &["GET", "app/key"]).starts_with("-NOAUTH"));
+    assert!(resp_call(&mut conn, &["AUTH", "alice", "wrong"]).starts_with("-WRONGPASS"));
+    assert_eq!(resp_call(&mut conn, &["AUTH", "alice", "s3cret"]), "+OK");
+    assert_eq!(resp_call(&mut conn, &["SET", "app/key", "value"]), "+OK");
+    assert!(resp_call(&mut conn, &["SET", "other", "value"]).starts_with("-NOPERM"));
+
+    let mut conn = BufReader::new(TcpStream::connect("127.0.0.1:4152")?);
+    assert_eq!(resp_call(&mut conn, &["AUTH", "t0ken"]), "+OK");
+    assert_eq!(resp_call(&mut conn, &["GET", "app/key"]), "$5");
+    Ok(())
+}
+
+fn http_status(addr: &str, method: &str, target: &str, authorization: Option<&str>) -> u16 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: 5\r\n",
+        method, target
+    );
+    if let Some(authorization) = authorization {
+        req.push_str(&format!("Authorization: {}\r\n", authorization));
+    }
+    req.push_str("\r\nvalue");
+    stream.write_all(req.as_bytes()).unwrap();
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp).unwrap();
+    rsp.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn http_auth() {
+    let _dir = start_server("127.0.0.1:4153", &[(Protocol::Http, "127.0.0.1:4154")]);
+    let addr = "127.0.0.1:4154";
+    // "alice:s3cret"
+    let basic = Some("Basic YWxpY2U6czNjcmV0");
+
+    assert_eq!(http_status(addr, "GET", "/health", None), 200);
+    assert_eq!(http_status(addr, "GET", "/keys/app%2Fkey", None), 401);
+    assert_eq!(
+        http_status(addr, "GET", "/keys/app%2Fkey", Some("Bearer wrong")),
+        401
+    );
+    assert_eq!(http_status(addr, "PUT", "/keys/app%2Fkey", basic), 204);
+    assert_eq!(http_status(addr, "PUT", "/keys/other", basic), 403);
+    assert_eq!(
+        http_status(addr, "GET", "/keys/app%2Fkey", Some("Bearer t0ken")),
+        200
+    );
+    assert_eq!(
+        http_status(addr, "DELETE", "/keys/app%2Fkey", Some("Bearer t0ken")),
+        403
+    );
+}
+
+#[test]
+fn memcache_refused_with_acl() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4155", &[(Protocol::Memcache, "127.0.0.1:4156")]);
+    let mut conn = BufReader::new(TcpStream::connect("127.0.0.1:4156")?);
+    conn.get_mut().write_all(b"get key\r\n")?;
+    let mut line = String::new();
+    conn.read_line(&mut line)?;
+    assert_eq!(line, "SERVER_ERROR authentication required\r\n");
+    Ok(())
+}
+
+#[test]
+fn cli_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl");
+    fs::write(&acl, ACL).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4157", "--acl", acl.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "app/key", "value", "--addr", "127.0.0.1:4157"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "app/key",
+            "value",
+            "--addr",
+            "127.0.0.1:4157",
+            "--user",
+            "alice",
+            "--password",
+            "s3cret",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "app/key",
+            "--addr",
+            "127.0.0.1:4157",
+            "--token",
+            "t0ken",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
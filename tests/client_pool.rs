@@ -0,0 +1,158 @@
+use kvs::{Error, KvsClient, KvsClientPool, PoolOptions, Result};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, Process};
+
+#[test]
+fn pool_shares_connections() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4290", |_| {});
+    let options = PoolOptions {
+        max_connections: 3,
+        ..Default::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4290", options)?;
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.get()?.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(pool.get()?.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let status = pool.status();
+    assert!(status.open <= 3 && status.open >= 1, "{:?}", status);
+    assert_eq!(status.idle, status.open);
+    // the info request itself is on one of them
+    let info = pool.get()?.info()?;
+    assert_eq!(info.connections_total, status.open as u64);
+    Ok(())
+}
+
+#[test]
+fn pool_checkout_times_out() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4291", |_| {});
+    let options = PoolOptions {
+        max_connections: 1,
+        checkout_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4291", options)?;
+    let mut client = pool.get()?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let started = Instant::now();
+    assert!(matches!(pool.get(), Err(Error::PoolTimeout)));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // a connection returned by another thread is handed out
+    let waiter = {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            pool.get()
+                .and_then(|mut client| client.get("key".to_owned()))
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    drop(client);
+    assert_eq!(waiter.join().unwrap()?, Some("value".to_owned()));
+
+    // a discarded connection makes room for a new one
+    pool.get()?.discard();
+    assert_eq!(pool.status().open, 0);
+    pool.get()?.ping()?;
+    Ok(())
+}
+
+#[test]
+fn pool_keeps_min_connections() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4292", |_| {});
+    let options = PoolOptions {
+        min_connections: 2,
+        max_connections: 4,
+        idle_timeout: Some(Duration::from_millis(200)),
+        health_check_interval: None,
+        ..Default::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4292", options)?;
+    let status = pool.status();
+    assert_eq!((status.open, status.idle), (2, 2));
+
+    let clients: Vec<_> = (0..4).map(|_| pool.get()).collect::<Result<_>>()?;
+    assert_eq!(pool.status().open, 4);
+    drop(clients);
+    assert_eq!(pool.status().idle, 4);
+
+    // idle connections above the minimum are closed
+    thread::sleep(Duration::from_millis(600));
+    let status = pool.status();
+    assert_eq!((status.open, status.idle), (2, 2));
+    let info = KvsClient::new("127.0.0.1:4292")?.info()?;
+    assert_eq!(info.connections_active, 3);
+
+    assert!(KvsClientPool::new("127.0.0.1:4299", options).is_err());
+    Ok(())
+}
+
+#[test]
+fn health_checks_do_not_keep_connections_open() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4315", |_| {});
+    let options = PoolOptions {
+        idle_timeout: Some(Duration::from_millis(400)),
+        health_check_interval: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4315", options)?;
+    let clients: Vec<_> = (0..2).map(|_| pool.get()).collect::<Result<_>>()?;
+    drop(clients);
+
+    // pinged several times, and still closed once idle for too long
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.status().idle, 2);
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(pool.status().open, 0);
+    let info = KvsClient::new("127.0.0.1:4315")?.info()?;
+    assert_eq!(info.connections_active, 1);
+    Ok(())
+}
+
+#[test]
+fn pool_replaces_dead_connections() -> Result<()> {
+    let dir = TempDir::new()?;
+    let spawn = || {
+        let process = Process::spawn(&["--addr", "127.0.0.1:4293"], dir.path());
+        thread::sleep(Duration::from_secs(1));
+        process
+    };
+    let process = spawn();
+    let options = PoolOptions {
+        min_connections: 1,
+        health_check_interval: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let pool = KvsClientPool::new("127.0.0.1:4293", options)?;
+    pool.get()?.set("key".to_owned(), "value".to_owned())?;
+    let mut client = pool.get()?;
+    drop(process);
+
+    // broken by the restart, not returned to the pool
+    let _process = spawn();
+    assert!(client.get("key".to_owned()).is_err());
+    drop(client);
+    assert!(pool.status().open <= 1);
+
+    // the idle one died as well, it fails its health check and is replaced
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(pool.get()?.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
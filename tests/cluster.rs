@@ -0,0 +1,155 @@
+use kvs::raft::{NodeId, Role};
+use kvs::{ClusterInfo, KvsClient, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{wait_for, Process};
+
+// Clients keep their connection, and a worker, while they live.
+const CONFIG: &str = r#"
+[thread_pool]
+size = 4
+
+[cluster]
+id = 1
+tick_ms = 50
+secret = "s3cret"
+nodes = [
+    { id = 1, addr = "127.0.0.1:4260", raft_addr = "127.0.0.1:4263" },
+    { id = 2, addr = "127.0.0.1:4261", raft_addr = "127.0.0.1:4264" },
+    { id = 3, addr = "127.0.0.1:4262", raft_addr = "127.0.0.1:4265" },
+]
+"#;
+
+// A single node cluster, on ports of its own.
+const SINGLE_CONFIG: &str = r#"
+[cluster]
+id = 1
+tick_ms = 50
+secret = "s3cret"
+nodes = [{ id = 1, addr = "127.0.0.1:4311", raft_addr = "127.0.0.1:4312" }]
+"#;
+
+fn addr(id: NodeId) -> String {
+    format!("127.0.0.1:{}", 4259 + id)
+}
+
+// Starts node `id` in `dir`.
+fn start_node(id: NodeId, dir: &Path, config: &Path) -> Process {
+    let (id, addr) = (id.to_string(), addr(id));
+    let config = config.to_str().unwrap();
+    let args = ["--config", config, "--node-id", &id, "--addr", &addr];
+    let process = Process::spawn(&args, dir);
+    thread::sleep(Duration::from_millis(500));
+    process
+}
+
+fn cluster_info(id: NodeId) -> Option<ClusterInfo> {
+    KvsClient::new(addr(id)).ok()?.info().ok()?.cluster
+}
+
+// Waits until one of `ids` leads the others.
+fn wait_for_leader(ids: &[NodeId]) -> NodeId {
+    let mut leader = None;
+    wait_for(|| {
+        let infos: Option<Vec<ClusterInfo>> = ids.iter().map(|&id| cluster_info(id)).collect();
+        leader = infos.and_then(|infos| {
+            let leader = infos.iter().find(|info| info.role == Role::Leader)?;
+            let term = leader.term;
+            infos
+                .iter()
+                .all(|info| info.term == term && info.leader == Some(leader.id))
+                .then_some(leader.id)
+        });
+        leader.is_some()
+    });
+    leader.unwrap()
+}
+
+fn wait_for_value(id: NodeId, key: &str, value: &str) {
+    wait_for(|| {
+        KvsClient::new(addr(id))
+            .and_then(|mut client| client.get(key.to_owned()))
+            .is_ok_and(|found| found.as_deref() == Some(value))
+    });
+}
+
+#[test]
+fn cluster_elects_and_replicates() -> Result<()> {
+    let config_dir = TempDir::new()?;
+    let config = config_dir.path().join("kvs.toml");
+    fs::write(&config, CONFIG)?;
+    let dirs = [TempDir::new()?, TempDir::new()?, TempDir::new()?];
+    let mut nodes: Vec<Option<Process>> = (1..=3)
+        .map(|id| Some(start_node(id, dirs[id as usize - 1].path(), &config)))
+        .collect();
+
+    let leader = wait_for_leader(&[1, 2, 3]);
+    // writes sent to a follower are redirected to the leader
+    let follower = (1..=3).find(|&id| id != leader).unwrap();
+    let mut client = KvsClient::new(addr(follower))?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // entries are applied in order
+    for id in 1..=3 {
+        wait_for_value(id, "key1", "value1");
+        let mut client = KvsClient::new(addr(id))?;
+        assert_eq!(client.get("key2".to_owned())?, None);
+    }
+
+    // the others elect a new leader when it fails
+    nodes[leader as usize - 1] = None;
+    let others: Vec<NodeId> = (1..=3).filter(|&id| id != leader).collect();
+    let new_leader = wait_for_leader(&others);
+    assert_ne!(new_leader, leader);
+    let mut client = KvsClient::new(addr(others[0]))?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    for &id in &others {
+        wait_for_value(id, "key3", "value3");
+    }
+
+    // and the failed node catches up once restarted
+    let dir = dirs[leader as usize - 1].path();
+    nodes[leader as usize - 1] = Some(start_node(leader, dir, &config));
+    wait_for_value(leader, "key3", "value3");
+    wait_for_value(leader, "key1", "value1");
+    assert_eq!(wait_for_leader(&[1, 2, 3]), new_leader);
+    Ok(())
+}
+
+#[test]
+fn raft_connections_need_the_secret() -> Result<()> {
+    let config_dir = TempDir::new()?;
+    let config = config_dir.path().join("kvs.toml");
+    fs::write(&config, SINGLE_CONFIG)?;
+    let dir = TempDir::new()?;
+    let args = [
+        "--config",
+        config.to_str().unwrap(),
+        "--addr",
+        "127.0.0.1:4311",
+    ];
+    let _node = Process::spawn(&args, dir.path());
+    thread::sleep(Duration::from_millis(500));
+
+    let hello = |secret: &str| -> Result<TcpStream> {
+        let mut stream = TcpStream::connect("127.0.0.1:4312")?;
+        stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+        writeln!(stream, "{{\"secret\":\"{}\"}}", secret)?;
+        Ok(stream)
+    };
+    // a wrong secret is disconnected
+    let mut buf = [0; 1];
+    assert_eq!(hello("guess")?.read(&mut buf)?, 0);
+    // the right one is kept
+    assert!(hello("s3cret")?.read(&mut buf).is_err());
+    Ok(())
+}
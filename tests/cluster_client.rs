@@ -0,0 +1,149 @@
+use kvs::{Error, HashRing, KvsClient, KvsClusterClient, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, Process};
+
+#[test]
+fn cluster_client_spreads_keys() -> Result<()> {
+    let nodes = [
+        ("127.0.0.1:4282", 1),
+        ("127.0.0.1:4283", 1),
+        ("127.0.0.1:4284", 2),
+    ];
+    let _dirs = nodes.map(|(addr, _)| start_server(addr, |_| {}));
+
+    let mut client = KvsClusterClient::weighted(nodes)?;
+    for i in 0..400 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..400 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned())?;
+    assert!(client.remove("key0".to_owned()).is_err());
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.scan("key1".to_owned())?.len(), 111);
+
+    // each server holds the keys of its points, the heavier one more of them
+    let ring = HashRing::weighted(nodes)?;
+    let mut counts = Vec::new();
+    for (addr, _) in nodes {
+        let keys = KvsClient::new(addr)?.scan(String::new())?;
+        assert!(keys.iter().all(|key| ring.node(key) == addr));
+        counts.push(keys.len());
+    }
+    assert_eq!(counts.iter().sum::<usize>(), 399);
+    assert!(
+        counts[2] > counts[0] && counts[2] > counts[1],
+        "{:?}",
+        counts
+    );
+
+    let health = client.health();
+    assert_eq!(health.len(), 3);
+    assert_eq!(health[2].weight, 2);
+    assert!(health.iter().all(|node| node.up && node.connected));
+    assert!(health
+        .iter()
+        .all(|node| node.requests > 0 && node.failures == 0));
+    Ok(())
+}
+
+#[test]
+fn cluster_client_fails_over() -> Result<()> {
+    let addrs = ["127.0.0.1:4285", "127.0.0.1:4286", "127.0.0.1:4287"];
+    let _dirs = [addrs[0], addrs[1]].map(|addr| start_server(addr, |_| {}));
+    // the third one runs in a process of its own, to be killed
+    let dir = TempDir::new()?;
+    let spawn = || Process::spawn(&["--addr", addrs[2], "--threads", "4"], dir.path());
+    let process = spawn();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClusterClient::new(addrs)?;
+    client.set_replicas(2);
+    client.set_retry_interval(Duration::from_millis(500));
+    for i in 0..50 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    drop(process);
+    // values on the failed server are read from their other replica
+    for i in 0..50 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    for i in 50..60 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // and overwritten or removed on it
+    for i in 0..10 {
+        client.set(format!("key{}", i), format!("updated{}", i))?;
+    }
+    client.remove("key10".to_owned())?;
+    for i in 50..60 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    // keys of the failed server alone are unavailable rather than moved
+    let ring = HashRing::new(addrs)?;
+    let key = (0..)
+        .map(|i| format!("single{}", i))
+        .find(|key| ring.node(key) == addrs[2])
+        .unwrap();
+    let mut single = KvsClusterClient::new(addrs)?;
+    for _ in 0..2 {
+        assert!(matches!(
+            single.set(key.clone(), "value".to_owned()),
+            Err(Error::ServerError(e)) if e.starts_with("no server available")
+        ));
+    }
+    assert!(matches!(single.get(key), Err(Error::ServerError(_))));
+    for addr in &addrs[..2] {
+        assert!(KvsClient::new(*addr)?.scan("single".to_owned())?.is_empty());
+    }
+
+    let health = client.health();
+    assert!(health[0].up && health[1].up);
+    assert!(!health[2].up && !health[2].connected);
+    assert_eq!(health[2].failures, health[2].consecutive_failures);
+    assert!(health[2].failures >= 1);
+    assert!(health[2].last_error.is_some());
+    assert!(health[2].pending_repairs > 0);
+
+    // the server is used again once back and retried
+    let _process = spawn();
+    thread::sleep(Duration::from_secs(1));
+    for i in 0..60 {
+        client.get(format!("key{}", i))?;
+    }
+    let health = client.health();
+    assert!(health[2].up && health[2].connected);
+    assert_eq!(health[2].consecutive_failures, 0);
+    // and gets the writes it missed
+    assert_eq!(health[2].pending_repairs, 0);
+    let mut restarted = KvsClient::new(addrs[2])?;
+    for i in 0..60 {
+        let key = format!("key{}", i);
+        if !ring.indexes(&key)[..2].contains(&2) {
+            continue;
+        }
+        let expected = match i {
+            0..=9 => Some(format!("updated{}", i)),
+            10 => None,
+            _ => Some(format!("value{}", i)),
+        };
+        assert_eq!(client.get(key.clone())?, expected);
+        assert_eq!(restarted.get(key)?, expected);
+    }
+    Ok(())
+}
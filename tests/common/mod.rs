@@ -0,0 +1,64 @@
+//! Fixtures shared by the integration tests, each of which uses some of them
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Server started by `start_server`
+pub type TestServer = KvsServer<KvStore, SharedQueueThreadPool>;
+
+/// Run a server of 4 threads over the data in `dir` on `addr`, set up by
+/// `setup`, and give it time to start listening.
+pub fn start_server_in(addr: &'static str, dir: &Path, setup: impl FnOnce(&mut TestServer)) {
+    let engine = KvStore::open(dir).unwrap();
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    let mut server = KvsServer::new(engine, pool);
+    setup(&mut server);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+}
+
+/// `start_server_in` a new directory, removed when dropped
+pub fn start_server(addr: &'static str, setup: impl FnOnce(&mut TestServer)) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    start_server_in(addr, temp_dir.path(), setup);
+    temp_dir
+}
+
+/// A kvs-server process, killed when dropped
+pub struct Process(pub Child);
+
+impl Process {
+    /// Start kvs-server with `args` in `dir`, without waiting for it to listen
+    pub fn spawn(args: &[&str], dir: &Path) -> Process {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        Process(child)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Poll `f` until it holds, for 10 seconds at most.
+pub fn wait_for(mut f: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,235 @@
+use assert_cmd::prelude::*;
+use kvs::auth::Credentials;
+use kvs::config::{ServerConfig, ThreadPoolKind};
+use kvs::{Quota, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn config_parse() -> Result<()> {
+    let config: ServerConfig = r#"
+        engine = "sled"
+
+        [thread_pool]
+        kind = "rayon"
+        size = 2
+
+        [listen]
+        resp = "127.0.0.1:6379"
+
+        [limits]
+        idle_timeout = 30
+
+        [log]
+        slow_threshold_ms = 250
+
+        [rate_limit]
+        ops_per_sec = 100
+
+        [replication]
+        leader = "127.0.0.1:4001"
+        token = "s3cret"
+
+        [[quota]]
+        prefix = "batch/"
+        max_bytes = 1024
+    "#
+    .parse()?;
+    assert_eq!(config.engine.as_deref(), Some("sled"));
+    assert_eq!(config.thread_pool.kind, ThreadPoolKind::Rayon);
+    assert_eq!(config.thread_pool.size, 2);
+    assert_eq!(config.listen.addr, "127.0.0.1:4000");
+    assert_eq!(config.listen.resp.as_deref(), Some("127.0.0.1:6379"));
+    assert_eq!(
+        config.limits.connection_limits().idle_timeout,
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(config.limits.shutdown_timeout, 10);
+    let request_log = config.log.request_log();
+    assert!(request_log.redact_values);
+    assert_eq!(request_log.slow_threshold, Some(Duration::from_millis(250)));
+    assert_eq!(config.rate_limit.rate_limits().ops_per_sec, Some(100));
+    assert_eq!(config.rate_limit.rate_limits().bytes_per_sec, None);
+    let replication = &config.replication;
+    assert_eq!(replication.leader.as_deref(), Some("127.0.0.1:4001"));
+    assert_eq!(
+        replication.credentials(),
+        Some(Credentials::Token("s3cret".to_owned()))
+    );
+    assert_eq!(replication.log_capacity, 10_000);
+    assert_eq!(
+        config.quotas(),
+        vec![Quota {
+            prefix: "batch/".to_owned(),
+            max_bytes: 1024
+        }]
+    );
+
+    // the printed config reads back the same, without the token
+    let printed = config.to_toml();
+    assert!(!printed.contains("s3cret"));
+    let mut redacted = config.clone();
+    redacted.replication.token = Some("***".to_owned());
+    assert_eq!(printed.parse::<ServerConfig>()?, redacted);
+    assert_eq!("".parse::<ServerConfig>()?, ServerConfig::default());
+
+    assert!("engine = \"rocks\"".parse::<ServerConfig>().is_err());
+    assert!("[listen]\nport = 4000".parse::<ServerConfig>().is_err());
+    assert!("[thread_pool]\nkind = \"forking\""
+        .parse::<ServerConfig>()
+        .is_err());
+    assert!("[limits]\nread_timeout = 0"
+        .parse::<ServerConfig>()
+        .is_err());
+    assert!("[rate_limit]\nops_per_sec = 0"
+        .parse::<ServerConfig>()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn config_cluster() -> Result<()> {
+    let nodes = r#"
+        secret = "s3cret"
+        nodes = [
+            { id = 1, addr = "127.0.0.1:4000", raft_addr = "127.0.0.1:4100" },
+            { id = 2, addr = "127.0.0.1:4001", raft_addr = "127.0.0.1:4101" },
+        ]
+    "#;
+    let config: ServerConfig = format!("[cluster]\nid = 2\n{}", nodes).parse()?;
+    let cluster = config.cluster.as_ref().unwrap().cluster(Path::new("data"));
+    assert_eq!(cluster.id, 2);
+    assert_eq!(cluster.nodes[1].raft_addr, "127.0.0.1:4101");
+    assert_eq!(cluster.state_dir, Path::new("data").join("raft"));
+    assert_eq!(cluster.tick, Duration::from_millis(100));
+    assert_eq!(cluster.secret, "s3cret");
+    let printed = config.to_toml();
+    assert!(!printed.contains("s3cret"));
+    let mut redacted = config.clone();
+    redacted.cluster.as_mut().unwrap().secret = "***".to_owned();
+    assert_eq!(printed.parse::<ServerConfig>()?, redacted);
+
+    let config: ServerConfig = format!(
+        "[cluster]\nid = 1\nstate_dir = \"state\"\ntick_ms = 20\n{}",
+        nodes
+    )
+    .parse()?;
+    let cluster = config.cluster.unwrap().cluster(Path::new("data"));
+    assert_eq!(cluster.state_dir, Path::new("state"));
+    assert_eq!(cluster.tick, Duration::from_millis(20));
+
+    // the server must be one of the nodes
+    assert!(format!("[cluster]\nid = 3\n{}", nodes)
+        .parse::<ServerConfig>()
+        .is_err());
+    assert!(
+        format!("[cluster]\nid = 1\n{}", nodes.replace("id = 2", "id = 1"))
+            .parse::<ServerConfig>()
+            .is_err()
+    );
+    // and share a secret
+    assert!(
+        format!("[cluster]\nid = 1\n{}", nodes.replace("s3cret", ""))
+            .parse::<ServerConfig>()
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_print_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "engine = \"sled\"\n[listen]\naddr = \"127.0.0.1:5000\"\nhttp = \"127.0.0.1:5001\"\n",
+    )
+    .unwrap();
+
+    // flags override the file
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--config",
+            "kvs.toml",
+            "--addr",
+            "127.0.0.1:6000",
+            "--print-config",
+        ])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let config: ServerConfig = String::from_utf8(output.stdout).unwrap().parse().unwrap();
+    assert_eq!(config.engine.as_deref(), Some("sled"));
+    assert_eq!(config.listen.addr, "127.0.0.1:6000");
+    assert_eq!(config.listen.http.as_deref(), Some("127.0.0.1:5001"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "missing.toml", "--print-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    fs::write(temp_dir.path().join("bad.toml"), "[listen]\nport = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "bad.toml", "--print-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `port`"));
+}
+
+#[test]
+fn cli_run_from_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        r#"
+data_dir = "data"
+
+[thread_pool]
+kind = "naive"
+
+[listen]
+addr = "127.0.0.1:4190"
+
+[log]
+level = "warn"
+"#,
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4190"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4190"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let data_dir = temp_dir.path().join("data");
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    assert!(!temp_dir.path().join("engine").exists());
+}
@@ -0,0 +1,178 @@
+use kvs::{KvsClient, Protocol, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+mod common;
+
+fn start_server(addr: &'static str, http_addr: &'static str) -> TempDir {
+    common::start_server(addr, |server| {
+        server.listen(Protocol::Http, http_addr).unwrap()
+    })
+}
+
+// Sends one request on a keep-alive connection, returns status and JSON body.
+fn request(
+    reader: &mut BufReader<TcpStream>,
+    method: &str,
+    target: &str,
+    body: &str,
+) -> (u16, Value) {
+    let req = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    );
+    reader.get_mut().write_all(req.as_bytes()).unwrap();
+
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').unwrap();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).unwrap()
+    };
+    (status, body)
+}
+
+fn connect(addr: &str) -> BufReader<TcpStream> {
+    BufReader::new(TcpStream::connect(addr).unwrap())
+}
+
+#[test]
+fn http_key_crud() {
+    let _dir = start_server("127.0.0.1:4110", "127.0.0.1:4111");
+    let mut conn = connect("127.0.0.1:4111");
+
+    let (status, body) = request(&mut conn, "GET", "/keys/key1", "");
+    assert_eq!(status, 404);
+    assert_eq!(body, json!({ "error": "Key not found" }));
+
+    assert_eq!(request(&mut conn, "PUT", "/keys/key1", "value1").0, 204);
+    let (status, body) = request(&mut conn, "GET", "/keys/key1", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "key": "key1", "value": "value1" }));
+
+    assert_eq!(request(&mut conn, "PUT", "/keys/key1", "value2").0, 204);
+    let (_, body) = request(&mut conn, "GET", "/keys/key1", "");
+    assert_eq!(body["value"], "value2");
+
+    assert_eq!(request(&mut conn, "DELETE", "/keys/key1", "").0, 204);
+    assert_eq!(request(&mut conn, "DELETE", "/keys/key1", "").0, 404);
+    assert_eq!(request(&mut conn, "GET", "/keys/key1", "").0, 404);
+
+    // percent-encoded keys
+    assert_eq!(request(&mut conn, "PUT", "/keys/a%2Fb%20c", "v").0, 204);
+    let (_, body) = request(&mut conn, "GET", "/keys/a%2Fb%20c", "");
+    assert_eq!(body["key"], "a/b c");
+
+    assert_eq!(request(&mut conn, "POST", "/keys/key1", "").0, 405);
+    assert_eq!(request(&mut conn, "GET", "/nowhere", "").0, 404);
+}
+
+#[test]
+fn http_list_keys_by_prefix() {
+    let _dir = start_server("127.0.0.1:4112", "127.0.0.1:4113");
+    let mut conn = connect("127.0.0.1:4113");
+    for key in ["user:1", "user:2", "user:10", "order:1"] {
+        assert_eq!(
+            request(&mut conn, "PUT", &format!("/keys/{}", key), "v").0,
+            204
+        );
+    }
+
+    let (status, body) = request(&mut conn, "GET", "/keys?prefix=user%3A", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "keys": ["user:1", "user:10", "user:2"] }));
+
+    let (_, body) = request(&mut conn, "GET", "/keys", "");
+    assert_eq!(body["keys"].as_array().unwrap().len(), 4);
+}
+
+#[test]
+fn http_health_and_stats() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4114", "127.0.0.1:4115");
+    let mut client = KvsClient::new("127.0.0.1:4114")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut conn = connect("127.0.0.1:4115");
+    let (status, body) = request(&mut conn, "GET", "/health", "");
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "status": "ok" }));
+
+    let (status, body) = request(&mut conn, "GET", "/stats", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["keys"], 1);
+    assert_eq!(body["connections_active"], 2);
+    assert!(body["requests_total"].as_u64().unwrap() >= 2);
+
+    // the gateway and `KvsClient` share one engine
+    let (_, body) = request(&mut conn, "GET", "/keys/key1", "");
+    assert_eq!(body["value"], "value1");
+    Ok(())
+}
+
+#[test]
+fn http_bad_request() {
+    let _dir = start_server("127.0.0.1:4116", "127.0.0.1:4117");
+    let mut stream = TcpStream::connect("127.0.0.1:4117").unwrap();
+    stream.write_all(b"garbage\r\n\r\n").unwrap();
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp).unwrap();
+    assert!(rsp.starts_with("HTTP/1.1 400"));
+    assert!(rsp.contains("Connection: close"));
+}
+
+#[test]
+fn http_expect_continue() {
+    let _dir = start_server("127.0.0.1:4319", "127.0.0.1:4320");
+    let mut conn = connect("127.0.0.1:4320");
+    conn.get_mut()
+        .write_all(b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\nExpect: 100-continue\r\n\r\n")
+        .unwrap();
+    let mut line = String::new();
+    conn.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 100 Continue\r\n");
+    line.clear();
+    conn.read_line(&mut line).unwrap();
+    assert_eq!(line, "\r\n");
+    conn.get_mut().write_all(b"value1").unwrap();
+    line.clear();
+    conn.read_line(&mut line).unwrap();
+    assert!(line.starts_with("HTTP/1.1 204"));
+
+    // a body shorter than announced is a bad request, without a buffer of its announced size
+    let mut stream = TcpStream::connect("127.0.0.1:4320").unwrap();
+    stream
+        .write_all(b"PUT /keys/key2 HTTP/1.1\r\nContent-Length: 60000000\r\n\r\nvalue2")
+        .unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp).unwrap();
+    assert!(rsp.starts_with("HTTP/1.1 400"));
+}
@@ -0,0 +1,58 @@
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn info_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(3)?;
+    let mut server = KvsServer::new(engine, pool);
+    thread::spawn(move || server.run("127.0.0.1:4210"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::new("127.0.0.1:4210")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set("key2".to_owned(), "value1".to_owned())?;
+    assert!(client.remove("key3".to_owned()).is_err());
+
+    let info = client.info()?;
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.keys, 2);
+    assert!(info.disk_bytes > info.garbage_bytes);
+    assert!(info.garbage_bytes > 0);
+    assert_eq!(
+        info.garbage_ratio,
+        info.garbage_bytes as f64 / info.disk_bytes as f64
+    );
+    assert_eq!(info.connections_active, 1);
+    assert_eq!(info.connections_total, 1);
+    assert_eq!(info.requests_total, 4);
+    assert_eq!(info.errors_total, 1);
+    assert_eq!(info.threads, Some(3));
+    assert_eq!(info.queued_jobs, 0);
+    Ok(())
+}
+
+#[test]
+fn info_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let pool = NaiveThreadPool::new(1)?;
+    let mut server = KvsServer::new(engine, pool);
+    thread::spawn(move || server.run("127.0.0.1:4211"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::new("127.0.0.1:4211")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let info = client.info()?;
+    assert_eq!(info.engine, "sled");
+    assert_eq!(info.keys, 1);
+    assert!(info.disk_bytes > 0);
+    assert_eq!(info.threads, None);
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -81,6 +81,35 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// The key count follows writes and survives reopening.
+fn count_keys<E: KvsEngine>(open: impl Fn(&TempDir) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value1".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert!(store.remove("key3".to_owned()).is_err());
+    assert_eq!(store.stats()?.keys, 2);
+    store.flush()?;
+    drop(store);
+
+    let store = open(&temp_dir)?;
+    assert_eq!(store.stats()?.keys, 2);
+    Ok(())
+}
+
+#[test]
+fn count_keys_kvs() -> Result<()> {
+    count_keys(|dir| KvStore::open(dir.path()))
+}
+
+#[test]
+fn count_keys_sled() -> Result<()> {
+    count_keys(|dir| SledKvsEngine::open(dir.path()))
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -209,3 +238,32 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// No compaction with a zero threshold, and every write is on disk without a flush.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        sync_writes: true,
+        compaction_threshold: 0,
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let mut current_size = dir_size();
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        let new_size = dir_size();
+        assert!(new_size > current_size);
+        current_size = new_size;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use kvs::{ConnectionLimits, Error, KvsClient, Protocol, Result};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+fn start_server(addr: &'static str, http_addr: &'static str, limits: ConnectionLimits) -> TempDir {
+    common::start_server(addr, |server| {
+        server.set_limits(limits);
+        server.listen(Protocol::Http, http_addr).unwrap();
+    })
+}
+
+fn stats(http_addr: &str) -> Value {
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    stream
+        .write_all(b"GET /stats HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp).unwrap();
+    let (_, body) = rsp.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn max_connections() -> Result<()> {
+    let limits = ConnectionLimits {
+        max_connections: Some(3),
+        ..ConnectionLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4180", "127.0.0.1:4181", limits);
+
+    let mut first = KvsClient::new("127.0.0.1:4180")?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let mut second = KvsClient::new("127.0.0.1:4180")?;
+    second.get("key1".to_owned())?;
+    let mut third = KvsClient::new("127.0.0.1:4180")?;
+    third.get("key1".to_owned())?;
+
+    let mut rejected = KvsClient::new("127.0.0.1:4180")?;
+    assert!(matches!(
+        rejected.get("key1".to_owned()),
+        Err(Error::ServerBusy)
+    ));
+
+    drop(third);
+    thread::sleep(Duration::from_millis(200));
+    // the HTTP listener shares the limit
+    let stats = stats("127.0.0.1:4181");
+    assert_eq!(stats["connections_rejected"], 1);
+
+    let mut client = KvsClient::new("127.0.0.1:4180")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn idle_and_read_timeouts() -> Result<()> {
+    let limits = ConnectionLimits {
+        idle_timeout: Some(Duration::from_secs(1)),
+        read_timeout: Some(Duration::from_millis(300)),
+        ..ConnectionLimits::default()
+    };
+    let _dir = start_server("127.0.0.1:4182", "127.0.0.1:4183", limits);
+
+    // requests spaced within the idle timeout keep the connection open
+    let mut client = KvsClient::new("127.0.0.1:4182")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // an idle connection is closed
+    let mut idle = TcpStream::connect("127.0.0.1:4182")?;
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // a request stalling halfway is cut off by the shorter read timeout
+    let mut stalled = TcpStream::connect("127.0.0.1:4182")?;
+    stalled.write_all(br#"{"Get":"#)?;
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(stalled.read(&mut [0; 16])?, 0);
+
+    let stats = stats("127.0.0.1:4183");
+    assert_eq!(stats["connections_timed_out"], 3);
+    Ok(())
+}
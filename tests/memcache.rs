@@ -0,0 +1,237 @@
+use kvs::{KvStore, KvsClient, KvsEngine, Protocol, Result, ShutdownHandle};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+fn start_server(addr: &'static str, memcache_addr: &'static str) -> TempDir {
+    common::start_server(addr, |server| {
+        server.listen(Protocol::Memcache, memcache_addr).unwrap()
+    })
+}
+
+// Same as `start_server` over the data in `dir`, returns the handle to stop it.
+fn start_server_in(dir: &Path, addr: &'static str, memcache_addr: &'static str) -> ShutdownHandle {
+    let mut shutdown = None;
+    common::start_server_in(addr, dir, |server| {
+        server.listen(Protocol::Memcache, memcache_addr).unwrap();
+        shutdown = Some(server.shutdown_handle());
+    });
+    shutdown.unwrap()
+}
+
+struct MemcacheClient {
+    reader: BufReader<TcpStream>,
+}
+
+impl MemcacheClient {
+    fn connect(addr: &str) -> MemcacheClient {
+        MemcacheClient {
+            reader: BufReader::new(TcpStream::connect(addr).unwrap()),
+        }
+    }
+
+    fn send(&mut self, req: &str) {
+        self.reader.get_mut().write_all(req.as_bytes()).unwrap();
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    }
+
+    fn call(&mut self, req: &str) -> String {
+        self.send(req);
+        self.line()
+    }
+
+    // Reads lines of a retrieval reply up to `END`.
+    fn retrieve(&mut self, req: &str) -> Vec<String> {
+        self.send(req);
+        let mut lines = Vec::new();
+        loop {
+            let line = self.line();
+            if line == "END" {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+}
+
+#[test]
+fn memcache_storage_commands() {
+    let _dir = start_server("127.0.0.1:4120", "127.0.0.1:4121");
+    let mut client = MemcacheClient::connect("127.0.0.1:4121");
+
+    assert_eq!(client.retrieve("get key1\r\n"), Vec::<String>::new());
+    assert_eq!(client.call("set key1 5 0 6\r\nvalue1\r\n"), "STORED");
+    assert_eq!(
+        client.retrieve("get key1 missing\r\n"),
+        vec!["VALUE key1 5 6", "value1"]
+    );
+    assert_eq!(client.call("add key1 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("add key2 0 0 1\r\nx\r\n"), "STORED");
+    assert_eq!(client.call("replace key3 0 0 1\r\nx\r\n"), "NOT_STORED");
+    assert_eq!(client.call("replace key2 0 0 1\r\ny\r\n"), "STORED");
+    assert_eq!(client.retrieve("get key2\r\n"), vec!["VALUE key2 0 1", "y"]);
+
+    assert_eq!(client.call("delete key2\r\n"), "DELETED");
+    assert_eq!(client.call("delete key2\r\n"), "NOT_FOUND");
+
+    // noreply suppresses the reply
+    client.send("set key4 0 0 2 noreply\r\nv4\r\n");
+    assert_eq!(
+        client.retrieve("get key4\r\n"),
+        vec!["VALUE key4 0 2", "v4"]
+    );
+
+    assert_eq!(client.call("set key5 0 -1 1\r\nx\r\n"), "STORED");
+    assert_eq!(client.retrieve("get key5\r\n"), Vec::<String>::new());
+
+    assert_eq!(client.call("bogus\r\n"), "ERROR");
+    assert_eq!(
+        client.call("set key1 0 0\r\n"),
+        "CLIENT_ERROR bad command line format"
+    );
+    assert_eq!(
+        client.call("version\r\n"),
+        format!("VERSION {}", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn memcache_cas() {
+    let _dir = start_server("127.0.0.1:4122", "127.0.0.1:4123");
+    let mut client = MemcacheClient::connect("127.0.0.1:4123");
+
+    assert_eq!(client.call("cas key1 0 0 1 1\r\nx\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("set key1 0 0 2\r\nv1\r\n"), "STORED");
+    let reply = client.retrieve("gets key1\r\n");
+    let cas: u64 = reply[0].split_whitespace().nth(4).unwrap().parse().unwrap();
+
+    // A second `gets` returns the same token while the key is unchanged.
+    let again = client.retrieve("gets key1\r\n");
+    assert_eq!(reply, again);
+
+    assert_eq!(
+        client.call(&format!("cas key1 0 0 2 {}\r\nv2\r\n", cas)),
+        "STORED"
+    );
+    assert_eq!(
+        client.call(&format!("cas key1 0 0 2 {}\r\nv3\r\n", cas)),
+        "EXISTS"
+    );
+    assert_eq!(
+        client.retrieve("get key1\r\n"),
+        vec!["VALUE key1 0 2", "v2"]
+    );
+}
+
+#[test]
+fn memcache_cas_after_value_is_written_back() {
+    let _dir = start_server("127.0.0.1:4323", "127.0.0.1:4324");
+    let mut client = MemcacheClient::connect("127.0.0.1:4324");
+
+    assert_eq!(client.call("set key1 0 0 1\r\nA\r\n"), "STORED");
+    let reply = client.retrieve("gets key1\r\n");
+    let cas: u64 = reply[0].split_whitespace().nth(4).unwrap().parse().unwrap();
+    assert_eq!(client.call("set key1 0 0 1\r\nB\r\n"), "STORED");
+    assert_eq!(client.call("set key1 0 0 1\r\nA\r\n"), "STORED");
+
+    // The value is back to A, but it was written since the token was read.
+    assert_eq!(
+        client.call(&format!("cas key1 0 0 1 {}\r\nC\r\n", cas)),
+        "EXISTS"
+    );
+    assert_eq!(client.retrieve("get key1\r\n"), vec!["VALUE key1 0 1", "A"]);
+}
+
+#[test]
+fn memcache_expirations_are_stored() {
+    let temp_dir = TempDir::new().unwrap();
+    let shutdown = start_server_in(temp_dir.path(), "127.0.0.1:4325", "127.0.0.1:4326");
+    let mut client = MemcacheClient::connect("127.0.0.1:4326");
+
+    assert_eq!(client.call("set short 0 1 1\r\na\r\n"), "STORED");
+    assert_eq!(client.call("set long 7 5 1\r\nb\r\n"), "STORED");
+    assert_eq!(client.call("set gone 0 0 1\r\nc\r\n"), "STORED");
+    // A negative expiration time removes the key rather than writing it.
+    assert_eq!(client.call("set gone 0 -1 1\r\nd\r\n"), "STORED");
+    assert_eq!(client.call("set never 0 -1 1\r\ne\r\n"), "STORED");
+    assert_eq!(client.retrieve("get gone never\r\n"), Vec::<String>::new());
+
+    // `short` expires without being read.
+    thread::sleep(Duration::from_millis(1500));
+    shutdown.shutdown();
+    thread::sleep(Duration::from_millis(500));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("short".to_owned()).unwrap(), None);
+    assert_eq!(store.get("gone".to_owned()).unwrap(), None);
+    assert_eq!(store.get("never".to_owned()).unwrap(), None);
+    assert_eq!(store.get("long".to_owned()).unwrap(), Some("b".to_owned()));
+    drop(store);
+
+    // The flags and expiration time of `long` outlive the server.
+    let _shutdown = start_server_in(temp_dir.path(), "127.0.0.1:4327", "127.0.0.1:4328");
+    let mut client = MemcacheClient::connect("127.0.0.1:4328");
+    assert_eq!(client.retrieve("get long\r\n"), vec!["VALUE long 7 1", "b"]);
+    assert_eq!(
+        KvsClient::new("127.0.0.1:4327")
+            .unwrap()
+            .info()
+            .unwrap()
+            .keys,
+        1
+    );
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(client.retrieve("get long\r\n"), Vec::<String>::new());
+}
+
+#[test]
+fn memcache_incr_decr() {
+    let _dir = start_server("127.0.0.1:4124", "127.0.0.1:4125");
+    let mut client = MemcacheClient::connect("127.0.0.1:4125");
+
+    assert_eq!(client.call("incr counter 1\r\n"), "NOT_FOUND");
+    assert_eq!(client.call("set counter 0 0 2\r\n10\r\n"), "STORED");
+    assert_eq!(client.call("incr counter 5\r\n"), "15");
+    assert_eq!(client.call("decr counter 20\r\n"), "0");
+    assert_eq!(
+        client.call("incr counter abc\r\n"),
+        "CLIENT_ERROR invalid numeric delta argument"
+    );
+    assert_eq!(client.call("set text 0 0 3\r\nabc\r\n"), "STORED");
+    assert_eq!(
+        client.call("incr text 1\r\n"),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value"
+    );
+}
+
+// Writes from other protocols change the CAS token.
+#[test]
+fn memcache_shares_engine_with_kvs_protocol() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4126", "127.0.0.1:4127");
+    let mut kvs = KvsClient::new("127.0.0.1:4126")?;
+    let mut client = MemcacheClient::connect("127.0.0.1:4127");
+
+    kvs.set("key1".to_owned(), "value1".to_owned())?;
+    let reply = client.retrieve("gets key1\r\n");
+    assert_eq!(reply[1], "value1");
+    let cas: u64 = reply[0].split_whitespace().nth(4).unwrap().parse().unwrap();
+
+    kvs.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        client.call(&format!("cas key1 0 0 1 {}\r\nx\r\n", cas)),
+        "EXISTS"
+    );
+
+    let stats = client.retrieve("stats\r\n");
+    assert!(stats.contains(&"STAT curr_items 1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer, Metrics, Protocol, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn scrape(addr: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp).unwrap();
+    let (head, body) = rsp.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    body.to_owned()
+}
+
+// Value of the sample with exactly this name and labels.
+fn sample(metrics: &str, series: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", series, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut server = KvsServer::new(engine, pool);
+    server.listen(Protocol::Metrics, "127.0.0.1:4201")?;
+    thread::spawn(move || server.run("127.0.0.1:4200"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::new("127.0.0.1:4200")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let metrics = scrape("127.0.0.1:4201");
+    assert_eq!(sample(&metrics, r#"kvs_ops_total{op="set"}"#), 2.0);
+    assert_eq!(sample(&metrics, r#"kvs_ops_total{op="get"}"#), 1.0);
+    assert_eq!(sample(&metrics, r#"kvs_op_errors_total{op="set"}"#), 0.0);
+    assert_eq!(sample(&metrics, r#"kvs_op_errors_total{op="remove"}"#), 1.0);
+    assert_eq!(
+        sample(
+            &metrics,
+            r#"kvs_op_duration_seconds_bucket{op="set",le="+Inf"}"#
+        ),
+        2.0
+    );
+    assert_eq!(
+        sample(&metrics, r#"kvs_op_duration_seconds_count{op="set"}"#),
+        2.0
+    );
+    // the client and the scrape itself
+    assert_eq!(sample(&metrics, "kvs_connections_active"), 2.0);
+    assert_eq!(sample(&metrics, "kvs_thread_pool_queue_depth"), 0.0);
+    assert!(sample(&metrics, "kvs_log_bytes") > 0.0);
+    assert!(sample(&metrics, "kvs_garbage_bytes") > 0.0);
+    assert!(metrics.contains("# TYPE kvs_op_duration_seconds histogram"));
+
+    drop(client);
+    let mut stream = TcpStream::connect("127.0.0.1:4201")?;
+    stream.write_all(b"GET /keys HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut rsp = String::new();
+    stream.read_to_string(&mut rsp)?;
+    assert!(rsp.starts_with("HTTP/1.1 404"));
+    Ok(())
+}
+
+#[test]
+fn compaction_metrics() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        compaction_threshold: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let metrics = Metrics::new();
+    store.set_metrics(metrics.clone());
+
+    for i in 0..1000 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    let rendered = metrics.render();
+    assert!(sample(&rendered, "kvs_compactions_total") >= 1.0);
+    assert!(sample(&rendered, "kvs_compaction_duration_seconds_count") >= 1.0);
+    assert!(sample(&rendered, "kvs_garbage_bytes") <= 4096.0);
+    assert!(sample(&rendered, "kvs_log_bytes") < 8192.0);
+    Ok(())
+}
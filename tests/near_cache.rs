@@ -0,0 +1,133 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Protocol, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, wait_for};
+
+// Get `key` until it has `value`, as the invalidation may take a moment.
+fn wait_for_value(client: &mut KvsClient, key: &str, value: Option<&str>) {
+    wait_for(|| client.get(key.to_owned()).unwrap().as_deref() == value);
+}
+
+#[test]
+fn near_cache_is_invalidated_by_writes() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4305", |_| {});
+    let mut writer = KvsClient::new("127.0.0.1:4305")?;
+    writer.set("conf:a".to_owned(), "1".to_owned())?;
+
+    let mut client = KvsClient::builder("127.0.0.1:4305").near_cache(2).build()?;
+    assert_eq!(client.get("conf:a".to_owned())?, Some("1".to_owned()));
+    for _ in 0..10 {
+        assert_eq!(client.get("conf:a".to_owned())?, Some("1".to_owned()));
+    }
+    let stats = client.cache_stats().unwrap();
+    assert!(stats.tracking);
+    assert_eq!((stats.hits, stats.misses, stats.entries), (10, 1, 1));
+
+    // written by another client
+    writer.set("conf:a".to_owned(), "2".to_owned())?;
+    wait_for_value(&mut client, "conf:a", Some("2"));
+    assert!(client.cache_stats().unwrap().invalidations >= 1);
+    writer.remove("conf:a".to_owned())?;
+    wait_for_value(&mut client, "conf:a", None);
+    // missing keys are cached too
+    let misses = client.cache_stats().unwrap().misses;
+    assert_eq!(client.get("conf:a".to_owned())?, None);
+    assert_eq!(client.cache_stats().unwrap().misses, misses);
+
+    // written by the client itself, seen at once
+    let invalidations = client.cache_stats().unwrap().invalidations;
+    client.set("conf:a".to_owned(), "3".to_owned())?;
+    assert_eq!(client.get("conf:a".to_owned())?, Some("3".to_owned()));
+    // and invalidated by the server too, which would keep the next values out
+    wait_for(|| client.cache_stats().unwrap().invalidations > invalidations);
+
+    // the least recently used values are dropped
+    client.get("conf:b".to_owned())?;
+    client.get("conf:c".to_owned())?;
+    assert_eq!(client.cache_stats().unwrap().entries, 2);
+    assert!(KvsClient::new("127.0.0.1:4305")?.info()?.connections_active >= 3);
+    Ok(())
+}
+
+#[test]
+fn near_cache_prefixes() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4306", |_| {});
+    let mut writer = KvsClient::new("127.0.0.1:4306")?;
+    writer.set("conf:a".to_owned(), "1".to_owned())?;
+    writer.set("user:a".to_owned(), "1".to_owned())?;
+
+    let mut client = KvsClient::builder("127.0.0.1:4306")
+        .near_cache(100)
+        .cache_prefixes(vec!["conf:".to_owned()])
+        .build()?;
+    for _ in 0..3 {
+        client.get("conf:a".to_owned())?;
+        client.get("user:a".to_owned())?;
+    }
+    let stats = client.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 4, 1));
+
+    // writes to other keys are not sent to the client
+    writer.set("user:a".to_owned(), "2".to_owned())?;
+    writer.set("conf:a".to_owned(), "2".to_owned())?;
+    wait_for_value(&mut client, "conf:a", Some("2"));
+    assert_eq!(client.cache_stats().unwrap().invalidations, 1);
+    assert_eq!(client.get("user:a".to_owned())?, Some("2".to_owned()));
+    assert!(KvsClient::new("127.0.0.1:4306")?.cache_stats().is_none());
+    Ok(())
+}
+
+#[test]
+fn near_cache_drops_expired_keys() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4329", |server| {
+        server.listen(Protocol::Resp, "127.0.0.1:4330").unwrap()
+    });
+    let mut resp = TcpStream::connect("127.0.0.1:4330")?;
+    let set = ["SET", "conf:a", "1", "PX", "500"];
+    let mut req = format!("*{}\r\n", set.len());
+    for arg in set {
+        req += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    resp.write_all(req.as_bytes())?;
+    let mut reply = [0; 5];
+    resp.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"+OK\r\n");
+
+    let mut client = KvsClient::builder("127.0.0.1:4329")
+        .near_cache(10)
+        .build()?;
+    assert_eq!(client.get("conf:a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(client.get("conf:a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(client.cache_stats().unwrap().hits, 1);
+
+    // removed by the server once expired, without anyone reading it
+    wait_for_value(&mut client, "conf:a", None);
+    assert!(client.cache_stats().unwrap().invalidations >= 1);
+    Ok(())
+}
+
+// Tracking connections are streamed to outside the thread pool, a server
+// with a worker for each of its clients still serves them.
+#[test]
+fn trackers_do_not_hold_workers() -> Result<()> {
+    let dir = TempDir::new()?;
+    let engine = KvStore::open(dir.path())?;
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run("127.0.0.1:4316"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::builder("127.0.0.1:4316")
+        .near_cache(10)
+        .build()?;
+    let mut writer = KvsClient::new("127.0.0.1:4316")?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.cache_stats().unwrap().tracking);
+    Ok(())
+}
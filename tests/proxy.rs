@@ -0,0 +1,164 @@
+use assert_cmd::prelude::*;
+use kvs::auth::Credentials;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{ConnectionLimits, Error, HashRing, KvsClient, KvsProxy, Result};
+use predicates::str::contains;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, TestServer};
+
+// Servers on `addrs`, kept until the returned directories are dropped.
+fn start_backends(addrs: &[&'static str], acl: Option<&str>) -> Vec<TempDir> {
+    let setup = |server: &mut TestServer| {
+        if let Some(acl) = acl {
+            server.set_acl(acl.parse().unwrap());
+        }
+    };
+    addrs.iter().map(|addr| start_server(addr, setup)).collect()
+}
+
+fn start_proxy(addr: &'static str, backends: &[&str], limits: ConnectionLimits) {
+    let ring = HashRing::new(backends.iter().copied()).unwrap();
+    let mut proxy = KvsProxy::new(ring, SharedQueueThreadPool::new(4).unwrap());
+    proxy.set_limits(limits);
+    thread::spawn(move || proxy.run(addr));
+    thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn proxy_routes_keys() -> Result<()> {
+    let backends = ["127.0.0.1:4270", "127.0.0.1:4271", "127.0.0.1:4272"];
+    let _dirs = start_backends(&backends, None);
+    start_proxy("127.0.0.1:4273", &backends, ConnectionLimits::default());
+
+    let mut client = KvsClient::new("127.0.0.1:4273")?;
+    for i in 0..50 {
+        client.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    assert_eq!(client.get("key07".to_owned())?, Some("value7".to_owned()));
+    client.remove("key00".to_owned())?;
+    assert!(client.remove("key00".to_owned()).is_err());
+
+    // every backend holds its share of the keys, and only it
+    let ring = HashRing::new(backends)?;
+    let mut total = 0;
+    for backend in backends {
+        let keys = KvsClient::new(backend)?.scan(String::new())?;
+        assert!(!keys.is_empty(), "no keys on {}", backend);
+        assert!(keys.iter().all(|key| ring.node(key) == backend));
+        total += keys.len();
+    }
+    assert_eq!(total, 49);
+
+    // requests on several keys are split and merged
+    let keys = ["key03", "key00", "key42", "missing", "key17"];
+    assert_eq!(
+        client.get_many(keys.iter().map(|key| key.to_string()).collect())?,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value42".to_owned()),
+            None,
+            Some("value17".to_owned())
+        ]
+    );
+    let expected: Vec<String> = (10..20).map(|i| format!("key{}", i)).collect();
+    assert_eq!(client.scan("key1".to_owned())?, expected);
+    assert_eq!(client.scan(String::new())?.len(), 49);
+    assert!(client.info().is_err());
+    Ok(())
+}
+
+#[test]
+fn proxy_authenticates_to_backends() -> Result<()> {
+    let backends = ["127.0.0.1:4274", "127.0.0.1:4275"];
+    let acl = "token app s3cret\nallow app read,write app/";
+    let _dirs = start_backends(&backends, Some(acl));
+    start_proxy("127.0.0.1:4276", &backends, ConnectionLimits::default());
+
+    let mut client = KvsClient::new("127.0.0.1:4276")?;
+    assert!(client
+        .set("app/key".to_owned(), "value".to_owned())
+        .is_err());
+    client.auth(Credentials::Token("s3cret".to_owned()))?;
+    for i in 0..10 {
+        client.set(format!("app/key{}", i), "value".to_owned())?;
+    }
+    assert!(client.set("other".to_owned(), "value".to_owned()).is_err());
+    assert_eq!(client.scan(String::new())?.len(), 10);
+
+    let mut client = KvsClient::new("127.0.0.1:4276")?;
+    assert!(client.auth(Credentials::Token("wrong".to_owned())).is_err());
+    Ok(())
+}
+
+#[test]
+fn reshard_moves_keys() -> Result<()> {
+    let old = ["127.0.0.1:4277", "127.0.0.1:4278"];
+    let new = ["127.0.0.1:4277", "127.0.0.1:4278", "127.0.0.1:4279"];
+    let _dirs = start_backends(&new, None);
+    start_proxy("127.0.0.1:4280", &old, ConnectionLimits::default());
+    let mut client = KvsClient::new("127.0.0.1:4280")?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let (old_ring, new_ring) = (HashRing::new(old)?, HashRing::new(new)?);
+    let expected = (0..100)
+        .filter(|i| old_ring.node(&format!("key{}", i)) != new_ring.node(&format!("key{}", i)))
+        .count();
+    Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(["reshard", "--from", &old.join(","), "--to", &new.join(",")])
+        .assert()
+        .success()
+        .stdout(contains(format!("{} keys moved", expected)));
+
+    // only the keys of the new backend moved, all of them
+    let moved = KvsClient::new(new[2])?.scan(String::new())?;
+    assert_eq!(moved.len(), expected);
+    for backend in new {
+        let keys = KvsClient::new(backend)?.scan(String::new())?;
+        assert!(keys.iter().all(|key| new_ring.node(key) == backend));
+    }
+    start_proxy("127.0.0.1:4281", &new, ConnectionLimits::default());
+    let mut client = KvsClient::new("127.0.0.1:4281")?;
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn proxy_limits_connections() -> Result<()> {
+    let backends = ["127.0.0.1:4313"];
+    let _dirs = start_backends(&backends, None);
+    let limits = ConnectionLimits {
+        max_connections: Some(2),
+        ..ConnectionLimits::default()
+    };
+    start_proxy("127.0.0.1:4314", &backends, limits);
+
+    let mut first = KvsClient::new("127.0.0.1:4314")?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let mut second = KvsClient::new("127.0.0.1:4314")?;
+    second.get("key1".to_owned())?;
+    let mut rejected = KvsClient::new("127.0.0.1:4314")?;
+    assert!(matches!(
+        rejected.get("key1".to_owned()),
+        Err(Error::ServerBusy)
+    ));
+
+    drop(second);
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::new("127.0.0.1:4314")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use kvs::raft::{Message, NodeId, RaftConfig, RaftNode, Role};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Nodes exchanging messages in memory, some of them cut off from the others.
+struct Network {
+    nodes: HashMap<NodeId, RaftNode<u64>>,
+    applied: HashMap<NodeId, Vec<u64>>,
+    isolated: HashSet<NodeId>,
+    queue: VecDeque<(NodeId, NodeId, Message<u64>)>,
+}
+
+impl Network {
+    fn new(count: u64) -> Self {
+        let ids: Vec<NodeId> = (1..=count).collect();
+        let nodes = ids
+            .iter()
+            .map(|&id| {
+                let config = RaftConfig::new(id, ids.clone());
+                (id, RaftNode::new(config, Default::default(), Vec::new(), 0))
+            })
+            .collect();
+        Network {
+            nodes,
+            applied: ids.iter().map(|&id| (id, Vec::new())).collect(),
+            isolated: HashSet::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn tick(&mut self) {
+        for node in self.nodes.values_mut() {
+            node.tick();
+        }
+        self.deliver();
+    }
+
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    // Handles what every node has ready until no message is left in flight.
+    fn deliver(&mut self) {
+        loop {
+            for (&id, node) in self.nodes.iter_mut() {
+                let ready = node.ready();
+                for (to, msg) in ready.messages {
+                    self.queue.push_back((id, to, msg));
+                }
+                let applied = self.applied.get_mut(&id).unwrap();
+                applied.extend(ready.committed.into_iter().filter_map(|e| e.command));
+            }
+            if self.queue.is_empty() {
+                return;
+            }
+            while let Some((from, to, msg)) = self.queue.pop_front() {
+                if !self.isolated.contains(&from) && !self.isolated.contains(&to) {
+                    self.nodes.get_mut(&to).unwrap().step(from, msg);
+                }
+            }
+        }
+    }
+
+    fn leaders(&self) -> Vec<NodeId> {
+        let mut leaders: Vec<NodeId> = self
+            .nodes
+            .values()
+            .filter(|node| node.role() == Role::Leader && !self.isolated.contains(&node.id()))
+            .map(|node| node.id())
+            .collect();
+        leaders.sort_unstable();
+        leaders
+    }
+
+    fn leader(&mut self) -> NodeId {
+        for _ in 0..100 {
+            if let [leader] = self.leaders()[..] {
+                return leader;
+            }
+            self.tick();
+        }
+        panic!("no leader elected");
+    }
+
+    fn propose(&mut self, id: NodeId, command: u64) {
+        self.nodes.get_mut(&id).unwrap().propose(command).unwrap();
+        self.deliver();
+    }
+}
+
+#[test]
+fn elects_one_leader() {
+    let mut network = Network::new(3);
+    let leader = network.leader();
+    network.run(50);
+    assert_eq!(network.leaders(), vec![leader]);
+    let term = network.nodes[&leader].term();
+    for node in network.nodes.values() {
+        assert_eq!(node.term(), term);
+        assert_eq!(node.leader(), Some(leader));
+    }
+}
+
+#[test]
+fn replicates_commands() {
+    let mut network = Network::new(5);
+    let leader = network.leader();
+    for command in 1..=10 {
+        network.propose(leader, command);
+    }
+    network.run(5);
+    let expected: Vec<u64> = (1..=10).collect();
+    for (id, applied) in &network.applied {
+        assert_eq!(applied, &expected, "node {}", id);
+    }
+
+    // followers tell where the leader is
+    let follower = (1..=5).find(|&id| id != leader).unwrap();
+    let node = network.nodes.get_mut(&follower).unwrap();
+    assert_eq!(node.propose(11), Err(Some(leader)));
+}
+
+#[test]
+fn reelects_after_leader_failure() {
+    let mut network = Network::new(3);
+    let old = network.leader();
+    network.propose(old, 1);
+    network.run(5);
+
+    network.isolated.insert(old);
+    // not committed without a majority, and discarded later
+    network.propose(old, 2);
+    let new = network.leader();
+    assert_ne!(new, old);
+    network.propose(new, 3);
+    network.run(5);
+    assert_eq!(network.nodes[&old].role(), Role::Leader);
+    assert_eq!(network.applied[&old], vec![1]);
+
+    network.isolated.clear();
+    network.run(50);
+    assert_eq!(network.leaders(), vec![new]);
+    for (id, applied) in &network.applied {
+        assert_eq!(applied, &vec![1, 3], "node {}", id);
+    }
+}
+
+#[test]
+fn minority_cannot_commit() {
+    let mut network = Network::new(5);
+    let leader = network.leader();
+    network.propose(leader, 1);
+    network.run(5);
+
+    let others: Vec<NodeId> = (1..=5).filter(|&id| id != leader).collect();
+    network.isolated.extend(&others[..3]);
+    network.propose(leader, 2);
+    network.run(50);
+    assert_eq!(network.applied[&leader], vec![1]);
+
+    // the others stood for election meanwhile, the leader steps down on hearing of it
+    network.isolated.clear();
+    network.run(50);
+    let leader = network.leader();
+    network.propose(leader, 3);
+    network.run(50);
+    for applied in network.applied.values() {
+        assert_eq!(applied.last(), Some(&3));
+        assert!(!applied.contains(&2) || applied == &vec![1, 2, 3]);
+    }
+}
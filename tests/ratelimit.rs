@@ -0,0 +1,160 @@
+use kvs::auth::Credentials;
+use kvs::{Error, KvStore, KvsClient, KvsEngine, Protocol, Quota, RateLimits, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, start_server_in};
+
+#[test]
+fn ops_per_sec() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4240", |server| {
+        server.set_rate_limits(RateLimits {
+            ops_per_sec: Some(5),
+            ..RateLimits::default()
+        })
+    });
+    let mut client = KvsClient::new("127.0.0.1:4240")?;
+    // one second of requests in a burst
+    for _ in 0..5 {
+        client.get("key1".to_owned())?;
+    }
+    let retry_after = match client.get("key1".to_owned()) {
+        Err(Error::RateLimited(retry_after)) => retry_after,
+        res => panic!("expected rate limited, got {:?}", res),
+    };
+    assert!(retry_after > Duration::ZERO);
+    assert!(retry_after <= Duration::from_millis(200));
+
+    // the connection stays usable once the client backs off
+    thread::sleep(retry_after);
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    // the limit is per client, not per connection
+    let mut other = KvsClient::new("127.0.0.1:4240")?;
+    assert!(matches!(
+        other.get("key1".to_owned()),
+        Err(Error::RateLimited(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn principals_have_their_own_limits() -> Result<()> {
+    let acl = "token a t0ken-a\ntoken b t0ken-b\nallow a read *\nallow b read *";
+    let _dir = start_server("127.0.0.1:4241", |server| {
+        server.set_acl(acl.parse().unwrap());
+        server.set_rate_limits(RateLimits {
+            ops_per_sec: Some(2),
+            ..RateLimits::default()
+        })
+    });
+    let mut a = KvsClient::new("127.0.0.1:4241")?;
+    a.auth(Credentials::Token("t0ken-a".to_owned()))?;
+    a.get("key1".to_owned())?;
+    a.get("key1".to_owned())?;
+    assert!(matches!(
+        a.get("key1".to_owned()),
+        Err(Error::RateLimited(_))
+    ));
+
+    let mut b = KvsClient::new("127.0.0.1:4241")?;
+    b.auth(Credentials::Token("t0ken-b".to_owned()))?;
+    assert_eq!(b.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn bytes_per_sec() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4242", |server| {
+        server.set_rate_limits(RateLimits {
+            bytes_per_sec: Some(100),
+            ..RateLimits::default()
+        })
+    });
+    let mut client = KvsClient::new("127.0.0.1:4242")?;
+    client.set("key1".to_owned(), "v".repeat(76))?;
+    match client.set("key2".to_owned(), "v".repeat(76)) {
+        Err(Error::RateLimited(retry_after)) => {
+            assert!(retry_after >= Duration::from_millis(500));
+            assert!(retry_after <= Duration::from_secs(1));
+        }
+        res => panic!("expected rate limited, got {:?}", res),
+    }
+    // a small request fits in what is left
+    client.get("key1".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn quotas() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    {
+        let store = KvStore::open(temp_dir.path())?;
+        // 15 bytes under app/ before the server starts
+        store.set("app/a".to_owned(), "0123456789".to_owned())?;
+        store.set("other".to_owned(), "0123456789".to_owned())?;
+    }
+    start_server_in("127.0.0.1:4243", temp_dir.path(), |server| {
+        let quota = Quota {
+            prefix: "app/".to_owned(),
+            max_bytes: 32,
+        };
+        server.set_quotas(vec![quota]).unwrap();
+    });
+
+    let mut client = KvsClient::new("127.0.0.1:4243")?;
+    client.set("app/b".to_owned(), "01234".to_owned())?;
+    // 15 + 10 + 15 > 32
+    assert!(client
+        .set("app/c".to_owned(), "0123456789".to_owned())
+        .is_err());
+    assert_eq!(client.get("app/c".to_owned())?, None);
+    // other prefixes are not limited
+    client.set("other".to_owned(), "v".repeat(100))?;
+
+    // overwriting with a smaller value and removing free space
+    client.set("app/a".to_owned(), "0".to_owned())?;
+    client.set("app/c".to_owned(), "0123456789".to_owned())?;
+    client.remove("app/c".to_owned())?;
+    client.set("app/d".to_owned(), "0123456789".to_owned())?;
+    assert!(client.set("app/e".to_owned(), "0123".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn other_protocols() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4244", |server| {
+        server.set_rate_limits(RateLimits {
+            ops_per_sec: Some(1),
+            ..RateLimits::default()
+        });
+        server.listen(Protocol::Http, "127.0.0.1:4245").unwrap();
+        server.listen(Protocol::Resp, "127.0.0.1:4246").unwrap();
+    });
+
+    let http_get = || {
+        let mut stream = TcpStream::connect("127.0.0.1:4245").unwrap();
+        stream
+            .write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut rsp = String::new();
+        stream.read_to_string(&mut rsp).unwrap();
+        rsp
+    };
+    assert!(http_get().starts_with("HTTP/1.1 404"));
+    let rsp = http_get();
+    assert!(rsp.starts_with("HTTP/1.1 429 Too Many Requests"));
+    assert!(rsp.contains("Retry-After: 1\r\n"));
+
+    // the client is the same address for every protocol
+    let mut conn = BufReader::new(TcpStream::connect("127.0.0.1:4246")?);
+    conn.get_mut().write_all(b"GET key1\r\n")?;
+    let mut line = String::new();
+    conn.read_line(&mut line)?;
+    assert!(line.starts_with("-RATELIMITED retry in "));
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use kvs::auth::Credentials;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server_in, wait_for};
+
+#[test]
+fn follower_applies_writes() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let chained_dir = TempDir::new()?;
+    start_server_in("127.0.0.1:4250", leader_dir.path(), |server| {
+        server.set_open_admin(true)
+    });
+    start_server_in("127.0.0.1:4251", follower_dir.path(), |server| {
+        server.set_open_admin(true);
+        server.follow("127.0.0.1:4250", None)
+    });
+    // a follower can be followed in turn
+    start_server_in("127.0.0.1:4252", chained_dir.path(), |server| {
+        server.follow("127.0.0.1:4251", None)
+    });
+
+    let mut leader = KvsClient::new("127.0.0.1:4250")?;
+    let mut follower = KvsClient::new("127.0.0.1:4251")?;
+    let mut chained = KvsClient::new("127.0.0.1:4252")?;
+    for i in 0..10 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    leader.remove("key0".to_owned())?;
+    wait_for(|| follower.get("key9".to_owned()).unwrap().is_some());
+    wait_for(|| chained.get("key9".to_owned()).unwrap().is_some());
+    for client in [&mut follower, &mut chained] {
+        assert_eq!(client.get("key0".to_owned())?, None);
+        assert_eq!(client.get("key5".to_owned())?, Some("value5".to_owned()));
+    }
+
+    // followers are read-only
+    assert!(follower.set("key1".to_owned(), "other".to_owned()).is_err());
+    assert!(follower.remove("key1".to_owned()).is_err());
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let info = leader.info()?.replication;
+    assert_eq!(info.log_seq, 11);
+    assert_eq!(info.followers, 1);
+    assert_eq!(info.leader, None);
+
+    wait_for(|| follower.info().unwrap().replication.lag_entries == 0);
+    let info = follower.info()?.replication;
+    assert_eq!(info.leader.as_deref(), Some("127.0.0.1:4250"));
+    assert!(info.connected);
+    assert_eq!(info.applied_seq, 11);
+    assert_eq!(info.followers, 1);
+    assert!(info.last_contact_ms.unwrap() < 5000);
+    Ok(())
+}
+
+#[test]
+fn follower_bootstraps_from_snapshot() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    {
+        // data of the follower from before is replaced
+        let store = KvStore::open(follower_dir.path())?;
+        store.set("stale".to_owned(), "value".to_owned())?;
+        store.set("key1".to_owned(), "old".to_owned())?;
+    }
+    start_server_in("127.0.0.1:4253", leader_dir.path(), |server| {
+        server.set_open_admin(true);
+        server.set_replication_log(2)
+    });
+    let mut leader = KvsClient::new("127.0.0.1:4253")?;
+    for i in 0..20 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    start_server_in("127.0.0.1:4254", follower_dir.path(), |server| {
+        server.follow("127.0.0.1:4253", None)
+    });
+    let mut follower = KvsClient::new("127.0.0.1:4254")?;
+    wait_for(|| follower.info().unwrap().replication.applied_seq == 20);
+    assert_eq!(follower.get("stale".to_owned())?, None);
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.info()?.keys, 20);
+
+    // writes after the snapshot are streamed
+    leader.set("key1".to_owned(), "new".to_owned())?;
+    wait_for(|| follower.get("key1".to_owned()).unwrap() == Some("new".to_owned()));
+    assert_eq!(follower.info()?.replication.applied_seq, 21);
+    Ok(())
+}
+
+#[test]
+fn follower_authenticates() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let follower_dir = TempDir::new()?;
+    let reader_dir = TempDir::new()?;
+    let acl = "token replica r3plica\ntoken reader r3ader\n\
+               allow replica admin *\nallow reader read *";
+    start_server_in("127.0.0.1:4255", leader_dir.path(), |server| {
+        server.set_acl(acl.parse().unwrap())
+    });
+    start_server_in("127.0.0.1:4256", follower_dir.path(), |server| {
+        server.follow(
+            "127.0.0.1:4255",
+            Some(Credentials::Token("r3plica".to_owned())),
+        )
+    });
+    // reading is not enough to replicate
+    start_server_in("127.0.0.1:4257", reader_dir.path(), |server| {
+        server.follow(
+            "127.0.0.1:4255",
+            Some(Credentials::Token("r3ader".to_owned())),
+        )
+    });
+
+    let mut leader = KvsClient::new("127.0.0.1:4255")?;
+    leader.auth(Credentials::Token("r3plica".to_owned()))?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut follower = KvsClient::new("127.0.0.1:4256")?;
+    wait_for(|| follower.get("key1".to_owned()).unwrap().is_some());
+    assert!(follower.info()?.replication.connected);
+
+    let mut reader = KvsClient::new("127.0.0.1:4257")?;
+    let info = reader.info()?.replication;
+    assert!(!info.connected);
+    assert_eq!(info.last_contact_ms, None);
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Followers are streamed to outside the thread pool, a leader with a
+// single worker still serves its clients.
+#[test]
+fn followers_do_not_hold_workers() -> Result<()> {
+    let leader_dir = TempDir::new()?;
+    let engine = KvStore::open(leader_dir.path())?;
+    let mut leader = KvsServer::new(engine, SharedQueueThreadPool::new(1)?);
+    leader.set_open_admin(true);
+    thread::spawn(move || leader.run("127.0.0.1:4258"));
+    thread::sleep(Duration::from_millis(500));
+    let dirs = [TempDir::new()?, TempDir::new()?];
+    for (dir, addr) in dirs.iter().zip(["127.0.0.1:4259", "127.0.0.1:4310"]) {
+        start_server_in(addr, dir.path(), |server| {
+            server.follow("127.0.0.1:4258", None)
+        });
+    }
+
+    let mut client = KvsClient::new("127.0.0.1:4258")?;
+    wait_for(|| client.info().unwrap().replication.followers == 2);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut follower = KvsClient::new("127.0.0.1:4310")?;
+    wait_for(|| follower.get("key1".to_owned()).unwrap().is_some());
+    Ok(())
+}
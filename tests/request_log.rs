@@ -0,0 +1,70 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, Result};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Runs a server with `args`, sends it a few requests and returns its log.
+fn server_log(addr: &str, rust_log: Option<&str>, args: &[&str]) -> Result<String> {
+    let temp_dir = TempDir::new()?;
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--addr", addr])
+        .args(args)
+        .env_remove("RUST_LOG")
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped());
+    if let Some(rust_log) = rust_log {
+        cmd.env("RUST_LOG", rust_log);
+    }
+    let mut child = cmd.spawn()?;
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "s3cret-value".to_owned())?;
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("s3cret-value".to_owned())
+    );
+    drop(client);
+    // the slow log is written after the response
+    thread::sleep(Duration::from_millis(200));
+
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output()?;
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn slow_requests() -> Result<()> {
+    let log = server_log("127.0.0.1:4230", None, &["--slow-threshold", "0"])?;
+    assert!(log.contains(r#"slow request set "key1" <12 bytes> took "#));
+    assert!(log.contains(r#"slow request get "key1" took "#));
+    assert!(log.contains("(queue "));
+    assert!(log.contains(", engine "));
+    assert!(!log.contains("s3cret-value"));
+
+    let log = server_log("127.0.0.1:4231", None, &["--slow-threshold", "60000"])?;
+    assert!(!log.contains("slow request"));
+    let log = server_log("127.0.0.1:4232", None, &[])?;
+    assert!(!log.contains("slow request"));
+    Ok(())
+}
+
+#[test]
+fn values_redacted_at_debug() -> Result<()> {
+    let log = server_log("127.0.0.1:4233", Some("kvs=debug"), &[])?;
+    assert!(log.contains(r#"req set "key1" <12 bytes>"#));
+    assert!(log.contains("rsp ok <12 bytes>"));
+    assert!(!log.contains("s3cret-value"));
+
+    let log = server_log(
+        "127.0.0.1:4234",
+        Some("kvs=debug"),
+        &["--log-values", "--slow-threshold", "0"],
+    )?;
+    assert!(log.contains(r#"req set "key1" "s3cret-value""#));
+    assert!(log.contains(r#"rsp ok "s3cret-value""#));
+    assert!(log.contains(r#"slow request set "key1" "s3cret-value" took "#));
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use kvs::{KvsClient, Protocol, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+
+// A minimal RESP2 client, so the tests do not depend on `redis-cli`.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> RespClient {
+        let stream = TcpStream::connect(addr).unwrap();
+        RespClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> Value {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(buf.as_bytes()).unwrap();
+        self.read_value()
+    }
+
+    fn read_value(&mut self) -> Value {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Value::Simple(rest.to_owned()),
+            "-" => Value::Error(rest.to_owned()),
+            ":" => Value::Integer(rest.parse().unwrap()),
+            "$" => {
+                let len: i64 = rest.parse().unwrap();
+                if len < 0 {
+                    return Value::Bulk(None);
+                }
+                let mut buf = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                buf.truncate(len as usize);
+                Value::Bulk(Some(String::from_utf8(buf).unwrap()))
+            }
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                Value::Array((0..len).map(|_| self.read_value()).collect())
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+fn bulk(s: &str) -> Value {
+    Value::Bulk(Some(s.to_owned()))
+}
+
+fn start_server(addr: &'static str, resp_addr: &'static str) -> TempDir {
+    common::start_server(addr, |server| {
+        server.listen(Protocol::Resp, resp_addr).unwrap()
+    })
+}
+
+#[test]
+fn resp_basic_commands() {
+    let _dir = start_server("127.0.0.1:4100", "127.0.0.1:4101");
+    let mut client = RespClient::connect("127.0.0.1:4101");
+
+    assert_eq!(client.call(&["PING"]), Value::Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(client.call(&["GET", "key1"]), Value::Bulk(None));
+    assert_eq!(
+        client.call(&["SET", "key1", "value1"]),
+        Value::Simple("OK".to_owned())
+    );
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(
+        client.call(&["SET", "key2", "value2"]),
+        Value::Simple("OK".to_owned())
+    );
+    assert_eq!(
+        client.call(&["MGET", "key1", "missing", "key2"]),
+        Value::Array(vec![bulk("value1"), Value::Bulk(None), bulk("value2")])
+    );
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key2", "missing"]),
+        Value::Integer(2)
+    );
+    assert_eq!(client.call(&["DEL", "key1", "missing"]), Value::Integer(1));
+    assert_eq!(client.call(&["GET", "key1"]), Value::Bulk(None));
+
+    match client.call(&["GET"]) {
+        Value::Error(e) => assert!(e.contains("wrong number of arguments")),
+        v => panic!("unexpected reply {:?}", v),
+    }
+    match client.call(&["FLUSHALL"]) {
+        Value::Error(e) => assert!(e.contains("unknown command")),
+        v => panic!("unexpected reply {:?}", v),
+    }
+    match client.call(&["INFO"]) {
+        Value::Bulk(Some(info)) => {
+            assert!(info.contains(env!("CARGO_PKG_VERSION")));
+            assert!(info.contains("keys:1"));
+        }
+        v => panic!("unexpected reply {:?}", v),
+    }
+}
+
+#[test]
+fn resp_set_options() {
+    let _dir = start_server("127.0.0.1:4102", "127.0.0.1:4103");
+    let mut client = RespClient::connect("127.0.0.1:4103");
+
+    assert_eq!(
+        client.call(&["SET", "key", "v1", "NX"]),
+        Value::Simple("OK".to_owned())
+    );
+    assert_eq!(client.call(&["SET", "key", "v2", "NX"]), Value::Bulk(None));
+    assert_eq!(client.call(&["GET", "key"]), bulk("v1"));
+    assert_eq!(client.call(&["SET", "other", "v", "XX"]), Value::Bulk(None));
+    assert_eq!(client.call(&["GET", "other"]), Value::Bulk(None));
+
+    assert_eq!(
+        client.call(&["SET", "key", "v3", "PX", "200"]),
+        Value::Simple("OK".to_owned())
+    );
+    assert_eq!(client.call(&["GET", "key"]), bulk("v3"));
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.call(&["GET", "key"]), Value::Bulk(None));
+    assert_eq!(client.call(&["EXISTS", "key"]), Value::Integer(0));
+    assert_eq!(
+        client.call(&["SET", "key", "v4", "NX", "EX", "10"]),
+        Value::Simple("OK".to_owned())
+    );
+
+    match client.call(&["SET", "key", "v", "EX", "zero"]) {
+        Value::Error(e) => assert!(e.contains("invalid expire time")),
+        v => panic!("unexpected reply {:?}", v),
+    }
+}
+
+#[test]
+fn resp_scan() {
+    let _dir = start_server("127.0.0.1:4104", "127.0.0.1:4105");
+    let mut client = RespClient::connect("127.0.0.1:4105");
+    for i in 0..25 {
+        client.call(&["SET", &format!("user:{:02}", i), "v"]);
+    }
+    client.call(&["SET", "other", "v"]);
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let reply = client.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "10"]);
+        match reply {
+            Value::Array(mut items) => {
+                let page = items.pop().unwrap();
+                cursor = match items.pop().unwrap() {
+                    Value::Bulk(Some(c)) => c,
+                    v => panic!("unexpected cursor {:?}", v),
+                };
+                match page {
+                    Value::Array(page) => keys.extend(page),
+                    v => panic!("unexpected page {:?}", v),
+                }
+            }
+            v => panic!("unexpected reply {:?}", v),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<Value> = (0..25).map(|i| bulk(&format!("user:{:02}", i))).collect();
+    assert_eq!(keys, expected);
+
+    match client.call(&["SCAN", "0", "MATCH", "user:1?", "COUNT", "100"]) {
+        Value::Array(items) => assert_eq!(
+            items[1],
+            Value::Array((10..20).map(|i| bulk(&format!("user:{}", i))).collect())
+        ),
+        v => panic!("unexpected reply {:?}", v),
+    }
+}
+
+// Patterns are matched without backtracking over every `*`, long ones are refused.
+#[test]
+fn resp_scan_patterns() {
+    let _dir = start_server("127.0.0.1:4321", "127.0.0.1:4322");
+    let mut client = RespClient::connect("127.0.0.1:4322");
+    let key = "a".repeat(64);
+    client.call(&["SET", &key, "v"]);
+    client.call(&["SET", "[x]\\", "v"]);
+
+    let page = |client: &mut RespClient, pattern: &str| match client
+        .call(&["SCAN", "0", "MATCH", pattern, "COUNT", "100"])
+    {
+        Value::Array(mut items) => items.pop().unwrap(),
+        v => panic!("unexpected reply {:?}", v),
+    };
+    let start = Instant::now();
+    let pattern = format!("{}b", "a*".repeat(30));
+    assert_eq!(page(&mut client, &pattern), Value::Array(vec![]));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let pattern = "*".repeat(1000);
+    assert_eq!(
+        page(&mut client, &pattern),
+        Value::Array(vec![bulk("[x]\\"), bulk(&key)])
+    );
+    assert_eq!(
+        page(&mut client, "\\[[w-y]]\\"),
+        Value::Array(vec![bulk("[x]\\")])
+    );
+    assert_eq!(page(&mut client, "a*a?"), Value::Array(vec![bulk(&key)]));
+
+    match client.call(&["SCAN", "0", "MATCH", &"*".repeat(200_000)]) {
+        Value::Error(e) => assert!(e.contains("pattern too long")),
+        v => panic!("unexpected reply {:?}", v),
+    }
+}
+
+// Keys written through RESP are visible to `KvsClient`, and the other way around.
+#[test]
+fn resp_shares_engine_with_kvs_protocol() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4106", "127.0.0.1:4107");
+    let mut resp = RespClient::connect("127.0.0.1:4107");
+    let mut client = KvsClient::new("127.0.0.1:4106")?;
+
+    resp.call(&["SET", "key1", "value1"]);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(resp.call(&["GET", "key2"]), bulk("value2"));
+
+    // inline commands
+    resp.writer.write_all(b"PING\r\n").unwrap();
+    assert_eq!(resp.read_value(), Value::Simple("PONG".to_owned()));
+    Ok(())
+}
+
+// Frames declaring huge sizes are refused rather than allocated.
+#[test]
+fn resp_rejects_oversized_frames() {
+    let _dir = start_server("127.0.0.1:4108", "127.0.0.1:4109");
+    for frame in [
+        &b"*99999999999999\r\n"[..],
+        b"*1\r\n$99999999999999\r\n",
+        &[b'a'; 64 * 1024],
+    ] {
+        let mut resp = RespClient::connect("127.0.0.1:4109");
+        resp.writer.write_all(frame).unwrap();
+        match resp.read_value() {
+            Value::Error(e) => assert!(e.starts_with("ERR Protocol error"), "{}", e),
+            v => panic!("unexpected reply {:?}", v),
+        }
+    }
+    let mut resp = RespClient::connect("127.0.0.1:4109");
+    assert_eq!(resp.call(&["PING"]), Value::Simple("PONG".to_owned()));
+}
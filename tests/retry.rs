@@ -0,0 +1,179 @@
+use kvs::{KvsClient, Result, RetryPolicy};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+mod common;
+use common::{start_server, Process};
+
+// Accepts connections and never answers, returns how many were accepted.
+fn silent_server(addr: &'static str) -> Arc<AtomicUsize> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&accepted);
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+        for stream in listener.incoming() {
+            count.fetch_add(1, Ordering::SeqCst);
+            streams.push(stream);
+        }
+    });
+    accepted
+}
+
+#[test]
+fn client_reconnects_after_restart() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().to_path_buf();
+    let spawn = move || Process::spawn(&["--addr", "127.0.0.1:4300"], &path);
+    let process = spawn.clone()();
+    thread::sleep(Duration::from_secs(1));
+
+    let policy = RetryPolicy {
+        max_retries: 10,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(500),
+        jitter: true,
+    };
+    let mut client = KvsClient::builder("127.0.0.1:4300")
+        .connect_timeout(Duration::from_secs(1))
+        .retry(policy)
+        .build()?;
+    let mut plain = KvsClient::new("127.0.0.1:4300")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the server comes back while the client is still retrying
+    drop(process);
+    let restart = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        spawn()
+    });
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(plain.get("key1".to_owned()).is_err());
+    let _process = restart.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn client_timeouts() -> Result<()> {
+    let accepted = silent_server("127.0.0.1:4301");
+    let start = Instant::now();
+    let mut client = KvsClient::builder("127.0.0.1:4301")
+        .read_timeout(Duration::from_millis(200))
+        .retry(RetryPolicy::none())
+        .build()?;
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // reads are retried over new connections
+    let policy = RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(10),
+        ..RetryPolicy::default()
+    };
+    let before = accepted.load(Ordering::SeqCst);
+    let mut client = KvsClient::builder("127.0.0.1:4301")
+        .read_timeout(Duration::from_millis(200))
+        .retry(policy)
+        .build()?;
+    assert!(client.get("key1".to_owned()).is_err());
+    assert_eq!(accepted.load(Ordering::SeqCst) - before, 3);
+
+    // writes without a request id are not
+    let before = accepted.load(Ordering::SeqCst);
+    let mut client = KvsClient::builder("127.0.0.1:4301")
+        .read_timeout(Duration::from_millis(200))
+        .retry(policy)
+        .request_ids(false)
+        .build()?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(accepted.load(Ordering::SeqCst) - before, 1);
+    let before = accepted.load(Ordering::SeqCst);
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(accepted.load(Ordering::SeqCst) - before, 1);
+
+    // nothing listens there, connecting is retried with a backoff
+    let start = Instant::now();
+    let res = KvsClient::builder("127.0.0.1:4302")
+        .connect_timeout(Duration::from_millis(200))
+        .retry(RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            jitter: false,
+            ..policy
+        })
+        .build();
+    assert!(res.is_err());
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2),
+        "{:?}",
+        elapsed
+    );
+    Ok(())
+}
+
+#[test]
+fn rate_limit_wait_is_capped() -> Result<()> {
+    // answers every request with a wait of a minute
+    let listener = TcpListener::bind("127.0.0.1:4317")?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || {
+                let reader = serde_json::Deserializer::from_reader(stream.try_clone().unwrap());
+                let mut writer = stream;
+                for _ in reader.into_iter::<Value>() {
+                    let rsp = json!({"body": {"RateLimited": {"retry_after_ms": 60_000}}});
+                    if write!(writer, "{}", rsp).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let start = Instant::now();
+    let mut client = KvsClient::builder("127.0.0.1:4317")
+        .retry(RetryPolicy {
+            max_retries: 2,
+            max_backoff: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        })
+        .build()?;
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+#[test]
+fn repeated_request_id_is_served_once() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4303", |_| {});
+    KvsClient::new("127.0.0.1:4303")?.set("key1".to_owned(), "value1".to_owned())?;
+
+    let stream = TcpStream::connect("127.0.0.1:4303")?;
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+    let mut writer = stream;
+    let mut call = |seq: u64| -> Result<Value> {
+        let req = json!({"WithId": {
+            "id": {"client": "c1", "seq": seq},
+            "request": {"Remove": {"key": "key1"}},
+        }});
+        writeln!(writer, "{}", req)?;
+        Ok(Value::deserialize(&mut reader)?)
+    };
+    let ok = json!({"body": {"Ok": null}});
+    assert_eq!(call(1)?, ok);
+    // a retry gets the first response, rather than a missing key
+    assert_eq!(call(1)?, ok);
+    assert_eq!(call(2)?, json!({"body": {"Err": "Key not found"}}));
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Protocol, Result};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+#[test]
+fn shutdown_handle_stops_server() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut server = KvsServer::new(engine, pool);
+    server.listen(Protocol::Resp, "127.0.0.1:4161")?;
+    server.set_shutdown_timeout(Duration::from_secs(5));
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run("127.0.0.1:4160"));
+    thread::sleep(Duration::from_millis(500));
+
+    // an idle connection does not hold up the shutdown
+    let mut client = KvsClient::new("127.0.0.1:4160")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let start = Instant::now();
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(shutdown.is_shutdown());
+
+    assert!(TcpStream::connect("127.0.0.1:4160").is_err());
+    assert!(TcpStream::connect("127.0.0.1:4161").is_err());
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4162"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4162"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
@@ -0,0 +1,229 @@
+use assert_cmd::prelude::*;
+use kvs::net::Listener;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{tls, KvStore, KvsClient, KvsServer, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.self_signed(&key).unwrap();
+        Ca { cert, key }
+    }
+
+    // Issues a certificate, writes `<name>.pem` and `<name>.key` into `dir`.
+    fn issue(&self, dir: &Path, name: &str, usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let mut params =
+            CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn write(&self, dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(format!("{}.pem", name));
+        fs::write(&path, self.cert.pem()).unwrap();
+        path
+    }
+}
+
+fn start_server(addr: &'static str, dir: &Path, config: Arc<tls::TlsServerConfig>) {
+    let engine = KvStore::open(dir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let mut server = KvsServer::new(engine, pool);
+    let listener = Listener::bind_tls(addr, config).unwrap();
+    thread::spawn(move || server.run_listener(listener));
+    thread::sleep(Duration::from_millis(300));
+}
+
+#[test]
+fn tls_client_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test ca");
+    let ca_path = ca.write(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(
+        temp_dir.path(),
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    start_server(
+        "127.0.0.1:4140",
+        temp_dir.path(),
+        tls::server_config(&cert, &key, None)?,
+    );
+
+    let config = tls::client_config(&ca_path, None)?;
+    let mut client = KvsClient::connect_tls("127.0.0.1:4140", "localhost", config.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // the certificate is also valid for the IP address
+    let mut client = KvsClient::connect_tls("127.0.0.1:4140", "127.0.0.1", config.clone())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // but not for another name
+    assert!(KvsClient::connect_tls("127.0.0.1:4140", "example.com", config).is_err());
+
+    // a client trusting another CA rejects the server
+    let other_ca = Ca::new("other ca").write(temp_dir.path(), "other-ca");
+    let config = tls::client_config(&other_ca, None)?;
+    assert!(KvsClient::connect_tls("127.0.0.1:4140", "localhost", config).is_err());
+
+    // a plaintext client cannot talk to a TLS server
+    let mut client = KvsClient::new("127.0.0.1:4140")?;
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn tls_client_auth() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test ca");
+    let ca_path = ca.write(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(
+        temp_dir.path(),
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let (client_cert, client_key) = ca.issue(
+        temp_dir.path(),
+        "client",
+        ExtendedKeyUsagePurpose::ClientAuth,
+    );
+    start_server(
+        "127.0.0.1:4141",
+        temp_dir.path(),
+        tls::server_config(&cert, &key, Some(&ca_path))?,
+    );
+
+    let config = tls::client_config(&ca_path, Some((&client_cert, &client_key)))?;
+    let mut client = KvsClient::connect_tls("127.0.0.1:4141", "localhost", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // without a client certificate, the server refuses the connection
+    let config = tls::client_config(&ca_path, None)?;
+    let rejected = match KvsClient::connect_tls("127.0.0.1:4141", "localhost", config) {
+        Ok(mut client) => client.get("key1".to_owned()).is_err(),
+        Err(_) => true,
+    };
+    assert!(rejected);
+
+    // nor with a certificate from an unknown CA
+    let other_ca = Ca::new("other ca");
+    let (other_cert, other_key) = other_ca.issue(
+        temp_dir.path(),
+        "other",
+        ExtendedKeyUsagePurpose::ClientAuth,
+    );
+    let config = tls::client_config(&ca_path, Some((&other_cert, &other_key)))?;
+    let rejected = match KvsClient::connect_tls("127.0.0.1:4141", "localhost", config) {
+        Ok(mut client) => client.get("key1".to_owned()).is_err(),
+        Err(_) => true,
+    };
+    assert!(rejected);
+    Ok(())
+}
+
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test ca");
+    let ca_path = ca.write(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(
+        temp_dir.path(),
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let (client_cert, client_key) = ca.issue(
+        temp_dir.path(),
+        "client",
+        ExtendedKeyUsagePurpose::ClientAuth,
+    );
+    let [ca_path, cert, key, client_cert, client_key] =
+        [ca_path, cert, key, client_cert, client_key].map(|p| p.to_str().unwrap().to_owned());
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--addr",
+            "127.0.0.1:4142",
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &key,
+        ])
+        .args(["--tls-client-ca", &ca_path])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let tls_args = [
+        "--addr",
+        "127.0.0.1:4142",
+        "--tls-ca",
+        &ca_path,
+        "--tls-cert",
+        &client_cert,
+        "--tls-key",
+        &client_key,
+    ];
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            "127.0.0.1:4142",
+            "--tls-ca",
+            &ca_path,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
@@ -0,0 +1,98 @@
+use kvs::{Codec, KvStore, KvsClient, KvsEngine, Result, TypedKvsClient, TypedKvsEngine};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+mod common;
+use common::start_server;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+    manager: Option<Box<User>>,
+}
+
+fn user(name: &str) -> User {
+    User {
+        name: name.to_owned(),
+        age: 42,
+        tags: vec!["admin".to_owned(), "ops".to_owned()],
+        manager: Some(Box::new(User {
+            name: "boss".to_owned(),
+            age: 60,
+            tags: Vec::new(),
+            manager: None,
+        })),
+    }
+}
+
+fn codecs() -> Vec<Codec> {
+    vec![
+        Codec::Json,
+        #[cfg(feature = "bincode")]
+        Codec::Bincode,
+        #[cfg(feature = "msgpack")]
+        Codec::MessagePack,
+    ]
+}
+
+#[test]
+fn typed_engine_round_trips() -> Result<()> {
+    let dir = TempDir::new()?;
+    let engine = KvStore::open(dir.path())?;
+    for codec in codecs() {
+        let users = TypedKvsEngine::<_, User>::new(engine.clone(), codec);
+        let key = format!("user:{:?}", codec);
+        users.set(key.clone(), &user("ann"))?;
+        assert_eq!(users.get(key.clone())?, Some(user("ann")));
+        users.clone().remove(key.clone())?;
+        assert_eq!(users.get(key)?, None);
+    }
+
+    // JSON is stored as is, and values of another type do not decode
+    let users = TypedKvsEngine::<_, User>::new(engine.clone(), Codec::Json);
+    users.set("user".to_owned(), &user("bob"))?;
+    let stored = engine.get("user".to_owned())?.unwrap();
+    assert!(
+        stored.starts_with(r#"{"name":"bob","age":42"#),
+        "{}",
+        stored
+    );
+    let numbers = TypedKvsEngine::<_, u64>::new(engine, Codec::Json);
+    assert!(numbers.get("user".to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn typed_client_round_trips() -> Result<()> {
+    let _dir = start_server("127.0.0.1:4304", |_| {});
+
+    for codec in codecs() {
+        let mut users = TypedKvsClient::<User>::new(KvsClient::new("127.0.0.1:4304")?, codec);
+        users.set("ann".to_owned(), &user("ann"))?;
+        users.set("bob".to_owned(), &user("bob"))?;
+        assert_eq!(users.get("ann".to_owned())?, Some(user("ann")));
+        assert_eq!(
+            users.get_many(vec!["bob".to_owned(), "eve".to_owned()])?,
+            vec![Some(user("bob")), None]
+        );
+        users.remove("ann".to_owned())?;
+        assert_eq!(users.get("ann".to_owned())?, None);
+        assert_eq!(users.client_mut().scan(String::new())?, vec!["bob"]);
+        users.into_inner().remove("bob".to_owned())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn codecs_encode_to_strings() -> Result<()> {
+    let value = (1u8, "two".to_owned(), vec![3.5f64; 3]);
+    for codec in codecs() {
+        let encoded = codec.encode(&value)?;
+        assert_eq!(codec.decode::<(u8, String, Vec<f64>)>(&encoded)?, value);
+        assert!(codec.decode::<(u8, String, Vec<f64>)>("?!").is_err());
+    }
+    assert_eq!(Codec::default(), Codec::Json);
+    Ok(())
+}
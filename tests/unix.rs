@@ -0,0 +1,173 @@
+#![cfg(unix)]
+
+use assert_cmd::prelude::*;
+use kvs::net::Listener;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Error, KvStore, KvsClient, KvsServer, RateLimits, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn unix_socket_client() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server = KvsServer::new(engine, pool);
+    let listener = Listener::bind_unix(&socket, Some(0o600))?;
+    thread::spawn(move || server.run_listener(listener));
+    thread::sleep(Duration::from_millis(200));
+
+    let mode = fs::metadata(&socket)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the private directory it was bound in is gone
+    for entry in fs::read_dir(temp_dir.path())? {
+        let name = entry?.file_name();
+        assert!(
+            !name.to_string_lossy().starts_with(".kvs.sock"),
+            "{:?}",
+            name
+        );
+    }
+
+    let mut client = KvsClient::connect_unix(&socket)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Local clients are rate limited by process, not all together.
+#[test]
+fn unix_socket_clients_have_their_own_limits() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let mut server = KvsServer::new(engine, pool);
+    server.set_rate_limits(RateLimits {
+        ops_per_sec: Some(2),
+        ..RateLimits::default()
+    });
+    let listener = Listener::bind_unix(&socket, None)?;
+    thread::spawn(move || server.run_listener(listener));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect_unix(&socket)?;
+    client.get("key1".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(Error::RateLimited(_))
+    ));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--unix"])
+        .arg(&socket)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Key not found\n");
+    Ok(())
+}
+
+#[test]
+fn unix_socket_bind_replaces_stale_socket() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+
+    // a socket file without a listener behind it
+    drop(UnixListener::bind(&socket)?);
+    assert!(socket.exists());
+    let listener = Listener::bind_unix(&socket, None)?;
+
+    // a live listener is not replaced
+    assert!(Listener::bind_unix(&socket, None).is_err());
+
+    drop(listener);
+    assert!(!socket.exists());
+
+    fs::write(&socket, "not a socket")?;
+    assert!(Listener::bind_unix(&socket, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let socket = socket.to_str().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--addr",
+            "127.0.0.1:4130",
+            "--unix",
+            socket,
+            "--unix-mode",
+            "660",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mode = fs::metadata(socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o660);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--unix", socket])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--unix", socket])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // the TCP listener serves the same engine
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4130"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A redirect to the leader is not followed over TCP, which would send the
+// credentials elsewhere than the socket the client was given.
+#[test]
+fn unix_socket_client_is_not_redirected() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let listener = UnixListener::bind(&socket)?;
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        while stream.read(&mut buf).unwrap() > 0 {
+            let rsp = r#"{"body":{"NotLeader":{"leader":"127.0.0.1:4131"}}}"#;
+            stream.write_all(rsp.as_bytes()).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::connect_unix(&socket)?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(Error::NotLeader(leader)) => assert_eq!(leader.as_deref(), Some("127.0.0.1:4131")),
+        res => panic!("unexpected {:?}", res),
+    }
+    Ok(())
+}